@@ -1,13 +1,55 @@
+//! C bindings for cliodb.
+//!
+//! Memory ownership: values handed to a callback (`query`,
+//! `entity_get`, `pull`) are borrowed for the duration of the call
+//! and freed once it returns. A caller that wants to keep one copies
+//! it out with `cliodb_value_copy` and later releases the copy with
+//! `cliodb_value_free`. Functions that return values through an out
+//! pointer, like `query_collect`, transfer ownership of the whole
+//! array, which is released with `cliodb_row_free`.
+//!
+//! Threads: connections and dbs are referred to by handles rather
+//! than pointers, and every handle may be used from any thread.
+//! Calls on the same connection are serialized; a db is an immutable
+//! snapshot, so calls on it run concurrently. Callbacks run on the
+//! calling thread. Closing a handle that another thread is using
+//! lets that call finish, after which the handle is invalid. Calls
+//! with an invalid handle, including one that has already been
+//! closed, return `CLIODB_INVALID_HANDLE`.
+
 extern crate cliodb;
 extern crate zmq;
 
+mod registry;
+
 use std::ffi::{CStr, CString};
-use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
 use std::os::raw::{c_char, c_int, c_long};
 
-use cliodb::{Result, Value, Relation, TxReport};
+use cliodb::{Result, Value, Relation, TxReport, QueryBuilder, var};
 use cliodb::conn::{Conn, store_from_uri};
 use cliodb::db::Db;
+use registry::{Handle, Registry};
+
+pub const CLIODB_OK: c_int = 0;
+pub const CLIODB_ERROR: c_int = -1;
+pub const CLIODB_INVALID_HANDLE: c_int = -2;
+
+static CONNS: Registry<Mutex<Conn>> = Registry::new();
+static DBS: Registry<Db> = Registry::new();
+
+/// Resolves a handle, returning `CLIODB_INVALID_HANDLE` from the
+/// calling function if it isn't live.
+macro_rules! resolve {
+    ( $registry:expr, $handle:expr ) => {
+        match $registry.get($handle) {
+            Some(value) => value,
+            None => return CLIODB_INVALID_HANDLE,
+        }
+    }
+}
 
 fn conn_from_c_strings(store_uri: &CStr, tx_addr: &CStr) -> Result<Conn> {
     let store = store_from_uri(store_uri.to_str()?)?;
@@ -17,50 +59,51 @@ fn conn_from_c_strings(store_uri: &CStr, tx_addr: &CStr) -> Result<Conn> {
 }
 
 #[no_mangle]
-pub extern "C" fn connect(uri_ptr: *mut c_char, tx_ptr: *mut c_char, ret_ptr: *mut *mut Conn) -> c_int {
+pub extern "C" fn connect(uri_ptr: *mut c_char, tx_ptr: *mut c_char, ret_ptr: *mut Handle) -> c_int {
     let uri = unsafe { CStr::from_ptr(uri_ptr) };
     let tx_addr = unsafe { CStr::from_ptr(tx_ptr) };
     match conn_from_c_strings(uri, tx_addr) {
         Ok(conn) => {
-            unsafe { *ret_ptr = mem::transmute(Box::new(conn)) };
-            return 0;
+            unsafe { *ret_ptr = CONNS.insert(Mutex::new(conn)) };
+            CLIODB_OK
         }
         Err(e) => {
             println!("{:?}", e);
-            return -1;
+            CLIODB_ERROR
         }
     }
 }
 
 #[no_mangle]
-pub extern "C" fn get_db(conn_ptr: *mut Conn, ret_ptr: *mut *mut Db) -> c_int {
-    let db_result = unsafe { (*conn_ptr).db() };
+pub extern "C" fn get_db(conn: Handle, ret_ptr: *mut Handle) -> c_int {
+    let conn = resolve!(CONNS, conn);
+    let db_result = conn.lock().unwrap().db();
     match db_result {
         Ok(db) => {
-            unsafe { *ret_ptr = mem::transmute(Box::new(db)) };
-            return 0;
+            unsafe { *ret_ptr = DBS.insert(db) };
+            CLIODB_OK
         }
         Err(e) => {
             println!("{:?}", e);
-            return -1;
+            CLIODB_ERROR
         }
     }
 }
 
 #[no_mangle]
-pub extern "C" fn drop_db(db: *mut Db) -> c_int {
-    unsafe {
-        let _ = Box::from_raw(db);
+pub extern "C" fn drop_db(db: Handle) -> c_int {
+    match DBS.remove(db) {
+        Some(_) => CLIODB_OK,
+        None => CLIODB_INVALID_HANDLE,
     }
-
-    0
 }
 
 #[no_mangle]
 /// Drops the connection created by `connect`.
-pub extern "C" fn close(conn: *mut Conn) {
-    unsafe {
-        let _ = Box::from_raw(conn);
+pub extern "C" fn close(conn: Handle) -> c_int {
+    match CONNS.remove(conn) {
+        Some(_) => CLIODB_OK,
+        None => CLIODB_INVALID_HANDLE,
     }
 }
 
@@ -80,10 +123,11 @@ impl<'a> From<&'a Value> for CValue {
         match *v {
             Value::String(ref s) => CValue::string(s),
             Value::Ref(cliodb::Entity(e)) => CValue::entity(e),
-            Value::Ident(ref i) => CValue::string(i),
+            Value::Ident(ref i) => CValue { tag: ValueTag::Ident, ..CValue::string(i) },
             Value::Timestamp(t) => CValue::string(&t.to_string()),
             Value::Boolean(b) => CValue::boolean(b),
             Value::Long(l) => CValue::long(l),
+            Value::Geo(g) => CValue::string(&g.to_string()),
         }
     }
 }
@@ -96,9 +140,8 @@ pub struct CValue {
     int_val: c_long,
 }
 
-// These functions are leaky to facilitate passing the resulting
-// structs over the FFI.  You MUST reclaim the string_val field with
-// `CString::from_raw` in order to avoid a memory leak.
+// Every CValue owns its string_val (an empty string for values
+// that aren't strings), which is reclaimed by `free_string`.
 impl CValue {
     fn string(val: &str) -> CValue {
         CValue {
@@ -126,76 +169,276 @@ impl CValue {
 
     fn long(val: i64) -> CValue {
         CValue {
-            tag: ValueTag::Entity,
+            tag: ValueTag::Long,
             string_val: CString::default().into_raw(),
             int_val: val as c_long,
         }
     }
+
+    /// Frees the string, leaving a null pointer so that freeing the
+    /// value again is harmless.
+    unsafe fn free_string(&mut self) {
+        if !self.string_val.is_null() {
+            let _ = CString::from_raw(self.string_val as *mut c_char);
+            self.string_val = ptr::null();
+        }
+    }
+}
+
+/// Passes `values` to `f` as CValues, freeing their strings once it
+/// returns.
+fn with_c_values<F: FnOnce(&[CValue])>(values: &[Value], f: F) {
+    let mut c_values: Vec<CValue> = values.iter().map(|v| v.into()).collect();
+    f(&c_values);
+    for val in c_values.iter_mut() {
+        unsafe { val.free_string() };
+    }
+}
+
+/// Copies a borrowed value (e.g. one passed to a callback) into
+/// `dst`, which the caller then owns and frees with
+/// `cliodb_value_free`.
+#[no_mangle]
+pub extern "C" fn cliodb_value_copy(src: *const CValue, dst: *mut CValue) -> c_int {
+    if src.is_null() || dst.is_null() {
+        return CLIODB_ERROR;
+    }
+    let src = unsafe { &*src };
+    let string_val = if src.string_val.is_null() {
+        ptr::null()
+    } else {
+        unsafe { CStr::from_ptr(src.string_val) }.to_owned().into_raw() as *const c_char
+    };
+    unsafe {
+        ptr::write(dst, CValue { string_val, ..src.clone() });
+    }
+    CLIODB_OK
+}
+
+/// Frees the string owned by a value copied with `cliodb_value_copy`.
+/// The CValue itself belongs to the caller.
+#[no_mangle]
+pub extern "C" fn cliodb_value_free(value: *mut CValue) {
+    if !value.is_null() {
+        unsafe { (*value).free_string() };
+    }
+}
+
+/// Frees an array of `len` values returned by cliodb, along with
+/// their strings.
+#[no_mangle]
+pub extern "C" fn cliodb_row_free(row: *mut CValue, len: c_int) {
+    if row.is_null() {
+        return;
+    }
+    unsafe {
+        let mut values = Box::from_raw(slice::from_raw_parts_mut(row, len as usize));
+        for value in values.iter_mut() {
+            value.free_string();
+        }
+    }
+}
+
+/// Runs a query and copies its results out as one row-major array of
+/// `*num_rows` rows of `*num_columns` values each, which the caller
+/// frees with `cliodb_row_free(*rows, *num_rows * *num_columns)`.
+#[no_mangle]
+pub extern "C" fn query_collect(
+    db: Handle,
+    query_string_ptr: *const c_char,
+    rows: *mut *mut CValue,
+    num_rows: *mut c_int,
+    num_columns: *mut c_int,
+) -> c_int {
+    let db = resolve!(DBS, db);
+    let query_str = unsafe { CStr::from_ptr(query_string_ptr) };
+    let result = query_str.to_str()
+        .map_err(|e| e.into())
+        .and_then(|q| cliodb::parse_query(q).map_err(|e| e.into()))
+        .and_then(|q| cliodb::query(q, &db));
+
+    match result {
+        Ok(Relation(vars, tuples)) => {
+            let values: Vec<CValue> = tuples.iter().flat_map(|row| row.iter().map(|v| v.into())).collect();
+            unsafe {
+                *num_rows = tuples.len() as c_int;
+                *num_columns = vars.len() as c_int;
+                *rows = Box::into_raw(values.into_boxed_slice()) as *mut CValue;
+            }
+            CLIODB_OK
+        }
+        Err(e) => {
+            println!("error {:?}", e);
+            CLIODB_ERROR
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn query(
-    db_ptr: *mut Db,
+    db: Handle,
     query_string_ptr: *const c_char,
     cb: extern "C" fn(num_items: c_int, row: *const CValue),
 ) -> c_int {
-    let db: &Db = unsafe { &*db_ptr };
+    let db = resolve!(DBS, db);
     let query_str = unsafe { CStr::from_ptr(query_string_ptr) };
     let q = match cliodb::parse_query(query_str.to_str().unwrap()) {
         Ok(q) => q,
         Err(err) => {
             // FIXME: implement a more robust way to retrieve error msgs
             println!("error {}", err);
-            return -1;
+            return CLIODB_ERROR;
         }
     };
 
     match cliodb::query(q, &db) {
         Ok(Relation(vars, rows)) => {
             for row in rows {
-                let row_vec: Vec<CValue> = row.iter().map(|v| v.into()).collect();
-                cb(vars.len() as i32, row_vec.as_ptr());
-                // Free the CString's value.
-                for val in row_vec {
-                    unsafe {
-                        CString::from_raw(val.string_val as *mut i8);
-                    }
-                }
+                with_c_values(&row, |row| cb(vars.len() as i32, row.as_ptr()));
             }
-            return 0;
+            return CLIODB_OK;
+        }
+        Err(e) => {
+            println!("error {:?}", e);
+            return CLIODB_ERROR;
+        }
+    }
+}
+
+/// Calls `cb` once with all of the entity's values for the attribute
+/// `attr_ptr`, in sorted order. An entity without any calls it with
+/// no values.
+#[no_mangle]
+pub extern "C" fn entity_get(
+    db: Handle,
+    entity: c_long,
+    attr_ptr: *const c_char,
+    cb: extern "C" fn(num_values: c_int, values: *const CValue),
+) -> c_int {
+    let db = resolve!(DBS, db);
+    let attr = unsafe { CStr::from_ptr(attr_ptr) };
+    let values = attr.to_str()
+        .map_err(|e| e.into())
+        .and_then(|attr| entity_values(&db, entity, attr));
+
+    match values {
+        Ok(values) => {
+            with_c_values(&values, |values| cb(values.len() as c_int, values.as_ptr()));
+            CLIODB_OK
+        }
+        Err(e) => {
+            println!("error {:?}", e);
+            CLIODB_ERROR
+        }
+    }
+}
+
+fn entity_values(db: &Db, entity: c_long, attr: &str) -> Result<Vec<Value>> {
+    let q = QueryBuilder::find(&["?v"])
+        .where_clause(cliodb::Entity(entity as i64), attr, var("v"))
+        .build()?;
+    let Relation(_, tuples) = cliodb::query(q, db)?;
+    let mut values: Vec<Value> = tuples.into_iter().map(|mut t| t.remove(0)).collect();
+    values.sort();
+    Ok(values)
+}
+
+/// Calls `cb` once per attribute of the entity named by `spec_ptr`
+/// (as for `resolve_entity`) matched by `pattern_ptr`, with the
+/// attribute's ident and all of its values. The pattern is `*` for
+/// every attribute, or a space-separated list of attribute idents;
+/// attributes the entity has no values for are skipped.
+#[no_mangle]
+pub extern "C" fn pull(
+    db: Handle,
+    spec_ptr: *const c_char,
+    pattern_ptr: *const c_char,
+    cb: extern "C" fn(attr: *const c_char, num_values: c_int, values: *const CValue),
+) -> c_int {
+    let db = resolve!(DBS, db);
+    let pattern = match unsafe { CStr::from_ptr(pattern_ptr) }.to_str() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            println!("error {:?}", e);
+            return CLIODB_ERROR;
+        }
+    };
+
+    let spec = unsafe { CStr::from_ptr(spec_ptr) };
+    let attributes = spec.to_str()
+        .map_err(|e| e.into())
+        .and_then(cliodb::parse_entity_spec)
+        .and_then(|spec| db.entity(spec));
+    let attributes = match attributes {
+        Ok(attributes) => attributes,
+        Err(e) => {
+            println!("error {:?}", e);
+            return CLIODB_ERROR;
+        }
+    };
+    let mut idents: Vec<&String> = attributes.keys()
+        .filter(|ident| pattern.trim() == "*" || pattern.split_whitespace().any(|p| p == ident.as_str()))
+        .collect();
+    idents.sort();
+
+    for ident in idents {
+        let attr = CString::new(ident.as_str()).unwrap();
+        let values = &attributes[ident];
+        with_c_values(values, |values| cb(attr.as_ptr(), values.len() as c_int, values.as_ptr()));
+    }
+    CLIODB_OK
+}
+
+/// Writes the id of the entity named by `spec_ptr` to `ret_ptr`, for
+/// passing to `entity_get`. The spec is an entity id, an ident, or a
+/// lookup ref such as `[email "bob@x.com"]`.
+#[no_mangle]
+pub extern "C" fn resolve_entity(db: Handle, spec_ptr: *const c_char, ret_ptr: *mut c_long) -> c_int {
+    let db = resolve!(DBS, db);
+    let spec = unsafe { CStr::from_ptr(spec_ptr) };
+    let entity = spec.to_str()
+        .map_err(|e| e.into())
+        .and_then(cliodb::parse_entity_spec)
+        .and_then(|spec| db.resolve(&spec));
+
+    match entity {
+        Ok(cliodb::Entity(id)) => {
+            unsafe { *ret_ptr = id as c_long };
+            CLIODB_OK
         }
         Err(e) => {
             println!("error {:?}", e);
-            return -1;
+            CLIODB_ERROR
         }
     }
 }
 
 #[no_mangle]
-pub extern "C" fn transact(conn_ptr: *mut Conn, tx_ptr: *const c_char) -> c_int {
-    let conn: &Conn = unsafe { &*conn_ptr };
+pub extern "C" fn transact(conn: Handle, tx_ptr: *const c_char) -> c_int {
+    let conn = resolve!(CONNS, conn);
     let tx_str = unsafe { CStr::from_ptr(tx_ptr) };
     let tx = match cliodb::parse_tx(tx_str.to_str().unwrap()) {
         Ok(tx) => tx,
         // FIXME: signal error
         Err(e) => {
             println!("error {:?}", e);
-            return -1;
+            return CLIODB_ERROR;
         }
     };
 
-    match conn.transact(tx) {
+    let result = conn.lock().unwrap().transact(tx);
+    match result {
         // FIXME: Return list of new entities
         // (via result callback like query?)
-        Ok(TxReport::Success { .. }) => return 0,
+        Ok(TxReport::Success { .. }) => return CLIODB_OK,
         // FIXME: Signal error
         Ok(TxReport::Failure(f)) => {
             println!("error {:?}", f);
-            return -1;
+            return CLIODB_ERROR;
         },
         Err(e) => {
             println!("error {:?}", e);
-            return -1;
+            return CLIODB_ERROR;
         },
     }
 }
@@ -0,0 +1,99 @@
+//! Maps the opaque handles given out over the FFI to the objects
+//! they stand for.
+//!
+//! A handle packs a slot index with the slot's generation, which is
+//! bumped whenever the slot is emptied, so a handle that has been
+//! closed (or was never valid) fails to resolve instead of pointing
+//! at freed or reused memory.
+
+use std::sync::{Arc, Mutex};
+
+pub type Handle = u64;
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+struct Slots<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+pub struct Registry<T> {
+    slots: Mutex<Slots<T>>,
+}
+
+impl<T> Registry<T> {
+    pub const fn new() -> Registry<T> {
+        Registry { slots: Mutex::new(Slots { slots: Vec::new(), free: Vec::new() }) }
+    }
+
+    pub fn insert(&self, value: T) -> Handle {
+        let mut slots = self.slots.lock().unwrap();
+        let index = match slots.free.pop() {
+            Some(index) => index,
+            None => {
+                // Generations start at 1, so 0 is never a valid handle.
+                slots.slots.push(Slot { generation: 1, value: None });
+                slots.slots.len() - 1
+            }
+        };
+        let slot = &mut slots.slots[index];
+        slot.value = Some(Arc::new(value));
+        (u64::from(slot.generation) << 32) | index as u64
+    }
+
+    /// Returns the object behind a live handle. The object stays
+    /// alive while the returned Arc is held, even if the handle is
+    /// removed by another thread in the meantime.
+    pub fn get(&self, handle: Handle) -> Option<Arc<T>> {
+        let slots = self.slots.lock().unwrap();
+        let (index, generation) = split(handle);
+        slots.slots.get(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.value.clone())
+    }
+
+    /// Invalidates a handle, returning its object if it was live.
+    pub fn remove(&self, handle: Handle) -> Option<Arc<T>> {
+        let mut slots = self.slots.lock().unwrap();
+        let (index, generation) = split(handle);
+        let value = match slots.slots.get_mut(index) {
+            Some(slot) if slot.generation == generation && slot.value.is_some() => {
+                slot.generation = slot.generation.wrapping_add(1).max(1);
+                slot.value.take()
+            }
+            _ => return None,
+        };
+        slots.free.push(index);
+        value
+    }
+}
+
+fn split(handle: Handle) -> (usize, u32) {
+    ((handle & 0xffff_ffff) as usize, (handle >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_handles() {
+        let registry = Registry::new();
+        let a = registry.insert("a");
+        assert_eq!(registry.get(a).map(|v| *v), Some("a"));
+        assert!(registry.get(0).is_none());
+
+        assert!(registry.remove(a).is_some());
+        assert!(registry.remove(a).is_none());
+        assert!(registry.get(a).is_none());
+
+        // The slot is reused, but the old handle doesn't reach it.
+        let b = registry.insert("b");
+        assert_ne!(a, b);
+        assert!(registry.get(a).is_none());
+        assert_eq!(registry.get(b).map(|v| *v), Some("b"));
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::sync::Arc;
+
+use im::HashSet;
+
+use {Entity, Record, Value};
+use schema::Schema;
+
+/// Visibility rules for a single client of the database. A policy
+/// is attached to a Conn (or directly to a Db via `Db::restrict`),
+/// and every record read through the resulting Db is checked
+/// against it, so that datoms the client isn't allowed to see never
+/// make it into query results.
+///
+/// Attributes are denied by namespace, i.e. the part of the ident
+/// before the first `:`; denying `secret` hides `secret:ssn` and
+/// `secret:salary` but not `secretary`. Entities are denied by
+/// predicate, and a denied entity is hidden both as the subject of a
+/// record and as the target of a ref.
+#[derive(Clone, Default)]
+pub struct AccessPolicy {
+    denied_namespaces: Vec<String>,
+    denied_entities: Vec<Arc<dyn Fn(Entity) -> bool + Send + Sync>>,
+}
+
+impl AccessPolicy {
+    /// Returns a policy that allows everything.
+    pub fn new() -> AccessPolicy {
+        AccessPolicy::default()
+    }
+
+    pub fn deny_namespace<S: Into<String>>(mut self, namespace: S) -> AccessPolicy {
+        self.denied_namespaces.push(namespace.into());
+        self
+    }
+
+    pub fn deny_entities<F>(mut self, predicate: F) -> AccessPolicy
+    where
+        F: Fn(Entity) -> bool + Send + Sync + 'static,
+    {
+        self.denied_entities.push(Arc::new(predicate));
+        self
+    }
+
+    pub fn denies_ident(&self, ident: &str) -> bool {
+        let namespace = match ident.find(':') {
+            Some(idx) => &ident[..idx],
+            None => return false,
+        };
+
+        self.denied_namespaces.iter().any(|ns| ns == namespace)
+    }
+
+    pub fn denies_entity(&self, entity: Entity) -> bool {
+        self.denied_entities.iter().any(|denied| denied(entity))
+    }
+}
+
+impl fmt::Debug for AccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessPolicy")
+            .field("denied_namespaces", &self.denied_namespaces)
+            .field("denied_entities", &self.denied_entities.len())
+            .finish()
+    }
+}
+
+/// An access policy resolved against a particular schema. Resolving
+/// the denied namespaces to attribute entities up front keeps the
+/// per-record check cheap.
+#[derive(Clone, Debug)]
+pub struct AccessFilter {
+    policy: Arc<AccessPolicy>,
+    denied_attributes: HashSet<Entity>,
+}
+
+impl AccessFilter {
+    pub fn new(policy: Arc<AccessPolicy>, schema: &Schema) -> AccessFilter {
+        // An attribute is denied if any of its names are.
+        let denied_attributes = schema.idents
+            .iter()
+            .chain(schema.aliases.iter())
+            .filter(|(ident, _)| policy.denies_ident(ident))
+            .map(|(_, entity)| *entity)
+            .collect();
+
+        AccessFilter { policy, denied_attributes }
+    }
+
+    pub fn policy(&self) -> Arc<AccessPolicy> {
+        self.policy.clone()
+    }
+
+    pub fn allows(&self, record: &Record) -> bool {
+        if self.denied_attributes.contains(&record.attribute) {
+            return false;
+        }
+
+        if self.policy.denies_entity(record.entity) {
+            return false;
+        }
+
+        match record.value {
+            Value::Ref(e) => !self.policy.denies_entity(e),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denies_ident_by_namespace() {
+        let policy = AccessPolicy::new().deny_namespace("secret");
+        assert!(policy.denies_ident("secret:ssn"));
+        assert!(!policy.denies_ident("secretary"));
+        assert!(!policy.denies_ident("name"));
+        assert!(!policy.denies_ident("db:ident"));
+    }
+
+    #[test]
+    fn test_filter_hides_refs_to_denied_entities() {
+        let policy = Arc::new(AccessPolicy::new().deny_entities(|e| e == Entity(1)));
+        let filter = AccessFilter::new(policy, &Schema::empty());
+
+        assert!(!filter.allows(&Record::addition(Entity(1), Entity(5), "x", Entity(0))));
+        assert!(!filter.allows(&Record::addition(Entity(2), Entity(5), Entity(1), Entity(0))));
+        assert!(filter.allows(&Record::addition(Entity(2), Entity(5), Entity(3), Entity(0))));
+    }
+}
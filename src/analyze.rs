@@ -0,0 +1,292 @@
+//! Attribute usage analytics, to guide decisions about which
+//! attributes to index, mark `db:noHistory` or retire.
+//!
+//! `analyze` makes one pass over a db's AEVT index and reports, for
+//! each attribute, how many datoms it currently has, how many are
+//! stored (counting retractions and the values they retract, which
+//! `db:noHistory` would save), how many were added by recent
+//! transactions, and the sizes of its values as stored. Attributes
+//! in the schema with no current datoms are reported as unused, and
+//! breaches of the soft limits in `lint` as warnings. `clio-admin
+//! analyze` prints the report.
+
+use std::fmt;
+
+use chrono::prelude::{DateTime, Utc};
+use rmp_serde;
+
+use db::Db;
+use lint::{MAX_INDEXED_STRING_BYTES, MAX_VALUES_PER_ENTITY};
+use {Entity, Record, Result, Value};
+
+/// Value sizes are counted in power-of-two buckets: bucket `i` holds
+/// the values of at most 2^i bytes that don't fit in bucket `i - 1`.
+const SIZE_BUCKETS: usize = 33;
+
+/// The distribution of the encoded sizes of an attribute's values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeDistribution {
+    pub count: u64,
+    pub total: u64,
+    pub max: u64,
+    buckets: [u64; SIZE_BUCKETS],
+}
+
+impl Default for SizeDistribution {
+    fn default() -> SizeDistribution {
+        SizeDistribution { count: 0, total: 0, max: 0, buckets: [0; SIZE_BUCKETS] }
+    }
+}
+
+impl SizeDistribution {
+    pub fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.total += size;
+        self.max = self.max.max(size);
+        let bucket = (64 - size.saturating_sub(1).leading_zeros()) as usize;
+        self.buckets[bucket.min(SIZE_BUCKETS - 1)] += 1;
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// An upper bound on the size of the smallest `fraction` of the
+    /// values, to within a factor of two.
+    pub fn percentile(&self, fraction: f64) -> u64 {
+        let target = (self.count as f64 * fraction).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target.max(1) {
+                return (1u64 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// How one attribute is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeUsage {
+    pub entity: Entity,
+    pub ident: String,
+    pub indexed: bool,
+    pub no_history: bool,
+    /// Datoms asserted and not since retracted.
+    pub datoms: u64,
+    /// Every datom in the index, including retractions and the
+    /// assertions they retracted.
+    pub stored: u64,
+    /// Datoms added (asserted or retracted) by the recent
+    /// transactions.
+    pub recent: u64,
+    /// The sizes of the current values, as stored.
+    pub sizes: SizeDistribution,
+    /// The most current values any one entity has.
+    pub max_per_entity: u64,
+}
+
+impl AttributeUsage {
+    fn new(db: &Db, entity: Entity) -> AttributeUsage {
+        AttributeUsage {
+            entity,
+            ident: db.ident_for(entity).map_or_else(|| entity.0.to_string(), String::from),
+            indexed: db.schema.is_indexed(entity),
+            no_history: db.schema.no_history.contains(&entity),
+            datoms: 0,
+            stored: 0,
+            recent: 0,
+            sizes: SizeDistribution::default(),
+            max_per_entity: 0,
+        }
+    }
+}
+
+/// The result of `analyze`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    /// The number of transactions counted as recent, and when the
+    /// first of them was made.
+    pub recent_txs: usize,
+    pub recent_since: Option<DateTime<Utc>>,
+    /// Every attribute with datoms or in the schema, with the most
+    /// datoms first.
+    pub attributes: Vec<AttributeUsage>,
+}
+
+impl UsageReport {
+    /// Attributes in the schema which have no current datoms, apart
+    /// from the built-in `db:` ones.
+    pub fn unused(&self) -> Vec<&str> {
+        self.attributes
+            .iter()
+            .filter(|a| a.datoms == 0 && !a.ident.starts_with("db:"))
+            .map(|a| a.ident.as_str())
+            .collect()
+    }
+
+    /// Attributes which break the soft limits in `lint`.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        for a in &self.attributes {
+            if a.indexed && a.sizes.max > MAX_INDEXED_STRING_BYTES as u64 {
+                warnings.push(format!("{} is indexed but has values of up to {} bytes", a.ident, a.sizes.max));
+            }
+            if a.max_per_entity > MAX_VALUES_PER_ENTITY {
+                warnings.push(format!("{} has {} values on a single entity", a.ident, a.max_per_entity));
+            }
+        }
+        warnings
+    }
+}
+
+/// Analyzes the attributes of `db`, counting the last `recent_txs`
+/// transactions as recent.
+pub fn analyze(db: &Db, recent_txs: usize) -> Result<UsageReport> {
+    let (first_recent, recent_since) = recent_window(db, recent_txs)?;
+
+    let mut attributes: Vec<AttributeUsage> = vec![];
+    let mut records = db.aev.iter().peekable();
+    // The entity whose datoms are being counted, and how many of
+    // them are current.
+    let mut entity_values = (Entity(-1), 0);
+    while let Some(first) = records.next() {
+        if attributes.last().map(|a| a.entity) != Some(first.attribute) {
+            attributes.push(AttributeUsage::new(db, first.attribute));
+            entity_values = (Entity(-1), 0);
+        }
+        let usage = attributes.last_mut().unwrap();
+
+        // Records for the same datom are together, oldest first, so
+        // the last says whether it's current.
+        let same_datom = |r: &Record| r.entity == first.entity && r.attribute == first.attribute && r.value == first.value;
+        let mut last = first.clone();
+        for record in ::std::iter::once(first.clone()).chain(::std::iter::from_fn(|| records.next_if(same_datom))) {
+            usage.stored += 1;
+            if record.tx.0 >= first_recent {
+                usage.recent += 1;
+            }
+            last = record;
+        }
+        if !last.retracted {
+            usage.datoms += 1;
+            usage.sizes.add(encoded_size(&last.value)?);
+            if entity_values.0 != last.entity {
+                entity_values = (last.entity, 0);
+            }
+            entity_values.1 += 1;
+            usage.max_per_entity = usage.max_per_entity.max(entity_values.1);
+        }
+    }
+
+    for &(_, entity) in db.schema.idents.iter() {
+        if db.schema.value_types.contains_key(&entity) && !attributes.iter().any(|a| a.entity == entity) {
+            attributes.push(AttributeUsage::new(db, entity));
+        }
+    }
+    attributes.sort_by(|a, b| b.datoms.cmp(&a.datoms).then_with(|| a.ident.cmp(&b.ident)));
+
+    Ok(UsageReport { recent_txs, recent_since, attributes })
+}
+
+/// The id of the first of the last `count` transactions, and when it
+/// was made. Transactions are found by their `db:txTimestamp`.
+fn recent_window(db: &Db, count: usize) -> Result<(i64, Option<DateTime<Utc>>)> {
+    let attr = db.schema.resolve("db:txTimestamp").ok_or("db:txTimestamp is missing from the schema")?;
+    if count == 0 {
+        return Ok((i64::MAX, None));
+    }
+
+    let start = Record::addition(Entity(0), attr, Value::String("".into()), Entity(0));
+    let mut txs: Vec<(i64, Value)> = db.aev
+        .range_from(start)
+        .take_while(|rec| rec.attribute == attr)
+        .filter(|rec| !rec.retracted)
+        .map(|rec| (rec.entity.0, rec.value))
+        .collect();
+    txs.sort_by_key(|tx| tx.0);
+
+    match txs.len().checked_sub(count).and_then(|i| txs.get(i)).or_else(|| txs.first()) {
+        Some(&(id, Value::Timestamp(time))) => Ok((id, Some(time))),
+        Some(&(id, _)) => Ok((id, None)),
+        None => Ok((i64::MAX, None)),
+    }
+}
+
+fn encoded_size(value: &Value) -> Result<u64> {
+    Ok(rmp_serde::to_vec(value)?.len() as u64)
+}
+
+impl fmt::Display for UsageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.recent_since {
+            Some(time) => writeln!(f, "Recent: the last {} transactions, since {}", self.recent_txs, time.to_rfc3339())?,
+            None => writeln!(f, "Recent: the last {} transactions", self.recent_txs)?,
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<32} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8}  flags",
+            "attribute", "datoms", "stored", "recent", "avg B", "p50 B", "p99 B", "max B"
+        )?;
+        for a in &self.attributes {
+            let mut flags = vec![];
+            if a.indexed {
+                flags.push("indexed");
+            }
+            if a.no_history {
+                flags.push("noHistory");
+            }
+            writeln!(
+                f,
+                "{:<32} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8}  {}",
+                a.ident,
+                a.datoms,
+                a.stored,
+                a.recent,
+                a.sizes.mean(),
+                a.sizes.percentile(0.5),
+                a.sizes.percentile(0.99),
+                a.sizes.max,
+                flags.join(",")
+            )?;
+        }
+
+        let unused = self.unused();
+        if !unused.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Unused: {}", unused.join(", "))?;
+        }
+
+        let warnings = self.warnings();
+        if !warnings.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Warnings:")?;
+            for warning in warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_distribution() {
+        let mut sizes = SizeDistribution::default();
+        for size in 1..=100 {
+            sizes.add(size);
+        }
+        sizes.add(5000);
+
+        assert_eq!((sizes.count, sizes.max, sizes.mean()), (101, 5000, 99));
+        assert_eq!(sizes.percentile(0.5), 64);
+        assert_eq!(sizes.percentile(0.99), 128);
+        assert_eq!(sizes.percentile(1.0), 5000);
+        assert_eq!(SizeDistribution::default().percentile(0.5), 0);
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use {Error, Result, KVStore};
+use tx::TxRaw;
+use backends::TxStream;
+
+/// A store that keeps everything in memory, for tests and for
+/// embedding a throwaway database. Its contents are lost when it's
+/// dropped.
+#[derive(Default)]
+pub struct MemStore {
+    kvs: Mutex<HashMap<String, Vec<u8>>>,
+    /// The log, by position.
+    txs: Arc<Mutex<BTreeMap<i64, TxRaw>>>,
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        MemStore::default()
+    }
+}
+
+fn fetch_txs(txs: &BTreeMap<i64, TxRaw>, from: i64, limit: usize) -> Vec<TxRaw> {
+    txs.range(from.saturating_add(1)..).take(limit).map(|(_, tx)| tx.clone()).collect()
+}
+
+impl KVStore for MemStore {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match self.kvs.lock()?.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => Err("key not found".into()),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.kvs.lock()?.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.kvs.lock()?.remove(key);
+        Ok(())
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+        let mut kvs = self.kvs.lock()?;
+        if kvs.get(key).map(|v| &v[..]) != expected {
+            return Err(Error::Conflict { key: key.to_string() });
+        }
+        kvs.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+        Ok(fetch_txs(&*self.txs.lock()?, from, usize::MAX))
+    }
+
+    fn stream_txs(&self, from: i64) -> TxStream {
+        let txs = self.txs.clone();
+        TxStream::new(from, Box::new(move |after, limit| Ok(fetch_txs(&*txs.lock()?, after, limit))))
+    }
+
+    fn add_tx(&self, tx: &TxRaw) -> Result<()> {
+        let mut txs = self.txs.lock()?;
+        if txs.values().any(|t| t.epoch > tx.epoch) {
+            return Err(format!("tx {} rejected: writer epoch {} has been fenced off", tx.id, tx.epoch).into());
+        }
+        if txs.contains_key(&tx.seq) || txs.values().any(|t| t.id == tx.id) {
+            return Err(format!("tx {} already exists", tx.id).into());
+        }
+        txs.insert(tx.seq, tx.clone());
+        Ok(())
+    }
+}
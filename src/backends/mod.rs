@@ -1,11 +1,15 @@
 pub mod sqlite;
 pub mod mysql;
+pub mod mem;
 
+use std::collections::VecDeque;
 use std::marker::{Send, Sync};
+use std::thread;
+use std::time::Duration;
 
 use db::DbMetadata;
 use tx::TxRaw;
-use super::Result;
+use super::{Error, Result};
 
 /// Abstracts over various backends; all that's required for a ClioDB
 /// backend is the ability to add a key, retrieve a key, and
@@ -13,28 +17,181 @@ use super::Result;
 pub trait KVStore: Send + Sync {
     /// Set a value in the store. This method implies only eventual consistency;
     /// use `compare_and_set` when consistency is required.
-    // FIXME: This is currently used for setting db_metadata as well as index segments,
-    // which isn't ACID-safe.
     fn set(&self, key: &str, value: &[u8]) -> Result<()>;
 
-    // TODO: implement: fn compare_and_set for db metadata
+    /// Sets `key` to `value` only if its current value is `expected`
+    /// (`None` meaning it isn't set), and fails with
+    /// `Error::Conflict` otherwise. The check and the write are
+    /// atomic.
+    fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()>;
 
     /// Get a value out of the store.
     fn get(&self, key: &str) -> Result<Vec<u8>>;
 
+    /// Remove a value from the store. Deleting a key which isn't set
+    /// isn't an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
     // FIXME: return a Result<Option<DbMetadata>>
     fn get_metadata(&self) -> Result<DbMetadata> {
-        let serialized = self.get("db_metadata")?;
-        let metadata: DbMetadata = rmp_serde::from_read_ref(&serialized)?;
-        Ok(metadata)
+        DbMetadata::from_stored(&self.get("db_metadata")?)
+    }
+
+    /// Returns the metadata if its version is newer than `version`,
+    /// which only costs reading the version when it isn't. Metadata
+    /// from before versions existed is always returned.
+    fn get_metadata_if_newer(&self, version: u64) -> Result<Option<DbMetadata>> {
+        if let Ok(serialized) = self.get("db_metadata_version") {
+            let current: u64 = rmp_serde::from_read_ref(&serialized)?;
+            if current <= version {
+                return Ok(None);
+            }
+        }
+        self.get_metadata().map(Some)
     }
 
     fn set_metadata(&self, metadata: &DbMetadata) -> Result<()> {
-        let buf = rmp_serde::to_vec(metadata)?;
+        let buf = metadata.to_stored()?;
 
-        self.set("db_metadata", &buf)
+        self.set("db_metadata", &buf)?;
+        // Written second, so a reader that sees the new version also
+        // sees the metadata it belongs to.
+        self.set("db_metadata_version", &rmp_serde::to_vec(&metadata.version)?)
     }
 
+    /// Replaces the metadata only if it's still `expected`, as
+    /// serialized when it was read, so that a writer which read
+    /// stale metadata can't clobber a newer writer's. Returns the
+    /// metadata as written.
+    fn compare_and_set_metadata(&self, expected: Option<&[u8]>, metadata: &DbMetadata) -> Result<Vec<u8>> {
+        let buf = metadata.to_stored()?;
+
+        self.compare_and_set("db_metadata", expected, &buf)?;
+        self.set("db_metadata_version", &rmp_serde::to_vec(&metadata.version)?)?;
+        Ok(buf)
+    }
+
+    /// Appends a transaction to the log. Implementations must refuse
+    /// (atomically) to append a transaction whose epoch is older than
+    /// that of any transaction already in the log; this is what
+    /// keeps a transactor which has lost its lease from writing. A
+    /// transaction whose log position is taken is refused too.
     fn add_tx(&self, raw_tx: &TxRaw) -> Result<()>;
+
+    /// Appends several transactions, in order, for group commit.
+    /// Backends which can should write them in one round trip.
+    fn add_txs(&self, raw_txs: &[TxRaw]) -> Result<()> {
+        for raw_tx in raw_txs {
+            self.add_tx(raw_tx)?;
+        }
+        Ok(())
+    }
+    /// Returns the transactions after log position `from`, in order.
     fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>>;
+
+    /// Returns a stream over the transactions after log position
+    /// `from`, which keeps track of its position so that the log can
+    /// be tailed without re-reading it.
+    fn stream_txs(&self, from: i64) -> TxStream;
+
+    /// Checks every transaction in the log against its checksum, and
+    /// returns the keys (see `checksum::tx_key`) of those which are
+    /// corrupt. Backends which can should check each entry on its
+    /// own; this stops at the first corrupt one.
+    fn corrupt_txs(&self) -> Result<Vec<String>> {
+        match self.get_txs(-1) {
+            Ok(_) => Ok(vec![]),
+            Err(Error::Corruption { key }) => Ok(vec![key]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the transaction whose entity is `id`, if it's in the
+    /// log. Backends which can look it up by id should; this reads
+    /// the whole log.
+    fn get_tx(&self, id: i64) -> Result<Option<TxRaw>> {
+        Ok(self.get_txs(-1)?.into_iter().find(|tx| tx.id == id))
+    }
+}
+
+/// Fetches up to `limit` transactions after the given log position,
+/// in order.
+pub type FetchTxs = Box<dyn FnMut(i64, usize) -> Result<Vec<TxRaw>> + Send>;
+
+const STREAM_BATCH_SIZE: usize = 256;
+
+/// A cursor over the tx log. As an `Iterator` it never ends: once it
+/// has caught up it polls the store for new transactions,
+/// sleeping `poll_interval` between attempts. `poll` returns what's
+/// available without waiting.
+pub struct TxStream {
+    fetch: FetchTxs,
+    watermark: i64,
+    buffered: VecDeque<TxRaw>,
+    poll_interval: Duration,
+}
+
+impl TxStream {
+    pub fn new(from: i64, fetch: FetchTxs) -> TxStream {
+        TxStream {
+            fetch,
+            watermark: from,
+            buffered: VecDeque::new(),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    /// The log position of the last transaction read from the store.
+    pub fn watermark(&self) -> i64 {
+        self.watermark
+    }
+
+    fn fetch_batch(&mut self) -> Result<usize> {
+        let txs = (self.fetch)(self.watermark, STREAM_BATCH_SIZE)?;
+        let count = txs.len();
+        if let Some(last) = txs.last() {
+            self.watermark = last.seq;
+        }
+        self.buffered.extend(txs);
+        Ok(count)
+    }
+
+    /// The next transaction, or `None` once the stream has caught up
+    /// with the store, rather than waiting for one as `next` does.
+    pub fn next_available(&mut self) -> Option<Result<TxRaw>> {
+        if self.buffered.is_empty() {
+            if let Err(e) = self.fetch_batch() {
+                return Some(Err(e));
+            }
+        }
+        self.buffered.pop_front().map(Ok)
+    }
+
+    /// Returns every transaction appended since the last call,
+    /// without waiting for new ones.
+    pub fn poll(&mut self) -> Result<Vec<TxRaw>> {
+        while self.fetch_batch()? == STREAM_BATCH_SIZE {}
+        Ok(self.buffered.drain(..).collect())
+    }
+}
+
+impl Iterator for TxStream {
+    type Item = Result<TxRaw>;
+
+    fn next(&mut self) -> Option<Result<TxRaw>> {
+        loop {
+            if let Some(tx) = self.buffered.pop_front() {
+                return Some(Ok(tx));
+            }
+            match self.fetch_batch() {
+                Ok(0) => thread::sleep(self.poll_interval),
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
@@ -1,7 +1,10 @@
 use mysql;
+use mysql::prelude::GenericConnection;
 
-use {Result, KVStore, Record};
+use {Error, Result, KVStore, Record};
+use checksum;
 use tx::TxRaw;
+use backends::TxStream;
 
 pub struct MysqlStore {
     pool: mysql::Pool,
@@ -20,16 +23,74 @@ impl MysqlStore {
             empty_params.clone()
         )?;
         pool.prep_exec(
-            "CREATE TABLE IF NOT EXISTS cliodb_txs (id INTEGER NOT NULL PRIMARY KEY, val BLOB)",
-            empty_params
+            "CREATE TABLE IF NOT EXISTS cliodb_txs (id INTEGER NOT NULL PRIMARY KEY, epoch BIGINT NOT NULL DEFAULT 0, val BLOB, \
+             seq BIGINT, UNIQUE INDEX cliodb_txs_seq (seq))",
+            empty_params.clone()
         )?;
 
+        // Logs created before writer epochs existed lack the column.
+        let has_epoch = pool.first_exec(
+            "SELECT 1 FROM information_schema.columns \
+             WHERE table_schema = DATABASE() AND table_name = 'cliodb_txs' AND column_name = 'epoch'",
+            empty_params.clone()
+        )?.is_some();
+        if !has_epoch {
+            pool.prep_exec(
+                "ALTER TABLE cliodb_txs ADD COLUMN epoch BIGINT NOT NULL DEFAULT 0",
+                empty_params.clone()
+            )?;
+        }
+
+        // Nor do they have log positions, which were the tx ids.
+        let has_seq = pool.first_exec(
+            "SELECT 1 FROM information_schema.columns \
+             WHERE table_schema = DATABASE() AND table_name = 'cliodb_txs' AND column_name = 'seq'",
+            empty_params.clone()
+        )?.is_some();
+        if !has_seq {
+            pool.prep_exec(
+                "ALTER TABLE cliodb_txs ADD COLUMN seq BIGINT, ADD UNIQUE INDEX cliodb_txs_seq (seq)",
+                empty_params.clone()
+            )?;
+            pool.prep_exec("UPDATE cliodb_txs SET seq = id", empty_params)?;
+        }
+
         let store = MysqlStore { pool };
 
         Ok(store)
     }
 }
 
+/// Reads the transactions after log position `from` in order, at
+/// most `limit` of them.
+fn fetch_txs(pool: &mysql::Pool, from: i64, limit: u64) -> Result<Vec<TxRaw>> {
+    read_txs(pool.prep_exec("SELECT seq, id, epoch, val FROM cliodb_txs WHERE seq > ? ORDER BY seq LIMIT ?", (from, limit))?)
+}
+
+fn read_txs(rows: mysql::QueryResult) -> Result<Vec<TxRaw>> {
+    let mut txs = vec![];
+    for row in rows {
+        let row = row?;
+        let seq: i64 = row.get(0).ok_or("missing tx log position")?;
+        let id: i64 = row.get(1).ok_or("missing tx id")?;
+        let epoch: u64 = row.get(2).ok_or("missing tx epoch")?;
+        txs.push(TxRaw {
+            seq,
+            id,
+            epoch,
+            records: decode_records(id, row.get(3))?,
+        });
+    }
+    Ok(txs)
+}
+
+/// Checks and decodes the records of the log entry for tx `id`.
+fn decode_records(id: i64, bytes: Option<Vec<u8>>) -> Result<Vec<Record>> {
+    let corrupt = || Error::Corruption { key: checksum::tx_key(id) };
+    let bytes = bytes.ok_or_else(corrupt)?;
+    rmp_serde::from_read_ref(checksum::unseal(&checksum::tx_key(id), &bytes)?).map_err(|_| corrupt())
+}
+
 impl KVStore for MysqlStore {
     fn get(&self, key: &str) -> Result<Vec<u8>> {
         self.pool.first_exec(
@@ -58,34 +119,86 @@ impl KVStore for MysqlStore {
         ) .map(|_| ()).map_err(|e| e.into())
     }
 
+    fn delete(&self, key: &str) -> Result<()> {
+        self.pool.prep_exec("DELETE FROM cliodb_kvs WHERE `key` = ?", (key,))?;
+        Ok(())
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+        let changed = match expected {
+            Some(expected) => self.pool.prep_exec(
+                "UPDATE cliodb_kvs SET val = ? WHERE `key` = ? AND val = ?",
+                (value, key, expected)
+            )?.affected_rows(),
+            None => self.pool.prep_exec(
+                "INSERT IGNORE INTO cliodb_kvs (`key`, val) VALUES (?, ?)",
+                (key, value)
+            )?.affected_rows(),
+        };
+        // MySQL only counts rows whose value actually changed, so
+        // setting the value it already has looks like a conflict.
+        if changed == 0 && (expected != Some(value) || self.get(key).ok().as_deref() != Some(value)) {
+            return Err(Error::Conflict { key: key.to_string() });
+        }
+        Ok(())
+    }
+
     fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
-        let results = self.pool.prep_exec("SELECT id, val FROM cliodb_txs WHERE id > ?", (from,))?
-            .map(|row_result| {
-                row_result
-                    .map_err(|e| e.to_string())
-                    .and_then(|row| {
-                        let id: i64 = row.get(0).unwrap();
-                        let bytes: Vec<u8> = row.get(1).unwrap();
-                        let res: Vec<Record> = rmp_serde::from_read_ref(&bytes)
-                            .map_err(|e| e.to_string())?;
-
-                        Ok(TxRaw {
-                            id: id,
-                            records: res,
-                        })
-                    }).map_err(|e| e.to_string())
-            });
-        let mut txs = vec![];
-        for result in results {
-            txs.push(result?);
+        fetch_txs(&self.pool, from, u64::MAX)
+    }
+
+    fn stream_txs(&self, from: i64) -> TxStream {
+        let pool = self.pool.clone();
+        TxStream::new(from, Box::new(move |after, limit| fetch_txs(&pool, after, limit as u64)))
+    }
+
+    fn get_tx(&self, id: i64) -> Result<Option<TxRaw>> {
+        Ok(read_txs(self.pool.prep_exec("SELECT seq, id, epoch, val FROM cliodb_txs WHERE id = ?", (id,))?)?.pop())
+    }
+
+    fn corrupt_txs(&self) -> Result<Vec<String>> {
+        let mut corrupt = vec![];
+        for row in self.pool.prep_exec("SELECT id, val FROM cliodb_txs ORDER BY seq", ())? {
+            let row = row?;
+            let id: i64 = row.get(0).ok_or("missing tx id")?;
+            match decode_records(id, row.get(1)) {
+                Ok(_) => {}
+                Err(Error::Corruption { key }) => corrupt.push(key),
+                Err(e) => return Err(e),
+            }
         }
-        Ok(txs)
+        Ok(corrupt)
     }
 
     fn add_tx(&self, tx: &TxRaw) -> Result<()> {
-        let serialized = rmp_serde::to_vec(&tx.records)?;
+        insert_tx(&mut self.pool.get_conn()?, tx)
+    }
 
-        self.pool.prep_exec("INSERT INTO cliodb_txs (id, val) VALUES (?, ?)", (tx.id, serialized))?;
-        Ok(())
+    /// Appends the transactions in one MySQL transaction, so they
+    /// are committed together.
+    fn add_txs(&self, txs: &[TxRaw]) -> Result<()> {
+        let mut transaction = self.pool.start_transaction(false, None, None)?;
+        for tx in txs {
+            insert_tx(&mut transaction, tx)?;
+        }
+        Ok(transaction.commit()?)
+    }
+}
+
+fn insert_tx<C: GenericConnection>(conn: &mut C, tx: &TxRaw) -> Result<()> {
+    let serialized = checksum::seal(&rmp_serde::to_vec(&tx.records)?);
+
+    // The epoch check and the insert happen in one statement so
+    // that a newer writer can't slip in between them. (The
+    // derived table is needed because MySQL won't select from the
+    // table being inserted into.)
+    let result = conn.prep_exec(
+        "INSERT INTO cliodb_txs (id, epoch, val, seq) SELECT ?, ?, ?, ? FROM DUAL \
+         WHERE NOT EXISTS (SELECT 1 FROM (SELECT epoch FROM cliodb_txs WHERE epoch > ?) AS newer)",
+        (tx.id, tx.epoch, serialized, tx.seq, tx.epoch)
+    )?;
+    if result.affected_rows() == 0 {
+        return Err(format!("tx {} rejected: writer epoch {} has been fenced off", tx.id, tx.epoch).into());
     }
+    Ok(())
 }
@@ -5,8 +5,10 @@ use rusqlite as sql;
 
 use rmp_serde;
 
-use {Result, KVStore, Record};
+use {Error, Result, KVStore, Record};
+use checksum;
 use tx::TxRaw;
+use backends::TxStream;
 
 pub struct SqliteStore {
     conn: Arc<Mutex<sql::Connection>>,
@@ -22,7 +24,35 @@ impl SqliteStore {
             sql::NO_PARAMS,
         )?;
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS cliodb_txs (id INTEGER NOT NULL PRIMARY KEY, val BLOB)",
+            "CREATE TABLE IF NOT EXISTS cliodb_txs (id INTEGER NOT NULL PRIMARY KEY, epoch INTEGER NOT NULL DEFAULT 0, val BLOB, seq INTEGER)",
+            sql::NO_PARAMS,
+        )?;
+
+        // Logs created before writer epochs existed lack the column.
+        let has_epoch: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('cliodb_txs') WHERE name = 'epoch'",
+            sql::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        if has_epoch == 0 {
+            conn.execute(
+                "ALTER TABLE cliodb_txs ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0",
+                sql::NO_PARAMS,
+            )?;
+        }
+
+        // Nor do they have log positions, which were the tx ids.
+        let has_seq: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('cliodb_txs') WHERE name = 'seq'",
+            sql::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        if has_seq == 0 {
+            conn.execute("ALTER TABLE cliodb_txs ADD COLUMN seq INTEGER", sql::NO_PARAMS)?;
+            conn.execute("UPDATE cliodb_txs SET seq = id", sql::NO_PARAMS)?;
+        }
+        conn.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS cliodb_txs_seq ON cliodb_txs (seq)",
             sql::NO_PARAMS,
         )?;
 
@@ -31,6 +61,43 @@ impl SqliteStore {
     }
 }
 
+/// Reads the transactions after log position `from` in order, at
+/// most `limit` of them (or all of them if `limit` is negative).
+fn fetch_txs(conn: &sql::Connection, from: i64, limit: i64) -> Result<Vec<TxRaw>> {
+    let mut stmt = conn.prepare("SELECT seq, id, epoch, val FROM cliodb_txs WHERE seq > ?1 ORDER BY seq LIMIT ?2")?;
+    read_txs(&mut stmt, sql::params![&from, &limit])
+}
+
+fn read_txs(stmt: &mut sql::Statement, params: &[&dyn sql::ToSql]) -> Result<Vec<TxRaw>> {
+    let rows = stmt.query_map(params, |row| {
+        let seq: i64 = row.get(0)?;
+        let id: i64 = row.get(1)?;
+        let epoch: i64 = row.get(2)?;
+        let bytes: Option<Vec<u8>> = row.get(3)?;
+        Ok((seq, id, epoch, bytes))
+    })?;
+
+    let mut txs = vec![];
+    for row in rows {
+        let (seq, id, epoch, bytes) = row?;
+        txs.push(TxRaw {
+            seq,
+            id,
+            epoch: epoch as u64,
+            records: decode_records(id, bytes)?,
+        });
+    }
+
+    Ok(txs)
+}
+
+/// Checks and decodes the records of the log entry for tx `id`.
+fn decode_records(id: i64, bytes: Option<Vec<u8>>) -> Result<Vec<Record>> {
+    let corrupt = || Error::Corruption { key: checksum::tx_key(id) };
+    let bytes = bytes.ok_or_else(corrupt)?;
+    rmp_serde::from_read_ref(checksum::unseal(&checksum::tx_key(id), &bytes)?).map_err(|_| corrupt())
+}
+
 impl KVStore for SqliteStore {
     fn get(&self, key: &str) -> Result<Vec<u8>> {
         let conn = self.conn.lock().unwrap();
@@ -56,38 +123,107 @@ impl KVStore for SqliteStore {
         Ok(())
     }
 
-    fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
-        // FIXME: handle errors
+    fn delete(&self, key: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT id, val FROM cliodb_txs WHERE id > ?1")
-            .unwrap();
-        let results: Vec<TxRaw> = stmt.query_map(sql::params![&from], |ref row| {
-            let maybe_bytes: Option<Vec<u8>> = row.get(1).unwrap();
-            let bytes = maybe_bytes.unwrap();
-            let res: Vec<Record> = rmp_serde::from_read_ref(&bytes).expect("corrupt data");
-            let id: i64 = row.get(0).unwrap();
-            Ok(TxRaw {
-                id: id,
-                records: res,
-            })
-        }).unwrap()
-            .map(|r| r.unwrap())
-            .collect();
-
-        Ok(results)
+        conn.execute("DELETE FROM cliodb_kvs WHERE key = ?1", sql::params![key])?;
+        Ok(())
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+        let conn = self.conn.lock()?;
+        // Each statement checks and writes at once, so other
+        // connections to the same database can't interleave.
+        let changed = match expected {
+            Some(expected) => conn.execute(
+                "UPDATE cliodb_kvs SET val = ?2 WHERE key = ?1 AND val = ?3",
+                sql::params![key, value, expected],
+            )?,
+            None => conn.execute(
+                "INSERT OR IGNORE INTO cliodb_kvs (key, val) VALUES (?1, ?2)",
+                sql::params![key, value],
+            )?,
+        };
+        if changed == 0 {
+            return Err(Error::Conflict { key: key.to_string() });
+        }
+        Ok(())
+    }
+
+    fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+        let conn = self.conn.lock()?;
+        fetch_txs(&conn, from, -1)
+    }
+
+    fn stream_txs(&self, from: i64) -> TxStream {
+        let conn = self.conn.clone();
+        TxStream::new(from, Box::new(move |after, limit| {
+            let conn = conn.lock()?;
+            fetch_txs(&conn, after, limit as i64)
+        }))
+    }
+
+    fn get_tx(&self, id: i64) -> Result<Option<TxRaw>> {
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare("SELECT seq, id, epoch, val FROM cliodb_txs WHERE id = ?1")?;
+        Ok(read_txs(&mut stmt, sql::params![&id])?.pop())
+    }
+
+    fn corrupt_txs(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare("SELECT id, val FROM cliodb_txs ORDER BY seq")?;
+        let rows = stmt.query_map(sql::NO_PARAMS, |row| {
+            let id: i64 = row.get(0)?;
+            let bytes: Option<Vec<u8>> = row.get(1)?;
+            Ok((id, bytes))
+        })?;
+
+        let mut corrupt = vec![];
+        for row in rows {
+            let (id, bytes) = row?;
+            match decode_records(id, bytes) {
+                Ok(_) => {}
+                Err(Error::Corruption { key }) => corrupt.push(key),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(corrupt)
     }
 
     fn add_tx(&self, tx: &TxRaw) -> Result<()> {
-        let serialized: Vec<u8> = rmp_serde::to_vec(&tx.records)?;
+        insert_tx(&self.conn.lock().unwrap(), tx)
+    }
 
+    /// Appends the transactions in one sqlite transaction, so they
+    /// are synced to disk once.
+    fn add_txs(&self, txs: &[TxRaw]) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("INSERT INTO cliodb_txs (id, val) VALUES (?1, ?2)")
-            .unwrap();
+        conn.execute_batch("BEGIN")?;
+        match txs.iter().try_for_each(|tx| insert_tx(&conn, tx)) {
+            Ok(()) => Ok(conn.execute_batch("COMMIT")?),
+            Err(e) => {
+                conn.execute_batch("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+}
 
-        stmt.execute(sql::params![tx.id, &serialized])?;
+fn insert_tx(conn: &sql::Connection, tx: &TxRaw) -> Result<()> {
+    let serialized: Vec<u8> = checksum::seal(&rmp_serde::to_vec(&tx.records)?);
 
-        Ok(())
+    // The epoch check and the insert happen in one statement so
+    // that a newer writer can't slip in between them.
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO cliodb_txs (id, epoch, val, seq) SELECT ?1, ?2, ?3, ?4 \
+         WHERE NOT EXISTS (SELECT 1 FROM cliodb_txs WHERE epoch > ?2)"
+    )?;
+
+    let inserted = stmt.execute(sql::params![tx.id, tx.epoch as i64, &serialized, tx.seq])?;
+    if inserted == 0 {
+        return Err(format!("tx {} rejected: writer epoch {} has been fenced off", tx.id, tx.epoch).into());
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -99,11 +235,130 @@ mod tests {
 
     #[test]
     fn test_kv_store() {
-        let root: Node<String> = Node::Leaf(LeafNode { items: vec![] });
+        let root: Node<String> = Node::Leaf(LeafNode::new(vec![]));
         let store = SqliteStore::new("/tmp/cliodb.db").unwrap();
         let buf = rmp_serde::to_vec(&root).unwrap();
         store.set("my_key", &buf).unwrap();
 
         assert_eq!(store.get("my_key").unwrap(), buf)
     }
+
+    #[test]
+    fn test_metadata_if_newer() {
+        use schema::Schema;
+        use db::DbMetadata;
+
+        let store = SqliteStore::new(":memory:").unwrap();
+        let metadata = |version| DbMetadata {
+            next_id: 0,
+            last_indexed_tx: 0,
+            last_indexed_seq: None,
+            epoch: 0,
+            version,
+            schema: Schema::empty(),
+            eav: "eav".into(),
+            ave: "ave".into(),
+            aev: "aev".into(),
+            vae: "vae".into(),
+            tea: "tea".into(),
+            stats: Default::default(),
+            latest_tx: 0,
+        };
+
+        // Metadata from before versions is always newer.
+        store.set("db_metadata", &rmp_serde::to_vec(&metadata(0)).unwrap()).unwrap();
+        assert!(store.get_metadata_if_newer(0).unwrap().is_some());
+
+        store.set_metadata(&metadata(3)).unwrap();
+        assert_eq!(store.get_metadata_if_newer(2).unwrap().map(|m| m.version), Some(3));
+        assert!(store.get_metadata_if_newer(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        let store = SqliteStore::new(":memory:").unwrap();
+        let conflict = |r: Result<()>| matches!(r, Err(Error::Conflict { ref key }) if key == "k");
+
+        store.compare_and_set("k", None, b"one").unwrap();
+        assert!(conflict(store.compare_and_set("k", None, b"two")));
+        assert!(conflict(store.compare_and_set("k", Some(b"two"), b"three")));
+        store.compare_and_set("k", Some(b"one"), b"two").unwrap();
+        assert_eq!(store.get("k").unwrap(), b"two");
+        // Setting the value it already has isn't a conflict.
+        store.compare_and_set("k", Some(b"two"), b"two").unwrap();
+    }
+
+    #[test]
+    fn test_fenced_writer_cannot_append() {
+        let store = SqliteStore::new(":memory:").unwrap();
+        let tx = |id, epoch| TxRaw { seq: id, id, epoch, records: vec![] };
+
+        store.add_tx(&tx(1, 1)).unwrap();
+        store.add_tx(&tx(2, 2)).unwrap();
+        assert!(store.add_tx(&tx(3, 1)).is_err());
+        store.add_tx(&tx(3, 2)).unwrap();
+
+        let epochs: Vec<u64> = store.get_txs(0).unwrap().iter().map(|tx| tx.epoch).collect();
+        assert_eq!(epochs, vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn test_stream_txs() {
+        let store = SqliteStore::new(":memory:").unwrap();
+        // Streams follow log positions, not tx ids.
+        let tx = |seq| TxRaw { seq, id: seq * 10, epoch: 1, records: vec![] };
+
+        store.add_tx(&tx(1)).unwrap();
+        store.add_tx(&tx(2)).unwrap();
+
+        let mut stream = store.stream_txs(0);
+        let ids: Vec<i64> = stream.poll().unwrap().iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![10, 20]);
+        assert!(stream.poll().unwrap().is_empty());
+
+        store.add_tx(&tx(3)).unwrap();
+        assert_eq!(stream.next().unwrap().unwrap().id, 30);
+        assert_eq!(stream.watermark(), 3);
+        assert!(stream.next_available().is_none());
+        store.add_tx(&tx(4)).unwrap();
+        assert_eq!(stream.next_available().unwrap().unwrap().id, 40);
+        assert_eq!(store.get_tx(20).unwrap().map(|tx| tx.seq), Some(2));
+        assert!(store.add_tx(&TxRaw { id: 40, ..tx(3) }).is_err());
+    }
+
+    #[test]
+    fn test_legacy_log_positions() {
+        use uuid::Uuid;
+
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let conn = sql::Connection::open(&uri).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cliodb_txs (id INTEGER NOT NULL PRIMARY KEY, val BLOB); \
+             INSERT INTO cliodb_txs (id, val) VALUES (5, X'90'), (9, X'90');"
+        ).unwrap();
+
+        // The tx ids of a log from before positions become its
+        // positions.
+        let store = SqliteStore::new(&uri).unwrap();
+        let seqs: Vec<(i64, i64)> = store.get_txs(5).unwrap().iter().map(|tx| (tx.seq, tx.id)).collect();
+        assert_eq!(seqs, vec![(9, 9)]);
+    }
+
+    #[test]
+    fn test_corrupt_tx_is_reported() {
+        let store = SqliteStore::new(":memory:").unwrap();
+        for id in 1..4 {
+            store.add_tx(&TxRaw { seq: id, id, epoch: 1, records: vec![] }).unwrap();
+        }
+        store.conn.lock().unwrap().execute(
+            "UPDATE cliodb_txs SET val = X'C10000000090' WHERE id IN (1, 3)",
+            sql::NO_PARAMS,
+        ).unwrap();
+
+        match store.get_txs(0) {
+            Err(Error::Corruption { key }) => assert_eq!(key, "tx:1"),
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        assert_eq!(store.corrupt_txs().unwrap(), vec!["tx:1".to_string(), "tx:3".to_string()]);
+    }
 }
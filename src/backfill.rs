@@ -0,0 +1,192 @@
+//! Builds an index of an existing database from its EAVT index, for
+//! databases created before the index existed, without dumping and
+//! reloading the data.
+//!
+//! Every other index holds (a subset of) the records in EAVT, so a
+//! new one can be built by reading EAVT's durable tree in order and
+//! inserting its records into an empty index of the new order. They
+//! are merged into the new durable tree a batch at a time, as a
+//! reindex merges novelty, so only one batch is held in memory. The
+//! records after the last reindex are still only in the log, and get
+//! into the new index when it's replayed, as for the others.
+//!
+//! The transactor backfills the indices missing from the metadata
+//! when it starts. `clio-admin backfill` builds one offline, e.g. to
+//! rebuild an index whose order has changed; the transactor mustn't
+//! be running, and if it writes the metadata meanwhile the backfill
+//! is refused.
+
+use std::sync::Arc;
+
+use log::info;
+
+use {Record, Result, Value, EAVT, AVET, AEVT, VAET, TEAV};
+use backends::KVStore;
+use db::DbMetadata;
+use durable_tree::{DurableTree, RebuildProgress};
+use index::{Comparator, Index};
+
+/// The indices which can be built from EAVT.
+pub const INDICES: &[&str] = &["ave", "aev", "vae", "tea"];
+
+/// How many records are merged into the new index at a time.
+const BATCH_SIZE: usize = 50_000;
+
+impl DbMetadata {
+    /// The indices with no durable tree. A new index's root goes at
+    /// the end of the metadata with `#[serde(default)]`, so that
+    /// metadata written before it existed reads as an empty root.
+    pub fn missing_indices(&self) -> Vec<&'static str> {
+        INDICES.iter().cloned().filter(|name| self.index_root(name).is_some_and(|root| root.is_empty())).collect()
+    }
+
+    fn index_root(&self, name: &str) -> Option<&String> {
+        match name {
+            "ave" => Some(&self.ave),
+            "aev" => Some(&self.aev),
+            "vae" => Some(&self.vae),
+            "tea" => Some(&self.tea),
+            _ => None,
+        }
+    }
+
+    fn index_root_mut(&mut self, name: &str) -> Option<&mut String> {
+        match name {
+            "ave" => Some(&mut self.ave),
+            "aev" => Some(&mut self.aev),
+            "vae" => Some(&mut self.vae),
+            "tea" => Some(&mut self.tea),
+            _ => None,
+        }
+    }
+}
+
+/// Builds the named index from the durable EAVT index `metadata`
+/// names, and points the stored metadata at it. Returns the new
+/// metadata.
+pub fn backfill(store: Arc<dyn KVStore>, name: &str) -> Result<DbMetadata> {
+    let expected = store.get("db_metadata")?;
+    let mut metadata = DbMetadata::from_stored(&expected)?;
+    let root = build_index(store.clone(), &metadata, name, BATCH_SIZE)?;
+
+    *metadata.index_root_mut(name).unwrap() = root;
+    metadata.version += 1;
+    store.compare_and_set_metadata(Some(&expected), &metadata)?;
+    Ok(metadata)
+}
+
+/// Writes the durable tree of the named index, built from the
+/// records in the durable EAVT index, and returns its root.
+fn build_index(store: Arc<dyn KVStore>, metadata: &DbMetadata, name: &str, batch_size: usize) -> Result<String> {
+    let eav = DurableTree::from_ref(metadata.eav.clone(), store.clone(), EAVT);
+    let records = eav.iter()?;
+    match name {
+        "ave" => build(store, records, AVET::new(&metadata.schema), |_| true, batch_size),
+        "aev" => build(store, records, AEVT, |_| true, batch_size),
+        // As in `Db::add_record`, only refs go in VAET.
+        "vae" => build(store, records, VAET, |r| matches!(r.value, Value::Ref(_)), batch_size),
+        "tea" => build(store, records, TEAV, |_| true, batch_size),
+        _ => Err(format!("can't backfill unknown index {:?}, expected one of {:?}", name, INDICES).into()),
+    }
+}
+
+fn build<I, C, F>(store: Arc<dyn KVStore>, records: I, comparator: C, keep: F, batch_size: usize) -> Result<String>
+    where I: Iterator<Item = Result<Record>>,
+          C: Comparator<Item = Record>,
+          F: Fn(&Record) -> bool
+{
+    let root = DurableTree::<Record, C>::create(store.clone(), comparator.clone())?.root;
+    let mut index = Index::new(root, store, comparator);
+    let progress = Arc::new(RebuildProgress::new(0));
+    let (mut batched, mut total) = (0, 0);
+    for record in records {
+        let record = record?;
+        if !keep(&record) {
+            continue;
+        }
+        index = index.insert(record);
+        batched += 1;
+        if batched == batch_size {
+            index = index.rebuild_with_progress(&progress, None);
+            total += batched;
+            batched = 0;
+            info!("Backfilled {} records", total);
+        }
+    }
+    index = index.rebuild_with_progress(&progress, None);
+    info!("Backfilled {} records", total + batched);
+    Ok(index.durable_root())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backends::mem::MemStore;
+    use db::Db;
+    use tx::Transactor;
+    use Entity;
+
+    #[test]
+    fn test_backfill_matches_rebuilt_index() {
+        let store: Arc<dyn KVStore> = Arc::new(MemStore::new());
+        Transactor::new(store.clone()).unwrap();
+        let mut db = Db::new(store.get_metadata().unwrap(), store.clone());
+        let doc = db.schema.idents["db:doc"];
+        for i in 0..250 {
+            let record = if i % 3 == 0 {
+                Record::addition(Entity(1000 + i), Entity(999), Value::Ref(Entity(1000 + i / 2)), Entity(1))
+            } else {
+                Record::addition(Entity(1000 + i), doc, Value::String(format!("doc {}", i)), Entity(1))
+            };
+            db = db.add_record(record).unwrap();
+        }
+        let mut metadata = store.get_metadata().unwrap();
+        metadata.eav = db.eav.rebuild().durable_root();
+        metadata.ave = db.ave.rebuild().durable_root();
+        metadata.aev = db.aev.rebuild().durable_root();
+        metadata.vae = db.vae.rebuild().durable_root();
+        metadata.version += 1;
+        store.set_metadata(&metadata).unwrap();
+        let expected = Db::new(metadata.clone(), store.clone());
+        assert_eq!(expected.vae.iter().count(), 84);
+
+        for &name in INDICES {
+            let root = build_index(store.clone(), &metadata, name, 40).unwrap();
+            let mut built = metadata.clone();
+            *built.index_root_mut(name).unwrap() = root;
+            let built = Db::new(built, store.clone());
+            assert_eq!(built.ave.iter().collect::<Vec<_>>(), expected.ave.iter().collect::<Vec<_>>(), "{}", name);
+            assert_eq!(built.aev.iter().collect::<Vec<_>>(), expected.aev.iter().collect::<Vec<_>>(), "{}", name);
+            assert_eq!(built.vae.iter().collect::<Vec<_>>(), expected.vae.iter().collect::<Vec<_>>(), "{}", name);
+        }
+        assert!(build_index(store.clone(), &metadata, "eav", 40).is_err());
+    }
+
+    #[test]
+    fn test_transactor_backfills_missing_indices() {
+        let store: Arc<dyn KVStore> = Arc::new(MemStore::new());
+        Transactor::new(store.clone()).unwrap();
+        let mut metadata = store.get_metadata().unwrap();
+        let expected = Db::new(metadata.clone(), store.clone()).aev.iter().collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        assert!(metadata.missing_indices().is_empty());
+
+        // Metadata from before AEVT existed.
+        metadata.aev = String::new();
+        metadata.version += 1;
+        store.set_metadata(&metadata).unwrap();
+        assert_eq!(store.get_metadata().unwrap().missing_indices(), vec!["aev"]);
+
+        Transactor::new(store.clone()).unwrap();
+        let metadata = store.get_metadata().unwrap();
+        assert!(metadata.missing_indices().is_empty());
+        assert_eq!(Db::new(metadata, store.clone()).aev.iter().collect::<Vec<_>>(), expected);
+
+        // The backfill is recorded as an admin op in the log.
+        let backfills = store.get_txs(0).unwrap().into_iter()
+            .flat_map(|tx| tx.records)
+            .filter(|rec| rec.value == Value::Ident("db:admin:backfill".into()))
+            .count();
+        assert_eq!(backfills, 1);
+    }
+}
@@ -0,0 +1,127 @@
+extern crate cliodb;
+extern crate clap;
+extern crate log;
+extern crate env_logger;
+
+use std::process;
+use log::error;
+
+use cliodb::analyze::analyze;
+use cliodb::backfill::{backfill, INDICES};
+use cliodb::conn::{Conn, store_from_uri};
+use cliodb::verify::verify;
+use clap::{Arg, App, AppSettings, SubCommand};
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ClioDB admin")
+        .version("0.1.0")
+        .about("Administrative reports about and maintenance of a database")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Reports how each attribute is used: datoms, growth, value sizes, and unused attributes")
+                .arg(
+                    Arg::with_name("uri")
+                        .help("The location of the backing key-value store")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("recent")
+                        .short("r")
+                        .long("recent-txs")
+                        .value_name("N")
+                        .help("Sets how many of the latest transactions count as recent growth")
+                        .default_value("1000")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Builds an index from the EAVT index, e.g. one added after the database was created. The transactor must not be running")
+                .arg(
+                    Arg::with_name("uri")
+                        .help("The location of the backing key-value store")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .help("The index to build")
+                        .required(true)
+                        .possible_values(INDICES)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the metadata, every index node and every tx log entry against its checksum, and lists the corrupt ones")
+                .arg(
+                    Arg::with_name("uri")
+                        .help("The location of the backing key-value store")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("analyze") {
+        let uri = matches.value_of("uri").unwrap();
+        let recent_txs: usize = matches.value_of("recent").unwrap().parse().unwrap_or_else(|_| {
+            error!("--recent-txs must be a number");
+            process::exit(1);
+        });
+        let store = store_from_uri(uri).unwrap_or_else(|e| {
+            error!("Failed to open {}: {:?}", uri, e);
+            process::exit(1);
+        });
+
+        match Conn::open_read_only(store).db().and_then(|db| analyze(&db, recent_txs)) {
+            Ok(report) => print!("{}", report),
+            Err(e) => {
+                error!("Analysis failed: {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("backfill") {
+        let uri = matches.value_of("uri").unwrap();
+        let index = matches.value_of("index").unwrap();
+        let store = store_from_uri(uri).unwrap_or_else(|e| {
+            error!("Failed to open {}: {:?}", uri, e);
+            process::exit(1);
+        });
+
+        match backfill(store, index) {
+            Ok(_) => println!("Built the {} index", index),
+            Err(e) => {
+                error!("Backfill failed: {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let uri = matches.value_of("uri").unwrap();
+        let store = store_from_uri(uri).unwrap_or_else(|e| {
+            error!("Failed to open {}: {:?}", uri, e);
+            process::exit(1);
+        });
+
+        match verify(&*store) {
+            Ok(ref corrupt) if corrupt.is_empty() => println!("No corruption found"),
+            Ok(corrupt) => {
+                for key in corrupt {
+                    println!("{} is corrupt", key);
+                }
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Verification failed: {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
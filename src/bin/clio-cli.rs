@@ -2,16 +2,148 @@ extern crate cliodb;
 extern crate rustyline;
 extern crate log;
 extern crate env_logger;
+extern crate ctrlc;
 
 use cliodb::*;
+use cliodb::config::Config;
 use cliodb::conn::{Conn, store_from_uri};
-use std::time::{Instant};
+use cliodb::db::Db;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use log::info;
-use std::env::args;
+use std::env::{self, args};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process::{Command, Stdio};
 
 use rustyline::error::ReadlineError;
 
-fn run(store_uri: &str, transactor_address: &str) {
+/// Options changed with `\set`.
+struct Settings {
+    /// Whether to render refs to entities without an ident by their
+    /// value for `label_attribute`. Idents are always shown.
+    resolve_refs: bool,
+    label_attribute: String,
+    /// Results with more rows than this are cut short, with a note
+    /// of how many rows were left out. None shows every row.
+    max_rows: Option<usize>,
+    /// Whether to send output taller than the terminal through
+    /// `$PAGER` (or `less`).
+    pager: bool,
+}
+
+impl Settings {
+    fn set(&mut self, option: &str, value: &str) -> Result<()> {
+        match option {
+            "resolve-refs" => match value {
+                "on" => self.resolve_refs = true,
+                "off" => self.resolve_refs = false,
+                _ => return Err(format!("resolve-refs must be on or off, not {}", value).into()),
+            },
+            "label-attribute" => self.label_attribute = value.to_string(),
+            "maxrows" => self.max_rows = match value {
+                "off" | "0" => None,
+                n => Some(n.parse().map_err(|_| format!("maxrows must be a number or off, not {}", n))?),
+            },
+            "pager" => match value {
+                "on" => self.pager = true,
+                "off" => self.pager = false,
+                _ => return Err(format!("pager must be on or off, not {}", value).into()),
+            },
+            _ => return Err(format!("unknown option {}", option).into()),
+        }
+        Ok(())
+    }
+
+    fn render(&self, relation: &Relation, db: &Db) -> String {
+        let (relation, omitted) = match self.max_rows {
+            Some(max) if relation.1.len() > max => {
+                let rows = relation.1.iter().take(max).cloned().collect();
+                (Relation(relation.0.clone(), rows), relation.1.len() - max)
+            }
+            _ => (relation.clone(), 0),
+        };
+
+        let resolved = relation.with_idents(db);
+        let mut output = if self.resolve_refs {
+            format!("{}", resolved.with_label(&self.label_attribute))
+        } else {
+            format!("{}", resolved)
+        };
+        if omitted > 0 {
+            output.push_str(&format!("… {} more rows (\\set maxrows to change the limit)\n", omitted));
+        }
+        output
+    }
+
+    /// Prints output, through the pager if it's enabled and the
+    /// output won't fit on the screen.
+    fn print(&self, output: &str) {
+        let screen_lines = env::var("LINES").ok().and_then(|l| l.parse().ok()).unwrap_or(24);
+        if self.pager && output.lines().count() >= screen_lines && page(output).is_ok() {
+            return;
+        }
+        print!("{}", output);
+    }
+}
+
+/// Runs a query, showing a spinner and the elapsed time on stderr
+/// once it has taken more than a second.
+fn run_query(q: Query, db: &Db, cancel: &CancelToken) -> Result<Relation> {
+    let done = Arc::new(AtomicBool::new(false));
+    let spinner_done = done.clone();
+    let spinner = thread::spawn(move || {
+        let start = Instant::now();
+        let mut shown = false;
+        for frame in ['|', '/', '-', '\\'].iter().cycle() {
+            thread::sleep(Duration::from_millis(100));
+            if spinner_done.load(Ordering::SeqCst) {
+                break;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= Duration::from_secs(1) {
+                eprint!("\r{} {:.1}s (Ctrl-C to cancel)", frame, elapsed.as_secs_f64());
+                shown = true;
+            }
+        }
+        if shown {
+            eprint!("\r{}\r", " ".repeat(40));
+        }
+    });
+
+    let result = query_with_cancel(q, db, cancel);
+    done.store(true, Ordering::SeqCst);
+    spinner.join().unwrap();
+    result
+}
+
+fn page(output: &str) -> io::Result<()> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less -FRX".into());
+    let mut child = Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn()?;
+    child.stdin.take().expect("pager stdin is piped").write_all(output.as_bytes())?;
+    child.wait()?;
+    Ok(())
+}
+
+/// Transacts the datoms in a file, one transaction at a time,
+/// returning how many were committed.
+fn load_datoms(conn: &Conn, path: &str) -> Result<usize> {
+    let datoms = datoms::DatomReader::new(BufReader::new(File::open(path)?))?;
+    let mut count = 0;
+    for tx in datoms::to_txs(datoms) {
+        if let TxReport::Failure(msg) = conn.transact(tx?)? {
+            return Err(msg.into());
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Connects with a store URI and transactor address, or with a
+/// single connection URI if there's no transactor address.
+fn run(uri: &str, transactor_address: Option<&str>) {
     println!(
         "
 cliodb
@@ -19,11 +151,42 @@ Commands:
   quit - exit the progam;
   test - load sample data (overwrites your current DB!)
   dump - display the metadata of the DB as a table.
+  \\schema - list attributes with their types and docs.
+  \\set resolve-refs on|off - show refs by the target's label attribute.
+  \\copy (<query>) to '<file>' - write query results as CSV.
+  \\copy '<file>' to (<attribute>...) - load CSV rows as new entities.
+  \\dump '<file>' - write the transaction log as datoms.
+  \\load '<file>' - transact the datoms written by \\dump.
+  \\run <name> <value>... - run the query stored as <name> with the given parameters.
+  \\reindex - ask the transactor to rebuild the indices now.
+  \\explain <query> - show how a query would run, and whether it needs a sort.
+  \\tx <id> - show what a transaction added and retracted.
+  \\set label-attribute <ident> - the label attribute (default `name`).
+  \\set maxrows <n>|off - limit the rows shown per result (default 1000).
+  \\set pager on|off - page long results through $PAGER.
 "
     );
-    let store = store_from_uri(store_uri).expect("Couldn't create store");
+    let mut settings = Settings {
+        resolve_refs: false,
+        label_attribute: "name".into(),
+        max_rows: Some(1000),
+        pager: true,
+    };
+    // Ctrl-C at the prompt is handled by rustyline; while a query is
+    // running it cancels the query instead of exiting.
+    let running_query = Arc::new(Mutex::new(CancelToken::new()));
+    let handler_query = running_query.clone();
+    ctrlc::set_handler(move || handler_query.lock().unwrap().cancel())
+        .expect("Couldn't install Ctrl-C handler");
+
     let context = zmq::Context::new();
-    let mut conn = Conn::new(store.clone(), transactor_address, &context).expect("Couldn't connect to DB -- does it exist?");
+    let mut conn = match transactor_address {
+        Some(transactor_address) => {
+            let store = store_from_uri(uri).expect("Couldn't create store");
+            Conn::new(store, transactor_address, &context)
+        }
+        None => Conn::connect(uri, &context),
+    }.expect("Couldn't connect to DB -- does it exist?");
     let mut rl = rustyline::Editor::<()>::new();
     loop {
         let readline = rl.readline("> ");
@@ -40,14 +203,27 @@ Commands:
                         let db = conn.db().unwrap();
                         let db_fetched_at = Instant::now();
                         let db_fetch_time = db_fetched_at.duration_since(start);
-                        match query(q, &db) {
+                        let cancel = CancelToken::new();
+                        *running_query.lock().unwrap() = cancel.clone();
+                        match run_query(q, &db, &cancel) {
                             Ok(res) => {
                                 let end = Instant::now();
                                 let total_time = end.duration_since(start);
                                 let query_time = end.duration_since(db_fetched_at);
-                                println!("{}", res);
+                                settings.print(&settings.render(&res, &db));
                                 info!("Query executed in {} ms ({} to fetch db, {} to execute query)", total_time.as_millis(), db_fetch_time.as_millis(), query_time.as_millis());
                             },
+                            Err(Error::Cancelled) => println!("Query cancelled."),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::Run(name, params)) => {
+                        let db = conn.db().unwrap();
+                        let cancel = CancelToken::new();
+                        *running_query.lock().unwrap() = cancel.clone();
+                        match db.named_query(&name, &params).and_then(|q| run_query(q, &db, &cancel)) {
+                            Ok(res) => settings.print(&settings.render(&res, &db)),
+                            Err(Error::Cancelled) => println!("Query cancelled."),
                             Err(e) => println!("ERROR: {:?}", e),
                         }
                     }
@@ -73,16 +249,118 @@ Commands:
                         }
                     }
                     Ok(Input::Dump) => {
-                        println!(
-                            "{}",
-                            query(
-                                parse_query(
-                                    "find ?ent ?attname ?val where (?ent ?att \
-                                     ?val) (?att db:ident ?attname)",
-                                ).unwrap(),
-                                &conn.db().unwrap()
-                            ).unwrap()
-                        )
+                        let db = conn.db().unwrap();
+                        let res = query(
+                            parse_query(
+                                "find ?ent ?attname ?val where (?ent ?att \
+                                 ?val) (?att db:ident ?attname)",
+                            ).unwrap(),
+                            &db
+                        ).unwrap();
+                        settings.print(&settings.render(&res, &db));
+                    }
+                    Ok(Input::CopyTo(q, path)) => {
+                        let result = conn.db()
+                            .and_then(|db| {
+                                let file = File::create(&path)?;
+                                let vars = q.find.clone();
+                                csv_io::write_rows(&vars, query_iter(q, &db), file)
+                            });
+                        match result {
+                            Ok(rows) => println!("Wrote {} rows to {}", rows, path),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::CopyFrom(path, attributes)) => {
+                        let result = conn.db()
+                            .and_then(|db| csv_io::read_entities(&db, &attributes, File::open(&path)?))
+                            .and_then(|tx| conn.transact(tx));
+                        match result {
+                            Ok(TxReport::Success { new_entities, .. }) => {
+                                println!("Loaded {} entities from {}", new_entities.len(), path)
+                            }
+                            Ok(report) => println!("{:?}", report),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::DumpTo(path)) => {
+                        let result = conn.db()
+                            .and_then(|db| datoms::write_log(&db, BufWriter::new(File::create(&path)?)));
+                        match result {
+                            Ok(written) => println!("Wrote {} datoms to {}", written, path),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::Load(path)) => {
+                        match load_datoms(&conn, &path) {
+                            Ok(txs) => println!("Loaded {} transactions from {}", txs, path),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::Explain(q)) => {
+                        match conn.db().and_then(|db| plan::plan_query(q, &db)) {
+                            Ok(plan) => print!("{}", plan),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::TxData(tx)) => {
+                        let db = conn.db().unwrap();
+                        match db.tx_data(tx) {
+                            Ok(data) => {
+                                let timestamp = data.timestamp.map_or("unknown".to_string(), |t| t.to_rfc3339());
+                                println!("Transaction {} at {}", tx.0, timestamp);
+                                for (attribute, value) in data.annotations {
+                                    println!("  {} {}", attribute, value);
+                                }
+                                let rows = data.records.into_iter().map(|rec| vec![
+                                    Value::String((if rec.retracted { "retract" } else { "add" }).into()),
+                                    Value::Ref(rec.entity),
+                                    Value::String(db.ident_for(rec.attribute).map_or_else(|| rec.attribute.0.to_string(), String::from)),
+                                    rec.value,
+                                ]).collect();
+                                let vars = ["op", "entity", "attribute", "value"].iter().map(|v| Var::new(*v)).collect();
+                                settings.print(&settings.render(&Relation(vars, rows), &db));
+                            }
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::Reindex) => {
+                        match conn.reindex() {
+                            Ok(true) => println!("Reindex started"),
+                            Ok(false) => println!("A reindex is already running"),
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
+                    }
+                    Ok(Input::Set(option, value)) => {
+                        if let Err(e) = settings.set(&option, &value) {
+                            println!("ERROR: {}", e.message());
+                        }
+                    }
+                    Ok(Input::Schema) => {
+                        match conn.db().and_then(|db| db.attributes()) {
+                            Ok(attrs) => {
+                                let rows = attrs.into_iter().map(|attr| {
+                                    let metadata: Vec<_> = attr.metadata
+                                        .iter()
+                                        .map(|(k, v)| format!("{}={}", k, v))
+                                        .collect();
+                                    vec![
+                                        Value::String(attr.ident),
+                                        Value::String(attr.value_type.map(|t| t.ident()).unwrap_or("").into()),
+                                        Value::String(attr.indexed.to_string()),
+                                        Value::String(attr.allowed_values.join(" ")),
+                                        Value::String(attr.doc.unwrap_or_default()),
+                                        Value::String(metadata.join(" ")),
+                                    ]
+                                }).collect();
+                                let vars = ["ident", "type", "indexed", "allowed", "doc", "metadata"]
+                                    .iter()
+                                    .map(|v| Var::new(*v))
+                                    .collect();
+                                println!("{}", Relation(vars, rows));
+                            }
+                            Err(e) => println!("ERROR: {:?}", e),
+                        }
                     }
                     Err(e) => println!("Oh no! {}", e),
                 };
@@ -97,13 +375,52 @@ Commands:
     }
 }
 
+/// The settings which can come from a config file or the environment
+/// instead of the command line (see `cliodb::config`).
+const SETTINGS: &[&str] = &["uri", "transactor-address", "node-cache-size", "log-level"];
+
 fn main() {
-    env_logger::init();
-    let argv: Vec<_> = args().collect();
-    if argv.len() != 3 {
-        println!("Usage: {} <db-uri> <transactor-address>", argv[0]);
+    let mut argv: Vec<_> = args().collect();
+    let config_path = match argv.iter().position(|arg| arg == "--config") {
+        Some(i) if i + 1 < argv.len() => {
+            let path = argv.remove(i + 1);
+            argv.remove(i);
+            Some(path)
+        }
+        Some(_) => usage(&argv[0]),
+        None => None,
+    };
+    let config = Config::load(config_path.as_deref(), SETTINGS).unwrap_or_else(|e| {
+        eprintln!("{}", e.message());
         std::process::exit(1);
+    });
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let (Err(_), Some(filter)) = (env::var("RUST_LOG"), config.get("log-level")) {
+        logger.parse(filter);
     }
+    logger.init();
+    if let Some(nodes) = config.get("node-cache-size") {
+        match nodes.parse() {
+            Ok(nodes) => set_node_cache_size(nodes),
+            Err(_) => {
+                eprintln!("node-cache-size must be a number");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Arguments override the config file and the environment.
+    let uri = argv.get(1).map(String::as_str).or_else(|| config.get("uri"));
+    let transactor_address = argv.get(2).map(String::as_str).or_else(|| config.get("transactor-address"));
+    match uri {
+        Some(uri) if argv.len() <= 3 => run(uri, transactor_address),
+        _ => usage(&argv[0]),
+    }
+}
 
-    run(&argv[1], &argv[2]);
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--config <file>] <db-uri> <transactor-address>", program);
+    println!("       {} [--config <file>] cliodb://<transactor-host>:<port>[?store=<db-uri>]", program);
+    std::process::exit(1);
 }
@@ -0,0 +1,74 @@
+extern crate cliodb;
+extern crate clap;
+extern crate log;
+extern crate env_logger;
+
+use std::process;
+use std::time::Duration;
+use log::error;
+
+use cliodb::conn::store_from_uri;
+use cliodb::replication::Replicator;
+use clap::{Arg, App};
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ClioDB replicator")
+        .version("0.1.0")
+        .about("Continuously copies a database to a secondary store, for disaster recovery")
+        .arg(
+            Arg::with_name("primary")
+                .help("The store to copy from")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("secondary")
+                .help("The store to copy to, which may use a different backend")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .value_name("SECONDS")
+                .help("Sets how often to copy new data")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("Copies everything once and exits, e.g. for the final sync before a cutover")
+                .required(false),
+        )
+        .get_matches();
+
+    let open = |uri| store_from_uri(uri).unwrap_or_else(|e| {
+        error!("Failed to open {}: {:?}", uri, e);
+        process::exit(1);
+    });
+    let primary = open(matches.value_of("primary").unwrap());
+    let secondary = open(matches.value_of("secondary").unwrap());
+    let interval: u64 = matches.value_of("interval").unwrap().parse().unwrap_or_else(|_| {
+        error!("--interval must be a number of seconds");
+        process::exit(1);
+    });
+
+    let mut replicator = Replicator::new(primary, secondary).unwrap_or_else(|e| {
+        error!("Failed to start replicating: {:?}", e);
+        process::exit(1);
+    });
+    if matches.is_present("once") {
+        match replicator.sync() {
+            Ok(report) => println!("{:?}", report),
+            Err(e) => {
+                error!("Sync failed: {:?}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        replicator.run(Duration::from_secs(interval));
+    }
+}
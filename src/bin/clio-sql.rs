@@ -0,0 +1,70 @@
+extern crate cliodb;
+extern crate clap;
+extern crate log;
+extern crate env_logger;
+extern crate zmq;
+
+use std::process;
+use log::error;
+
+use cliodb::conn::{Conn, store_from_uri};
+use cliodb::pgwire::SqlServer;
+use clap::{Arg, App};
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ClioDB SQL frontend")
+        .version("0.1.0")
+        .arg(
+            Arg::with_name("uri")
+                .short("u")
+                .long("uri")
+                .value_name("URI")
+                .help("Sets the location of the backing key-value store")
+                .required_unless("connect")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("transactor")
+                .short("t")
+                .long("transactor")
+                .value_name("ADDRESS")
+                .help("Sets the address of the transactor")
+                .default_value("tcp://127.0.0.1:10405")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect")
+                .short("c")
+                .long("connect")
+                .value_name("URI")
+                .help("Sets the transactor and, optionally, the store, as cliodb://<host>:<port>[?store=<uri>]")
+                .conflicts_with("uri")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("Sets the address to accept Postgres clients on")
+                .default_value("127.0.0.1:5432")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let context = zmq::Context::new();
+    let conn = match matches.value_of("connect") {
+        Some(uri) => Conn::connect(uri, &context),
+        None => {
+            let store = store_from_uri(matches.value_of("uri").unwrap()).expect("Couldn't create store");
+            Conn::new(store, matches.value_of("transactor").unwrap(), &context)
+        }
+    }.expect("Couldn't connect to DB -- does it exist?");
+
+    let server = SqlServer::new(conn);
+    server.listen(matches.value_of("bind").unwrap()).unwrap_or_else(|e| {
+        error!("Failed to start server: {:?}", e);
+        process::exit(1);
+    }).join().unwrap();
+}
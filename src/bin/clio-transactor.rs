@@ -2,24 +2,157 @@ extern crate cliodb;
 extern crate clap;
 extern crate log;
 extern crate env_logger;
+extern crate libc;
 
+use std::env;
+use std::fs;
 use std::process;
-use log::error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::{error, info};
 
-use cliodb::server::TransactorService;
+use cliodb::config::Config;
+use cliodb::conn::store_from_uri;
+use cliodb::encryption::Keyring;
+use cliodb::reindex::{ReindexPolicy, Schedule};
+use cliodb::server::{QueryLimits, TransactorService};
+use cliodb::tx::Transactor;
+use cliodb::set_node_cache_size;
 use clap::{Arg, App};
 
+/// The flags which can also be set in a config file or the
+/// environment (see `cliodb::config`).
+const SETTINGS: &[&str] = &[
+    "uri",
+    "advertise-store",
+    "bind",
+    "health-bind",
+    "shutdown-grace-ms",
+    "serve-queries",
+    "query-timeout-ms",
+    "query-max-rows",
+    "reindex-schedule",
+    "reindex-novelty",
+    "reindex-latency-factor",
+    "verify-reindex",
+    "encryption-key-file",
+    "node-cache-size",
+    "log-level",
+];
+
 fn main() {
-    env_logger::init();
     let matches = App::new("ClioDB transactor")
         .version("0.1.0")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads settings from a TOML file, with a key for each flag below")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("uri")
                 .short("u")
                 .long("uri")
                 .value_name("URI")
-                .help("Sets the location of the backing key-value store")
-                .required(true)
+                .help("Sets the location of the backing key-value store (required, here or in the config)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("Sets the address to listen on (default tcp://0.0.0.0:$PORT if PORT is set, else tcp://127.0.0.1:10405)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("health-bind")
+                .long("health-bind")
+                .value_name("ADDRESS")
+                .help("Answers HTTP health checks (GET /live and /ready) on a host:port, e.g. 0.0.0.0:8080")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown-grace-ms")
+                .long("shutdown-grace-ms")
+                .value_name("MS")
+                .help("On SIGTERM or SIGINT, waits this long for pending transactions to commit (default 10000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("advertise-store")
+                .long("advertise-store")
+                .value_name("URI")
+                .help("Gives clients which ask for the store this URI (by default, they aren't told)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serve-queries")
+                .long("serve-queries")
+                .help("Runs queries for clients which can't reach the store, within the query limits")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("query-timeout-ms")
+                .long("query-timeout-ms")
+                .value_name("MS")
+                .help("Cancels served queries which run longer than this (default 5000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("query-max-rows")
+                .long("query-max-rows")
+                .value_name("ROWS")
+                .help("Fails served queries which return more rows than this (default 10000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reindex-schedule")
+                .long("reindex-schedule")
+                .value_name("CRON")
+                .help("Reindexes in the minutes (UTC) matching a cron-style spec, e.g. \"*/10 2-4 * * *\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reindex-novelty")
+                .long("reindex-novelty")
+                .value_name("RECORDS")
+                .help("Always reindexes once this many records have been added (default 100000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reindex-latency-factor")
+                .long("reindex-latency-factor")
+                .value_name("FACTOR")
+                .help("Reindexes when served queries slow down by this factor (default 2, 0 to disable)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify-reindex")
+                .long("verify-reindex")
+                .help("Compares rebuilt indices with the old ones before switching to them")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("encryption-key-file")
+                .long("encryption-key-file")
+                .value_name("FILE")
+                .help("Encrypts db:encrypted attributes with the 64 hex digit key in FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("node-cache-size")
+                .long("node-cache-size")
+                .value_name("NODES")
+                .help("Sets how many index nodes each index keeps decoded in memory (default 1024)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("FILTER")
+                .help("Sets the log filter, e.g. info or cliodb=debug, if RUST_LOG isn't set (default error)")
                 .takes_value(true),
         )
         .arg(
@@ -31,14 +164,97 @@ fn main() {
         )
         .get_matches();
 
-    let backing_store_uri = matches.value_of("uri").unwrap();
-    // FIXME: accept as arg
-    let bind_address ="tcp://127.0.0.1:10405";
+    let config = Config::load(matches.value_of("config"), SETTINGS).unwrap_or_else(|e| {
+        eprintln!("{}", e.message());
+        process::exit(1);
+    });
+    // Flags override the config file and the environment.
+    let setting = |key| matches.value_of(key).or_else(|| config.get(key));
+    let switch = |key| matches.is_present(key) || config.is_set(key);
+    let number = |key| setting(key).map(|n| n.parse::<u64>().unwrap_or_else(|_| fail(&format!("{} must be a number", key))));
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let (Err(_), Some(filter)) = (env::var("RUST_LOG"), setting("log-level")) {
+        logger.parse(filter);
+    }
+    logger.init();
+
+    let backing_store_uri = setting("uri").unwrap_or_else(|| fail("the store URI must be given with --uri or in the config"));
+    let bind_address = match (setting("bind"), env::var("PORT")) {
+        (Some(address), _) => address.to_string(),
+        (None, Ok(port)) => format!("tcp://0.0.0.0:{}", port),
+        (None, Err(_)) => "tcp://127.0.0.1:10405".to_string(),
+    };
+    let grace = Duration::from_millis(number("shutdown-grace-ms").unwrap_or(10_000));
+    if let Some(nodes) = number("node-cache-size") {
+        set_node_cache_size(nodes as usize);
+    }
+
+    let mut policy = ReindexPolicy::default();
+    if let Some(spec) = setting("reindex-schedule") {
+        policy.schedule = Some(Schedule::parse(spec).unwrap_or_else(|e| fail(&e.message())));
+    }
+    if let Some(n) = number("reindex-novelty") {
+        policy.max_novelty = n as usize;
+        policy.min_novelty = policy.min_novelty.min(policy.max_novelty);
+    }
+    if let Some(factor) = setting("reindex-latency-factor") {
+        let factor: f64 = factor.parse().unwrap_or_else(|_| fail("reindex-latency-factor must be a number"));
+        policy.latency_factor = if factor > 0.0 { Some(factor) } else { None };
+    }
+    policy.verify = switch("verify-reindex");
 
     let context = zmq::Context::new();
-    let server = TransactorService::new(backing_store_uri, &context).unwrap();
-    server.listen(bind_address).unwrap_or_else(|e| {
+    let store = store_from_uri(backing_store_uri).unwrap();
+    let mut transactor = Transactor::new(store).unwrap_or_else(|e| fail(&e.message()));
+    transactor.set_reindex_policy(policy);
+    if let Some(path) = setting("encryption-key-file") {
+        let hex = fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("can't read {}: {}", path, e)));
+        transactor.set_keyring(Keyring::from_hex(&hex).unwrap_or_else(|e| fail(&e.message())));
+    }
+    let mut server = TransactorService::with_transactor(transactor, &context).unwrap();
+    if let Some(uri) = setting("advertise-store") {
+        server.advertise_store(uri);
+    }
+    if switch("serve-queries") {
+        let mut limits = QueryLimits::default();
+        if let Some(ms) = number("query-timeout-ms") {
+            limits.timeout = Duration::from_millis(ms);
+        }
+        if let Some(rows) = number("query-max-rows") {
+            limits.max_rows = rows as usize;
+        }
+        server.serve_queries(limits);
+    }
+    server.listen(&bind_address).unwrap_or_else(|e| {
         error!("Failed to start server: {:?}", e);
         process::exit(1);
-    }).join();
+    });
+    if let Some(address) = setting("health-bind") {
+        server.serve_health(address).unwrap_or_else(|e| fail(&e.message()));
+    }
+
+    let handler = request_stop as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    while !STOP_REQUESTED.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    info!("Shutting down");
+    server.shutdown(grace).unwrap_or_else(|e| fail(&e.message()));
+}
+
+/// Set by SIGTERM or SIGINT, after which the transactor commits what
+/// it has been sent and exits.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn fail(msg: &str) -> ! {
+    error!("{}", msg);
+    process::exit(1);
 }
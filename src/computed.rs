@@ -0,0 +1,78 @@
+//! Computed attributes, whose values the transactor derives from
+//! other attributes of the same entity and keeps up to date as those
+//! change.
+//!
+//! A computed attribute is a `TxListener`: register it with
+//! `Transactor::add_listener`. Its values are ordinary datoms,
+//! asserted and retracted in the transaction that changed the inputs.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use im::HashMap;
+
+use {Entity, Fact, Result, TxItem, Value};
+use tx::{PendingTx, TxListener};
+
+/// Computes an attribute's value from an entity's current values for
+/// the inputs (keyed by ident, with inputs the entity lacks left
+/// out). `None` means the entity shouldn't have a value.
+pub type Compute = Arc<dyn Fn(&HashMap<String, Vec<Value>>) -> Option<Value> + Send + Sync>;
+
+pub struct ComputedAttribute {
+    attribute: String,
+    inputs: Vec<String>,
+    compute: Compute,
+}
+
+impl ComputedAttribute {
+    /// Declares `attribute` as computed from `inputs`, e.g. a
+    /// `person:fullName` from `person:first` and `person:last`. The
+    /// attribute has to be in the schema before it's first computed.
+    pub fn new<F>(attribute: &str, inputs: &[&str], compute: F) -> ComputedAttribute
+    where
+        F: Fn(&HashMap<String, Vec<Value>>) -> Option<Value> + Send + Sync + 'static,
+    {
+        ComputedAttribute {
+            attribute: attribute.to_string(),
+            inputs: inputs.iter().map(|i| i.to_string()).collect(),
+            compute: Arc::new(compute),
+        }
+    }
+}
+
+impl TxListener for ComputedAttribute {
+    fn before_commit(&mut self, tx: &PendingTx) -> Result<Vec<TxItem>> {
+        let inputs: Vec<Entity> = self.inputs
+            .iter()
+            .filter_map(|ident| tx.db_after.schema.resolve(ident))
+            .collect();
+        let changed: BTreeSet<Entity> = tx.records
+            .iter()
+            .filter(|r| inputs.contains(&r.attribute))
+            .map(|r| r.entity)
+            .collect();
+
+        let mut items = vec![];
+        for entity in changed {
+            let mut values = tx.db_after.entity(entity)?;
+            let old = values.remove(&self.attribute).unwrap_or_default();
+            let values: HashMap<String, Vec<Value>> = values
+                .into_iter()
+                .filter(|(ident, _)| self.inputs.contains(ident))
+                .collect();
+            let new = (self.compute)(&values);
+
+            for value in old.iter().filter(|v| Some(*v) != new.as_ref()) {
+                items.push(TxItem::Retraction(Fact::new(entity, self.attribute.clone(), value.clone())));
+            }
+            if let Some(value) = new {
+                if !old.contains(&value) {
+                    items.push(TxItem::Addition(Fact::new(entity, self.attribute.clone(), value)));
+                }
+            }
+        }
+
+        Ok(items)
+    }
+}
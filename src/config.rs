@@ -0,0 +1,121 @@
+//! Config files for the binaries, so deployments can be set up
+//! declaratively instead of with long command lines. A config file is
+//! TOML with a key for each of a binary's long flags:
+//!
+//! ```toml
+//! uri = "cliodb:sqlite:///var/lib/cliodb/db.sqlite"
+//! bind = "tcp://0.0.0.0:10405"
+//! reindex-novelty = 50000
+//! verify-reindex = true
+//! ```
+//!
+//! Every key can also be set with an environment variable named after
+//! it, `CLIODB_` followed by the key in capitals with underscores for
+//! dashes (e.g. `CLIODB_REINDEX_NOVELTY`), which overrides the file.
+//! Flags given on the command line override both.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use toml;
+
+use Result;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    /// Reads the config file at `path`, if there is one, and the
+    /// environment. Only `keys` can be set; any other key in the file
+    /// is an error, so that a misspelled setting isn't ignored.
+    pub fn load(path: Option<&str>, keys: &[&str]) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+                Config::parse(&text, keys).map_err(|e| format!("{}: {}", path, e.message()))?
+            }
+            None => Config::default(),
+        };
+        for key in keys {
+            if let Ok(value) = env::var(env_var(key)) {
+                config.values.insert(key.to_string(), value);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parses the text of a config file.
+    pub fn parse(text: &str, keys: &[&str]) -> Result<Config> {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let error = &parser.errors[0];
+                let (line, _) = parser.to_linecol(error.lo);
+                return Err(format!("line {}: {}", line + 1, error.desc).into());
+            }
+        };
+
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            if !keys.contains(&key.as_str()) {
+                return Err(format!("unknown setting {}", key).into());
+            }
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(n) => n.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                v => return Err(format!("{} must be a string, number or boolean, not {}", key, v.type_str()).into()),
+            };
+            values.insert(key, value);
+        }
+        Ok(Config { values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    /// Whether a switch is turned on, with `true` (or `1` in the
+    /// environment).
+    pub fn is_set(&self, key: &str) -> bool {
+        matches!(self.get(key), Some("true") | Some("1"))
+    }
+}
+
+/// The environment variable which overrides `key`.
+pub fn env_var(key: &str) -> String {
+    format!("CLIODB_{}", key.to_uppercase().replace('-', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &[&str] = &["uri", "reindex-novelty", "reindex-latency-factor", "verify-reindex"];
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            uri = "cliodb:sqlite:///tmp/db.sqlite"
+            reindex-novelty = 5000
+            reindex-latency-factor = 1.5
+            verify-reindex = true
+        "#, KEYS).unwrap();
+        assert_eq!(config.get("uri"), Some("cliodb:sqlite:///tmp/db.sqlite"));
+        assert_eq!(config.get("reindex-novelty"), Some("5000"));
+        assert_eq!(config.get("reindex-latency-factor"), Some("1.5"));
+        assert!(config.is_set("verify-reindex"));
+        assert_eq!(env_var("reindex-novelty"), "CLIODB_REINDEX_NOVELTY");
+
+        let err = Config::parse("uri = \"x\"\nreindex-novelty = [1]", KEYS).unwrap_err().message();
+        assert!(err.contains("must be a string, number or boolean"), "{}", err);
+        let err = Config::parse("url = \"x\"", KEYS).unwrap_err().message();
+        assert_eq!(err, "unknown setting url");
+        assert!(Config::parse("uri = ", KEYS).unwrap_err().message().starts_with("line 1:"));
+    }
+}
@@ -1,21 +1,65 @@
+use std::ops::Range;
+use std::result;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rmp_serde;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
 
-use {Result, Tx, TxReport, Entity, EAVT, AEVT, AVET, VAET};
-use backends::KVStore;
+use {Error, Result, Relation, Tx, TxReport, Entity, Value, EAVT, AEVT, AVET, VAET, TEAV};
+use parser::{parse_query, parse_tx};
+use queries::execution;
+use backends::{KVStore, TxStream};
 use backends::sqlite::SqliteStore;
 use backends::mysql::MysqlStore;
 use db::{Db, DbMetadata};
 use index::Index;
+use tx::drop_fenced_txs;
+use access::AccessPolicy;
+use encryption::Keyring;
+use schema::{AttributeDef, Schema};
+use lint::LookupTracker;
+use server::{Request, StoreInfo, PROTOCOL_VERSION};
 
 
 pub struct Conn {
-    socket: Arc<Mutex<zmq::Socket>>, // FIXME: is this actually necessary?
+    /// The transactor's socket, or None for a read-only Conn.
+    socket: Option<Arc<Mutex<zmq::Socket>>>, // FIXME: is this actually necessary?
     store: Arc<dyn KVStore>,
     latest_db: Option<Db>,
     last_known_tx: Option<i64>,
+    /// Tails the tx log from `last_known_tx`.
+    tx_stream: Option<TxStream>,
     last_seen_metadata: Option<DbMetadata>,
+    /// The newest writer epoch seen in the tx log, used to skip
+    /// entries appended by a fenced-off transactor.
+    writer_epoch: u64,
+    access_policy: Option<Arc<AccessPolicy>>,
+    keyring: Option<Arc<Keyring>>,
+    /// When set, `db()` waits up to this long for the tx log to
+    /// include the last transaction committed through this Conn.
+    read_your_writes: Option<Duration>,
+    /// When set, `db()` waits up to the given time for a Db at most
+    /// this stale (see `Db::staleness`).
+    max_staleness: Option<(i64, Duration)>,
+    /// The id of the last transaction committed through this Conn,
+    /// or -1 if there hasn't been one.
+    last_written_tx: AtomicI64,
+    /// The schema as of a transaction the log replay hasn't reached
+    /// yet, from `refresh_schema`.
+    newer_schema: Option<(i64, Schema)>,
+    /// Set when a transaction committed through this Conn changed
+    /// the schema, so the next `db()` refreshes it.
+    schema_stale: AtomicBool,
+    /// When set, the top this many levels of each index are
+    /// prefetched in the background whenever the index changes.
+    prefetch_levels: Option<usize>,
+    /// How queries through this Conn have looked up unindexed
+    /// attributes, shared by every Db it returns.
+    lookups: Arc<LookupTracker>,
 }
 
 // TODO: conn should have a way of subscribing to transactions
@@ -29,47 +73,306 @@ impl Conn {
     ) -> Result<Conn> {
         let socket = context.socket(zmq::REQ)?;
         socket.connect(transactor_address)?;
-        Ok(Conn {
-            socket: Arc::new(Mutex::new(socket)),
+        Ok(Conn::with_socket(store, Some(socket)))
+    }
+
+    /// Connects to a transactor and reads from the store it
+    /// advertises, so the transactor's address is the only
+    /// configuration a client needs.
+    pub fn from_transactor(transactor_address: &str, context: &zmq::Context) -> Result<Conn> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.connect(transactor_address)?;
+        let info = store_info(&socket)?;
+        Ok(Conn::with_socket(store_from_uri(&info.store_uri)?, Some(socket)))
+    }
+
+    /// Opens the store for reading only, without contacting a
+    /// transactor. `transact` and anything else that would need the
+    /// transactor fail with `Error::ReadOnly`, so analytics jobs and
+    /// dashboards can't write even if they're misconfigured.
+    pub fn open_read_only(store: Arc<dyn KVStore>) -> Conn {
+        Conn::with_socket(store, None)
+    }
+
+    fn with_socket(store: Arc<dyn KVStore>, socket: Option<zmq::Socket>) -> Conn {
+        Conn {
+            socket: socket.map(|socket| Arc::new(Mutex::new(socket))),
             store,
             latest_db: None,
             last_known_tx: None,
-            last_seen_metadata: None
-        })
+            tx_stream: None,
+            last_seen_metadata: None,
+            writer_epoch: 0,
+            access_policy: None,
+            keyring: None,
+            read_your_writes: None,
+            max_staleness: None,
+            last_written_tx: AtomicI64::new(-1),
+            newer_schema: None,
+            schema_stale: AtomicBool::new(false),
+            prefetch_levels: None,
+            lookups: Arc::default(),
+        }
+    }
+
+    /// Connects with a single URI naming the transactor and, unless
+    /// it should be asked, the store; see `parse_connection_uri`.
+    pub fn connect(uri: &str, context: &zmq::Context) -> Result<Conn> {
+        match parse_connection_uri(uri)? {
+            (transactor_address, Some(store_uri)) => Conn::new(store_from_uri(&store_uri)?, &transactor_address, context),
+            (transactor_address, None) => Conn::from_transactor(&transactor_address, context),
+        }
+    }
+
+    /// Asks the transactor for the store's location, its protocol
+    /// version and its latest transaction.
+    pub fn store_info(&self) -> Result<StoreInfo> {
+        store_info(&*self.transactor()?.lock()?)
+    }
+
+    /// Reserves `n` entity ids from the transactor, for bulk imports
+    /// which assign ids to rows themselves so that rows can refer to
+    /// each other. No other entity will be given an id in the range.
+    pub fn allocate_ids(&self, n: u64) -> Result<Range<i64>> {
+        allocate_ids(&*self.transactor()?.lock()?, n)
+    }
+
+    /// Asks the transactor to rebuild the indices now, e.g. at a
+    /// quiet time. Returns false if a rebuild was already running.
+    pub fn reindex(&self) -> Result<bool> {
+        reindex(&*self.transactor()?.lock()?)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.socket.is_none()
+    }
+
+    fn transactor(&self) -> Result<&Mutex<zmq::Socket>> {
+        self.socket.as_deref().ok_or(Error::ReadOnly)
+    }
+
+    /// Restricts every Db returned by this connection to the records
+    /// allowed by `policy`.
+    pub fn set_access_policy(&mut self, policy: AccessPolicy) {
+        self.access_policy = Some(Arc::new(policy));
+    }
+
+    /// Decrypts the values of encrypted attributes in every Db
+    /// returned by this connection. The key must be the one the
+    /// transactor encrypts them with.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.keyring = Some(Arc::new(keyring));
+    }
+
+    /// Makes every Db returned by this connection reflect the
+    /// transactions committed through it, waiting up to `timeout`
+    /// for them to show up in the store's tx log.
+    pub fn set_read_your_writes(&mut self, timeout: Duration) {
+        self.read_your_writes = Some(timeout);
+    }
+
+    /// Makes every Db returned by this connection lag the log by at
+    /// most `max_staleness` (see `Db::staleness`; 0 means fully
+    /// caught up), waiting up to `timeout` for the replay to catch up
+    /// and failing if it doesn't. This matters with stores whose tx
+    /// log reads can lag its writes.
+    pub fn set_max_staleness(&mut self, max_staleness: i64, timeout: Duration) {
+        self.max_staleness = Some((max_staleness, timeout));
+    }
+
+    /// Warms the node cache in the background after each reindex by
+    /// fetching the top `levels` levels of every index (2 covers the
+    /// root and the nodes directly below it).
+    pub fn set_prefetch_levels(&mut self, levels: usize) {
+        self.prefetch_levels = Some(levels);
     }
 
     pub fn db(&mut self) -> Result<Db> {
-        let metadata: DbMetadata = self.store.get_metadata()?;
+        if self.schema_stale.swap(false, Ordering::SeqCst) {
+            self.refresh_schema()?;
+        }
+        let db = match self.read_your_writes {
+            Some(timeout) => self.wait_for_db(self.last_written_tx(), timeout)?,
+            None => self.latest_db()?,
+        };
+        let db = match self.max_staleness {
+            Some((max_staleness, timeout)) => self.wait_for_fresh_db(db, max_staleness, timeout)?,
+            None => db,
+        };
+
+        let db = self.with_newer_schema(db);
+        Ok(self.restricted(db))
+    }
+
+    /// Returns a db that includes at least transaction `tx_id`,
+    /// waiting up to `timeout` for it to show up in the tx log.
+    pub fn db_at_least(&mut self, tx_id: i64, timeout: Duration) -> Result<Db> {
+        let db = self.wait_for_db(tx_id, timeout)?;
+        let db = self.with_newer_schema(db);
+        Ok(self.restricted(db))
+    }
+
+    /// The id of the last transaction committed through this Conn,
+    /// or -1 if there hasn't been one.
+    pub fn last_written_tx(&self) -> i64 {
+        self.last_written_tx.load(Ordering::SeqCst)
+    }
+
+    /// Whether `db` is missing a transaction committed through this
+    /// Conn, in which case it should be replaced (e.g. with
+    /// `db_at_least(conn.last_written_tx(), timeout)`) before reading
+    /// back what was written.
+    pub fn is_stale(&self, db: &Db) -> bool {
+        db.basis_tx < self.last_written_tx()
+    }
+
+    /// Fetches the current schema from the transactor, for use by
+    /// every Db this Conn returns until its replay of the log catches
+    /// up with it. Without this, an attribute created by a
+    /// transaction the replay hasn't reached yet doesn't exist yet:
+    /// queries naming it find nothing, and describing it fails with
+    /// "invalid attribute". It's called automatically after a
+    /// transaction through this Conn changes the schema, and is worth
+    /// calling when another client is known to have changed it.
+    pub fn refresh_schema(&mut self) -> Result<()> {
+        let reply: result::Result<(i64, Schema), String> = request(&*self.transactor()?.lock()?, &Request::GetSchema)?;
+        self.newer_schema = Some(reply?);
+        Ok(())
+    }
 
-        if Some(&metadata) != self.last_seen_metadata.as_ref() {
-            // The underlying index has changed, so we need a new database. Invalidate the cache.
-            self.last_known_tx = None;
-            self.latest_db = None;
-            self.last_seen_metadata = Some(metadata.clone());
+    /// Gives `db` the schema from `refresh_schema` if it's newer than
+    /// its own.
+    fn with_newer_schema(&mut self, db: Db) -> Db {
+        match self.newer_schema {
+            Some((tx, ref schema)) if db.basis_tx < tx => Db { schema: schema.clone(), ..db },
+            Some(_) => {
+                self.newer_schema = None;
+                db
+            }
+            None => db,
+        }
+    }
+
+    fn restricted(&self, db: Db) -> Db {
+        let db = match self.keyring {
+            Some(ref keyring) => db.with_keyring(keyring.clone()),
+            None => db,
+        };
+        match self.access_policy {
+            Some(ref policy) => db.restrict(policy.clone()),
+            None => db,
+        }
+    }
+
+    fn wait_for_db(&mut self, target: i64, timeout: Duration) -> Result<Db> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let db = self.latest_db()?;
+            if self.last_known_tx.unwrap_or(-1) >= target {
+                return Ok(db);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("timed out waiting for tx {} to reach the tx log", target).into());
+            }
+            thread::sleep(Duration::from_millis(10));
         }
+    }
+
+    /// Replays the log until `db` is at most `max_staleness` behind
+    /// it, giving up after `timeout`.
+    fn wait_for_fresh_db(&mut self, mut db: Db, max_staleness: i64, timeout: Duration) -> Result<Db> {
+        let deadline = Instant::now() + timeout;
+        while db.staleness() > max_staleness {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "db is at tx {} but the log has reached tx {} (staleness {}, at most {} allowed)",
+                    db.basis_tx, db.latest_tx, db.staleness(), max_staleness
+                ).into());
+            }
+            thread::sleep(Duration::from_millis(10));
+            db = self.latest_db()?;
+        }
+        Ok(db)
+    }
+
+    /// Returns the latest db (unrestricted by the access policy),
+    /// replaying any transactions since the last call.
+    fn latest_db(&mut self) -> Result<Db> {
+        // Only decode the metadata when its version has moved on
+        // from the one already seen.
+        let newer_metadata = match self.last_seen_metadata {
+            Some(ref seen) => self.store.get_metadata_if_newer(seen.version)?,
+            None => Some(self.store.get_metadata()?),
+        };
+        if let Some(metadata) = newer_metadata {
+            if Some(&metadata) != self.last_seen_metadata.as_ref() {
+                // The underlying index has changed, so we need a new database. Invalidate the cache.
+                self.last_known_tx = None;
+                self.tx_stream = None;
+                self.latest_db = None;
+                self.last_seen_metadata = Some(metadata);
+            }
+        }
+        let metadata: &DbMetadata = self.last_seen_metadata.as_ref().expect("metadata was just fetched");
 
         // In order to avoid replaying transactions over and over on subsequent calls to db(),
         // we need to keep track of our place in the transaction log.
         let mut last_known_tx: i64 = self.last_known_tx.unwrap_or(metadata.last_indexed_tx);
+        let indexed_seq = metadata.indexed_seq();
+        let latest_in_log = metadata.latest_tx;
 
-        let mut db = self.latest_db.clone().unwrap_or_else(|| Db {
-            store: self.store.clone(),
-            schema: metadata.schema.clone(),
-            eav: Index::new(metadata.eav.clone(), self.store.clone(), EAVT),
-            ave: Index::new(metadata.ave.clone(), self.store.clone(), AVET),
-            aev: Index::new(metadata.aev.clone(), self.store.clone(), AEVT),
-            vae: Index::new(metadata.vae, self.store.clone(), VAET),
-        });
+        let mut db = match self.latest_db.clone() {
+            Some(db) => db,
+            None => {
+                let db = Db {
+                    store: self.store.clone(),
+                    schema: metadata.schema.clone().with_entity_idents(),
+                    eav: Index::new(metadata.eav.clone(), self.store.clone(), EAVT),
+                    ave: Index::new(metadata.ave.clone(), self.store.clone(), AVET::new(&metadata.schema)),
+                    aev: Index::new(metadata.aev.clone(), self.store.clone(), AEVT),
+                    vae: Index::new(metadata.vae.clone(), self.store.clone(), VAET),
+                    tea: Index::new(metadata.tea.clone(), self.store.clone(), TEAV),
+                    access: None,
+                    keyring: None,
+                    basis_tx: metadata.last_indexed_tx,
+                    latest_tx: metadata.last_indexed_tx,
+                    stats: Arc::new(metadata.stats.clone()),
+                    lookups: self.lookups.clone(),
+                    as_of: None,
+                    since: None,
+                    history: false,
+                };
+                if let Some(levels) = self.prefetch_levels {
+                    // The clone shares the indices' node caches.
+                    let prefetch_db = db.clone();
+                    thread::spawn(move || match prefetch_db.prefetch(levels) {
+                        Ok(n) => debug!("prefetched {} index nodes", n),
+                        Err(e) => warn!("index prefetch failed: {:?}", e),
+                    });
+                }
+                db
+            }
+        };
 
         // Read in latest transactions from the log.
-        for tx in self.store.get_txs(last_known_tx)? {
+        let store = &self.store;
+        let novelty = self.tx_stream
+            .get_or_insert_with(|| store.stream_txs(indexed_seq))
+            .poll()?;
+        for tx in drop_fenced_txs(novelty, &mut self.writer_epoch) {
             for record in tx.records {
-                let Entity(tx_id) = record.tx;
                 db = db.add_record(record)?;
-                last_known_tx = tx_id;
             }
+            last_known_tx = tx.id;
         }
 
+        // The metadata is written after each commit's log append, so
+        // it names a transaction the log has, even if reading the log
+        // doesn't return it yet.
+        db.latest_tx = db.latest_tx.max(latest_in_log);
+
         self.last_known_tx = Some(last_known_tx).clone();
         self.latest_db = Some(db.clone());
 
@@ -77,13 +380,166 @@ impl Conn {
     }
 
     pub fn transact(&self, tx: Tx) -> Result<TxReport> {
-        let sock = self.socket.lock()?;
-        sock.send(&rmp_serde::to_vec(&tx)?, 0)?;
-        let reply = sock.recv_bytes(0)?;
-        Ok(rmp_serde::from_read_ref(&reply)?)
+        let report: TxReport = request(&*self.transactor()?.lock()?, &Request::Transact(tx))?;
+
+        if let TxReport::Success { tx: Entity(tx_id), schema_changed, .. } = report {
+            self.last_written_tx.fetch_max(tx_id, Ordering::SeqCst);
+            if schema_changed {
+                self.schema_stale.store(true, Ordering::SeqCst);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Parses and runs a query against the latest db.
+    pub fn q(&mut self, query: &str) -> Result<Relation> {
+        let query = parse_query(query)?;
+        execution::query(query, &self.db()?)
+    }
+
+    /// Runs the query stored as `name` against the latest db. See
+    /// `Db::named_query`.
+    pub fn q_named(&mut self, name: &str, params: &[Value]) -> Result<Relation> {
+        let db = self.db()?;
+        execution::query(db.named_query(name, params)?, &db)
+    }
+
+    /// Parses and commits a transaction. Unlike `transact`, a
+    /// transaction the transactor rejects is returned as an error.
+    pub fn tx(&self, tx: &str) -> Result<TxReport> {
+        match self.transact(parse_tx(tx)?)? {
+            TxReport::Failure(msg) => Err(msg.into()),
+            report => Ok(report),
+        }
+    }
+
+    /// Makes the schema match `defs`, transacting only what's
+    /// missing or different (see `Db::schema_changes`), so it's safe
+    /// to call every time an application starts. Returns None if
+    /// there was nothing to do.
+    pub fn ensure_schema(&mut self, defs: &[AttributeDef]) -> Result<Option<TxReport>> {
+        // Diffed against the unrestricted db, since an access policy
+        // could hide facts about the attributes.
+        let items = self.latest_db()?.schema_changes(defs)?;
+        if items.is_empty() {
+            return Ok(None);
+        }
+
+        let tx = Tx {
+            items,
+            idempotency_key: None,
+            return_datoms: false,
+        };
+        match self.transact(tx)? {
+            TxReport::Failure(msg) => Err(msg.into()),
+            report => Ok(Some(report)),
+        }
+    }
+}
+
+/// A connection which does everything through the transactor, for
+/// clients that can't reach the store. Its queries run on the
+/// transactor, within whatever limits it sets (see
+/// `TransactorService::serve_queries`), rather than locally as with
+/// a `Conn`.
+pub struct RemoteConn {
+    socket: Mutex<zmq::Socket>,
+}
+
+impl RemoteConn {
+    pub fn new(transactor_address: &str, context: &zmq::Context) -> Result<RemoteConn> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.connect(transactor_address)?;
+        Ok(RemoteConn { socket: Mutex::new(socket) })
+    }
+
+    pub fn transact(&self, tx: Tx) -> Result<TxReport> {
+        request(&*self.socket.lock()?, &Request::Transact(tx))
+    }
+
+    /// Runs a query on the transactor, with `params` filling in its
+    /// `{}` placeholders as in `parse_query_with`.
+    pub fn q(&self, query: &str, params: &[Value]) -> Result<Relation> {
+        let reply: result::Result<Relation, String> =
+            request(&*self.socket.lock()?, &Request::Query(query.to_string(), params.to_vec()))?;
+        Ok(reply?)
+    }
+
+    pub fn store_info(&self) -> Result<StoreInfo> {
+        store_info(&*self.socket.lock()?)
+    }
+
+    /// Reserves `n` entity ids, as with `Conn::allocate_ids`.
+    pub fn allocate_ids(&self, n: u64) -> Result<Range<i64>> {
+        allocate_ids(&*self.socket.lock()?, n)
+    }
+
+    /// Asks the transactor to reindex, as with `Conn::reindex`.
+    pub fn reindex(&self) -> Result<bool> {
+        reindex(&*self.socket.lock()?)
     }
 }
 
+fn request<T: DeserializeOwned>(socket: &zmq::Socket, request: &Request) -> Result<T> {
+    socket.send(rmp_serde::to_vec(request)?, 0)?;
+    Ok(rmp_serde::from_read_ref(&socket.recv_bytes(0)?)?)
+}
+
+fn store_info(socket: &zmq::Socket) -> Result<StoreInfo> {
+    let reply: result::Result<StoreInfo, String> = request(socket, &Request::GetStoreInfo)?;
+    let info = reply?;
+    if info.protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "the transactor speaks protocol version {}, but this client speaks {}",
+            info.protocol_version, PROTOCOL_VERSION
+        ).into());
+    }
+    Ok(info)
+}
+
+fn allocate_ids(socket: &zmq::Socket, n: u64) -> Result<Range<i64>> {
+    let reply: result::Result<Range<i64>, String> = request(socket, &Request::AllocateIds(n))?;
+    Ok(reply?)
+}
+
+fn reindex(socket: &zmq::Socket) -> Result<bool> {
+    let reply: result::Result<bool, String> = request(socket, &Request::Reindex)?;
+    Ok(reply?)
+}
+
+/// Splits a connection URI, `cliodb://<host>:<port>?store=<store-uri>`,
+/// into the transactor's address and the store's URI. The store URI
+/// may leave off its `cliodb:` prefix, as in
+/// `store=mysql://user@db/cliodb`, and takes up the rest of the
+/// connection URI. Without a store, the transactor is asked for it.
+pub fn parse_connection_uri(uri: &str) -> Result<(String, Option<String>)> {
+    let rest = match uri.strip_prefix("cliodb://") {
+        Some(rest) => rest,
+        None => return Err(format!("not a connection URI: {}", uri).into()),
+    };
+    let (host, params) = match rest.find('?') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+    if host.is_empty() {
+        return Err(format!("no transactor address in {}", uri).into());
+    }
+
+    let store = if params.is_empty() {
+        None
+    } else if let Some(store) = params.strip_prefix("store=") {
+        if store.starts_with("cliodb:") {
+            Some(store.to_string())
+        } else {
+            Some(format!("cliodb:{}", store))
+        }
+    } else {
+        return Err(format!("unknown parameters in {}", uri).into());
+    };
+    Ok((format!("tcp://{}", host), store))
+}
+
 pub fn store_from_uri(uri: &str) -> Result<Arc<dyn KVStore>> {
     match &uri.split("//").collect::<Vec<_>>()[..] {
         &["cliodb:sqlite:", path] => {
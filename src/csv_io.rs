@@ -0,0 +1,156 @@
+//! Reads and writes CSV, for moving query results into spreadsheets
+//! and bulk-loading entities from them.
+//!
+//! Values are written in a form `parse_value` reads back: strings
+//! and idents as-is, refs as entity ids, timestamps in RFC 3339, and
+//! geo points as `lat lon`.
+
+use std::io::{Read, Write};
+
+use chrono::prelude::{DateTime, Utc};
+use im::HashMap;
+
+use {Entity, Relation, Result, Tx, TxItem, TxValue, Value, Var};
+use db::Db;
+use geo::GeoPoint;
+use schema::ValueType;
+
+/// Writes a query result as CSV, with a header row of the variable
+/// names.
+pub fn write_relation<W: Write>(relation: &Relation, writer: W) -> Result<()> {
+    let Relation(ref vars, ref tuples) = *relation;
+    write_rows(vars, tuples.iter().cloned().map(Ok), writer)?;
+    Ok(())
+}
+
+/// Writes rows as CSV as they come, e.g. from `query_iter`, with a
+/// header row of the variable names, and returns how many there
+/// were. An error from the rows stops the writing.
+pub fn write_rows<W, I>(vars: &[Var], rows: I, writer: W) -> Result<usize>
+    where W: Write,
+          I: IntoIterator<Item = Result<Vec<Value>>>
+{
+    let mut writer = csv::Writer::from_writer(writer);
+
+    writer.write_record(vars.iter().map(|var| var.name.as_str()))?;
+    let mut count = 0;
+    for tuple in rows {
+        writer.write_record(tuple?.iter().map(format_value))?;
+        count += 1;
+    }
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// Reads CSV rows as new entities, one per row. The first row is a
+/// header and is skipped; the columns are assigned to `attributes`
+/// in order, and parsed according to each attribute's value type.
+/// Empty cells are left out of their entity.
+pub fn read_entities<R: Read>(db: &Db, attributes: &[String], reader: R) -> Result<Tx> {
+    let mut value_types = vec![];
+    for attribute in attributes {
+        let value_type = db.schema.resolve(attribute)
+            .and_then(|entity| db.schema.value_types.get(&entity))
+            .ok_or_else(|| format!("invalid attribute: ident '{}' does not exist", attribute))?;
+        value_types.push(value_type.clone());
+    }
+
+    let mut items = vec![];
+    for (row, record) in csv::Reader::from_reader(reader).records().enumerate() {
+        let record = record?;
+        if record.len() != attributes.len() {
+            return Err(format!("row {} has {} columns, expected {}", row + 1, record.len(), attributes.len()).into());
+        }
+
+        let mut entity: HashMap<String, TxValue> = HashMap::new();
+        for ((attribute, value_type), field) in attributes.iter().zip(value_types.iter()).zip(record.iter()) {
+            if field.is_empty() {
+                continue;
+            }
+            let value = parse_value(value_type, field)
+                .map_err(|e| format!("row {}, column {}: {}", row + 1, attribute, e.message()))?;
+            entity.insert(attribute.clone(), TxValue::Value(value));
+        }
+        if !entity.is_empty() {
+            items.push(TxItem::NewEntity(entity));
+        }
+    }
+
+    Ok(Tx { items, idempotency_key: None, return_datoms: false })
+}
+
+/// The CSV form of a value, which `parse_value` reads back.
+pub fn format_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) | Value::Ident(ref s) => s.clone(),
+        Value::Ref(Entity(e)) => e.to_string(),
+        Value::Timestamp(t) => t.to_rfc3339(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Long(l) => l.to_string(),
+        Value::Geo(g) => format!("{} {}", g.lat(), g.lon()),
+    }
+}
+
+/// Parses a CSV field as a value of the given type.
+pub fn parse_value(value_type: &ValueType, field: &str) -> Result<Value> {
+    let invalid = || format!("invalid {} value {:?}", value_type.ident(), field);
+    Ok(match *value_type {
+        ValueType::String => Value::String(field.to_string()),
+        ValueType::Ident => Value::Ident(field.to_string()),
+        ValueType::Ref => Value::Ref(Entity(field.parse().map_err(|_| invalid())?)),
+        ValueType::Timestamp => {
+            let t = DateTime::parse_from_rfc3339(field).map_err(|_| invalid())?;
+            Value::Timestamp(t.with_timezone(&Utc))
+        }
+        ValueType::Boolean => Value::Boolean(field.parse().map_err(|_| invalid())?),
+        ValueType::Long => Value::Long(field.parse().map_err(|_| invalid())?),
+        ValueType::Geo => {
+            let coords: Vec<f64> = field.split_whitespace().map(|c| c.parse()).collect::<::std::result::Result<_, _>>()
+                .map_err(|_| invalid())?;
+            match coords.as_slice() {
+                [lat, lon] => Value::Geo(GeoPoint::new(*lat, *lon)),
+                _ => return Err(invalid().into()),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_relation_escapes_fields() {
+        let relation = Relation(
+            vec![Var::new("e"), Var::new("note")],
+            vec![
+                vec![Value::Ref(Entity(1)), Value::String("plain".into())],
+                vec![Value::Ref(Entity(2)), Value::String("has, comma and \"quotes\"".into())],
+            ],
+        );
+        let mut out = vec![];
+        write_relation(&relation, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "e,note\n1,plain\n2,\"has, comma and \"\"quotes\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let values = vec![
+            (ValueType::String, Value::String("a \"b\", c".into())),
+            (ValueType::Ident, Value::Ident("color:red".into())),
+            (ValueType::Ref, Value::Ref(Entity(42))),
+            (ValueType::Timestamp, Value::Timestamp("2019-03-01T12:00:00Z".parse().unwrap())),
+            (ValueType::Boolean, Value::Boolean(true)),
+            (ValueType::Long, Value::Long(-7)),
+            (ValueType::Geo, Value::Geo(GeoPoint::new(40.7128, -74.006))),
+        ];
+        for (value_type, value) in values {
+            assert_eq!(parse_value(&value_type, &format_value(&value)).unwrap(), value);
+        }
+        assert!(parse_value(&ValueType::Long, "seven").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! A line-delimited text format for datoms, shared by everything
+//! that moves datoms in or out of a database as text: `\dump` and
+//! `\load` in the CLI, backups, change-data-capture sinks and test
+//! fixtures.
+//!
+//! A file starts with the header `#cliodb-datoms 1` (the format
+//! version), followed by one datom per line:
+//!
+//! ```text
+//! #cliodb-datoms 1
+//! 1001 12 name "John" +
+//! 1001 12 parent #ref(11) +
+//! 1002 12 name "John" -
+//! ```
+//!
+//! The fields are the transaction id, the entity id, the attribute's
+//! ident, the value and the operation (`+` to assert, `-` to
+//! retract), separated by single spaces. Values are typed:
+//!
+//! - strings are quoted, with `\\`, `\"`, `\n`, `\r` and `\t` escaped,
+//!   and other control characters written as `\u{..}` (hex)
+//! - idents are written `:name`
+//! - refs are `#ref(<id>)` and longs are plain integers
+//! - booleans are `true` or `false`
+//! - timestamps are `#time(<RFC 3339, UTC>)`
+//! - geo points are `#geo(<lat> <lon>)`
+//!
+//! An ident or attribute containing whitespace, quotes or
+//! backslashes is quoted like a string. Blank lines are ignored.
+
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::iter;
+
+use chrono::prelude::{DateTime, SecondsFormat, Utc};
+
+use {Entity, Fact, Record, Result, Tx, TxItem, Value};
+use db::Db;
+use geo::GeoPoint;
+
+/// The version of the format written by this module.
+pub const VERSION: u32 = 1;
+
+const HEADER: &str = "#cliodb-datoms";
+
+/// One line of a datom file. Unlike a `Record`, it names its
+/// attribute, so it means the same thing in any database with the
+/// same schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datom {
+    pub tx: Entity,
+    pub entity: Entity,
+    pub attribute: String,
+    pub value: Value,
+    pub retracted: bool,
+}
+
+impl Datom {
+    pub fn from_record(db: &Db, record: &Record) -> Result<Datom> {
+        let attribute = db.ident_for(record.attribute)
+            .ok_or_else(|| format!("attribute {} has no ident", record.attribute.0))?;
+        Ok(Datom {
+            tx: record.tx,
+            entity: record.entity,
+            attribute: attribute.to_string(),
+            value: record.value.clone(),
+            retracted: record.retracted,
+        })
+    }
+
+    /// Parses one line (without its newline).
+    pub fn parse(line: &str) -> Result<Datom> {
+        let invalid = |what: &str| format!("invalid {} in datom {:?}", what, line);
+        let mut rest = line;
+        let tx = next_field(&mut rest).and_then(|f| f.parse().ok()).ok_or_else(|| invalid("tx"))?;
+        let entity = next_field(&mut rest).and_then(|f| f.parse().ok()).ok_or_else(|| invalid("entity"))?;
+        let attribute = parse_name(&mut rest).ok_or_else(|| invalid("attribute"))?;
+        expect_space(&mut rest).ok_or_else(|| invalid("attribute"))?;
+        let value = parse_value(&mut rest).ok_or_else(|| invalid("value"))?;
+        expect_space(&mut rest).ok_or_else(|| invalid("value"))?;
+        let retracted = match rest {
+            "+" => false,
+            "-" => true,
+            _ => return Err(invalid("operation").into()),
+        };
+
+        Ok(Datom { tx: Entity(tx), entity: Entity(entity), attribute, value, retracted })
+    }
+}
+
+impl fmt::Display for Datom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ", self.tx.0, self.entity.0)?;
+        write_name(f, &self.attribute)?;
+        write!(f, " ")?;
+        match self.value {
+            Value::String(ref s) => write_quoted(f, s)?,
+            Value::Ident(ref s) => {
+                write!(f, ":")?;
+                write_name(f, s)?;
+            }
+            Value::Ref(e) => write!(f, "#ref({})", e.0)?,
+            Value::Long(l) => write!(f, "{}", l)?,
+            Value::Boolean(b) => write!(f, "{}", b)?,
+            Value::Timestamp(t) => write!(f, "#time({})", t.to_rfc3339_opts(SecondsFormat::AutoSi, true))?,
+            Value::Geo(g) => write!(f, "#geo({} {})", g.lat(), g.lon())?,
+        }
+        write!(f, " {}", if self.retracted { "-" } else { "+" })
+    }
+}
+
+/// Writes a datom file: the header, then each datom on its own line.
+pub struct DatomWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DatomWriter<W> {
+    pub fn new(mut writer: W) -> Result<DatomWriter<W>> {
+        writeln!(writer, "{} {}", HEADER, VERSION)?;
+        Ok(DatomWriter { writer })
+    }
+
+    pub fn write(&mut self, datom: &Datom) -> Result<()> {
+        writeln!(self.writer, "{}", datom)?;
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the datoms of a datom file one at a time, after checking
+/// its header.
+pub struct DatomReader<R: BufRead> {
+    lines: ::std::io::Lines<R>,
+}
+
+impl<R: BufRead> DatomReader<R> {
+    pub fn new(reader: R) -> Result<DatomReader<R>> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or("empty datom file")??;
+        match header.strip_prefix(HEADER).map(|v| v.trim().parse::<u32>()) {
+            Some(Ok(VERSION)) => Ok(DatomReader { lines }),
+            Some(Ok(version)) => Err(format!("unsupported datom format version {}", version).into()),
+            _ => Err(format!("not a datom file (expected a {} header)", HEADER).into()),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DatomReader<R> {
+    type Item = Result<Datom>;
+
+    fn next(&mut self) -> Option<Result<Datom>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if !line.trim().is_empty() {
+                return Some(Datom::parse(&line));
+            }
+        }
+    }
+}
+
+/// Writes every transaction in `db`'s log, in order, returning the
+/// number of datoms written. The bootstrapped schema isn't in the
+/// log, so it isn't written either.
+pub fn write_log<W: Write>(db: &Db, writer: W) -> Result<usize> {
+    let mut writer = DatomWriter::new(writer)?;
+    let mut written = 0;
+    let mut txs = db.store.stream_txs(-1);
+    while let Some(tx) = txs.next_available() {
+        let tx = tx?;
+        if tx.id > db.basis_tx {
+            break;
+        }
+        for record in &tx.records {
+            writer.write(&Datom::from_record(db, record)?)?;
+            written += 1;
+        }
+    }
+    writer.into_inner()?;
+    Ok(written)
+}
+
+/// Turns datoms back into transactions, one for each run of datoms
+/// from the same transaction, as they're read. Datoms about the
+/// transactions themselves (like `db:txTimestamp`) are left out,
+/// since the transactor records its own. An error reading a datom
+/// takes the place of the transaction it was in.
+pub fn to_txs<I: IntoIterator<Item = Result<Datom>>>(datoms: I) -> impl Iterator<Item = Result<Tx>> {
+    let mut datoms = datoms.into_iter()
+        .filter(|datom| datom.as_ref().map_or(true, |datom| datom.entity != datom.tx))
+        .peekable();
+    let item = |datom: Datom| {
+        let fact = Fact::new(datom.entity, datom.attribute, datom.value);
+        if datom.retracted { TxItem::Retraction(fact) } else { TxItem::Addition(fact) }
+    };
+    iter::from_fn(move || {
+        let first = match datoms.next()? {
+            Ok(datom) => datom,
+            Err(e) => return Some(Err(e)),
+        };
+        let tx_entity = first.tx;
+        let mut tx = Tx { items: vec![item(first)], idempotency_key: None, return_datoms: false };
+        while let Some(datom) = datoms.next_if(|datom| datom.as_ref().map_or(true, |datom| datom.tx == tx_entity)) {
+            match datom {
+                Ok(datom) => tx.items.push(item(datom)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(tx))
+    })
+}
+
+fn needs_quotes(name: &str) -> bool {
+    name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\\')
+}
+
+fn write_name(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    if needs_quotes(name) {
+        write_quoted(f, name)
+    } else {
+        write!(f, "{}", name)
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Takes the field up to the next space, and the space.
+fn next_field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let end = rest.find(' ')?;
+    let field = &rest[..end];
+    *rest = &rest[end + 1..];
+    Some(field)
+}
+
+fn expect_space(rest: &mut &str) -> Option<()> {
+    *rest = rest.strip_prefix(' ')?;
+    Some(())
+}
+
+/// Takes a bare or quoted name.
+fn parse_name(rest: &mut &str) -> Option<String> {
+    if rest.starts_with('"') {
+        return parse_quoted(rest);
+    }
+    let end = rest.find(' ').unwrap_or(rest.len());
+    let name = &rest[..end];
+    *rest = &rest[end..];
+    if needs_quotes(name) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn parse_quoted(rest: &mut &str) -> Option<String> {
+    let mut chars = rest.strip_prefix('"')?.char_indices();
+    let mut s = String::new();
+    loop {
+        let (i, c) = chars.next()?;
+        match c {
+            '"' => {
+                *rest = &rest[i + 2..];
+                return Some(s);
+            }
+            '\\' => s.push(match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    if chars.next()?.1 != '{' {
+                        return None;
+                    }
+                    let mut hex = String::new();
+                    loop {
+                        match chars.next()?.1 {
+                            '}' => break,
+                            c => hex.push(c),
+                        }
+                    }
+                    ::std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c @ '"' | c @ '\\' => c,
+                _ => return None,
+            }),
+            c => s.push(c),
+        }
+    }
+}
+
+/// Takes the contents of `#<tag>(...)`.
+fn parse_tagged<'a>(rest: &mut &'a str, tag: &str) -> Option<&'a str> {
+    let inner = rest.strip_prefix('#')?.strip_prefix(tag)?.strip_prefix('(')?;
+    let end = inner.find(')')?;
+    *rest = &inner[end + 1..];
+    Some(&inner[..end])
+}
+
+fn parse_value(rest: &mut &str) -> Option<Value> {
+    if rest.starts_with('"') {
+        return parse_quoted(rest).map(Value::String);
+    }
+    if let Some(ident) = rest.strip_prefix(':') {
+        *rest = ident;
+        return parse_name(rest).map(Value::Ident);
+    }
+    if let Some(id) = parse_tagged(rest, "ref") {
+        return id.parse().ok().map(|id| Value::Ref(Entity(id)));
+    }
+    if let Some(t) = parse_tagged(rest, "time") {
+        return DateTime::parse_from_rfc3339(t).ok().map(|t| Value::Timestamp(t.with_timezone(&Utc)));
+    }
+    if let Some(coords) = parse_tagged(rest, "geo") {
+        let mut coords = coords.split(' ').map(|c| c.parse::<f64>());
+        return match (coords.next(), coords.next(), coords.next()) {
+            (Some(Ok(lat)), Some(Ok(lon)), None) => Some(Value::Geo(GeoPoint::new(lat, lon))),
+            _ => None,
+        };
+    }
+
+    let token = next_field_or_end(rest);
+    match token {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        _ => token.parse().ok().map(Value::Long),
+    }
+}
+
+/// Takes the field up to the next space, leaving the space.
+fn next_field_or_end<'a>(rest: &mut &'a str) -> &'a str {
+    let end = rest.find(' ').unwrap_or(rest.len());
+    let field = &rest[..end];
+    *rest = &rest[end..];
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_datom_roundtrip() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 3, 2, 30, 0).unwrap() + chrono::Duration::microseconds(15);
+        let values = vec![
+            Value::String("plain".into()),
+            Value::String("tab\there \"quoted\" back\\slash\nnewline \u{7}bell ünïcode".into()),
+            Value::String("".into()),
+            Value::Ident("color:red".into()),
+            Value::Ident("odd ident".into()),
+            Value::Ref(Entity(11)),
+            Value::Long(-42),
+            Value::Boolean(true),
+            Value::Timestamp(time),
+            Value::Geo(GeoPoint::new(51.4778, -0.0014)),
+        ];
+
+        let mut writer = DatomWriter::new(vec![]).unwrap();
+        let mut datoms = vec![];
+        for (i, value) in values.into_iter().enumerate() {
+            let datom = Datom {
+                tx: Entity(1000 + i as i64),
+                entity: Entity(12),
+                attribute: if i == 1 { "odd attribute".into() } else { "name".into() },
+                value,
+                retracted: i % 2 == 1,
+            };
+            writer.write(&datom).unwrap();
+            datoms.push(datom);
+        }
+        let text = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(text.starts_with("#cliodb-datoms 1\n1000 12 name \"plain\" +\n"), "{}", text);
+        assert_eq!(text.lines().count(), datoms.len() + 1);
+
+        let read: Vec<Datom> = DatomReader::new(text.as_bytes()).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(read, datoms);
+    }
+
+    #[test]
+    fn test_invalid_datoms() {
+        assert!(DatomReader::new("1 2 name 3 +\n".as_bytes()).is_err());
+        assert!(DatomReader::new("#cliodb-datoms 2\n".as_bytes()).is_err());
+        for invalid in &["1 2 name 3", "1 2 name 3 *", "x 2 name 3 +", "1 2 name \"open +", "1 2 name #ref(x) +", "1 2 name 3  +"] {
+            assert!(Datom::parse(invalid).is_err(), "{:?} should be invalid", invalid);
+        }
+
+        let text = "#cliodb-datoms 1\n\n5 6 name #ref(7) -\n";
+        let datoms: Vec<Datom> = DatomReader::new(text.as_bytes()).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(datoms, vec![Datom {
+            tx: Entity(5),
+            entity: Entity(6),
+            attribute: "name".into(),
+            value: Value::Ref(Entity(7)),
+            retracted: true,
+        }]);
+    }
+}
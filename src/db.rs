@@ -1,16 +1,33 @@
 use super::*;
 
+use std::cmp::Ordering;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
 
 use im::HashMap;
-use {Result, EAVT, AEVT, AVET, VAET};
-use index::Index;
-use schema::{Schema, ValueType};
-use queries::query;
+use {Result, EAVT, AEVT, AVET, VAET, TEAV};
+use index::{Comparator, Index};
+use schema::{Schema, ValueType, AttributeDef, AttributeInfo, Cardinality, Collation, Normalizer, SCHEMA_ATTRIBUTES};
+use access::{AccessFilter, AccessPolicy};
+use encryption::Keyring;
+use sandbox::Sandbox;
+use geo::{self, GeoPoint};
+use queries::{planner, query};
+use stats::Stats;
+use checksum;
+use lint::{self, LookupTracker};
+
+/// Records read from an index, in its order.
+type Records<'a> = Box<dyn Iterator<Item = Record> + 'a>;
 
 /// An *immutable* view of the database at a point in time.
 /// Only used for querying; for transactions, you need a Conn.
+///
+/// Cloning a Db is cheap (the indices are persistent and share their
+/// node caches), so to run several queries against the same basis,
+/// e.g. for the length of a web request, get one Db from the Conn
+/// and pass clones of it around rather than calling `Conn::db` again.
 #[derive(Clone)]
 pub struct Db {
     pub schema: Schema,
@@ -19,12 +36,46 @@ pub struct Db {
     pub ave: Index<Record, AVET>,
     pub aev: Index<Record, AEVT>,
     pub vae: Index<Record, VAET>,
+    /// The log index: every record, in transaction order, for
+    /// scanning ranges of transactions (see `tx_records`).
+    pub tea: Index<Record, TEAV>,
+    /// Visibility rules for the client reading this Db, if any.
+    /// Records the filter doesn't allow are dropped in
+    /// `records_matching`, so they can't reach query results.
+    pub access: Option<AccessFilter>,
+    /// The key for the values of `db:encrypted` attributes, if this
+    /// reader has it. Values are decrypted in `records_matching`;
+    /// in the transactor, they're also encrypted by `add`.
+    pub keyring: Option<Arc<Keyring>>,
+    /// The id of the latest transaction this Db includes.
+    pub basis_tx: i64,
+    /// The id of the latest transaction known to be in the log when
+    /// this Db was read. It's ahead of `basis_tx` when the log
+    /// couldn't be replayed that far yet, e.g. because the store's
+    /// reads lag behind its writes.
+    pub latest_tx: i64,
+    /// Statistics about the durable indices, for the query planner.
+    pub stats: Arc<Stats>,
+    /// How this peer's queries have looked up unindexed attributes.
+    pub lookups: Arc<LookupTracker>,
+    /// When set, only records from this transaction or earlier are
+    /// visible, as for `access`. See `as_of`.
+    pub as_of: Option<i64>,
+    /// When set, only records from after this transaction are
+    /// visible. See `since`.
+    pub since: Option<i64>,
+    /// When set, retractions are matched as records of their own
+    /// rather than cancelling the facts they retract. See `history`.
+    pub history: bool,
 }
 
 /// A structure designed to be stored in the backing store that enables
 /// a process to locate the indexes, tx log, etc.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct DbMetadata {
+    /// Ids below this may have been handed out. The transactor
+    /// reserves ids in blocks, so this is usually ahead of the ids
+    /// in the log.
     pub next_id: i64,
     pub last_indexed_tx: i64,
     pub schema: Schema,
@@ -32,43 +83,463 @@ pub struct DbMetadata {
     pub ave: String,
     pub aev: String,
     pub vae: String,
+    /// The epoch of the transactor which currently holds the write
+    /// lease. Each transactor claims a new epoch on startup.
+    #[serde(default)]
+    pub epoch: u64,
+    /// Incremented on every metadata write, so readers can tell
+    /// whether the metadata has changed without decoding it. Zero
+    /// for metadata written before versions existed.
+    #[serde(default)]
+    pub version: u64,
+    /// Per-attribute statistics, computed at the last reindex.
+    #[serde(default)]
+    pub stats: Stats,
+    /// The last transaction in the log when the metadata was
+    /// written, so readers can tell how far behind the log they are.
+    /// Zero for metadata written before it existed.
+    #[serde(default)]
+    pub latest_tx: i64,
+    /// The log position of `last_indexed_tx`. Missing from metadata
+    /// written before the log had positions of its own, when they
+    /// were the tx ids; see `indexed_seq`.
+    #[serde(default)]
+    pub last_indexed_seq: Option<i64>,
+    /// The log index's root. Empty in metadata written before it
+    /// existed, until the transactor backfills it (see `backfill`).
+    #[serde(default)]
+    pub tea: String,
+}
+
+impl DbMetadata {
+    /// The log position to replay the log from onto these indices.
+    pub fn indexed_seq(&self) -> i64 {
+        self.last_indexed_seq.unwrap_or(self.last_indexed_tx)
+    }
+
+    /// Serializes the metadata as it's stored, sealed with a checksum.
+    pub fn to_stored(&self) -> Result<Vec<u8>> {
+        Ok(checksum::seal(&rmp_serde::to_vec(self)?))
+    }
+
+    /// Reads metadata as stored by `to_stored`, or as it was stored
+    /// before it had a checksum.
+    pub fn from_stored(blob: &[u8]) -> Result<DbMetadata> {
+        let payload = checksum::unseal("db_metadata", blob)?;
+        Ok(rmp_serde::from_read_ref(payload)?)
+    }
+}
+
+/// What a single transaction changed, as returned by `Db::tx_data`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxData {
+    pub tx: Entity,
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Any other facts about the transaction entity, such as its
+    /// idempotency key or `db:admin` annotations, as (attribute
+    /// ident, value) pairs.
+    pub annotations: Vec<(String, Value)>,
+    /// The records the transaction added and retracted.
+    pub records: Vec<Record>,
 }
 
 impl Db {
     pub fn new(metadata: DbMetadata, store: Arc<dyn KVStore>) -> Db {
+        let ave = AVET::new(&metadata.schema);
         let db = Db {
             store: store.clone(),
-            schema: metadata.schema,
+            schema: metadata.schema.with_entity_idents(),
             eav: Index::new(metadata.eav, store.clone(), EAVT),
-            ave: Index::new(metadata.ave, store.clone(), AVET),
+            ave: Index::new(metadata.ave, store.clone(), ave),
             aev: Index::new(metadata.aev, store.clone(), AEVT),
-            vae: Index::new(metadata.vae, store, VAET),
+            vae: Index::new(metadata.vae, store.clone(), VAET),
+            tea: Index::new(metadata.tea, store, TEAV),
+            access: None,
+            keyring: None,
+            basis_tx: metadata.last_indexed_tx,
+            latest_tx: metadata.latest_tx.max(metadata.last_indexed_tx),
+            stats: Arc::new(metadata.stats),
+            lookups: Arc::default(),
+            as_of: None,
+            since: None,
+            history: false,
         };
 
         db
     }
 
+    /// Loads the top `levels` levels of each durable index into the
+    /// node cache, so that the first queries against this db don't
+    /// have to fetch them one round trip at a time.
+    pub fn prefetch(&self, levels: usize) -> Result<usize> {
+        Ok(self.eav.prefetch(levels)?
+            + self.ave.prefetch(levels)?
+            + self.aev.prefetch(levels)?
+            + self.vae.prefetch(levels)?
+            + if self.has_log_index() { self.tea.prefetch(levels)? } else { 0 })
+    }
+
     pub fn mem_index_size(&self) -> usize {
         self.eav.mem_index_size()
     }
 
+    /// Returns a view of this database which only exposes the
+    /// records allowed by `policy`.
+    pub fn restrict(&self, policy: Arc<AccessPolicy>) -> Db {
+        Db {
+            access: Some(AccessFilter::new(policy, &self.schema)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a view of this database which decrypts the values of
+    /// encrypted attributes with `keyring`.
+    pub fn with_keyring(&self, keyring: Arc<Keyring>) -> Db {
+        Db {
+            keyring: Some(keyring),
+            ..self.clone()
+        }
+    }
+
+    /// How far this Db lags behind the log, as the difference between
+    /// the ids of the latest transaction known to be in the log and
+    /// the latest one it includes. Ids are shared with entities, so
+    /// this isn't a count of transactions; zero means the Db is
+    /// up to date.
+    pub fn staleness(&self) -> i64 {
+        (self.latest_tx - self.basis_tx).max(0)
+    }
+
+    /// Returns a view of this database as it was just after
+    /// transaction `tx`: every index scan skips records from later
+    /// transactions, so facts asserted since are missing and facts
+    /// retracted since are back. The schema isn't rolled back, so
+    /// attributes created later can be named but have no values, and
+    /// the past values of `db:noHistory` attributes are lost.
+    pub fn as_of(&self, tx: Entity) -> Db {
+        Db {
+            as_of: Some(self.as_of.map_or(tx.0, |as_of| as_of.min(tx.0))),
+            basis_tx: self.basis_tx.min(tx.0),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a view of only the changes to this database after
+    /// transaction `tx`: every index scan skips records from it and
+    /// earlier transactions, so only facts asserted since are found,
+    /// and retractions of facts asserted before are dropped. Scans
+    /// which would read a whole index read just those transactions'
+    /// part of the log index instead.
+    pub fn since(&self, tx: Entity) -> Db {
+        Db {
+            since: Some(self.since.map_or(tx.0, |since| since.max(tx.0))),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a view of every assertion and retraction in this
+    /// database, rather than of the facts currently true: a clause
+    /// matches a fact once for each time it was asserted and once
+    /// for each time it was retracted. The transaction and kind of
+    /// each record can be bound with a clause's fourth and fifth
+    /// terms, as in `find ?v ?tx ?added where (?e name ?v ?tx ?added)`.
+    /// Combines with `as_of` and `since` to bound the history.
+    pub fn history(&self) -> Db {
+        Db {
+            history: true,
+            ..self.clone()
+        }
+    }
+
+    /// The visible records of the transactions in `txs` (by id), in
+    /// the order of the log index: by transaction, then entity,
+    /// attribute and value.
+    pub fn tx_records(&self, txs: RangeInclusive<i64>) -> Result<Vec<Record>> {
+        if !self.has_log_index() {
+            return Err("the log index hasn't been built yet".into());
+        }
+        let (first, last) = (*txs.start(), *txs.end());
+        let start = Record::addition(Entity(0), Entity(0), Value::String("".into()), Entity(first));
+        let records = self.tea
+            .range_from(start)
+            .take_while(|rec| rec.tx.0 <= last)
+            .filter(|rec| self.is_visible(rec))
+            .collect();
+        self.decrypt_records(records)
+    }
+
+    /// Describes what the transaction `tx` changed. Reads the log
+    /// index if it's been built, and the tx log otherwise.
+    pub fn tx_data(&self, tx: Entity) -> Result<TxData> {
+        let records = if self.has_log_index() {
+            self.tx_records(tx.0..=tx.0)?
+        } else {
+            let records = match self.store.get_tx(tx.0)? {
+                Some(raw) => raw.records.into_iter().filter(|rec| self.is_visible(rec)).collect(),
+                None => vec![],
+            };
+            self.decrypt_records(records)?
+        };
+        if records.is_empty() {
+            return Err(format!("no transaction {}", tx.0).into());
+        }
+
+        let mut data = TxData { tx, timestamp: None, annotations: vec![], records: vec![] };
+        for record in records {
+            if record.entity != tx {
+                data.records.push(record);
+                continue;
+            }
+            let ident = self.ident_for(record.attribute).map_or_else(|| record.attribute.0.to_string(), String::from);
+            match record.value {
+                Value::Timestamp(t) if ident == "db:txTimestamp" => data.timestamp = Some(t),
+                value => data.annotations.push((ident, value)),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Whether the log index has a durable tree yet, which it won't
+    /// in metadata from before it existed until it's backfilled.
+    fn has_log_index(&self) -> bool {
+        !self.tea.durable_root().is_empty()
+    }
+
+    /// The transactions whose part of the log index has `clause`'s
+    /// matches, when they're bounded below: by `since`, or by the
+    /// clause's bound transaction. Outside a history view, a fact
+    /// asserted in that transaction can be retracted in a later one,
+    /// so the range runs on to the end of the view.
+    fn log_range(&self, clause: &Clause) -> Option<RangeInclusive<i64>> {
+        if !self.has_log_index() {
+            return None;
+        }
+        let mut first = self.since.map(|since| since + 1);
+        let mut last = self.as_of.unwrap_or(i64::MAX);
+        if let Some(Term::Bound(tx)) = clause.tx {
+            first = Some(first.map_or(tx.0, |first| first.max(tx.0)));
+            if self.history {
+                last = last.min(tx.0);
+            }
+        }
+        first.map(|first| first..=last)
+    }
+
+    /// The records matching `clause` in the log index's part for the
+    /// transactions `txs`, in EAVT order, as if from the EAVT index.
+    /// Outside a history view, retractions of facts asserted before
+    /// the range are dropped.
+    fn log_records_matching(&self, clause: &Clause, binding: &Binding, txs: RangeInclusive<i64>) -> Vec<Record> {
+        let (first, last) = (*txs.start(), *txs.end());
+        let start = Record::addition(Entity(0), Entity(0), Value::String("".into()), Entity(first));
+        let mut records: Vec<Record> = self.tea
+            .range_from(start)
+            .take_while(|rec| rec.tx.0 <= last)
+            .filter(|rec| self.unify(binding, clause, rec).is_some())
+            .collect();
+        records.sort_by(|a, b| EAVT.compare(a, b));
+        if self.history { records } else { drop_unmatched_retractions(records) }
+    }
+
+    /// Forks this db into a `Sandbox`, where transactions apply to a
+    /// private in-memory copy and are discarded with it.
+    pub fn sandbox(&self) -> Sandbox {
+        Sandbox::new(self.clone())
+    }
+
+    fn is_visible(&self, record: &Record) -> bool {
+        if self.as_of.is_some_and(|as_of| record.tx.0 > as_of) || self.since.is_some_and(|since| record.tx.0 <= since) {
+            return false;
+        }
+        match self.access {
+            Some(ref filter) => filter.allows(record),
+            None => true,
+        }
+    }
+
     fn ident_entity(&self, ident: &Ident) -> Option<Entity> {
         match ident {
             &Ident::Entity(e) => Some(e),
-            &Ident::Name(ref name) => self.schema.idents.get(name).map(|e| *e)
+            &Ident::Name(ref name) => self.schema.resolve(name)
         }
     }
 
     // FIXME: make private
     // FIXME: should return a fallible iterator instead of a vec
     pub fn records_matching(&self, clause: &Clause, binding: &Binding) -> Result<Vec<Record>> {
+        let clause = self.encrypt_clause(clause.substitute(binding)?)?;
+        if let Some(attr) = match clause.attribute {
+            Term::Bound(ref a) => self.ident_entity(a),
+            Term::Unbound(_) => None,
+        } {
+            let by_value = matches!((&clause.entity, &clause.value), (Term::Unbound(_), Term::Bound(_)));
+            self.lookups.observe(&self.schema, attr, by_value);
+        }
+        let records = self.visible_records_matching(&clause, binding)?.collect();
+        self.decrypt_records(records)
+    }
+
+    /// The records matching `clause` which are visible in this view.
+    fn visible_records_matching<'a>(&'a self, clause: &'a Clause, binding: &'a Binding) -> Result<Records<'a>> {
+        let records = self.index_records_matching(clause, binding)?;
+        let records: Records = if self.access.is_none() && self.as_of.is_none() && self.since.is_none() {
+            records
+        } else {
+            Box::new(records.filter(move |rec| self.is_visible(rec)))
+        };
+        if self.since.is_none() {
+            return Ok(records);
+        }
+        // Drops the retractions whose assertions aren't among the
+        // records, as `drop_unmatched_retractions` does.
+        let mut last: Option<Record> = None;
+        Ok(Box::new(records.filter(move |record| {
+            let retracts_last = last.as_ref().is_some_and(|prev| {
+                !prev.retracted && prev.entity == record.entity && prev.attribute == record.attribute && prev.value == record.value
+            });
+            let keep = !record.retracted || retracts_last;
+            if keep {
+                last = Some(record.clone());
+            }
+            keep
+        })))
+    }
+
+    /// Encrypts the clause's value if it's for an encrypted
+    /// attribute, so that it matches the stored values.
+    fn encrypt_clause(&self, clause: Clause) -> Result<Clause> {
+        let attr = match clause.attribute {
+            Term::Bound(ref a) => self.ident_entity(a),
+            Term::Unbound(_) => None,
+        };
+        match (attr, &clause.value, &self.keyring) {
+            (Some(attr), Term::Bound(value), Some(keyring)) if self.schema.is_encrypted(attr) => {
+                let value = keyring.encrypt(attr, value)?;
+                Ok(Clause::new(clause.entity.clone(), clause.attribute.clone(), Term::Bound(value)))
+            }
+            _ => Ok(clause),
+        }
+    }
+
+    fn decrypt_records(&self, mut records: Vec<Record>) -> Result<Vec<Record>> {
+        let keyring = match self.keyring {
+            Some(ref keyring) if !self.schema.encrypted.is_empty() => keyring,
+            _ => return Ok(records),
+        };
+        for record in records.iter_mut().filter(|rec| self.schema.is_encrypted(rec.attribute)) {
+            record.value = keyring.decrypt(record.attribute, &record.value)?;
+        }
+        Ok(records)
+    }
+
+    /// Encrypts a value of an encrypted attribute for storage.
+    fn stored_value(&self, attr: Entity, value: Value) -> Result<Value> {
+        if !self.schema.is_encrypted(attr) {
+            return Ok(value);
+        }
+        match self.keyring {
+            Some(ref keyring) => keyring.encrypt(attr, &value),
+            None => Err(format!("attribute {} is encrypted, but the transactor has no encryption key", self.ident_for(attr).unwrap_or("?")).into()),
+        }
+    }
+
+    /// Checks a change to whether an attribute is encrypted. Values
+    /// already stored would be left in the wrong form, so the
+    /// attribute mustn't have any.
+    fn check_encryption_change(&self, attr: Entity, fact: &Fact) -> Result<()> {
+        if self.schema.idents.get("db:encrypted") != Some(&attr) {
+            return Ok(());
+        }
+        if self.ident_for(fact.entity).is_some_and(|i| i.starts_with("db:")) {
+            return Err("built-in attributes can't be encrypted".into());
+        }
+        if self.has_records(fact.entity) {
+            return Err(format!(
+                "can't change db:encrypted of {}, which already has values",
+                self.ident_for(fact.entity).unwrap_or("?")
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// Checks a change to an attribute's collation. The AVET index
+    /// would be left out of order if the attribute had any values.
+    fn check_collation_change(&self, attr: Entity, fact: &Fact) -> Result<()> {
+        if self.schema.idents.get("db:collation") != Some(&attr) || !self.has_records(fact.entity) {
+            return Ok(());
+        }
+        Err(format!(
+            "can't change db:collation of {}, which already has values",
+            self.ident_for(fact.entity).unwrap_or("?")
+        ).into())
+    }
+
+    /// Enforces `db:unique`: a value of a unique attribute can belong
+    /// to only one entity, which is looked up through the AVET index.
+    fn check_unique(&self, attr: Entity, fact: &Fact) -> Result<()> {
+        if !self.schema.is_unique(attr) {
+            return Ok(());
+        }
+        let clause = Clause::new(Term::Unbound("e".into()), Term::Bound(Ident::Entity(attr)), Term::Bound(fact.value.clone()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        match tuples.iter().find(|tuple| tuple[0] != Value::Ref(fact.entity)) {
+            Some(owner) => Err(format!(
+                "{} {} is unique, and already belongs to entity {}",
+                fact.attribute, fact.value, owner[0]
+            ).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that an attribute being made unique doesn't already
+    /// have a value shared by two entities. Encrypted values can't be
+    /// looked up, so encrypted attributes can't be unique.
+    fn check_unique_change(&self, attr: Entity, fact: &Fact) -> Result<()> {
+        if self.schema.idents.get("db:unique") != Some(&attr) || fact.value != Value::Boolean(true) {
+            return Ok(());
+        }
+        let ident = self.ident_for(fact.entity).unwrap_or("?");
+        if self.schema.is_encrypted(fact.entity) {
+            return Err(format!("{} is encrypted, so it can't be unique", ident).into());
+        }
+        let clause = Clause::new(Term::Unbound("e".into()), Term::Bound(Ident::Entity(fact.entity)), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        let mut owners = tuples.into_iter().map(|mut tuple| (tuple.pop().unwrap(), tuple.pop().unwrap())).collect::<Vec<_>>();
+        owners.sort();
+        match owners.windows(2).find(|pair| pair[0].0 == pair[1].0 && pair[0].1 != pair[1].1) {
+            Some(pair) => Err(format!(
+                "can't make {} unique: {} belongs to both {} and {}",
+                ident, pair[0].0, pair[0].1, pair[1].1
+            ).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether any records, including retractions, have `attr` as
+    /// their attribute.
+    fn has_records(&self, attr: Entity) -> bool {
+        let first = Record::addition(Entity(0), attr, Value::String("".into()), Entity(0));
+        self.aev.range_from(first).next().is_some_and(|rec| rec.attribute == attr)
+    }
+
+    /// The records matching `clause` in whichever index keeps them
+    /// together, in the order of that index.
+    fn index_records_matching<'a>(&'a self, clause: &'a Clause, binding: &'a Binding) -> Result<Records<'a>> {
         let expanded = clause.substitute(binding)?;
+        // A clause bounded to a range of transactions can be answered
+        // from just their part of the log index, if it's smaller.
+        if let Some(txs) = self.log_range(&expanded) {
+            if planner::prefers_log(&expanded, &txs, &self.stats, &self.schema) {
+                return Ok(Box::new(self.log_records_matching(&expanded, binding, txs).into_iter()));
+            }
+        }
         match expanded {
             // ?e a v => use the VAE index if value type is ref, AVET if indexed, otherwise AEV
             Clause {
                 entity: Term::Unbound(_),
                 attribute: Term::Bound(a),
                 value: Term::Bound(v),
+                ..
             } => {
                 let attr = self.ident_entity(&a).ok_or(format!("invalid attribute: {:?}", a))?;
                 let range_start = Record::addition(Entity(0), attr, v.clone(), Entity(0));
@@ -76,27 +547,24 @@ impl Db {
 
                 if let Value::Ref(_) = v {
                     // Since the value type is Ref, we can use the VAE index.
-                    Ok(
+                    Ok(Box::new(
                         self.vae
                             .range_from(range_start)
-                            .take_while(|rec| rec.attribute == attr && rec.value == v)
-                            .collect()
-                    )
+                            .take_while(move |rec| rec.attribute == attr && rec.value == v)
+                    ))
                 } else if self.schema.is_indexed(attr) {
-                    Ok(
+                    Ok(Box::new(
                         self.ave
                             .range_from(range_start)
-                            .take_while(|rec| rec.attribute == attr && rec.value == v)
-                            .collect()
-                    )
+                            .take_while(move |rec| rec.attribute == attr && rec.value == v)
+                    ))
                 } else {
-                    Ok(
+                    Ok(Box::new(
                         self.aev
                             .range_from(range_start)
-                            .take_while(|rec| rec.attribute == attr)
-                            .filter(|rec| rec.value == v)
-                            .collect()
-                    )
+                            .take_while(move |rec| rec.attribute == attr)
+                            .filter(move |rec| rec.value == v)
+                    ))
                 }
             }
 
@@ -105,33 +573,171 @@ impl Db {
                 entity: Term::Bound(e),
                 attribute: Term::Bound(a),
                 value: Term::Unbound(_),
+                ..
             } => {
                 match self.ident_entity(&a) {
                     Some(attr) => {
                         // Value::String("") is the lowest-sorted value
                         let range_start =
                             Record::addition(e, attr, Value::String("".into()), Entity(0));
-                        Ok(
+                        Ok(Box::new(
                             self.eav
                                 .range_from(range_start)
-                                .take_while(|rec| rec.entity == e && rec.attribute == attr)
-                                .collect(),
-                        )
+                                .take_while(move |rec| rec.entity == e && rec.attribute == attr)
+                        ))
                     }
                     _ => return Err("invalid attribute".into()),
                 }
             }
+            // e ?a ?v => use the eav index
+            Clause {
+                entity: Term::Bound(e),
+                attribute: Term::Unbound(_),
+                value: Term::Unbound(_),
+                ..
+            } => {
+                let range_start =
+                    Record::addition(e, Entity(0), Value::String("".into()), Entity(0));
+                Ok(Box::new(
+                    self.eav
+                        .range_from(range_start)
+                        .take_while(move |rec| rec.entity == e)
+                ))
+            }
             // FIXME: Implement other optimized index use cases? (multiple unknowns?)
             // Fallthrough case: just scan the EAV index. Correct but slow.
-            _ => {
-                Ok(
-                    self.eav
-                        .iter()
-                        .filter(|f| self.unify(&binding, &clause, &f).is_some())
-                        .collect(),
-                )
+            _ => Ok(Box::new(self.eav.iter().filter(move |f| self.unify(binding, clause, f).is_some()))),
+        }
+    }
+
+    /// Returns the query stored under `name` (by asserting
+    /// `db:query:name` and `db:query:text` on an entity), with any
+    /// `{}` placeholders in its text filled in from `params` as by
+    /// `parse_query_with`.
+    pub fn named_query(&self, name: &str, params: &[Value]) -> Result<query::Query> {
+        let named = query::Clause::new(
+            query::Term::Unbound("q".into()),
+            query::Term::Bound(Ident::Name("db:query:name".into())),
+            query::Term::Bound(Value::String(name.into())),
+        );
+        let entity = match self.fetch(&named)?.1.first().map(|t| t[0].clone()) {
+            Some(Value::Ref(e)) => e,
+            _ => return Err(format!("no query named {}", name).into()),
+        };
+
+        let text = query::Clause::new(
+            query::Term::Bound(entity),
+            query::Term::Bound(Ident::Name("db:query:text".into())),
+            query::Term::Unbound("t".into()),
+        );
+        match self.fetch(&text)?.1.first().map(|t| t[0].clone()) {
+            Some(Value::String(text)) => parse_query_with(&text, params),
+            _ => Err(format!("query {} has no db:query:text", name).into()),
+        }
+    }
+
+    /// Counts the facts matching a clause, i.e. the rows `fetch`
+    /// would return, without building them. All of an attribute's
+    /// current facts are counted from the statistics where they're
+    /// up to date (see `count_from_stats`).
+    pub fn count(&self, clause: &query::Clause) -> Result<usize> {
+        if let Some(count) = self.count_from_stats(clause) {
+            return Ok(count);
+        }
+
+        let binding = HashMap::new();
+        let clause = self.encrypt_clause(clause.clone())?;
+        let tx = match clause.tx {
+            Some(query::Term::Bound(tx)) => Some(tx),
+            _ => None,
+        };
+        let added = match clause.added {
+            Some(query::Term::Bound(added)) => Some(added),
+            _ => None,
+        };
+        let counts = |rec: &Record| tx.is_none_or(|tx| rec.tx == tx) && added.is_none_or(|added| rec.retracted != added);
+
+        // As in `facts_matching`, a retraction cancels the fact
+        // matched immediately before it, which is only counted if
+        // it's still in the count.
+        let (mut count, mut last_counted) = (0, false);
+        for record in self.visible_records_matching(&clause, &binding)? {
+            if record.retracted && !self.history {
+                if last_counted {
+                    count -= 1;
+                }
+                last_counted = false;
+            } else {
+                last_counted = counts(&record);
+                if last_counted {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Counts all the current facts of a clause's attribute, for a
+    /// clause which doesn't constrain them otherwise, from the
+    /// statistics: their datoms less the retractions and the
+    /// assertions those cancel. Only counts in an unfiltered view of
+    /// the latest facts, and while none of the attribute's facts
+    /// have been added since the statistics were computed.
+    fn count_from_stats(&self, clause: &query::Clause) -> Option<usize> {
+        if self.access.is_some() || self.as_of.is_some() || self.since.is_some() || self.history {
+            return None;
+        }
+        let (attr, mut vars) = match (&clause.entity, &clause.attribute, &clause.value) {
+            (query::Term::Unbound(e), query::Term::Bound(a), query::Term::Unbound(v)) => (self.ident_entity(a)?, vec![e, v]),
+            _ => return None,
+        };
+        match clause.tx {
+            Some(query::Term::Unbound(ref var)) => vars.push(var),
+            Some(query::Term::Bound(_)) => return None,
+            None => {}
+        }
+        match clause.added {
+            Some(query::Term::Unbound(ref var)) => vars.push(var),
+            Some(query::Term::Bound(_)) => return None,
+            None => {}
+        }
+        // A var used twice constrains the facts to those where the
+        // terms are equal.
+        if vars.iter().enumerate().any(|(i, var)| vars[..i].contains(var)) {
+            return None;
+        }
+
+        let stats = self.stats.attributes.get(&attr)?;
+        let retracted = stats.retracted?;
+        let first = Record::addition(Entity(0), attr, Value::String("".into()), Entity(0));
+        if self.aev.novelty_from(first).next().is_some_and(|rec| rec.attribute == attr) {
+            return None;
+        }
+        Some((stats.count - 2 * retracted) as usize)
+    }
+
+    /// The records matching `clause`, including its `tx` and `added`
+    /// terms. Outside a history view, a retraction cancels the
+    /// assertion it retracts and neither is returned.
+    fn facts_matching(&self, clause: &query::Clause) -> Result<Vec<Record>> {
+        let mut records: Vec<Record> = vec![];
+        for record in self.records_matching(clause, &HashMap::new())? {
+            if record.retracted && !self.history {
+                // If the matching record is a retraction, the fact it
+                // retracts will be the fact matched immediately
+                // beforehand.
+                records.pop();
+            } else {
+                records.push(record);
             }
         }
+        if let Some(query::Term::Bound(tx)) = clause.tx {
+            records.retain(|rec| rec.tx == tx);
+        }
+        if let Some(query::Term::Bound(added)) = clause.added {
+            records.retain(|rec| rec.retracted != added);
+        }
+        Ok(records)
     }
 
     /// Given a clause, fetch the relation of matching records.
@@ -161,27 +767,480 @@ impl Db {
                 selectors.push(Box::new(|record: &Record| record.value.clone()));
             }
         };
+        if let Some(query::Term::Unbound(ref var)) = clause.tx {
+            vars.push(var.clone());
+            selectors.push(Box::new(|record: &Record| Value::Ref(record.tx)));
+        }
+        if let Some(query::Term::Unbound(ref var)) = clause.added {
+            vars.push(var.clone());
+            selectors.push(Box::new(|record: &Record| Value::Boolean(!record.retracted)));
+        }
 
-        let mut values: Vec<Vec<Value>> = vec![];
-        // FIXME: will need to remove retracted records from the relation
-        // (and eventually deal with cardinality:one)
+        let mut values: Vec<Vec<Value>> = self.facts_matching(clause)?
+            .iter()
+            .map(|record| selectors.iter().map(|selector| selector(record)).collect())
+            .collect();
 
-        for record in self.records_matching(&clause, &HashMap::new())? {
-            let mut tuple: Vec<Value> = vec![];
-            if record.retracted {
-                // If the matching record is a retraction, the fact it
-                // retracts will be the fact matched immediately
-                // beforehand.
-                values.pop();
+        // Encrypted values come out of the indices in the order of
+        // their ciphertext; sorting puts the rows back in the order
+        // the planner expects of a fetch (see `Plan::sorted_by`).
+        let encrypted = match clause.attribute {
+            query::Term::Bound(ref a) => self.ident_entity(a).is_some_and(|a| self.schema.is_encrypted(a)),
+            query::Term::Unbound(_) => !self.schema.encrypted.is_empty(),
+        };
+        if encrypted && self.keyring.is_some() {
+            values.sort();
+        }
+
+        Ok(Relation(vars, values))
+    }
+
+    /// Answers a radius query. For indexed attributes, this scans
+    /// the AVET ranges of the geohash cells covering the search
+    /// area; otherwise it scans every value of the attribute. Either
+    /// way candidates are then refined by their exact distance.
+    pub fn within(&self, within: &query::Within) -> Result<Relation> {
+        let attr = self.ident_entity(&within.attribute)
+            .ok_or(format!("invalid attribute: {:?}", within.attribute))?;
+        if self.schema.value_types.get(&attr) != Some(&ValueType::Geo) {
+            return Err(format!("attribute {:?} is not of type db:type:geo", within.attribute).into());
+        }
+
+        let mut candidates: Vec<Record> = vec![];
+        if self.schema.is_indexed(attr) {
+            for (lo, hi) in geo::covering_ranges(&within.center, within.radius as f64) {
+                let range_start = Record::addition(Entity(0), attr, Value::Geo(GeoPoint::cell_start(lo)), Entity(0));
+                candidates.extend(
+                    self.ave
+                        .range_from(range_start)
+                        .take_while(|rec| rec.attribute == attr && match rec.value {
+                            Value::Geo(ref g) => g.cell() <= hi,
+                            _ => false,
+                        })
+                );
+            }
+        } else {
+            let range_start = Record::addition(Entity(0), attr, Value::String("".into()), Entity(0));
+            candidates.extend(self.aev.range_from(range_start).take_while(|rec| rec.attribute == attr));
+        }
+
+        let mut points: Vec<(Entity, GeoPoint)> = vec![];
+        for record in candidates.into_iter().filter(|rec| self.is_visible(rec)) {
+            match record.value {
+                // As in `fetch`, a retraction immediately follows
+                // the fact it retracts.
+                Value::Geo(_) if record.retracted => { points.pop(); },
+                Value::Geo(g) => points.push((record.entity, g)),
+                _ => {},
+            }
+        }
+
+        let entities = points.into_iter()
+            .filter(|(_, point)| point.distance_to(&within.center) <= within.radius as f64)
+            .map(|(entity, _)| entity)
+            .unique()
+            .map(|entity| vec![Value::Ref(entity)])
+            .collect();
+
+        Ok(Relation(vec![within.entity.clone()], entities))
+    }
+
+    /// Fetches the facts matching `clause`, an `(?e attr ?v)` clause,
+    /// whose values satisfy `predicate`, a `starts-with` or
+    /// `matches-ci` constraint on `?v`. Values starting with a prefix
+    /// are together in the AVET index under any collation, and values
+    /// equal but for case under `db:collation:caseInsensitive`, so for
+    /// indexed attributes only their range is read; otherwise every
+    /// value of the attribute is checked.
+    pub fn scan(&self, clause: &query::Clause, predicate: &query::Constraint) -> Result<Relation> {
+        let (attr, entity_var, value_var) = match (&clause.entity, &clause.attribute, &clause.value) {
+            (query::Term::Unbound(e), query::Term::Bound(a), query::Term::Unbound(v)) => {
+                (self.ident_entity(a).ok_or(format!("invalid attribute: {:?}", a))?, e, v)
+            }
+            _ => return Err(format!("can't scan for {}", clause).into()),
+        };
+        let pattern = match predicate.string_predicate() {
+            Some((var, pattern)) if var == value_var => Value::String(pattern.into()),
+            _ => return Err(format!("can't scan for {} with {}", clause, predicate).into()),
+        };
+        let collation = self.schema.collation(attr);
+        let matches = |value: &Value| predicate.comparator.holds(collation, value, &pattern);
+        self.lookups.observe(&self.schema, attr, true);
+
+        let ranged = self.schema.is_indexed(attr) && !self.schema.is_encrypted(attr) && match predicate.comparator {
+            query::Comparator::MatchesCi => collation == Collation::CaseInsensitive,
+            _ => true,
+        };
+        let records: Vec<Record> = if ranged {
+            // Never equal, so the range starts before every value
+            // which collates the same as the pattern.
+            self.ave
+                .range_from_by(|rec| rec.attribute.cmp(&attr).then_with(|| collation.compare(&rec.value, &pattern)).then(Ordering::Greater))
+                .take_while(|rec| rec.attribute == attr && matches(&rec.value))
+                .filter(|rec| self.is_visible(rec))
+                .collect()
+        } else {
+            let range_start = Record::addition(Entity(0), attr, Value::String("".into()), Entity(0));
+            self.aev.range_from(range_start).take_while(|rec| rec.attribute == attr).filter(|rec| self.is_visible(rec)).collect()
+        };
+
+        let mut tuples: Vec<Vec<Value>> = vec![];
+        // Encrypted values can only be matched once decrypted.
+        for record in self.decrypt_records(records)?.into_iter().filter(|rec| matches(&rec.value)) {
+            // As in `fetch`, a retraction immediately follows the
+            // fact it retracts.
+            if record.retracted && !self.history {
+                tuples.pop();
             } else {
-                for selector in selectors.iter() {
-                    tuple.push(selector(&record));
+                tuples.push(vec![Value::Ref(record.entity), record.value]);
+            }
+        }
+
+        Ok(Relation(vec![entity_var.clone(), value_var.clone()], tuples))
+    }
+
+    /// Returns the entities reachable from `from` by following the
+    /// ref attribute `attribute` at most `max_depth` times, in
+    /// breadth-first order. `from` itself isn't included.
+    pub fn reachable(&self, from: Entity, attribute: &str, max_depth: usize) -> Result<Vec<Entity>> {
+        let attr = self.ref_attribute(attribute)?;
+
+        let mut seen: HashMap<Entity, ()> = HashMap::new();
+        seen.insert(from, ());
+        let mut reached = vec![];
+        let mut frontier = vec![from];
+        for _ in 0..max_depth {
+            let mut next = vec![];
+            for entity in frontier {
+                for target in self.ref_targets(entity, attr)? {
+                    if seen.insert(target, ()).is_none() {
+                        reached.push(target);
+                        next.push(target);
+                    }
                 }
-                values.push(tuple);
             }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
         }
 
-        Ok(Relation(vars, values))
+        Ok(reached)
+    }
+
+    /// Finds a shortest path from `from` to `to` along the ref
+    /// attribute `attribute`, including both ends, or None if `to`
+    /// isn't reachable.
+    ///
+    /// The search runs from both ends at once, forwards over the EAVT
+    /// index and backwards over the VAET index, always expanding the
+    /// smaller frontier, which keeps it from fanning out through
+    /// highly connected entities the way a one-sided search would.
+    pub fn shortest_path(&self, from: Entity, to: Entity, attribute: &str) -> Result<Option<Vec<Entity>>> {
+        let attr = self.ref_attribute(attribute)?;
+        if from == to {
+            return Ok(Some(vec![from]));
+        }
+
+        // Each side maps the entities it has seen to the entity they
+        // were reached from and their distance from that side's end.
+        let mut forward: HashMap<Entity, (Entity, usize)> = HashMap::new();
+        let mut backward: HashMap<Entity, (Entity, usize)> = HashMap::new();
+        forward.insert(from, (from, 0));
+        backward.insert(to, (to, 0));
+        let mut forward_frontier = vec![from];
+        let mut backward_frontier = vec![to];
+
+        while !forward_frontier.is_empty() && !backward_frontier.is_empty() {
+            let meeting = if forward_frontier.len() <= backward_frontier.len() {
+                let (next, meeting) = Self::expand_frontier(&forward_frontier, &mut forward, &backward, |e| self.ref_targets(e, attr))?;
+                forward_frontier = next;
+                meeting
+            } else {
+                let (next, meeting) = Self::expand_frontier(&backward_frontier, &mut backward, &forward, |e| self.ref_sources(e, attr))?;
+                backward_frontier = next;
+                meeting
+            };
+
+            if let Some(meeting) = meeting {
+                let mut path = vec![meeting];
+                let mut entity = meeting;
+                while entity != from {
+                    entity = forward[&entity].0;
+                    path.push(entity);
+                }
+                path.reverse();
+                entity = meeting;
+                while entity != to {
+                    entity = backward[&entity].0;
+                    path.push(entity);
+                }
+                return Ok(Some(path));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Expands one level of a breadth-first search, returning the
+    /// next frontier and, if the search has met the one coming from
+    /// the other end, the meeting point giving the shortest path.
+    /// The whole level is expanded before choosing, since the first
+    /// meeting found isn't necessarily the closest to the other end.
+    fn expand_frontier<F>(
+        frontier: &[Entity],
+        seen: &mut HashMap<Entity, (Entity, usize)>,
+        other: &HashMap<Entity, (Entity, usize)>,
+        neighbours: F,
+    ) -> Result<(Vec<Entity>, Option<Entity>)>
+        where F: Fn(Entity) -> Result<Vec<Entity>>
+    {
+        let mut next = vec![];
+        let mut meeting: Option<(Entity, usize)> = None;
+        for &entity in frontier {
+            let distance = seen[&entity].1 + 1;
+            for neighbour in neighbours(entity)? {
+                if seen.contains_key(&neighbour) {
+                    continue;
+                }
+                seen.insert(neighbour, (entity, distance));
+                if let Some(&(_, remaining)) = other.get(&neighbour) {
+                    if meeting.is_none_or(|(_, best)| remaining < best) {
+                        meeting = Some((neighbour, remaining));
+                    }
+                }
+                next.push(neighbour);
+            }
+        }
+        Ok((next, meeting.map(|(entity, _)| entity)))
+    }
+
+    fn ref_attribute(&self, attribute: &str) -> Result<Entity> {
+        match self.schema.resolve(attribute) {
+            Some(attr) if self.schema.value_types.get(&attr) == Some(&ValueType::Ref) => Ok(attr),
+            Some(_) => Err(format!("attribute {} is not of type db:type:ref", attribute).into()),
+            None => Err(format!("invalid attribute: ident '{}' does not exist", attribute).into()),
+        }
+    }
+
+    /// The entities `entity` refers to through `attr`.
+    fn ref_targets(&self, entity: Entity, attr: Entity) -> Result<Vec<Entity>> {
+        let clause = Clause::new(Term::Bound(entity), Term::Bound(Ident::Entity(attr)), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        Ok(tuples.into_iter().filter_map(|t| match t[0] {
+            Value::Ref(e) => Some(e),
+            _ => None,
+        }).collect())
+    }
+
+    /// The entities which refer to `entity` through `attr`.
+    fn ref_sources(&self, entity: Entity, attr: Entity) -> Result<Vec<Entity>> {
+        let clause = Clause::new(Term::Unbound("e".into()), Term::Bound(Ident::Entity(attr)), Term::Bound(Value::Ref(entity)));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        Ok(tuples.into_iter().filter_map(|t| match t[0] {
+            Value::Ref(e) => Some(e),
+            _ => None,
+        }).collect())
+    }
+
+    /// Returns the `db:ident` of an entity, if it has one.
+    pub fn ident_for(&self, entity: Entity) -> Option<&str> {
+        self.schema.ident_for(entity)
+    }
+
+    /// Describes every attribute in the schema (i.e. every ident
+    /// with a value type), sorted by ident.
+    pub fn attributes(&self) -> Result<Vec<AttributeInfo>> {
+        let mut idents = self.schema.idents
+            .iter()
+            .filter(|(_, entity)| self.schema.value_types.contains_key(entity))
+            .map(|(ident, _)| ident.clone())
+            .collect::<Vec<_>>();
+        idents.sort();
+
+        idents.iter().map(|ident| self.attribute_info(ident)).collect()
+    }
+
+    /// Describes a single attribute, including its documentation
+    /// and any other facts asserted about the attribute entity.
+    pub fn attribute_info(&self, ident: &str) -> Result<AttributeInfo> {
+        let entity = match self.schema.resolve(ident) {
+            Some(e) => e,
+            None => return Err(format!("invalid attribute: ident '{}' does not exist", ident).into()),
+        };
+
+        let clause = Clause::new(Term::Bound(entity), Term::Unbound("a".into()), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+
+        let mut aliases = self.schema.aliases
+            .iter()
+            .filter(|(_, e)| *e == entity)
+            .map(|(alias, _)| alias.clone())
+            .collect::<Vec<_>>();
+        aliases.sort();
+
+        let mut metadata = vec![];
+        for tuple in tuples {
+            let attr_ident = match tuple[0] {
+                Value::Ref(attr) => self.ident_for(attr).map(String::from),
+                _ => None,
+            };
+
+            match attr_ident {
+                Some(ref i) if SCHEMA_ATTRIBUTES.contains(&i.as_str()) => {},
+                Some(i) => metadata.push((i, tuple[1].clone())),
+                None => metadata.push((format!("{}", tuple[0]), tuple[1].clone())),
+            }
+        }
+
+        Ok(AttributeInfo {
+            entity,
+            ident: self.ident_for(entity).unwrap_or(ident).to_string(),
+            value_type: self.schema.value_types.get(&entity).cloned(),
+            indexed: self.schema.is_indexed(entity),
+            doc: self.schema.docs.get(&entity).cloned(),
+            allowed_values: self.schema.allowed_values(entity).unwrap_or_default(),
+            normalizers: self.schema.normalizers.get(&entity).cloned().unwrap_or_default(),
+            no_history: self.schema.no_history.contains(&entity),
+            encrypted: self.schema.is_encrypted(entity),
+            cardinality: self.schema.cardinality(entity),
+            collation: self.schema.collation(entity),
+            unique: self.schema.is_unique(entity),
+            aliases,
+            metadata,
+        })
+    }
+
+    /// Returns the current facts about an entity, which can be named
+    /// by any `EntitySpec`, keyed by attribute ident. Each attribute
+    /// maps to all of its values, sorted; a cardinality-one attribute
+    /// has at most one.
+    pub fn entity<E: Into<EntitySpec>>(&self, entity: E) -> Result<HashMap<String, Vec<Value>>> {
+        let entity = self.resolve(&entity.into())?;
+        let clause = Clause::new(Term::Bound(entity), Term::Unbound("a".into()), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+
+        let mut attributes: HashMap<String, Vec<Value>> = HashMap::new();
+        for tuple in tuples {
+            let ident = match tuple[0] {
+                Value::Ref(attr) => self.ident_for(attr).map(String::from).unwrap_or_else(|| format!("{}", tuple[0])),
+                ref v => format!("{}", v),
+            };
+            attributes.entry(ident).or_default().push(tuple[1].clone());
+        }
+        for values in attributes.iter_mut() {
+            values.sort();
+        }
+
+        Ok(attributes)
+    }
+
+    /// The entity `spec` names. Ids are taken as they are, idents
+    /// (and aliases) are looked up in the schema, and lookup refs in
+    /// the AVET index (see `lookup`).
+    pub fn resolve(&self, spec: &EntitySpec) -> Result<Entity> {
+        match *spec {
+            EntitySpec::Id(entity) => Ok(entity),
+            EntitySpec::Ident(ref ident) => self.schema.resolve(ident)
+                .ok_or_else(|| format!("no entity has the ident {}", ident).into()),
+            EntitySpec::Lookup(ref lookup) => self.lookup(lookup),
+        }
+    }
+
+    /// The entity a lookup ref names, found through the AVET index.
+    /// Fails if the attribute isn't indexed, or if the value doesn't
+    /// belong to exactly one entity.
+    pub fn lookup(&self, lookup: &LookupRef) -> Result<Entity> {
+        let attr = self.schema.resolve(&lookup.attribute)
+            .ok_or_else(|| format!("invalid attribute in lookup ref {}", lookup))?;
+        if !self.schema.is_indexed(attr) {
+            return Err(format!("lookup ref {} needs {} to be db:indexed", lookup, lookup.attribute).into());
+        }
+        let value = self.schema.normalize(attr, lookup.value.clone());
+        let clause = Clause::new(Term::Unbound("e".into()), Term::Bound(Ident::Entity(attr)), Term::Bound(value));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        let entities: Vec<Entity> = tuples.into_iter()
+            .filter_map(|tuple| match tuple[0] {
+                Value::Ref(e) => Some(e),
+                _ => None,
+            })
+            .unique()
+            .collect();
+        match entities[..] {
+            [entity] => Ok(entity),
+            [] => Err(format!("lookup ref {} doesn't match any entity", lookup).into()),
+            _ => Err(format!("lookup ref {} isn't unique: it matches {} entities", lookup, entities.len()).into()),
+        }
+    }
+
+    /// Works out the transaction items which bring the schema in
+    /// line with `defs`: missing attributes are created, and the
+    /// properties of existing ones which differ are retracted and
+    /// reasserted. Returns no items if the schema already matches.
+    ///
+    /// Changing an existing attribute's value type would leave its
+    /// values mistyped, so it's an error, as is a def whose ident
+    /// names something other than an attribute.
+    pub fn schema_changes(&self, defs: &[AttributeDef]) -> Result<Vec<TxItem>> {
+        let mut items = vec![];
+
+        for def in defs {
+            let entity = match self.schema.resolve(&def.ident) {
+                Some(e) => e,
+                None => {
+                    items.push(TxItem::NewEntity(new_attribute(def)));
+                    continue;
+                }
+            };
+
+            match self.schema.value_types.get(&entity) {
+                Some(t) if *t == def.value_type => {}
+                Some(t) => {
+                    return Err(format!(
+                        "can't change the value type of {} from {} to {}",
+                        def.ident, t.ident(), def.value_type.ident()
+                    ).into())
+                }
+                None => return Err(format!("{} exists but is not an attribute", def.ident).into()),
+            }
+
+            let current = self.entity(entity)?;
+            let mut update = |attribute: &str, desired: Vec<Value>| {
+                let existing = current.get(attribute).cloned().unwrap_or_default();
+                for value in existing.iter().filter(|v| !desired.contains(v)) {
+                    items.push(TxItem::Retraction(Fact::new(entity, attribute, value.clone())));
+                }
+                for value in desired.into_iter().filter(|v| !existing.contains(v)) {
+                    items.push(TxItem::Addition(Fact::new(entity, attribute, value)));
+                }
+            };
+
+            // Unindexed and history-keeping attributes may have an
+            // explicit `false` asserted, which is left alone.
+            if def.indexed != self.schema.indexed.contains(&entity) {
+                update("db:indexed", vec![Value::Boolean(def.indexed)]);
+            }
+            if def.no_history != self.schema.no_history.contains(&entity) {
+                update("db:noHistory", if def.no_history { vec![Value::Boolean(true)] } else { vec![] });
+            }
+            if def.encrypted != self.schema.is_encrypted(entity) {
+                update("db:encrypted", if def.encrypted { vec![Value::Boolean(true)] } else { vec![] });
+            }
+            if def.cardinality != self.schema.cardinality(entity) {
+                update("db:cardinality", vec![Value::Ident(def.cardinality.ident().into())]);
+            }
+            if def.collation != self.schema.collation(entity) {
+                update("db:collation", vec![Value::Ident(def.collation.ident().into())]);
+            }
+            if def.unique != self.schema.is_unique(entity) {
+                update("db:unique", if def.unique { vec![Value::Boolean(true)] } else { vec![] });
+            }
+            update("db:doc", def.doc.iter().cloned().map(Value::String).collect());
+            update("db:allowedValue", def.allowed_values.iter().cloned().map(Value::Ident).collect());
+            update("db:normalize", def.normalizers.iter().map(|n| Value::Ident(n.ident().into())).collect());
+        }
+
+        Ok(items)
     }
 
     /// Attempts to unify a new record and a clause with existing
@@ -262,11 +1321,29 @@ impl Db {
         Some(new_env)
     }
 
+    /// Returns a db without any of `entity`'s records, with each
+    /// index rebuilt into a new durable tree.
+    pub fn excise(&self, entity: Entity) -> Result<Db> {
+        let keep = |record: &Record| record.entity != entity;
+        let ave = self.ave.filter(keep)?;
+        let tea = self.tea.filter(keep)?;
+        Ok(Db {
+            eav: self.eav.filter(keep)?,
+            aev: self.aev.filter(keep)?,
+            vae: self.vae.filter(keep)?,
+            stats: Arc::new(Stats::from_sorted(ave.iter()).with_log(tea.iter())),
+            ave,
+            tea,
+            ..self.clone()
+        })
+    }
+
     /// Add a record to the database. Does not validate that the fact
     /// fits the schema, in order to allow bootstrapping.
     pub fn add_record(&self, record: Record) -> Result<Db> {
         let new_eav = self.eav.insert(record.clone());
         let new_aev = self.aev.insert(record.clone());
+        let new_tea = self.tea.insert(record.clone());
 
         let mut new_vae = self.vae.clone();
         // TODO: only add to AVET if db:indexed is true
@@ -280,21 +1357,37 @@ impl Db {
         let mut new_schema = self.schema.clone();
         if record.attribute == *self.schema.idents.get("db:ident").expect("`db:ident` not in ident map") {
             match record.value {
-                Value::Ident(ref s) => new_schema = new_schema.add_ident(record.entity, s.clone()),
+                Value::Ident(ref s) if record.retracted => new_schema = new_schema.remove_ident(record.entity, s),
+                Value::Ident(ref s) => {
+                    if new_schema.aliases.get(s).is_some_and(|e| *e != record.entity) {
+                        return Err(format!("{} is already an alias of another entity", s).into());
+                    }
+                    new_schema = new_schema.add_ident(record.entity, s.clone())
+                }
                 _ => return Err("db:ident value must be an ident".into()),
             };
         };
 
+        // Databases created before db:alias existed won't have it.
+        if self.schema.idents.get("db:alias") == Some(&record.attribute) {
+            match record.value {
+                Value::Ident(ref s) if record.retracted => new_schema = new_schema.remove_alias(record.entity, s),
+                Value::Ident(ref s) => {
+                    if new_schema.resolve(s).is_some_and(|e| e != record.entity) {
+                        return Err(format!("{} already names another entity", s).into());
+                    }
+                    new_schema = new_schema.add_alias(record.entity, s.clone())
+                }
+                ref v => return Err(format!("invalid value type {:?} passed with db:alias", v).into()),
+            }
+        }
+
         if record.attribute == *self.schema.idents.get("db:valueType").expect("db:valueType not in ident map") {
             let value_type = match record.value {
                 Value::Ident(ref s) => {
-                    match s.as_str() {
-                        "db:type:string" => ValueType::String,
-                        "db:type:ident" => ValueType::Ident,
-                        "db:type:timestamp" => ValueType::Timestamp,
-                        "db:type:ref" => ValueType::Ref,
-                        "db:type:boolean" => ValueType::Boolean,
-                        _ => return Err(format!("{} is not a valid primitive type", s).into()),
+                    match ValueType::from_ident(s) {
+                        Some(value_type) => value_type,
+                        None => return Err(format!("{} is not a valid primitive type", s).into()),
                     }
                 },
                 _ => return Err("db:valueType must be an identifier".into()),
@@ -303,35 +1396,192 @@ impl Db {
             new_schema = new_schema.add_value_type(record.entity, value_type);
         };
 
-        if record.attribute == *self.schema.idents.get("db:indexed").unwrap() {
-            let indexed = match record.value {
-                Value::Boolean(b) => b,
-                v => return Err(format!("invalid value type {:?} passed with db:indexed", v).into())
+        // The record's entity is the attribute being indexed.
+        if self.schema.idents.get("db:indexed") == Some(&record.attribute) {
+            match record.value {
+                Value::Boolean(true) if !record.retracted => {
+                    new_schema = new_schema.add_indexed(record.entity)
+                }
+                Value::Boolean(_) => new_schema = new_schema.remove_indexed(&record.entity),
+                ref v => return Err(format!("invalid value type {:?} passed with db:indexed", v).into()),
+            }
+        }
+
+        // Databases created before db:doc existed won't have it.
+        if self.schema.idents.get("db:doc") == Some(&record.attribute) {
+            match record.value {
+                Value::String(ref doc) if !record.retracted => {
+                    new_schema = new_schema.add_doc(record.entity, doc.clone())
+                }
+                // Retracting an older doc leaves the current one.
+                Value::String(ref doc) => {
+                    if new_schema.docs.get(&record.entity) == Some(doc) {
+                        new_schema = new_schema.remove_doc(&record.entity)
+                    }
+                }
+                ref v => return Err(format!("invalid value type {:?} passed with db:doc", v).into()),
+            }
+        }
+
+        if self.schema.idents.get("db:allowedValue") == Some(&record.attribute) {
+            match record.value {
+                Value::Ident(ref v) if !record.retracted => {
+                    new_schema = new_schema.add_allowed_value(record.entity, v.clone())
+                }
+                Value::Ident(ref v) => new_schema = new_schema.remove_allowed_value(record.entity, v),
+                ref v => return Err(format!("invalid value type {:?} passed with db:allowedValue", v).into()),
+            }
+        }
+
+        if self.schema.idents.get("db:normalize") == Some(&record.attribute) {
+            let normalizer = match record.value {
+                Value::Ident(ref i) => match Normalizer::from_ident(i) {
+                    Some(n) => n,
+                    None => return Err(format!("{} is not a known normalizer", i).into()),
+                },
+                ref v => return Err(format!("invalid value type {:?} passed with db:normalize", v).into()),
+            };
+
+            if record.retracted {
+                new_schema = new_schema.remove_normalizer(record.entity, normalizer);
+            } else {
+                new_schema = new_schema.add_normalizer(record.entity, normalizer);
+            }
+        }
+
+        if self.schema.idents.get("db:encrypted") == Some(&record.attribute) {
+            match record.value {
+                Value::Boolean(true) if !record.retracted => {
+                    new_schema = new_schema.add_encrypted(record.entity)
+                }
+                Value::Boolean(_) => new_schema = new_schema.remove_encrypted(&record.entity),
+                ref v => return Err(format!("invalid value type {:?} passed with db:encrypted", v).into()),
+            }
+        }
+
+        if self.schema.idents.get("db:noHistory") == Some(&record.attribute) {
+            match record.value {
+                Value::Boolean(true) if !record.retracted => {
+                    new_schema = new_schema.add_no_history(record.entity)
+                }
+                Value::Boolean(_) => new_schema = new_schema.remove_no_history(&record.entity),
+                ref v => return Err(format!("invalid value type {:?} passed with db:noHistory", v).into()),
+            }
+        }
+
+        // Databases created before db:unique existed won't have it.
+        if self.schema.idents.get("db:unique") == Some(&record.attribute) {
+            match record.value {
+                Value::Boolean(true) if !record.retracted => {
+                    new_schema = new_schema.add_unique(record.entity)
+                }
+                Value::Boolean(_) => new_schema = new_schema.remove_unique(&record.entity),
+                ref v => return Err(format!("invalid value type {:?} passed with db:unique", v).into()),
+            }
+        }
+
+        // Databases created before db:cardinality existed won't have it.
+        if self.schema.idents.get("db:cardinality") == Some(&record.attribute) {
+            let cardinality = match record.value {
+                Value::Ident(ref i) => match Cardinality::from_ident(i) {
+                    Some(c) => c,
+                    None => return Err(format!("{} is not a valid cardinality", i).into()),
+                },
+                ref v => return Err(format!("invalid value type {:?} passed with db:cardinality", v).into()),
+            };
+
+            if record.retracted {
+                new_schema = new_schema.remove_cardinality(&record.entity);
+            } else {
+                new_schema = new_schema.add_cardinality(record.entity, cardinality);
+            }
+        }
+
+        // Databases created before db:collation existed won't have it.
+        if self.schema.idents.get("db:collation") == Some(&record.attribute) {
+            let collation = match record.value {
+                Value::Ident(ref i) => match Collation::from_ident(i) {
+                    Some(c) => c,
+                    None => return Err(format!("{} is not a known collation", i).into()),
+                },
+                ref v => return Err(format!("invalid value type {:?} passed with db:collation", v).into()),
             };
 
-            if indexed {
-                new_schema = new_schema.add_indexed(record.attribute);
+            if record.retracted {
+                new_schema = new_schema.remove_collation(&record.entity);
             } else {
-                new_schema = new_schema.remove_indexed(&record.attribute);
+                new_schema = new_schema.add_collation(record.entity, collation);
             }
         }
 
+        // The AVET index compares values by the new collations from
+        // here on. Collations can only change before an attribute has
+        // values, so what's already in the index stays in order.
+        let new_ave = if new_schema.collations != self.schema.collations {
+            new_ave.with_comparator(AVET::new(&new_schema))
+        } else {
+            new_ave
+        };
+
+        // New idents may fall into a denied namespace, so the
+        // filter has to be resolved against the new schema.
+        let access = match self.access {
+            Some(ref filter) if new_schema != self.schema => {
+                Some(AccessFilter::new(filter.policy(), &new_schema))
+            }
+            ref access => access.clone(),
+        };
+
         Ok(Db {
             eav: new_eav,
             ave: new_ave,
             aev: new_aev,
             vae: new_vae,
+            tea: new_tea,
             schema: new_schema,
             store: self.store.clone(),
+            access,
+            keyring: self.keyring.clone(),
+            basis_tx: self.basis_tx.max(record.tx.0),
+            latest_tx: self.latest_tx.max(record.tx.0),
+            stats: self.stats.clone(),
+            lookups: self.lookups.clone(),
+            as_of: self.as_of,
+            since: self.since,
+            history: self.history,
         })
     }
 
+    /// The retractions which make way for `fact`: if its attribute
+    /// is cardinality-one, the entity's other current values of it.
+    /// Returns `None` if `fact` is a cardinality-one attribute's
+    /// current value already, so there's nothing to add.
+    pub fn superseded_by(&self, fact: &Fact) -> Result<Option<Vec<Fact>>> {
+        let attr = match self.schema.resolve(&fact.attribute) {
+            Some(a) if self.schema.cardinality(a) == Cardinality::One => a,
+            _ => return Ok(Some(vec![])),
+        };
+        let value = self.schema.normalize(attr, fact.value.clone());
+        let clause = Clause::new(Term::Bound(fact.entity), Term::Bound(Ident::Entity(attr)), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.fetch(&clause)?;
+        let current: Vec<Value> = tuples.into_iter().filter_map(|mut tuple| tuple.pop()).collect();
+        if current == [value.clone()] {
+            return Ok(None);
+        }
+        Ok(Some(current
+            .into_iter()
+            .filter(|old| *old != value)
+            .map(|old| Fact::new(fact.entity, fact.attribute.clone(), old))
+            .collect()))
+    }
+
     /// Add a record to the DB, validating that it matches the schema.
     pub fn add(&self, fact: Fact, tx_entity: Entity) -> Result<(Db, Record)> {
-        let attr = match self.schema.idents.get(&fact.attribute) {
+        let attr = match self.schema.resolve(&fact.attribute) {
             Some(a) => a,
             None => return Err(format!("invalid attribute: ident '{:?}' does not exist", &fact.attribute).into())
         };
+        let fact = Fact::new(fact.entity, fact.attribute, self.schema.normalize(attr, fact.value));
 
         let fact_value_type = match fact.value {
             Value::String(_) => ValueType::String,
@@ -340,12 +1590,20 @@ impl Db {
             Value::Ident(_) => ValueType::Ident,
             Value::Boolean(_) => ValueType::Boolean,
             Value::Long(_) => ValueType::Long,
+            Value::Geo(_) => ValueType::Geo,
         };
 
+        self.check_allowed_value(attr, &fact)?;
+        self.check_encryption_change(attr, &fact)?;
+        self.check_collation_change(attr, &fact)?;
+        self.check_unique_change(attr, &fact)?;
+        self.check_unique(attr, &fact)?;
+        lint::check_value(&self.schema, attr, &fact.value);
+
         match self.schema.value_types.get(&attr) {
             Some(schema_type) => {
                 if *schema_type == fact_value_type {
-                    let record = Record::addition(fact.entity, *attr, fact.value, tx_entity);
+                    let record = Record::addition(fact.entity, attr, self.stored_value(attr, fact.value)?, tx_entity);
                     return self.add_record(record.clone()).map(|new_db| (new_db, record));
                 } else {
                     return Err(format!(
@@ -359,12 +1617,40 @@ impl Db {
         }
     }
 
+    /// Enforces `db:allowedValue` constraints. Ident values are
+    /// checked directly; ref values are checked by the ident of the
+    /// entity they point to, so enums can also be modeled as refs to
+    /// ident entities.
+    fn check_allowed_value(&self, attr: Entity, fact: &Fact) -> Result<()> {
+        let allowed = match self.schema.allowed_values.get(&attr) {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+
+        let ident = match fact.value {
+            Value::Ident(ref i) => Some(i.clone()),
+            Value::Ref(e) => self.ident_for(e).map(String::from),
+            _ => None,
+        };
+
+        match ident {
+            Some(ref i) if allowed.contains(i) => Ok(()),
+            _ => Err(format!(
+                "invalid value {} for attribute {}: allowed values are {}",
+                fact.value,
+                fact.attribute,
+                self.schema.allowed_values(attr).unwrap_or_default().join(", ")
+            ).into()),
+        }
+    }
+
     pub fn retract(&self, fact: Fact, tx_entity: Entity) -> Result<(Db, Record)> {
         // FIXME: dry
-        let attr = match self.schema.idents.get(&fact.attribute) {
+        let attr = match self.schema.resolve(&fact.attribute) {
             Some(a) => a,
             None => return Err(format!("invalid attribute: ident '{:?}' does not exist", &fact.attribute).into())
         };
+        let fact = Fact::new(fact.entity, fact.attribute, self.schema.normalize(attr, fact.value));
 
         let fact_value_type = match fact.value {
             Value::String(_) => ValueType::String,
@@ -373,12 +1659,15 @@ impl Db {
             Value::Ident(_) => ValueType::Ident,
             Value::Boolean(_) => ValueType::Boolean,
             Value::Long(_) => ValueType::Long,
+            Value::Geo(_) => ValueType::Geo,
         };
 
         match self.schema.value_types.get(&attr) {
             Some(schema_type) => {
                 if *schema_type == fact_value_type {
-                    let record = Record::retraction(fact.entity, *attr, fact.value, tx_entity);
+                    self.check_encryption_change(attr, &fact)?;
+                    self.check_collation_change(attr, &fact)?;
+                    let record = Record::retraction(fact.entity, attr, self.stored_value(attr, fact.value)?, tx_entity);
                     return self.add_record(record.clone()).map(|new_db| (new_db, record));
                 } else {
                     return Err(format!(
@@ -392,3 +1681,104 @@ impl Db {
         }
     }
 }
+
+/// The entity map which creates the attribute `def`.
+fn new_attribute(def: &AttributeDef) -> HashMap<String, TxValue> {
+    let mut attribute: HashMap<String, TxValue> = HashMap::new();
+    attribute.insert("db:ident".into(), Value::Ident(def.ident.clone()).into());
+    attribute.insert("db:valueType".into(), Value::Ident(def.value_type.ident().into()).into());
+    if def.indexed {
+        attribute.insert("db:indexed".into(), Value::Boolean(true).into());
+    }
+    if def.no_history {
+        attribute.insert("db:noHistory".into(), Value::Boolean(true).into());
+    }
+    if def.encrypted {
+        attribute.insert("db:encrypted".into(), Value::Boolean(true).into());
+    }
+    if def.cardinality == Cardinality::One {
+        attribute.insert("db:cardinality".into(), Value::Ident(Cardinality::One.ident().into()).into());
+    }
+    if def.collation != Collation::Binary {
+        attribute.insert("db:collation".into(), Value::Ident(def.collation.ident().into()).into());
+    }
+    if def.unique {
+        attribute.insert("db:unique".into(), Value::Boolean(true).into());
+    }
+    if let Some(ref doc) = def.doc {
+        attribute.insert("db:doc".into(), doc.as_str().into());
+    }
+    if !def.allowed_values.is_empty() {
+        let values = def.allowed_values.iter().map(|v| Value::Ident(v.clone()).into()).collect();
+        attribute.insert("db:allowedValue".into(), TxValue::Many(values));
+    }
+    if !def.normalizers.is_empty() {
+        let values = def.normalizers.iter().map(|n| Value::Ident(n.ident().into()).into()).collect();
+        attribute.insert("db:normalize".into(), TxValue::Many(values));
+    }
+    attribute
+}
+
+/// Drops the retractions whose assertions aren't among `records`,
+/// which are in the order of an index, where a retraction follows the
+/// assertion it retracts. In a `since` view, the assertion can be
+/// from before the view starts, and `fetch` would otherwise take the
+/// retraction to cancel whatever fact came before it.
+fn drop_unmatched_retractions(records: Vec<Record>) -> Vec<Record> {
+    let mut kept: Vec<Record> = Vec::with_capacity(records.len());
+    for record in records {
+        let retracts_previous = kept.last().is_some_and(|prev: &Record| {
+            !prev.retracted && prev.entity == record.entity && prev.attribute == record.attribute && prev.value == record.value
+        });
+        if !record.retracted || retracts_previous {
+            kept.push(record);
+        }
+    }
+    kept
+}
+
+/// Drops the superseded history of `db:noHistory` attributes from a
+/// sorted run of records, as they're rewritten during a reindex.
+///
+/// Records for the same (entity, attribute, value) are contiguous in
+/// every index, and ordered by tx. For each such group, we keep only
+/// as many of the latest assertions as are still in effect, so a
+/// value which was asserted and later retracted disappears entirely.
+/// A group which retracts more than it asserts continues a group
+/// from an earlier leaf, so it's left alone.
+pub fn drop_history(records: Vec<Record>, no_history: &im::HashSet<Entity>) -> Vec<Record> {
+    let mut kept = Vec::with_capacity(records.len());
+    let mut group: Vec<Record> = vec![];
+
+    let flush = |group: &mut Vec<Record>, kept: &mut Vec<Record>| {
+        let (mut depth, mut min_depth) = (0i64, 0i64);
+        for r in group.iter() {
+            depth += if r.retracted { -1 } else { 1 };
+            min_depth = min_depth.min(depth);
+        }
+        if min_depth < 0 {
+            kept.append(group);
+            return;
+        }
+        let live = group.iter().filter(|r| !r.retracted).count() - depth as usize;
+        kept.extend(group.drain(..).filter(|r| !r.retracted).skip(live));
+    };
+
+    for record in records {
+        if !no_history.contains(&record.attribute) {
+            flush(&mut group, &mut kept);
+            kept.push(record);
+            continue;
+        }
+        let same_group = group.last().is_some_and(|r: &Record| {
+            r.entity == record.entity && r.attribute == record.attribute && r.value == record.value
+        });
+        if !same_group {
+            flush(&mut group, &mut kept);
+        }
+        group.push(record);
+    }
+    flush(&mut group, &mut kept);
+
+    kept
+}
@@ -1,19 +1,24 @@
 use std::fmt::Debug;
 use std::iter::Peekable;
 use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::result;
 // TODO: replace mutex with futures::lock
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Instant;
 use log::{error};
 
 use itertools::Itertools;
 use lru_cache::LruCache;
 use serde::{Serialize, Deserialize};
 use serde::de::DeserializeOwned;
-use uuid::Uuid;
+use blake3;
 
 use backends::KVStore;
-use index::{Equivalent, Comparator};
-use {Result};
+use checksum;
+use index::{Equivalent, Comparator, LargeValue};
+use {Error, Result, ReindexStatus};
 
 ///! This module defines a data structure for storing facts in the
 ///! backing store. It is intended to be constructed once in a batch
@@ -30,13 +35,37 @@ use {Result};
 ///! in place until all leaves have been created, at which point the
 ///! interior nodes are converted from "draft nodes" in memory to
 ///! durable nodes in the backing store.
+///!
+///! Values larger than `LARGE_VALUE_THRESHOLD` are kept out of the
+///! leaves, as blobs stored under the hash of their contents. A blob
+///! is only fetched when its item is read (or compared against while
+///! searching its leaf), so large values don't slow down queries for
+///! the items stored beside them. Like segments, blobs are shared
+///! between every index and tree that holds the same value, and are
+///! left in the store when nothing refers to them any more.
 
 const NODE_CAPACITY: usize = 1024;
 
-// TODO: leaf max size should be in bytes, not records, in order to comply
-// with backing kv store size limits (e.g. 65kb mysql blobs)
 const LEAF_CAPACITY: usize = 16384;
 
+// Leaves are also cut short once their items add up to about this
+// many bytes, so that a leaf of wide items doesn't make for a huge
+// segment.
+const LEAF_MAX_BYTES: usize = 1 << 20;
+
+const LARGE_VALUE_THRESHOLD: usize = 4096;
+
+/// How many decoded nodes each tree keeps in memory. See
+/// `set_node_cache_size`.
+static NODE_CACHE_SIZE: AtomicUsize = AtomicUsize::new(1024);
+
+/// Sets how many decoded nodes each tree opened from now on keeps in
+/// memory (1024 by default), trading memory for fewer reads from the
+/// store.
+pub fn set_node_cache_size(nodes: usize) {
+    NODE_CACHE_SIZE.store(nodes.max(1), AtomicOrdering::Relaxed);
+}
+
 /// A link to another node of the tree. This can be either a string
 /// key for retrieving the node from the backing store, or a pointer
 /// to the node in memory. The pointers are used only during the
@@ -60,7 +89,17 @@ pub enum Node<T> {
 /// A leaf node is just an array of items.
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Serialize, Deserialize)]
 pub struct LeafNode<T> {
-    pub items: Vec<T>
+    pub items: Vec<T>,
+    /// The keys of the blobs holding values taken out of the items,
+    /// by item index, in order.
+    #[serde(default)]
+    pub blobs: Vec<(usize, String)>,
+}
+
+impl<T> LeafNode<T> {
+    pub fn new(items: Vec<T>) -> LeafNode<T> {
+        LeafNode { items, blobs: vec![] }
+    }
 }
 
 
@@ -78,12 +117,12 @@ pub struct InteriorNode<T> {
 pub struct DurableTree<T, C> {
     pub root: String,
     store: NodeStore<T>,
-    _comparator: C,
+    comparator: C,
 }
 
 impl<T, C> DurableTree<T, C>
 where
-    T: Equivalent + Serialize + DeserializeOwned + Clone + Debug,
+    T: Equivalent + LargeValue + Serialize + DeserializeOwned + Clone + Debug,
     C: Comparator<Item = T>,
 {
     pub fn create(store: Arc<dyn KVStore>, comparator: C) -> Result<DurableTree<T, C>> {
@@ -93,24 +132,19 @@ where
         Ok(DurableTree {
             root: root_ref,
             store: node_store,
-            _comparator: comparator,
+            comparator,
         })
     }
 
     /// Builds the tree from an iterator by chunking it into an
     /// iterator of leaf nodes and then constructing the tree of
     /// directory nodes on top of that.
-    // TODO: remove
-    #[cfg(test)]
     fn build_from_iter<I>(store: NodeStore<T>, iter: I, comparator: C) -> Result<DurableTree<T, C>>
     where
         I: Iterator<Item = T>,
     {
         // The items need to be chunked into leaf nodes.
-        let chunks = iter.chunks(LEAF_CAPACITY);
-        let leaves = chunks.into_iter()
-            .map(|chunk| chunk.collect::<Vec<_>>())
-            .map(|items| LeafNode { items });
+        let leaves = store.leaves_of(iter);
         let closure_store = store.clone();
         let leaf_node_links = leaves.map(|leaf| {
             let leaf_link = closure_store.add_node(&Node::Leaf(leaf.clone()))
@@ -139,7 +173,6 @@ where
         // of the tree is always last.
         let mut open_nodes: Vec<InteriorNode<T>> = vec![InteriorNode { links: vec![], keys: vec![] }];
 
-        let leaves = leaves.collect::<Vec<_>>();
         // error handling makes this a bit awkward; we need to process
         // the leaf links lazily, but return an error if we encounter
         // an error in the iterator, so instead of folding or
@@ -147,7 +180,7 @@ where
         // references
         // TODO: failable iterators?
         for result in leaves {
-            let LeafRef { node, mut db_key } = result.expect("no leaf ref");
+            let LeafRef { node, mut db_key } = result?;
             let mut key = node.items[0].clone();
             let mut layer = 0;
             loop {
@@ -187,7 +220,7 @@ where
                 DurableTree {
                     store: store,
                     root: root_ref,
-                    _comparator: comparator,
+                    comparator,
                 }
             )
         }
@@ -211,31 +244,94 @@ where
         Ok(DurableTree {
             store: store,
             root: link,
-            _comparator: comparator,
+            comparator,
         })
     }
 
+    #[cfg(test)]
     pub fn rebuild_with_novelty<I>(
         &self,
         novelty: I,
     ) -> Result<DurableTree<T, C>>
         where I: Iterator<Item = T>
     {
-        let rebuild_iterator = RebuildIter::new(
+        self.rebuild_with_progress(novelty, &Arc::new(RebuildProgress::new(0)), None)
+    }
+
+    /// Like `rebuild_with_novelty`, but counts the novelty merged
+    /// and the segments written in `progress`, and passes the items
+    /// of every rewritten leaf through `compact`.
+    pub fn rebuild_with_progress<I>(
+        &self,
+        novelty: I,
+        progress: &Arc<RebuildProgress>,
+        compact: Option<&Compactor<T>>,
+    ) -> Result<DurableTree<T, C>>
+        where I: Iterator<Item = T>
+    {
+        let tracking_store = NodeStore { progress: Some(progress.clone()), ..self.store.clone() };
+        let novelty_progress = progress.clone();
+        let mut rebuild_iterator = RebuildIter::new(
             self.iter_leaves(),
-            novelty,
-            self.store.clone(),
-            self._comparator,
+            novelty.inspect(move |_| novelty_progress.record_processed()),
+            tracking_store.clone(),
+            self.comparator.clone(),
         ).expect("could not construct RebuildIter");
-        Self::build_from_leaves(rebuild_iterator, self.store.clone(), self._comparator)
+        rebuild_iterator.compact = compact.cloned();
+        let mut tree = Self::build_from_leaves(rebuild_iterator, tracking_store, self.comparator.clone())?;
+        tree.store = self.store.clone();
+        Ok(tree)
     }
 
-    pub fn from_ref(db_ref: String, store: Arc<dyn KVStore>, _comparator: C) -> DurableTree<T, C> {
+    /// Builds a new tree holding just the items of `items`, which
+    /// must be sorted, in the same store as this one. Nodes whose
+    /// contents match one of this tree's share its key.
+    pub fn rebuild_from<I>(&self, items: I) -> Result<DurableTree<T, C>>
+        where I: Iterator<Item = T>
+    {
+        Self::build_from_iter(self.store.clone(), items, self.comparator.clone())
+    }
+
+    pub fn from_ref(db_ref: String, store: Arc<dyn KVStore>, comparator: C) -> DurableTree<T, C> {
         DurableTree {
             root: db_ref,
             store: NodeStore::new(store),
-            _comparator,
+            comparator,
+        }
+    }
+
+    pub fn with_comparator(&self, comparator: C) -> DurableTree<T, C> {
+        DurableTree { root: self.root.clone(), store: self.store.clone(), comparator }
+    }
+
+    /// Drops `keys` from the caches of this tree's store, after the
+    /// nodes or blobs they name have been deleted from it. Otherwise
+    /// a later build producing the same contents would find the key
+    /// cached and skip writing it back.
+    pub fn forget(&self, keys: &[String]) {
+        self.store.forget(keys)
+    }
+
+    /// Loads the nodes in the top `levels` levels of the tree (the
+    /// root being the first) into the node cache, returning how many
+    /// were fetched.
+    pub fn prefetch(&self, levels: usize) -> Result<usize> {
+        let mut fetched = 0;
+        let mut level = vec![self.root.clone()];
+        for _ in 0..levels {
+            let mut next_level = vec![];
+            for key in level {
+                if let Node::Interior(InteriorNode { ref links, .. }) = *self.store.get_node(&key)? {
+                    next_level.extend(links.iter().filter_map(|link| match *link {
+                        Link::DbKey(ref key) => Some(key.clone()),
+                        Link::Pointer(_) => None,
+                    }));
+                }
+                fetched += 1;
+            }
+            level = next_level;
         }
+        Ok(fetched)
     }
 
     fn iter_leaves(&self) -> LeafIter<T> {
@@ -253,6 +349,12 @@ where
     }
 
     pub fn range_from(&self, start: T) -> Result<ItemIter<T>> {
+        self.range_from_by(|item| self.comparator.compare(item, &start))
+    }
+
+    /// Iterates from the first item `seek` doesn't order before the
+    /// range, for ranges that don't start at an item.
+    pub fn range_from_by<F: Fn(&T) -> Ordering>(&self, seek: F) -> Result<ItemIter<T>> {
         let mut stack = vec![
             LeafIterState {
                 node_ref: Link::DbKey(self.root.clone()),
@@ -271,8 +373,8 @@ where
             let node = self.store.get_node(&node_ref)?;
 
             match *node {
-                Node::Leaf(LeafNode { ref items }) => {
-                    match items.binary_search_by(|other| C::compare(other, &start)) {
+                Node::Leaf(ref leaf) => {
+                    match self.store.search_leaf(leaf, &seek)? {
                         Ok(idx) => {
                             stack.push(LeafIterState {
                                 link_idx: idx + 1,
@@ -305,7 +407,7 @@ where
                     // child at that index, so it doesn't actually make a
                     // difference if the key exists in this node or
                     // not, except for the off-by-one error.
-                    let link_idx = match keys.binary_search_by(|other| C::compare(other, &start)) {
+                    let link_idx = match keys.binary_search_by(&seek) {
                         Ok(idx) => idx,
                         // This is not elegant, but it happens when
                         // the key doesn't exist and sorts between
@@ -345,6 +447,40 @@ where
     }
 }
 
+/// Returns the keys of the nodes in the tree rooted at `root`, and of
+/// the blobs its leaves use, leaving out (and not descending into)
+/// the nodes whose keys are in `skip`.
+pub fn node_keys<T>(store: Arc<dyn KVStore>, root: &str, skip: &HashSet<String>) -> Result<Vec<String>>
+where
+    T: LargeValue + Serialize + DeserializeOwned + Clone,
+{
+    let store: NodeStore<T> = NodeStore::new(store);
+    let mut keys = vec![];
+    let mut stack = vec![root.to_string()];
+    while let Some(key) = stack.pop() {
+        if skip.contains(&key) {
+            continue;
+        }
+
+        match *store.get_node(&key)? {
+            Node::Interior(InteriorNode { ref links, .. }) => {
+                for link in links {
+                    match *link {
+                        Link::DbKey(ref child) => stack.push(child.clone()),
+                        Link::Pointer(_) => unreachable!(),
+                    }
+                }
+            }
+            Node::Leaf(LeafNode { ref blobs, .. }) => {
+                keys.extend(blobs.iter().map(|(_, blob)| blob.clone()).filter(|blob| !skip.contains(blob)));
+            }
+        }
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 struct LeafRef<T> {
     db_key: String,
@@ -365,7 +501,7 @@ struct LeafIterState<T> {
 }
 
 impl<T> Iterator for LeafIter<T>
-where T: Clone + DeserializeOwned + Serialize + Debug,
+where T: LargeValue + Clone + DeserializeOwned + Serialize + Debug,
 {
     type Item = Result<LeafRef<T>>;
 
@@ -427,7 +563,7 @@ pub struct ItemIter<T>
     item_idx: usize,
 }
 
-impl<T> ItemIter<T> where T: Clone + DeserializeOwned + Serialize + Debug {
+impl<T> ItemIter<T> where T: LargeValue + Clone + DeserializeOwned + Serialize + Debug {
     fn from_leaves(mut leaves: LeafIter<T>, idx_in_leaf: usize) -> Result<ItemIter<T>> {
         let first_leaf = match leaves.next() {
             Some(Ok(LeafRef { node: leaf, .. })) => Some(leaf),
@@ -446,28 +582,29 @@ impl<T> ItemIter<T> where T: Clone + DeserializeOwned + Serialize + Debug {
 }
 
 impl<T> Iterator for ItemIter<T>
-where T: Clone + DeserializeOwned + Serialize + Debug,
+where T: LargeValue + Clone + DeserializeOwned + Serialize + Debug,
 {
     type Item = Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let items = match self.current_leaf.clone() {
-            Some(LeafNode { items }) => items,
-            None => return None,
-        };
+        loop {
+            let leaf = match self.current_leaf {
+                Some(ref leaf) => leaf,
+                None => return None,
+            };
+
+            if self.item_idx < leaf.items.len() {
+                let item = self.leaves.store.item(leaf, self.item_idx);
+                self.item_idx += 1;
+                return Some(item);
+            }
 
-        if self.item_idx < items.len() {
-            let item = items[self.item_idx].clone();
-            self.item_idx += 1;
-            return Some(Ok(item));
-        } else {
             self.current_leaf = match self.leaves.next() {
                 Some(Ok(LeafRef { node: leaf, .. })) => Some(leaf),
                 Some(Err(e)) => return Some(Err(e)),
                 None => None,
             };
             self.item_idx = 0;
-            return self.next();
         }
     }
 }
@@ -477,35 +614,231 @@ where T: Clone + DeserializeOwned + Serialize + Debug,
 #[derive(Clone)]
 struct NodeStore<T> {
     cache: Arc<Mutex<LruCache<String, Arc<Node<T>>>>>,
+    blobs: Arc<Mutex<LruCache<String, Arc<Vec<u8>>>>>,
     store: Arc<dyn KVStore>,
+    /// Values larger than this are stored as blobs.
+    large_value_threshold: usize,
+    /// Counts the nodes written during a rebuild.
+    progress: Option<Arc<RebuildProgress>>,
+}
+
+/// Rewrites the (sorted) items of a leaf being rebuilt, e.g. to drop
+/// superseded history. It must keep the items sorted, and can't
+/// assume that it sees every item related to the ones it's given:
+/// related items may be in neighbouring leaves.
+pub type Compactor<T> = Arc<dyn Fn(Vec<T>) -> Vec<T> + Send + Sync>;
+
+/// Counters for an index rebuild in progress, shared between the
+/// threads rebuilding each index and the transactor reporting on it.
+#[derive(Debug)]
+pub struct RebuildProgress {
+    started: Instant,
+    records_total: usize,
+    records_processed: AtomicUsize,
+    segments_written: AtomicUsize,
+}
+
+impl RebuildProgress {
+    /// `records_total` is the number of in-memory records the
+    /// rebuild will merge into the durable indices.
+    pub fn new(records_total: usize) -> RebuildProgress {
+        RebuildProgress {
+            started: Instant::now(),
+            records_total,
+            records_processed: AtomicUsize::new(0),
+            segments_written: AtomicUsize::new(0),
+        }
+    }
+
+    fn record_processed(&self) {
+        self.records_processed.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    fn segment_written(&self) {
+        self.segments_written.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Estimates the time remaining by extrapolating the rate at
+    /// which records have been merged so far.
+    pub fn status(&self) -> ReindexStatus {
+        let records_processed = self.records_processed.load(AtomicOrdering::Relaxed);
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        let eta_ms = if records_processed == 0 {
+            None
+        } else {
+            let remaining = self.records_total.saturating_sub(records_processed) as u64;
+            Some(elapsed_ms * remaining / records_processed as u64)
+        };
+
+        ReindexStatus {
+            records_processed,
+            records_total: self.records_total,
+            segments_written: self.segments_written.load(AtomicOrdering::Relaxed),
+            elapsed_ms,
+            eta_ms,
+        }
+    }
+}
+
+fn segment_key(buf: &[u8]) -> String {
+    // Keys are content-addressed, so identical nodes share a
+    // segment. 128 bits of the hash keep keys within the 36
+    // characters the backends allot for them.
+    blake3::hash(buf).to_hex()[..32].to_string()
+}
+
+/// Checks and decompresses a segment as it was read from the store.
+fn decode_segment(key: &str, blob: &[u8]) -> Result<Vec<u8>> {
+    let compressed = checksum::unseal(key, blob)?;
+    let mut serialized = Vec::new();
+    let mut decoder = snap::read::FrameDecoder::new(compressed);
+    std::io::copy(&mut decoder, &mut serialized).map_err(|_| Error::Corruption { key: key.to_string() })?;
+    Ok(serialized)
+}
+
+/// Copies the segments of the tree rooted at `root`, and the blobs
+/// its leaves refer to, from one store to another, checking each one
+/// on the way. Children are copied before their parents, so a node
+/// already in `to` has its whole subtree there and is skipped; after
+/// a reindex, only the rewritten nodes are copied. Returns the
+/// number of segments copied.
+pub fn copy_tree<T: DeserializeOwned>(root: &str, from: &dyn KVStore, to: &dyn KVStore) -> Result<usize> {
+    if to.get(root).is_ok() {
+        return Ok(0);
+    }
+
+    let sealed = from.get(root)?;
+    let corrupt = || Error::Corruption { key: root.to_string() };
+    let node: Node<T> = rmp_serde::from_read_ref(&decode_segment(root, &sealed)?).map_err(|_| corrupt())?;
+    let mut copied = 0;
+    match node {
+        Node::Interior(ref interior) => {
+            for link in &interior.links {
+                match *link {
+                    Link::DbKey(ref key) => copied += copy_tree::<T>(key, from, to)?,
+                    Link::Pointer(_) => return Err(corrupt()),
+                }
+            }
+        }
+        Node::Leaf(ref leaf) => {
+            for (_, key) in &leaf.blobs {
+                if to.get(key).is_err() {
+                    let blob = from.get(key)?;
+                    decode_segment(key, &blob)?;
+                    to.set(key, &blob)?;
+                    copied += 1;
+                }
+            }
+        }
+    }
+    to.set(root, &sealed)?;
+    Ok(copied + 1)
+}
+
+/// Reads and checks each segment of the tree rooted at `root`, and
+/// the blobs its leaves refer to, straight from the store. Segments
+/// in `seen` are skipped, and the rest are added to it. Returns the
+/// keys of those which are corrupt; the nodes under a corrupt one
+/// can't be found, so they aren't checked.
+pub fn verify_tree<T: DeserializeOwned>(root: &str, store: &dyn KVStore, seen: &mut HashSet<String>) -> Result<Vec<String>> {
+    let mut corrupt = vec![];
+    let mut stack = vec![root.to_string()];
+    while let Some(key) = stack.pop() {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+
+        let node = decode_segment(&key, &store.get(&key)?).and_then(|serialized| {
+            rmp_serde::from_read_ref::<_, Node<T>>(&serialized).map_err(|_| Error::Corruption { key: key.clone() })
+        });
+        match node {
+            Ok(Node::Interior(interior)) => {
+                for link in interior.links {
+                    match link {
+                        Link::DbKey(child) => stack.push(child),
+                        // In-memory links are never stored.
+                        Link::Pointer(_) => {
+                            corrupt.push(key);
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(Node::Leaf(leaf)) => {
+                for (_, blob) in leaf.blobs {
+                    if seen.insert(blob.clone()) {
+                        match decode_segment(&blob, &store.get(&blob)?) {
+                            Ok(_) => {}
+                            Err(Error::Corruption { key }) => corrupt.push(key),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+            }
+            Err(Error::Corruption { key }) => corrupt.push(key),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(corrupt)
 }
 
 impl<T> NodeStore<T>
 where
-    T: Serialize + DeserializeOwned + Clone,
+    T: LargeValue + Serialize + DeserializeOwned + Clone,
 {
     fn new(store: Arc<dyn KVStore>) -> NodeStore<T> {
         NodeStore {
-            // TODO make size configurable
-            cache: Arc::new(Mutex::new(LruCache::new(1024))),
+            cache: Arc::new(Mutex::new(LruCache::new(NODE_CACHE_SIZE.load(AtomicOrdering::Relaxed)))),
+            blobs: Arc::new(Mutex::new(LruCache::new(64))),
             store: store,
+            large_value_threshold: LARGE_VALUE_THRESHOLD,
+            progress: None,
         }
     }
 
-    fn add_node(&self, node: &Node<T>) -> Result<String> {
-        let buf = rmp_serde::to_vec(node)?;
+    fn write_segment(&self, key: &str, buf: &[u8]) -> Result<()> {
         let mut encoded = Vec::new();
-
         {
             let mut encoder = snap::write::FrameEncoder::new(&mut encoded);
             std::io::copy(&mut &buf[..], &mut encoder)?;
         }
+        self.store.set(key, &checksum::seal(&encoded))
+    }
+
+    fn read_segment(&self, key: &str) -> Result<Vec<u8>> {
+        decode_segment(key, &self.store.get(key)?)
+    }
+
+    fn add_node(&self, node: &Node<T>) -> Result<String> {
+        let stored;
+        let node = match *node {
+            Node::Leaf(ref leaf) if leaf.items.iter().any(|i| i.value_size() > self.large_value_threshold) => {
+                stored = Node::Leaf(self.take_large_values(leaf)?);
+                &stored
+            }
+            _ => node,
+        };
 
-        let key: String = Uuid::new_v4().to_string();
-        self.store.set(&key, &encoded)?;
+        let buf = rmp_serde::to_vec(node)?;
+        let key = segment_key(&buf);
+        if !self.cache.lock().unwrap().contains_key(&key) {
+            self.write_segment(&key, &buf)?;
+        }
+        if let Some(ref progress) = self.progress {
+            progress.segment_written();
+        }
         Ok(key)
     }
 
+    fn forget(&self, keys: &[String]) {
+        let mut cache = self.cache.lock().unwrap();
+        let mut blobs = self.blobs.lock().unwrap();
+        for key in keys {
+            cache.remove(key);
+            blobs.remove(key);
+        }
+    }
+
     /// Fetches and deserializes the node with the given key.
     fn get_node(&self, key: &str) -> Result<Arc<Node<T>>> {
         let mut cache = self.cache.lock().unwrap();
@@ -513,17 +846,109 @@ where
         match res {
             Some(node) => Ok(node.clone()),
             None => {
-                let compressed = self.store.get(key)?;
-                let mut serialized = Vec::new();
-                let mut decoder = snap::read::FrameDecoder::new(&compressed[..]);
-                std::io::copy(&mut decoder, &mut serialized)?;
-                let value: Node<T> = rmp_serde::from_read_ref(&serialized)?;
+                let serialized = self.read_segment(key)?;
+                let corrupt = || Error::Corruption { key: key.to_string() };
+                let value: Node<T> = rmp_serde::from_read_ref(&serialized).map_err(|_| corrupt())?;
                 let node: Arc<Node<T>> = Arc::new(value);
                 cache.insert(key.to_string(), node.clone());
                 Ok(node.clone())
             }
         }
     }
+
+    /// Moves the large values of a leaf's items into blobs. The
+    /// first and last items keep theirs, since they're compared
+    /// against while navigating and rebuilding the tree.
+    fn take_large_values(&self, leaf: &LeafNode<T>) -> Result<LeafNode<T>> {
+        let mut stored = leaf.clone();
+        for idx in 1..stored.items.len().saturating_sub(1) {
+            if stored.items[idx].value_size() <= self.large_value_threshold {
+                continue;
+            }
+            let value = stored.items[idx].take_value()?;
+            let key = segment_key(&value);
+            // A blob that's been read recently is surely still there.
+            if !self.blobs.lock().unwrap().contains_key(&key) {
+                self.write_segment(&key, &value)?;
+            }
+            stored.blobs.push((idx, key));
+        }
+        Ok(stored)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Arc<Vec<u8>>> {
+        if let Some(blob) = self.blobs.lock().unwrap().get_mut(key) {
+            return Ok(blob.clone());
+        }
+        let blob = Arc::new(self.read_segment(key)?);
+        self.blobs.lock().unwrap().insert(key.to_string(), blob.clone());
+        Ok(blob)
+    }
+
+    /// Returns a leaf's item, with its value put back if it was
+    /// stored as a blob.
+    fn item(&self, leaf: &LeafNode<T>, idx: usize) -> Result<T> {
+        let mut item = leaf.items[idx].clone();
+        if let Ok(blob_idx) = leaf.blobs.binary_search_by_key(&idx, |&(i, _)| i) {
+            let key = &leaf.blobs[blob_idx].1;
+            item.restore_value(&self.get_blob(key)?)
+                .map_err(|_| Error::Corruption { key: key.clone() })?;
+        }
+        Ok(item)
+    }
+
+    /// Returns all of a leaf's items, with their values put back.
+    fn items(&self, leaf: LeafNode<T>) -> Result<Vec<T>> {
+        if leaf.blobs.is_empty() {
+            return Ok(leaf.items);
+        }
+        (0..leaf.items.len()).map(|idx| self.item(&leaf, idx)).collect()
+    }
+
+    /// Like `binary_search_by` on the leaf's items, but only fetching
+    /// the blobs of the items it compares against.
+    fn search_leaf<F: Fn(&T) -> Ordering>(&self, leaf: &LeafNode<T>, seek: F) -> Result<result::Result<usize, usize>> {
+        if leaf.blobs.is_empty() {
+            return Ok(leaf.items.binary_search_by(seek));
+        }
+        let (mut low, mut high) = (0, leaf.items.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match seek(&self.item(leaf, mid)?) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    /// Splits sorted items into leaves of at most `LEAF_CAPACITY`
+    /// items and about `LEAF_MAX_BYTES`, not counting values that
+    /// will be stored as blobs.
+    fn split_leaves(&self, items: Vec<T>) -> Vec<LeafNode<T>> {
+        self.leaves_of(items.into_iter()).collect()
+    }
+
+    /// Like `split_leaves`, but splits the items as they're consumed,
+    /// so that only one leaf's worth is held at a time.
+    fn leaves_of<I: Iterator<Item = T>>(&self, mut items: I) -> impl Iterator<Item = LeafNode<T>> {
+        let large_value_threshold = self.large_value_threshold;
+        std::iter::from_fn(move || {
+            let mut current = vec![];
+            let mut current_bytes = 0;
+            for item in items.by_ref() {
+                let value_size = item.value_size();
+                let stored_size = if value_size > large_value_threshold { 0 } else { value_size };
+                current_bytes += std::mem::size_of::<T>() + stored_size;
+                current.push(item);
+                if current.len() == LEAF_CAPACITY || current_bytes >= LEAF_MAX_BYTES {
+                    break;
+                }
+            }
+            if current.is_empty() { None } else { Some(LeafNode::new(current)) }
+        })
+    }
 }
 
 
@@ -535,7 +960,8 @@ struct RebuildIter<T, L: Iterator<Item = Result<LeafRef<T>>>, I: Iterator<Item =
     new_leaves: Vec<Result<LeafRef<T>>>,
     novelty: Peekable<I>,
     store: NodeStore<T>,
-    _comparator: C,
+    compact: Option<Compactor<T>>,
+    comparator: C,
 }
 
 impl <T, L: Iterator<Item = Result<LeafRef<T>>>, I: Iterator<Item = T>, C: Comparator> RebuildIter<T, L, I, C>
@@ -550,13 +976,14 @@ where T: Clone + Debug {
             new_leaves: vec![],
             novelty: novelty.peekable(),
             store,
-            _comparator: comparator,
+            compact: None,
+            comparator,
         })
     }
 }
 
 impl <T, L, I, C> Iterator for RebuildIter<T, L, I, C>
-where T: Equivalent + Clone + Debug + DeserializeOwned + Serialize,
+where T: Equivalent + LargeValue + Clone + Debug + DeserializeOwned + Serialize,
       L: Iterator<Item = Result<LeafRef<T>>>,
       I: Iterator<Item = T>,
       C: Comparator<Item = T> {
@@ -586,8 +1013,10 @@ where T: Equivalent + Clone + Debug + DeserializeOwned + Serialize,
                         while let Some(item) = self.novelty.next() {
                             remaining_novelty.push(item);
                         }
-                        let mut created_leaves = remaining_novelty.into_iter().chunks(LEAF_CAPACITY).into_iter().map(|items| {
-                            let node = LeafNode { items: items.collect() };
+                        if let Some(ref compact) = self.compact {
+                            remaining_novelty = compact(remaining_novelty);
+                        }
+                        let mut created_leaves = self.store.split_leaves(remaining_novelty).into_iter().map(|node| {
                             self.store.add_node(&Node::Leaf(node.clone())).map(|db_key| LeafRef { node, db_key })
                         }).collect::<Vec<_>>();
                         while let Some(new_leaf) = created_leaves.pop() {
@@ -602,7 +1031,7 @@ where T: Equivalent + Clone + Debug + DeserializeOwned + Serialize,
                 match self.novelty.peek().cloned() {
                     None => self.new_leaves.push(Ok(LeafRef { node, db_key })),
                     Some(first_novel_item) => {
-                        if C::compare(&first_novel_item, &last_item) == Ordering::Greater {
+                        if self.comparator.compare(&first_novel_item, &last_item) == Ordering::Greater {
                             // we can reuse this leaf, since it doesn't overlap with the novelty
                             // TODO: check for reusability the other way as well?
                             self.new_leaves.push(Ok(LeafRef { node, db_key }));
@@ -616,21 +1045,29 @@ where T: Equivalent + Clone + Debug + DeserializeOwned + Serialize,
                             // this is just take_while(|i| C::compare(&i, &next_first_item) == Ordering::Less), but take_while
                             // consumes the rest of its iterator which we don't want
                             let mut overlapping_novelty = vec![];
+                            let comparator = &self.comparator;
                             // FIXME: tortured logic
                             while self.novelty.peek().map(|i| match next_leaf_first_item.clone() {
                                 None => true,
-                                Some(item) => C::compare(&i, &item) == Ordering::Less
+                                Some(item) => comparator.compare(&i, &item) == Ordering::Less
                             }) == Some(true) {
                                 overlapping_novelty.push(self.novelty.next().unwrap());
                             }
 
-                            let mut created_leaves = node.items.into_iter()
-                                .merge_by(overlapping_novelty, |a, b| C::compare(a, b) == Ordering::Less)
+                            let items = match self.store.items(node) {
+                                Ok(items) => items,
+                                Err(e) => return Some(Err(e)),
+                            };
+                            let mut merged = items.into_iter()
+                                .merge_by(overlapping_novelty, |a, b| comparator.compare(a, b) == Ordering::Less)
                                 .coalesce(|x, y| if x.equivalent(&y) { Ok(x) } else { Err((x, y)) })
-                                .chunks(LEAF_CAPACITY)
+                                .collect::<Vec<_>>();
+                            if let Some(ref compact) = self.compact {
+                                merged = compact(merged);
+                            }
+                            let mut created_leaves = self.store.split_leaves(merged)
                                 .into_iter()
-                                .map(|items| {
-                                    let node = LeafNode { items: items.collect() };
+                                .map(|node| {
                                     self.store.add_node(&Node::Leaf(node.clone())).map(|db_key| LeafRef { node, db_key })
                                 }).collect::<Vec<_>>();
 
@@ -646,7 +1083,13 @@ where T: Equivalent + Clone + Debug + DeserializeOwned + Serialize,
 
         }
 
-        return self.new_leaves.pop();
+        match self.new_leaves.pop() {
+            Some(leaf) => Some(leaf),
+            // Compaction can leave nothing of a leaf, in which case
+            // we move on to the next one.
+            None if self.current_leaf.is_some() || self.novelty.peek().is_some() => self.next(),
+            None => None,
+        }
     }
 }
 
@@ -677,6 +1120,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_identical_trees_share_segments() {
+        let store = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let node_store = NodeStore::new(store.clone());
+
+        let a: DurableTree<i64, _> = DurableTree::build_from_iter(node_store.clone(), 0..50_000, NumComparator).unwrap();
+        let b: DurableTree<i64, _> = DurableTree::build_from_iter(node_store.clone(), 0..50_000, NumComparator).unwrap();
+        assert_eq!(a.root, b.root);
+    }
+
+    #[test]
+    fn test_forgotten_segments_are_written_again() {
+        let store = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let node_store = NodeStore::new(store.clone());
+
+        let a: DurableTree<i64, _> = DurableTree::build_from_iter(node_store.clone(), 0..50_000, NumComparator).unwrap();
+        let keys = node_keys::<i64>(store.clone(), &a.root, &HashSet::new()).unwrap();
+        for key in keys.iter() {
+            store.delete(key).unwrap();
+        }
+        a.forget(&keys);
+
+        let b: DurableTree<i64, _> = DurableTree::build_from_iter(node_store.clone(), 0..50_000, NumComparator).unwrap();
+        let reopened = DurableTree::from_ref(b.root, store, NumComparator);
+        assert_equal(reopened.iter().unwrap().map(|r| r.unwrap()), 0..50_000);
+    }
+
     #[test]
     fn test_build_and_iter() {
         let iter = 0..10_000;
@@ -747,6 +1217,114 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_prefetch() {
+        let tree = test_tree(0..100_000);
+        // The root and its seven leaves.
+        assert_eq!(tree.prefetch(2).unwrap(), 8);
+        assert_eq!(tree.prefetch(5).unwrap(), 8);
+        assert!(tree.store.cache.lock().unwrap().contains_key(&tree.root));
+    }
+
+    #[test]
+    fn test_rebuild_with_compaction() {
+        let tree = test_tree((0..40_000).filter(|i| i % 2 == 0));
+        let progress = Arc::new(RebuildProgress::new(0));
+        let drop_small: Compactor<i64> = Arc::new(|items: Vec<i64>| items.into_iter().filter(|i| *i >= 35_000).collect());
+
+        // Only the leaf overlapping the novelty is rewritten, and
+        // compacting the first one away entirely doesn't end the
+        // rebuild early.
+        let rebuild = tree.rebuild_with_progress(vec![1, 39_997].into_iter(), &progress, Some(&drop_small)).unwrap();
+        let items: Vec<i64> = rebuild.iter().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(items.len(), 2501);
+        assert_eq!(items.first(), Some(&35_000));
+        assert_eq!(items.last(), Some(&39_998));
+    }
+
+    #[test]
+    fn test_large_values_stored_as_blobs() {
+        use {Entity, Record, AVET};
+
+        let store = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let mut node_store = NodeStore::new(store.clone());
+        node_store.large_value_threshold = 16;
+
+        let doc = |i: i64| format!("document {} {}", i, "x".repeat(100));
+        let record = |e: i64, v: String| Record::addition(Entity(e), Entity(1), v, Entity(100));
+        // Entities 3 and 4 hold the same document.
+        let records: Vec<Record> = (0..10).map(|i| record(i, doc(if i == 4 { 3 } else { i }))).collect();
+        let tree = DurableTree::build_from_iter(node_store.clone(), records.clone().into_iter(), AVET::default()).unwrap();
+        assert_equal(tree.iter().unwrap().map(|r| r.unwrap()), records.clone());
+
+        // The documents (other than the first and last) are kept out
+        // of the leaf, and the same document is stored once.
+        let leaf_key = match *node_store.get_node(&tree.root).unwrap() {
+            Node::Interior(ref node) => match node.links[0] {
+                Link::DbKey(ref key) => key.clone(),
+                Link::Pointer(_) => unreachable!(),
+            },
+            Node::Leaf(_) => unreachable!(),
+        };
+        let leaf = match *node_store.get_node(&leaf_key).unwrap() {
+            Node::Leaf(ref leaf) => leaf.clone(),
+            Node::Interior(_) => unreachable!(),
+        };
+        assert_eq!(leaf.blobs.iter().map(|b| b.0).collect::<Vec<_>>(), (1..9).collect::<Vec<_>>());
+        assert_eq!(leaf.blobs[2].1, leaf.blobs[3].1);
+        assert!(rmp_serde::to_vec(&leaf).unwrap().len() < 1000);
+
+        // Searching the leaf compares against the real values.
+        let found = tree.range_from(record(6, doc(6))).unwrap().next().unwrap().unwrap();
+        assert_eq!(found, records[6]);
+
+        let novel = record(20, doc(6) + "!");
+        let rebuilt = tree.rebuild_with_novelty(vec![novel.clone()].into_iter()).unwrap();
+        let mut expected = records.clone();
+        expected.insert(7, novel);
+        assert_equal(rebuilt.iter().unwrap().map(|r| r.unwrap()), expected);
+    }
+
+    #[test]
+    fn test_leaves_split_by_size() {
+        use {Entity, Record};
+
+        let store = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let node_store: NodeStore<Record> = NodeStore::new(store);
+        let record = |e: i64, size: usize| Record::addition(Entity(e), Entity(1), "x".repeat(size), Entity(100));
+
+        let small: Vec<Record> = (0..100).map(|e| record(e, 10)).collect();
+        assert_eq!(node_store.split_leaves(small).len(), 1);
+
+        // Wide values fill a leaf well before LEAF_CAPACITY items...
+        let wide: Vec<Record> = (0..1000).map(|e| record(e, 4000)).collect();
+        let leaves = node_store.split_leaves(wide);
+        assert!(leaves.len() > 1);
+        assert!(leaves.iter().all(|leaf| leaf.items.len() <= LEAF_MAX_BYTES / 4000 + 1));
+
+        // ...but values that will be stored as blobs don't count.
+        let large: Vec<Record> = (0..1000).map(|e| record(e, LARGE_VALUE_THRESHOLD + 1)).collect();
+        assert_eq!(node_store.split_leaves(large).len(), 1);
+
+        // Leaves are split off as the items come, so even endless
+        // items give a first leaf.
+        let first = node_store.leaves_of((0..).map(|e| record(e, 10))).next().unwrap();
+        assert!(!first.items.is_empty() && first.items.len() <= LEAF_CAPACITY);
+    }
+
+    #[test]
+    fn test_rebuild_progress() {
+        let tree = test_tree(0..32767);
+        let progress = Arc::new(RebuildProgress::new(1000));
+        tree.rebuild_with_progress(50_000..51_000, &progress, None).unwrap();
+
+        let status = progress.status();
+        assert_eq!(status.records_processed, 1000);
+        assert_eq!(status.eta_ms, Some(0));
+        // The new leaf and the root.
+        assert_eq!(status.segments_written, 2);
+    }
+
     #[test]
     fn test_rebuild_with_novelty_avoids_duplicates() {
         let tree = test_tree(0..1000);
@@ -761,10 +1339,7 @@ mod tests {
     #[ignore]
     fn test_node_height() {
         let store = Arc::new(SqliteStore::new(":memory:").unwrap());
-        let node_store = NodeStore {
-            cache: Arc::new(Mutex::new(LruCache::new(1024))),
-            store: store.clone(),
-        };
+        let node_store = NodeStore::new(store.clone());
 
         let iter = 0..10_000_000;
         let tree = DurableTree::build_from_iter(node_store.clone(), iter.clone(), NumComparator).unwrap();
@@ -786,10 +1361,7 @@ mod tests {
     fn bench_build_from_iter(b: &mut Bencher) {
         use super::super::backends::sqlite::SqliteStore;
         let store = Arc::new(SqliteStore::new("/tmp/cliodb_bench.db").unwrap());
-        let node_store: NodeStore<i64> = NodeStore {
-            cache: Arc::new(Mutex::new(LruCache::new(1024))),
-            store: store.clone()
-        };
+        let node_store: NodeStore<i64> = NodeStore::new(store.clone());
         b.iter(|| DurableTree::build_from_iter(node_store.clone(), 0..1_000_000, NumComparator))
     }
 
@@ -797,10 +1369,7 @@ mod tests {
     fn bench_rebuild_with_novelty(b: &mut Bencher) {
         use super::super::backends::sqlite::SqliteStore;
         let store = Arc::new(SqliteStore::new("/tmp/cliodb_bench.db").unwrap());
-        let node_store: NodeStore<i64> = NodeStore {
-            cache: Arc::new(Mutex::new(LruCache::new(1024))),
-            store: store.clone()
-        };
+        let node_store: NodeStore<i64> = NodeStore::new(store.clone());
         let tree = DurableTree::build_from_iter(node_store.clone(), 0..1_000_000, NumComparator).unwrap();
         b.iter(|| tree.rebuild_with_novelty(500_000..510_000).unwrap())
     }
@@ -809,10 +1378,7 @@ mod tests {
     fn bench_rebuild_with_novelty_mostly_novelty(b: &mut Bencher) {
         use super::super::backends::sqlite::SqliteStore;
         let store = Arc::new(SqliteStore::new("/tmp/cliodb_bench.db").unwrap());
-        let node_store: NodeStore<i64> = NodeStore {
-            cache: Arc::new(Mutex::new(LruCache::new(1024))),
-            store: store.clone()
-        };
+        let node_store: NodeStore<i64> = NodeStore::new(store.clone());
         let tree = DurableTree::build_from_iter(node_store.clone(), 0..100_000, NumComparator).unwrap();
         b.iter(|| tree.rebuild_with_novelty(0..1_000_000).unwrap())
     }
@@ -0,0 +1,145 @@
+//! Encryption of the values of sensitive attributes.
+//!
+//! Attributes declared with `db:encrypted` have their values
+//! encrypted by the transactor before they reach the tx log or the
+//! indices, so they're unreadable to anyone with access to the
+//! backing store but not the key. A Db given a `Keyring` (see
+//! `Conn::set_keyring`) decrypts them again as they're read; without
+//! one, they're returned as the opaque strings that were stored.
+//!
+//! Encryption is deterministic: a value always encrypts to the same
+//! string, so an encrypted attribute can still be looked up by value
+//! (`(?e ssn "123-45-6789")`), and a retraction matches the assertion
+//! it retracts. The price is that someone reading the store can tell
+//! when two entities have the same value. Values are stored in order
+//! of their ciphertext, so range lookups over them don't work.
+
+use std::fmt;
+
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+use {Entity, Result, Value};
+
+/// Marks a stored value as encrypted.
+const PREFIX: &str = "cliodb:encrypted:";
+
+const NONCE_SIZE: usize = 12;
+
+/// The key for the encrypted attributes of a database. Each attribute
+/// is encrypted with its own key, derived from the master key and the
+/// attribute's entity, so one secret covers every attribute without
+/// any two sharing a key.
+#[derive(Clone)]
+pub struct Keyring {
+    master: [u8; 32],
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Keyring(..)")
+    }
+}
+
+impl Keyring {
+    pub fn new(master: [u8; 32]) -> Keyring {
+        Keyring { master }
+    }
+
+    /// Parses a master key written as 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Keyring> {
+        let bytes = from_hex(hex.trim())?;
+        if bytes.len() != 32 {
+            return Err(format!("an encryption key should be 32 bytes, not {}", bytes.len()).into());
+        }
+        let mut master = [0; 32];
+        master.copy_from_slice(&bytes);
+        Ok(Keyring::new(master))
+    }
+
+    /// The cipher key for `attribute`, and the key for deriving
+    /// nonces from its values.
+    fn keys(&self, attribute: Entity) -> ([u8; 32], [u8; 32]) {
+        let mut material = self.master.to_vec();
+        material.extend_from_slice(&attribute.0.to_le_bytes());
+        (
+            blake3::derive_key("cliodb 2024 attribute cipher key", &material),
+            blake3::derive_key("cliodb 2024 attribute nonce key", &material),
+        )
+    }
+
+    pub fn encrypt(&self, attribute: Entity, value: &Value) -> Result<Value> {
+        let (cipher_key, nonce_key) = self.keys(attribute);
+        let plaintext = rmp_serde::to_vec(value)?;
+        // The nonce is a MAC of the value, which makes the encryption
+        // deterministic without ever reusing a nonce for a different
+        // value.
+        let mac = blake3::keyed_hash(&nonce_key, &plaintext);
+        let nonce = &mac.as_bytes()[..NONCE_SIZE];
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&cipher_key))
+            .encrypt(Nonce::from_slice(nonce), plaintext.as_ref())
+            .map_err(|_| "couldn't encrypt value")?;
+
+        let mut encoded = PREFIX.to_string();
+        encoded.push_str(&to_hex(nonce));
+        encoded.push_str(&to_hex(&ciphertext));
+        Ok(Value::String(encoded))
+    }
+
+    pub fn decrypt(&self, attribute: Entity, value: &Value) -> Result<Value> {
+        let bytes = match *value {
+            Value::String(ref s) if s.starts_with(PREFIX) => from_hex(&s[PREFIX.len()..])?,
+            _ => return Err(format!("value {} of attribute {} isn't encrypted", value, attribute.0).into()),
+        };
+        if bytes.len() < NONCE_SIZE {
+            return Err(format!("encrypted value of attribute {} is truncated", attribute.0).into());
+        }
+
+        let (cipher_key, _) = self.keys(attribute);
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&cipher_key))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| format!("couldn't decrypt a value of attribute {}; is the key right?", attribute.0))?;
+        Ok(rmp_serde::from_read_ref(&plaintext)?)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err("invalid hex".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "invalid hex".into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_roundtrip() {
+        let keyring = Keyring::from_hex(&"07".repeat(32)).unwrap();
+        let value = Value::String("123-45-6789".into());
+
+        let encrypted = keyring.encrypt(Entity(40), &value).unwrap();
+        assert_ne!(encrypted, value);
+        assert!(!format!("{}", encrypted).contains("123"));
+        assert_eq!(keyring.decrypt(Entity(40), &encrypted).unwrap(), value);
+
+        // Deterministic per attribute, but each attribute has its own key.
+        assert_eq!(keyring.encrypt(Entity(40), &value).unwrap(), encrypted);
+        assert_ne!(keyring.encrypt(Entity(41), &value).unwrap(), encrypted);
+        assert!(keyring.decrypt(Entity(41), &encrypted).is_err());
+
+        let other = Keyring::new([8; 32]);
+        assert!(other.decrypt(Entity(40), &encrypted).is_err());
+        assert!(keyring.decrypt(Entity(40), &value).is_err());
+        assert!(Keyring::from_hex("abcd").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Exports query results and attribute snapshots as Apache Parquet
+//! files (via Arrow record batches), so they can be loaded into
+//! analytics tools like DuckDB or Spark directly.
+//!
+//! Values map onto Arrow types as follows: strings and idents are
+//! `Utf8`, refs and longs are `Int64`, booleans are `Boolean`,
+//! timestamps are microsecond `Timestamp`s in UTC, and geo points are
+//! WKT `POINT(lon lat)` strings. A column whose values have mixed
+//! types falls back to the `Utf8` display form of each value.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema as ArrowSchema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use im::HashMap;
+
+use {Entity, Ident, Relation, Result, Value};
+use db::Db;
+use schema::ValueType;
+use queries::query::{Clause, Term};
+
+/// Converts a query result into a record batch with one column per
+/// variable.
+pub fn relation_to_record_batch(relation: &Relation) -> Result<RecordBatch> {
+    let Relation(ref vars, ref tuples) = *relation;
+
+    let mut fields = vec![];
+    let mut columns = vec![];
+    for (idx, var) in vars.iter().enumerate() {
+        let values: Vec<Option<&Value>> = tuples.iter().map(|t| Some(&t[idx])).collect();
+        let (field, column) = build_column(&var.name, infer_type(&values), &values, false)?;
+        fields.push(field);
+        columns.push(column);
+    }
+
+    Ok(RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), columns)?)
+}
+
+/// Writes a query result as Parquet.
+pub fn write_relation<W: Write + Send>(relation: &Relation, writer: W) -> Result<()> {
+    write_batch(relation_to_record_batch(relation)?, writer)
+}
+
+/// Snapshots the given attributes as a record batch with an `entity`
+/// column and one nullable column per attribute, typed according to
+/// the schema. Every entity with a value for at least one of the
+/// attributes gets a row; an entity with several values for an
+/// attribute gets one row per value.
+pub fn attributes_to_record_batch(db: &Db, idents: &[&str]) -> Result<RecordBatch> {
+    let mut values_by_attribute: Vec<HashMap<Entity, Vec<Value>>> = vec![];
+    let mut entities: Vec<Entity> = vec![];
+
+    for ident in idents {
+        let clause = Clause::new(
+            Term::Unbound("e".into()),
+            Term::Bound(Ident::Name(ident.to_string())),
+            Term::Unbound("v".into()),
+        );
+        let mut values: HashMap<Entity, Vec<Value>> = HashMap::new();
+        for tuple in db.fetch(&clause)?.1 {
+            if let Value::Ref(e) = tuple[0] {
+                values.entry(e).or_default().push(tuple[1].clone());
+                entities.push(e);
+            }
+        }
+        values_by_attribute.push(values);
+    }
+
+    entities.sort();
+    entities.dedup();
+
+    // Expand each entity into the cartesian product of its values.
+    let mut rows: Vec<(Entity, Vec<Option<Value>>)> = vec![];
+    for entity in entities {
+        let mut entity_rows: Vec<Vec<Option<Value>>> = vec![vec![]];
+        for values in values_by_attribute.iter() {
+            let options: Vec<Option<Value>> = match values.get(&entity) {
+                Some(vs) => vs.iter().cloned().map(Some).collect(),
+                None => vec![None],
+            };
+            entity_rows = entity_rows.into_iter()
+                .flat_map(|row| options.iter().map(move |v| {
+                    let mut row = row.clone();
+                    row.push(v.clone());
+                    row
+                }))
+                .collect();
+        }
+        rows.extend(entity_rows.into_iter().map(|row| (entity, row)));
+    }
+
+    let entity_values: Vec<Value> = rows.iter().map(|(e, _)| Value::Ref(*e)).collect();
+    let entity_refs: Vec<Option<&Value>> = entity_values.iter().map(Some).collect();
+    let (entity_field, entity_column) = build_column("entity", DataType::Int64, &entity_refs, false)?;
+    let mut fields = vec![entity_field];
+    let mut columns = vec![entity_column];
+
+    for (idx, ident) in idents.iter().enumerate() {
+        let attr = db.schema.resolve(ident)
+            .ok_or_else(|| format!("invalid attribute: ident '{}' does not exist", ident))?;
+        let values: Vec<Option<&Value>> = rows.iter().map(|(_, row)| row[idx].as_ref()).collect();
+        let data_type = match db.schema.value_types.get(&attr) {
+            Some(value_type) => arrow_type(value_type),
+            None => infer_type(&values),
+        };
+        let (field, column) = build_column(ident, data_type, &values, true)?;
+        fields.push(field);
+        columns.push(column);
+    }
+
+    Ok(RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), columns)?)
+}
+
+/// Writes a snapshot of the given attributes as Parquet.
+pub fn write_attributes<W: Write + Send>(db: &Db, idents: &[&str], writer: W) -> Result<()> {
+    write_batch(attributes_to_record_batch(db, idents)?, writer)
+}
+
+fn write_batch<W: Write + Send>(batch: RecordBatch, writer: W) -> Result<()> {
+    let mut writer = ArrowWriter::try_new(writer, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+fn arrow_type(value_type: &ValueType) -> DataType {
+    match *value_type {
+        ValueType::String | ValueType::Ident | ValueType::Geo => DataType::Utf8,
+        ValueType::Ref | ValueType::Long => DataType::Int64,
+        ValueType::Boolean => DataType::Boolean,
+        ValueType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+    }
+}
+
+fn infer_type(values: &[Option<&Value>]) -> DataType {
+    let mut types = values.iter().filter_map(|v| v.map(|v| arrow_type(&v.value_type())));
+    match types.next() {
+        Some(first) => if types.all(|t| t == first) { first } else { DataType::Utf8 },
+        None => DataType::Utf8,
+    }
+}
+
+fn build_column(name: &str, data_type: DataType, values: &[Option<&Value>], nullable: bool) -> Result<(Field, ArrayRef)> {
+    let column: ArrayRef = match data_type {
+        DataType::Int64 => Arc::new(values.iter().map(|v| match v {
+            Some(Value::Ref(Entity(e))) => Some(*e),
+            Some(Value::Long(l)) => Some(*l),
+            _ => None,
+        }).collect::<Int64Array>()),
+        DataType::Boolean => Arc::new(values.iter().map(|v| match v {
+            Some(Value::Boolean(b)) => Some(*b),
+            _ => None,
+        }).collect::<BooleanArray>()),
+        DataType::Timestamp(_, _) => Arc::new(values.iter().map(|v| match v {
+            Some(Value::Timestamp(t)) => Some(t.timestamp_micros()),
+            _ => None,
+        }).collect::<TimestampMicrosecondArray>().with_timezone_utc()),
+        _ => Arc::new(values.iter().map(|v| v.map(|v| match *v {
+            Value::String(ref s) | Value::Ident(ref s) => s.clone(),
+            Value::Geo(ref g) => format!("POINT({} {})", g.lon(), g.lat()),
+            ref other => format!("{}", other),
+        })).collect::<StringArray>()),
+    };
+
+    Ok((Field::new(name, data_type, nullable), column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use queries::query::Var;
+
+    #[test]
+    fn test_relation_columns_are_typed() {
+        let relation = Relation(
+            vec![Var::new("e"), Var::new("name"), Var::new("mixed")],
+            vec![
+                vec![Value::Ref(Entity(1)), Value::String("Bob".into()), Value::Long(3)],
+                vec![Value::Ref(Entity(2)), Value::String("John".into()), Value::String("x".into())],
+            ],
+        );
+
+        let batch = relation_to_record_batch(&relation).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Int64);
+        assert_eq!(batch.schema().field(1).data_type(), &DataType::Utf8);
+        assert_eq!(batch.schema().field(2).data_type(), &DataType::Utf8);
+
+        let mut buf = vec![];
+        write_relation(&relation, &mut buf).unwrap();
+        assert_eq!(&buf[..4], b"PAR1");
+    }
+}
@@ -0,0 +1,132 @@
+//! Transaction functions, which run inside a transaction and compute
+//! some of its items from the db as the transaction has left it so
+//! far. A read-modify-write done with one, like incrementing a
+//! counter, is atomic, with no round trip to the client between the
+//! read and the write.
+//!
+//! A transaction calls a function with `TxItem::Function`, or
+//! `call (db:inc 11 visits 1)` in the tx syntax. `db:cas` and
+//! `db:inc` are built in, and others can be registered with
+//! `Transactor::add_function`.
+
+use std::sync::Arc;
+
+use im::HashMap;
+
+use db::Db;
+use schema::{Cardinality, ValueType};
+use {Entity, Fact, Result, TxItem, Value};
+
+/// Computes a transaction's items from the in-flight db and the
+/// call's arguments. Returning an error fails the transaction.
+pub type TxFunction = Arc<dyn Fn(&Db, &[Value]) -> Result<Vec<TxItem>> + Send + Sync>;
+
+/// The transaction functions a transactor can call, by name.
+#[derive(Clone)]
+pub struct TxFunctions {
+    functions: HashMap<String, TxFunction>,
+}
+
+impl Default for TxFunctions {
+    fn default() -> TxFunctions {
+        TxFunctions::new()
+    }
+}
+
+impl TxFunctions {
+    /// A registry of just the built-in functions.
+    pub fn new() -> TxFunctions {
+        let mut functions = TxFunctions { functions: HashMap::new() };
+        functions.register("db:cas", cas);
+        functions.register("db:inc", inc);
+        functions
+    }
+
+    /// Registers `function` as `name`, replacing any function already
+    /// registered with that name.
+    pub fn register<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&Db, &[Value]) -> Result<Vec<TxItem>> + Send + Sync + 'static,
+    {
+        self.functions.insert(name.to_string(), Arc::new(function));
+    }
+
+    pub fn call(&self, name: &str, db: &Db, args: &[Value]) -> Result<Vec<TxItem>> {
+        match self.functions.get(name) {
+            Some(function) => function(db, args),
+            None => Err(format!("unknown transaction function {}", name).into()),
+        }
+    }
+}
+
+/// `(db:cas e a old new)` sets `e`'s `a` to `new` if it's currently
+/// `old`, and fails the transaction if it isn't. `(db:cas e a new)`
+/// only sets it if `e` has no `a` yet.
+fn cas(db: &Db, args: &[Value]) -> Result<Vec<TxItem>> {
+    let usage = "db:cas takes an entity, an attribute, the expected value if any, and a new value";
+    let (entity, attribute, expected, new) = match args {
+        [e, Value::Ident(a), old, new] => (entity_arg(e).ok_or(usage)?, a, Some(old), new),
+        [e, Value::Ident(a), new] => (entity_arg(e).ok_or(usage)?, a, None, new),
+        _ => return Err(usage.into()),
+    };
+    let current = current_value(db, entity, attribute)?;
+    let expected = expected.and_then(|v| db.schema.resolve(attribute).map(|attr| db.schema.normalize(attr, value_arg(db, attr, v))));
+    let new = db.schema.resolve(attribute).map_or_else(|| new.clone(), |attr| value_arg(db, attr, new));
+    if current != expected {
+        let shown = |v: Option<Value>| v.map_or("nothing".to_string(), |v| v.to_string());
+        return Err(format!(
+            "db:cas failed: {} of {} is {}, not {}",
+            attribute, entity.0, shown(current), shown(expected),
+        ).into());
+    }
+    Ok(vec![TxItem::Addition(Fact::new(entity, attribute.as_str(), new))])
+}
+
+/// `(db:inc e a n)` adds `n` to `e`'s `a`, a long attribute, counting
+/// a missing value as 0.
+fn inc(db: &Db, args: &[Value]) -> Result<Vec<TxItem>> {
+    let usage = "db:inc takes an entity, an attribute and an amount";
+    let (entity, attribute, amount) = match args {
+        [e, Value::Ident(a), Value::Long(n)] => (entity_arg(e).ok_or(usage)?, a, *n),
+        _ => return Err(usage.into()),
+    };
+    let current = match current_value(db, entity, attribute)? {
+        Some(Value::Long(n)) => n,
+        None => 0,
+        Some(v) => return Err(format!("db:inc can't add to {} value {}", attribute, v).into()),
+    };
+    match current.checked_add(amount) {
+        Some(n) => Ok(vec![TxItem::Addition(Fact::new(entity, attribute.as_str(), Value::Long(n)))]),
+        None => Err(format!("db:inc overflowed {} of {}", attribute, entity.0).into()),
+    }
+}
+
+/// An entity given as an argument, by its id. Numbers in the tx
+/// syntax's calls are longs, so either will do.
+fn entity_arg(arg: &Value) -> Option<Entity> {
+    match *arg {
+        Value::Ref(e) => Some(e),
+        Value::Long(n) => Some(Entity(n)),
+        _ => None,
+    }
+}
+
+/// A value of `attr` given as an argument: a long given for a ref
+/// attribute is the id of the entity it refers to.
+fn value_arg(db: &Db, attr: Entity, arg: &Value) -> Value {
+    match *arg {
+        Value::Long(n) if db.schema.value_types.get(&attr) == Some(&ValueType::Ref) => Value::Ref(Entity(n)),
+        ref v => v.clone(),
+    }
+}
+
+/// The value of a cardinality-one attribute of `entity`, if it has
+/// one.
+fn current_value(db: &Db, entity: Entity, attribute: &str) -> Result<Option<Value>> {
+    match db.schema.resolve(attribute) {
+        Some(attr) if db.schema.cardinality(attr) == Cardinality::One => {}
+        Some(_) => return Err(format!("{} isn't a cardinality-one attribute", attribute).into()),
+        None => return Err(format!("invalid attribute: ident '{}' does not exist", attribute).into()),
+    }
+    Ok(db.entity(entity)?.get(attribute).and_then(|values| values.first().cloned()))
+}
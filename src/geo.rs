@@ -0,0 +1,158 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+/// Coordinates are stored as fixed-point integers (in units of 1e-7
+/// degrees, about a centimeter) so that geo values can be compared,
+/// hashed and sorted like every other value.
+const FIXED_POINT_SCALE: f64 = 10_000_000.0;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+const METERS_PER_DEGREE_LAT: f64 = 111_320.0;
+
+/// A point on the earth's surface.
+///
+/// The first field is the point's position along a z-order
+/// (geohash-style) space-filling curve, so the derived ordering
+/// sorts points which are close together near each other. This is
+/// what lets AVET range scans answer radius queries: every point
+/// within a geohash cell lies in one contiguous range of the index.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash)]
+pub struct GeoPoint {
+    cell: u64,
+    lat: i32,
+    lon: i32,
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> GeoPoint {
+        let lat = lat.clamp(-90.0, 90.0);
+        let lon = lon.clamp(-180.0, 180.0);
+        GeoPoint {
+            cell: interleave(quantize_lat(lat), quantize_lon(lon)),
+            lat: (lat * FIXED_POINT_SCALE).round() as i32,
+            lon: (lon * FIXED_POINT_SCALE).round() as i32,
+        }
+    }
+
+    pub fn lat(&self) -> f64 {
+        f64::from(self.lat) / FIXED_POINT_SCALE
+    }
+
+    pub fn lon(&self) -> f64 {
+        f64::from(self.lon) / FIXED_POINT_SCALE
+    }
+
+    /// The lowest-sorted point in the given curve position, for use
+    /// as the start of an index range.
+    pub fn cell_start(cell: u64) -> GeoPoint {
+        GeoPoint { cell, lat: i32::MIN, lon: i32::MIN }
+    }
+
+    pub fn cell(&self) -> u64 {
+        self.cell
+    }
+
+    /// Great-circle distance in meters (haversine formula).
+    pub fn distance_to(&self, other: &GeoPoint) -> f64 {
+        let (lat_a, lat_b) = (self.lat().to_radians(), other.lat().to_radians());
+        let d_lat = lat_b - lat_a;
+        let d_lon = (other.lon() - self.lon()).to_radians();
+
+        let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * h.sqrt().asin()
+    }
+}
+
+impl fmt::Display for GeoPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#geo({} {})", self.lat(), self.lon())
+    }
+}
+
+/// Returns inclusive ranges of curve positions which together
+/// contain every point within `radius` meters of `center`. The
+/// ranges can contain points outside the radius, so results have
+/// to be refined with `GeoPoint::distance_to`.
+///
+/// The search box doesn't wrap around the antimeridian; it is
+/// clamped to [-180, 180] instead.
+pub fn covering_ranges(center: &GeoPoint, radius: f64) -> Vec<(u64, u64)> {
+    let d_lat = radius / METERS_PER_DEGREE_LAT;
+    let d_lon = d_lat / center.lat().to_radians().cos().max(0.01);
+
+    let (min_lat, max_lat) = ((center.lat() - d_lat).max(-90.0), (center.lat() + d_lat).min(90.0));
+    let (min_lon, max_lon) = ((center.lon() - d_lon).max(-180.0), (center.lon() + d_lon).min(180.0));
+
+    // Pick the finest level at which a cell is at least as large as
+    // the search box, so the box overlaps at most 2x2 cells.
+    let mut bits = 0;
+    while bits < 32
+        && 180.0 / 2f64.powi(bits + 1) >= max_lat - min_lat
+        && 360.0 / 2f64.powi(bits + 1) >= max_lon - min_lon
+    {
+        bits += 1;
+    }
+
+    if bits == 0 {
+        return vec![(0, u64::MAX)];
+    }
+
+    let shift = 32 - bits as u32;
+    let (lat_lo, lat_hi) = (quantize_lat(min_lat) >> shift, quantize_lat(max_lat) >> shift);
+    let (lon_lo, lon_hi) = (quantize_lon(min_lon) >> shift, quantize_lon(max_lon) >> shift);
+
+    let mut ranges = vec![];
+    for lat in lat_lo..=lat_hi {
+        for lon in lon_lo..=lon_hi {
+            let start = interleave(lat << shift, lon << shift);
+            let end = start.saturating_add((1u64 << (2 * shift)) - 1);
+            ranges.push((start, end));
+        }
+    }
+    ranges.sort();
+    ranges
+}
+
+fn quantize_lat(lat: f64) -> u32 {
+    (((lat + 90.0) / 180.0) * f64::from(u32::MAX)) as u32
+}
+
+fn quantize_lon(lon: f64) -> u32 {
+    (((lon + 180.0) / 360.0) * f64::from(u32::MAX)) as u32
+}
+
+/// Interleaves the bits of the two coordinates, longitude first as
+/// in a geohash.
+fn interleave(lat: u32, lon: u32) -> u64 {
+    let mut out = 0u64;
+    for i in (0..32).rev() {
+        out = (out << 1) | u64::from((lon >> i) & 1);
+        out = (out << 1) | u64::from((lat >> i) & 1);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let nyc = GeoPoint::new(40.7128, -74.0060);
+        let boston = GeoPoint::new(42.3601, -71.0589);
+        let d = nyc.distance_to(&boston);
+        assert!(d > 300_000.0 && d < 310_000.0, "{}", d);
+    }
+
+    #[test]
+    fn test_covering_ranges_contain_nearby_points() {
+        let center = GeoPoint::new(40.7128, -74.0060);
+        let nearby = GeoPoint::new(40.7200, -74.0000);
+        let ranges = covering_ranges(&center, 2_000.0);
+
+        assert!(ranges.len() <= 4);
+        assert!(ranges.iter().any(|&(lo, hi)| lo <= nearby.cell() && nearby.cell() <= hi));
+        assert!(ranges.iter().any(|&(lo, hi)| lo <= center.cell() && center.cell() <= hi));
+    }
+}
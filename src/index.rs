@@ -6,13 +6,14 @@ use serde::Serialize;
 use serde::de::DeserializeOwned;
 use itertools::Itertools;
 
+use Result;
 use backends::KVStore;
-use durable_tree::{DurableTree};
-use rbtree::RBTree;
+use durable_tree::{DurableTree, ItemIter, RebuildProgress, Compactor};
+use rbtree::{self, RBTree};
 
-pub trait Comparator: Copy + Debug {
+pub trait Comparator: Clone + Debug {
     type Item;
-    fn compare(a: &Self::Item, b: &Self::Item) -> Ordering;
+    fn compare(&self, a: &Self::Item, b: &Self::Item) -> Ordering;
 }
 
 /// The Equivalent trait is used to deduplicate facts in the
@@ -23,26 +24,38 @@ pub trait Equivalent {
     fn equivalent(&self, other: &Self) -> bool;
 }
 
+/// Lets the durable index store an item's value apart from the item
+/// when it's large, so that a few documents don't bloat the segments
+/// holding everything else.
+pub trait LargeValue {
+    /// The size in bytes of the item's value.
+    fn value_size(&self) -> usize;
+    /// Takes out the item's value, serialized, leaving a placeholder.
+    fn take_value(&mut self) -> Result<Vec<u8>>;
+    /// Puts back a value taken out by `take_value`.
+    fn restore_value(&mut self, value: &[u8]) -> Result<()>;
+}
+
 #[derive(Clone)]
 pub struct Index<T, C>
 where
-    T: Equivalent + Debug + Ord + Clone,
+    T: Equivalent + LargeValue + Debug + Ord + Clone,
     C: Comparator<Item = T>,
 {
     mem_index: RBTree<T, C>,
-    _comparator: C,
+    comparator: C,
     durable_index: DurableTree<T, C>,
 }
 
 impl<T, C> Index<T, C>
 where
-    T: Equivalent + Debug + Ord + Clone + Serialize + DeserializeOwned,
-    C: Comparator<Item = T> + Copy,
+    T: Equivalent + LargeValue + Debug + Ord + Clone + Serialize + DeserializeOwned,
+    C: Comparator<Item = T>,
 {
     pub fn new(root_ref: String, store: Arc<dyn KVStore>, comparator: C) -> Index<T, C> {
         Index {
-            _comparator: comparator,
-            mem_index: RBTree::new(comparator),
+            comparator: comparator.clone(),
+            mem_index: RBTree::new(comparator.clone()),
             durable_index: DurableTree::from_ref(root_ref, store, comparator),
         }
     }
@@ -52,34 +65,74 @@ where
     }
 
     pub fn range_from(&self, range_start: T) -> impl Iterator<Item = T> {
-        self.mem_index.range_from(range_start.clone()).merge_by(
-            self.durable_index
-                .range_from(range_start)
+        self.merge(self.mem_index.range_from(range_start.clone()), self.durable_index.range_from(range_start))
+    }
+
+    /// Iterates from the first item `seek` doesn't order before the
+    /// range, as `RBTree::range_from_by` does. `seek` should never
+    /// return `Equal`, or the range could start partway through the
+    /// items it's equal to.
+    pub fn range_from_by<F: Fn(&T) -> Ordering>(&self, seek: F) -> impl Iterator<Item = T> {
+        self.merge(self.mem_index.range_from_by(&seek), self.durable_index.range_from_by(&seek))
+    }
+
+    fn merge(&self, mem_items: rbtree::Iter<T>, durable_items: Result<ItemIter<T>>) -> impl Iterator<Item = T> {
+        let comparator = self.comparator.clone();
+        mem_items.merge_by(
+            durable_items
                 // FIXME: handle all these errors
                 .unwrap()
                 .map(|r| r.unwrap())
                 // deduplicate equivalent facts which may be in both the in-memory and durable index
                 .coalesce(|x, y| { if x.equivalent(&y) { Ok(x) } else { Err((x, y))} }),
-            |a, b| C::compare(a, b) == Ordering::Less,
+            move |a, b| comparator.compare(a, b) == Ordering::Less,
         )
     }
 
+    /// The items from `range_start` on which are only in memory, not
+    /// yet in the durable index.
+    pub fn novelty_from(&self, range_start: T) -> impl Iterator<Item = T> {
+        self.mem_index.range_from(range_start)
+    }
+
+    /// Warms the cache with the top `levels` levels of the durable
+    /// index.
+    pub fn prefetch(&self, levels: usize) -> Result<usize> {
+        self.durable_index.prefetch(levels)
+    }
+
+    /// See `DurableTree::forget`.
+    pub fn forget(&self, keys: &[String]) {
+        self.durable_index.forget(keys)
+    }
+
     pub fn durable_root(&self) -> String {
         self.durable_index.root.clone()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = T> {
+        let comparator = self.comparator.clone();
         // FIXME: signature should allow returning Result instead of unwrapping
         self.mem_index.iter().merge_by(
             self.durable_index.iter().unwrap().map(
                 |r| r.unwrap(),
             ),
-            |a, b| {
-                C::compare(a, b) == Ordering::Less
+            move |a, b| {
+                comparator.compare(a, b) == Ordering::Less
             },
         )
     }
 
+    /// The index with items compared by `comparator`, which must
+    /// order the items already in it as the old comparator did.
+    pub fn with_comparator(&self, comparator: C) -> Index<T, C> {
+        Index {
+            mem_index: self.mem_index.with_comparator(comparator.clone()),
+            durable_index: self.durable_index.with_comparator(comparator.clone()),
+            comparator,
+        }
+    }
+
     pub fn insert(&self, item: T) -> Index<T, C> {
         Index {
             mem_index: self.mem_index.insert(item),
@@ -87,13 +140,29 @@ where
         }
     }
 
+    /// Returns an index of just the items `keep` accepts, all in a
+    /// newly built durable tree.
+    pub fn filter<F: Fn(&T) -> bool>(&self, keep: F) -> Result<Index<T, C>> {
+        Ok(Index {
+            durable_index: self.durable_index.rebuild_from(self.iter().filter(|item| keep(item)))?,
+            mem_index: RBTree::new(self.comparator.clone()),
+            ..self.clone()
+        })
+    }
+
     pub fn rebuild(&self) -> Index<T, C> {
+        self.rebuild_with_progress(&Arc::new(RebuildProgress::new(self.mem_index_size())), None)
+    }
+
+    pub fn rebuild_with_progress(&self, progress: &Arc<RebuildProgress>, compact: Option<&Compactor<T>>) -> Index<T, C> {
         // FIXME: return a Result to avoid unwrapping
         Index {
-            durable_index: self.durable_index.rebuild_with_novelty(
-                self.mem_index.iter()
+            durable_index: self.durable_index.rebuild_with_progress(
+                self.mem_index.iter(),
+                progress,
+                compact,
             ).expect("error rebuilding durable index"),
-            mem_index: RBTree::new(self._comparator),
+            mem_index: RBTree::new(self.comparator.clone()),
             ..self.clone()
         }
     }
@@ -109,7 +178,7 @@ pub struct NumComparator;
 impl Comparator for NumComparator {
     type Item = i64;
 
-    fn compare(a: &i64, b: &i64) -> Ordering {
+    fn compare(&self, a: &i64, b: &i64) -> Ordering {
         a.cmp(b)
     }
 }
@@ -121,6 +190,22 @@ impl Equivalent for i64 {
     }
 }
 
+#[cfg(test)]
+impl LargeValue for i64 {
+    fn value_size(&self) -> usize {
+        0
+    }
+
+    fn take_value(&mut self) -> Result<Vec<u8>> {
+        Ok(::rmp_serde::to_vec(&std::mem::replace(self, 0))?)
+    }
+
+    fn restore_value(&mut self, value: &[u8]) -> Result<()> {
+        *self = ::rmp_serde::from_read_ref(value)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
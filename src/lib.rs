@@ -1,9 +1,13 @@
 #![cfg_attr(test, feature(test))]
 
 extern crate itertools;
+extern crate blake3;
+extern crate chacha20poly1305;
 
 #[macro_use]
 extern crate combine;
+extern crate crc32fast;
+extern crate csv;
 
 extern crate prettytable as pt;
 extern crate chrono;
@@ -18,10 +22,19 @@ extern crate mysql;
 extern crate log;
 extern crate lru_cache;
 extern crate snap;
+extern crate toml;
+extern crate unicode_normalization;
 extern crate uuid;
 
 extern crate zmq;
 
+#[cfg(feature = "parquet-export")]
+extern crate arrow_array;
+#[cfg(feature = "parquet-export")]
+extern crate arrow_schema;
+#[cfg(feature = "parquet-export")]
+extern crate parquet;
+
 #[cfg(test)]
 #[macro_use]
 extern crate proptest;
@@ -33,6 +46,7 @@ use itertools::*;
 
 use std::fmt::{self, Display, Formatter};
 use im::HashMap;
+use std::collections::HashSet;
 use std::iter;
 use std::ops::RangeBounds;
 use std::result;
@@ -46,16 +60,54 @@ pub mod backends;
 pub mod tx;
 pub mod conn;
 pub mod server;
-mod schema;
+pub mod access;
+pub mod encryption;
+pub mod sandbox;
+pub mod schema;
+pub mod geo;
+pub mod checksum;
+pub mod sql;
+pub mod pgwire;
+pub mod csv_io;
+pub mod datoms;
+pub mod computed;
+pub mod functions;
+pub mod stats;
+pub mod analyze;
+pub mod lint;
+pub mod replication;
+pub mod reindex;
+pub mod backfill;
+pub mod verify;
+pub mod config;
+#[cfg(feature = "parquet-export")]
+pub mod export;
 mod queries;
 mod rbtree;
 mod durable_tree;
 
-pub use parser::{parse_input, parse_tx, parse_query, Input};
-use queries::query::{Clause, Term, Var};
-pub use queries::execution::query;
-use index::{Comparator, Equivalent};
+pub use durable_tree::set_node_cache_size;
+pub use parser::{parse_input, parse_tx, parse_query, parse_query_with, parse_entity_spec, Input};
+use queries::query::{Clause, Term};
+pub use queries::query::{Query, QueryInput, Var};
+pub use queries::builder::{self, QueryBuilder, var};
+pub use queries::execution::{query, query_with_cancel, query_with_inputs, query_iter, query_iter_with_cancel, query_count, CancelToken};
+
+/// Query plans, for tools which build or rewrite plans themselves
+/// (query UIs, external optimizers) and run them against a Db.
+///
+/// This API is unstable: `Plan` changes shape as the planner grows,
+/// and may do so in any release. Plans are run as given, so a plan
+/// built by hand gets none of the planner's checks.
+pub mod plan {
+    pub use queries::planner::Plan;
+    pub use queries::execution::{execute_plan, plan_query};
+    pub use queries::query::{Clause, Term, Constraint, Comparator, Within, Hints, Strategy};
+}
+use index::{Comparator, Equivalent, LargeValue};
 use backends::KVStore;
+use geo::GeoPoint;
+use schema::{Collation, Schema, ValueType};
 
 use std::collections::Bound;
 use chrono::prelude::{DateTime, Utc};
@@ -84,6 +136,25 @@ impl Equivalent for Record {
             self.retracted == other.retracted
     }
 }
+
+impl LargeValue for Record {
+    fn value_size(&self) -> usize {
+        match self.value {
+            Value::String(ref s) | Value::Ident(ref s) => s.len(),
+            _ => 0,
+        }
+    }
+
+    fn take_value(&mut self) -> Result<Vec<u8>> {
+        let value = std::mem::replace(&mut self.value, Value::Boolean(false));
+        Ok(rmp_serde::to_vec(&value)?)
+    }
+
+    fn restore_value(&mut self, value: &[u8]) -> Result<()> {
+        self.value = rmp_serde::from_read_ref(value)?;
+        Ok(())
+    }
+}
 // We need a struct to represent facts that may not be in the database
 // and may not have valid attributes, for use by the parser and
 // unifier.
@@ -104,6 +175,90 @@ impl Fact {
     }
 }
 
+/// An entity named by a value of one of its indexed attributes,
+/// written `[email "bob@x.com"]`, for clients which don't know the
+/// entity's id. The value must belong to exactly one entity (see
+/// `Db::lookup`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct LookupRef {
+    pub attribute: String,
+    pub value: Value,
+}
+
+impl LookupRef {
+    pub fn new<A: Into<String>, V: Into<Value>>(attribute: A, value: V) -> LookupRef {
+        LookupRef {
+            attribute: attribute.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl Display for LookupRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "[{} {}]", self.attribute, self.value)
+    }
+}
+
+/// The ways of naming an entity: by id, by its `db:ident`, or by a
+/// lookup ref. `Db::resolve` finds the entity any of them names;
+/// transactions, queries and the entity API all accept them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum EntitySpec {
+    Id(Entity),
+    Ident(String),
+    Lookup(LookupRef),
+}
+
+impl Display for EntitySpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            EntitySpec::Id(Entity(id)) => write!(f, "{}", id),
+            EntitySpec::Ident(ref ident) => write!(f, "{}", ident),
+            EntitySpec::Lookup(ref lookup) => write!(f, "{}", lookup),
+        }
+    }
+}
+
+impl From<Entity> for EntitySpec {
+    fn from(e: Entity) -> EntitySpec {
+        EntitySpec::Id(e)
+    }
+}
+
+impl From<&str> for EntitySpec {
+    fn from(ident: &str) -> EntitySpec {
+        EntitySpec::Ident(ident.to_string())
+    }
+}
+
+impl From<LookupRef> for EntitySpec {
+    fn from(lookup: LookupRef) -> EntitySpec {
+        EntitySpec::Lookup(lookup)
+    }
+}
+
+/// A fact whose entity may be given by ident or lookup ref, and its
+/// value of a ref attribute by lookup ref. The transactor resolves
+/// them against the db when it applies the transaction. The value must be a
+/// `TxValue::Value` or `TxValue::Lookup`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct LookupFact {
+    pub entity: EntitySpec,
+    pub attribute: String,
+    pub value: TxValue,
+}
+
+impl LookupFact {
+    pub fn new<E: Into<EntitySpec>, A: Into<String>, V: Into<TxValue>>(e: E, a: A, v: V) -> LookupFact {
+        LookupFact {
+            entity: e.into(),
+            attribute: a.into(),
+            value: v.into(),
+        }
+    }
+}
+
 impl Record {
     pub fn addition<V: Into<Value>>(e: Entity, a: Entity, v: V, tx: Entity) -> Record {
         Record {
@@ -145,6 +300,21 @@ pub enum Value {
     Timestamp(DateTime<Utc>),
     Boolean(bool),
     Long(i64),
+    Geo(GeoPoint),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match *self {
+            Value::String(_) => ValueType::String,
+            Value::Ident(_) => ValueType::Ident,
+            Value::Ref(_) => ValueType::Ref,
+            Value::Timestamp(_) => ValueType::Timestamp,
+            Value::Boolean(_) => ValueType::Boolean,
+            Value::Long(_) => ValueType::Long,
+            Value::Geo(_) => ValueType::Geo,
+        }
+    }
 }
 
 impl Display for Value {
@@ -159,6 +329,7 @@ impl Display for Value {
                 Value::Timestamp(t) => format!("{}", t),
                 Value::Boolean(b) => format!("{}", b),
                 Value::Long(l) => format!("{}", l),
+                Value::Geo(g) => format!("{}", g),
             }
         )
     }
@@ -188,31 +359,154 @@ pub enum Ident {
     Entity(Entity)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Error(String);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Error {
+    Message(String),
+    /// The blob stored under `key` failed its checksum or couldn't
+    /// be decoded.
+    Corruption { key: String },
+    /// The operation was stopped through its `CancelToken`.
+    Cancelled,
+    /// A write (or other request to the transactor) was made through
+    /// a connection opened with `Conn::open_read_only`.
+    ReadOnly,
+    /// A `compare_and_set` of `key` found that another writer had
+    /// changed it since it was read.
+    Conflict { key: String },
+}
+
+impl Error {
+    pub fn message(&self) -> String {
+        match *self {
+            Error::Message(ref msg) => msg.clone(),
+            Error::Corruption { ref key } => format!("stored data for {} is corrupt", key),
+            Error::Cancelled => "cancelled".to_string(),
+            Error::ReadOnly => "the connection is read-only".to_string(),
+            Error::Conflict { ref key } => format!("{} was changed by another writer", key),
+        }
+    }
+}
 
 impl<S: ToString> From<S> for Error {
     fn from(other: S) -> Error {
-        Error(other.to_string())
+        Error::Message(other.to_string())
     }
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Relation(pub Vec<Var>, pub Vec<Vec<Value>>);
 
 impl Display for Relation {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write_table(f, |val| format!("{}", val))
+    }
+}
+
+/// Formats a relation with refs to ident entities (attributes,
+/// enum values) rendered as their idents. See `Relation::with_idents`.
+pub struct ResolvedRelation<'a> {
+    relation: &'a Relation,
+    db: &'a db::Db,
+    label: Option<&'a str>,
+}
+
+impl<'a> ResolvedRelation<'a> {
+    /// Also renders refs to entities without an ident by their value
+    /// for `attribute` (e.g. `name`), when they have one.
+    pub fn with_label(self, attribute: &'a str) -> ResolvedRelation<'a> {
+        ResolvedRelation { label: Some(attribute), ..self }
+    }
+
+    fn label_for(&self, entity: Entity) -> Option<String> {
+        let attribute = self.label?;
+        self.db.schema.resolve(attribute)?;
+        let clause = Clause::new(Term::Bound(entity), Term::Bound(Ident::Name(attribute.into())), Term::Unbound("v".into()));
+        let Relation(_, tuples) = self.db.fetch(&clause).ok()?;
+        tuples.into_iter().next().map(|tuple| match tuple[0] {
+            Value::String(ref s) => s.clone(),
+            ref v => format!("{}", v),
+        })
+    }
+}
+
+impl<'a> Display for ResolvedRelation<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.relation.write_table(f, |val| match *val {
+            Value::Ref(e) => match self.db.ident_for(e) {
+                Some(ident) => ident.to_string(),
+                None => self.label_for(e).unwrap_or_else(|| format!("{}", val)),
+            },
+            _ => format!("{}", val),
+        })
+    }
+}
+
+impl Relation {
+    pub fn with_idents<'a>(&'a self, db: &'a db::Db) -> ResolvedRelation<'a> {
+        ResolvedRelation { relation: self, db, label: None }
+    }
+
+    /// Joins two relations on the vars they share, as the clauses of
+    /// a query are joined. The result has this relation's vars, then
+    /// the other's that aren't among them. Relations with no vars in
+    /// common join into their cartesian product. Large joins spill
+    /// to temporary files, which can fail.
+    pub fn join(self, other: Relation) -> Result<Relation> {
+        queries::execution::join(self, other)
+    }
+
+    /// The rows in either relation, without duplicates. Like the
+    /// other set operations, it needs both relations to have the same
+    /// vars, though not in the same order; the result's columns are
+    /// in this relation's order.
+    pub fn union(self, other: Relation) -> Result<Relation> {
+        let other = other.aligned_with(&self.0)?;
+        let rows = self.1.into_iter().chain(other).unique().collect();
+        Ok(Relation(self.0, rows))
+    }
+
+    /// The rows in both relations, without duplicates.
+    pub fn intersect(self, other: Relation) -> Result<Relation> {
+        let other: HashSet<Vec<Value>> = other.aligned_with(&self.0)?.into_iter().collect();
+        let rows = self.1.into_iter().filter(|row| other.contains(row)).unique().collect();
+        Ok(Relation(self.0, rows))
+    }
+
+    /// The rows in this relation but not the other, without
+    /// duplicates.
+    pub fn difference(self, other: Relation) -> Result<Relation> {
+        let other: HashSet<Vec<Value>> = other.aligned_with(&self.0)?.into_iter().collect();
+        let rows = self.1.into_iter().filter(|row| !other.contains(row)).unique().collect();
+        Ok(Relation(self.0, rows))
+    }
+
+    /// This relation's rows with their columns reordered to match
+    /// `vars`, which must be the same set of vars.
+    fn aligned_with(self, vars: &[Var]) -> Result<Vec<Vec<Value>>> {
+        let positions = vars
+            .iter()
+            .map(|var| self.0.iter().position(|v| v == var))
+            .collect::<Option<Vec<usize>>>();
+        match positions {
+            Some(ref positions) if positions.len() == self.0.len() => {
+                Ok(self.1.into_iter().map(|row| positions.iter().map(|&i| row[i].clone()).collect()).collect())
+            }
+            _ => Err(format!("relations have different vars: {:?} and {:?}", vars, self.0).into()),
+        }
+    }
+
+    fn write_table<F: Fn(&Value) -> String>(&self, f: &mut Formatter, render: F) -> fmt::Result {
         let num_columns = self.0.len();
         let align = pt::format::Alignment::CENTER;
-        let mut titles: pt::row::Row = self.0.iter().map(|var| var.name.clone()).collect();
+        let mut titles: pt::Row = self.0.iter().map(|var| var.name.clone()).collect();
         titles.iter_mut().foreach(|c| c.align(align));
 
         let rows = self.1
             .iter()
             .map(|row| {
-                row.iter().map(|val| format!("{}", val)).into()
+                row.iter().map(&render).into()
             })
             .collect_vec();
 
@@ -242,21 +536,114 @@ impl Display for Relation {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Tx {
     pub items: Vec<TxItem>,
+    /// A client-chosen key identifying this transaction. If a
+    /// transaction with the same key has already been committed,
+    /// the transactor returns the original report instead of
+    /// applying it again, so clients can safely retry.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Asks for the datoms the transaction added and retracted to be
+    /// included in its report.
+    #[serde(default)]
+    pub return_datoms: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum TxItem {
     Addition(Fact),
     Retraction(Fact),
-    NewEntity(HashMap<String, Value>),
+    NewEntity(HashMap<String, TxValue>),
+    /// An addition naming entities by lookup ref, e.g.
+    /// `add ([email "bob@x.com"] nickname "Bob")`.
+    LookupAddition(LookupFact),
+    LookupRetraction(LookupFact),
+    /// A call to a transaction function, e.g. `(db:inc 11 visits 1)`,
+    /// which the transactor replaces with the items it returns. See
+    /// the `functions` module.
+    Function(String, Vec<Value>),
+}
+
+/// A value in a `TxItem::NewEntity` map. Ref attributes can be given
+/// a nested entity map instead of an entity id, and the transactor
+/// creates the nested entity along with its parent, so documents
+/// don't have to be flattened by hand.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxValue {
+    Value(Value),
+    Entity(HashMap<String, TxValue>),
+    /// Several values for a cardinality-many attribute, e.g. tags
+    /// or a list of nested entities, each asserted separately.
+    Many(Vec<TxValue>),
+    /// An existing entity, for a ref attribute, named by a lookup
+    /// ref.
+    Lookup(LookupRef),
+}
+
+impl<T: Into<Value>> From<T> for TxValue {
+    fn from(v: T) -> TxValue {
+        TxValue::Value(v.into())
+    }
+}
+
+impl TxItem {
+    /// Soft-deletes an entity by asserting `db:deletedAt`. Its facts
+    /// stay in the database, but `(active ?e)` clauses exclude it.
+    pub fn soft_delete(entity: Entity) -> TxItem {
+        TxItem::Addition(Fact::new(entity, "db:deletedAt", Value::Timestamp(Utc::now())))
+    }
+
+    /// Undoes a soft delete, given the `db:deletedAt` value which
+    /// was asserted.
+    pub fn restore(entity: Entity, deleted_at: DateTime<Utc>) -> TxItem {
+        TxItem::Retraction(Fact::new(entity, "db:deletedAt", Value::Timestamp(deleted_at)))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum TxReport {
-    Success { new_entities: Vec<Entity> },
+    /// `tx` is the entity of the committed transaction, which is
+    /// also its id in the tx log.
+    ///
+    /// `reindex` is set when the transaction was slowed down because
+    /// an index rebuild is running behind.
+    ///
+    /// `timestamp` is the transaction's `db:txTimestamp` (None only
+    /// from transactors which predate it), and `datoms` holds every
+    /// record it added or retracted when the Tx set `return_datoms`,
+    /// so clients can update caches or audit logs without querying.
+    ///
+    /// `schema_changed` is set when the transaction changed the
+    /// schema, so that Dbs read before it have a stale `Schema` (see
+    /// `Conn::refresh_schema`).
+    Success {
+        tx: Entity,
+        new_entities: Vec<Entity>,
+        #[serde(default)]
+        reindex: Option<ReindexStatus>,
+        #[serde(default)]
+        timestamp: Option<DateTime<Utc>>,
+        #[serde(default)]
+        datoms: Option<Vec<Record>>,
+        #[serde(default)]
+        schema_changed: bool,
+    },
     Failure(String),
 }
 
+/// A snapshot of the progress of an index rebuild.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ReindexStatus {
+    /// In-memory records merged into the durable indices so far,
+    /// out of `records_total`.
+    pub records_processed: usize,
+    pub records_total: usize,
+    pub segments_written: usize,
+    pub elapsed_ms: u64,
+    /// Estimated time until the rebuild finishes, once there's
+    /// enough progress to extrapolate from.
+    pub eta_ms: Option<u64>,
+}
+
 type Binding = HashMap<Var, Value>;
 
 macro_rules! comparator {
@@ -267,7 +654,7 @@ macro_rules! comparator {
         impl Comparator for $name {
             type Item = Record;
 
-            fn compare(a: &Record, b: &Record) -> std::cmp::Ordering {
+            fn compare(&self, a: &Record, b: &Record) -> std::cmp::Ordering {
                 a.$first.cmp(&b.$first)
                     .then(a.$second.cmp(&b.$second))
                     .then(a.$third.cmp(&b.$third))
@@ -279,10 +666,55 @@ macro_rules! comparator {
     }
 }
 
+/// Builds a query from a template with `{}` placeholders, which are
+/// filled in with the arguments as typed values (see
+/// `parse_query_with`), so user input can't inject clauses:
+///
+/// ```ignore
+/// let q = query!("find ?e where (?e email {})", user_input)?;
+/// ```
+#[macro_export]
+macro_rules! query {
+    ($template:expr $(, $param:expr)* $(,)*) => {
+        $crate::parse_query_with($template, &[$($crate::Value::from($param)),*])
+    };
+}
+
 comparator!(EAVT, entity, attribute, value, tx);
 comparator!(AEVT, attribute, entity, value, tx);
-comparator!(AVET, attribute, value, entity, tx);
 comparator!(VAET, value, attribute, entity, tx);
+// The log index, for scanning transactions.
+comparator!(TEAV, tx, entity, attribute, value);
+
+/// Orders records by attribute, value, entity and tx, like the other
+/// comparators, except that attributes with a collation have their
+/// values compared by it. Values the collation considers equal are
+/// ordered byte-wise, so each value still has one place in the index.
+#[derive(Debug, Clone, Default)]
+pub struct AVET {
+    collations: HashMap<Entity, Collation>,
+}
+
+impl AVET {
+    pub fn new(schema: &Schema) -> AVET {
+        AVET { collations: schema.collations.clone() }
+    }
+}
+
+impl Comparator for AVET {
+    type Item = Record;
+
+    fn compare(&self, a: &Record, b: &Record) -> std::cmp::Ordering {
+        a.attribute.cmp(&b.attribute)
+            .then_with(|| match self.collations.get(&a.attribute) {
+                Some(collation) => collation.compare(&a.value, &b.value).then_with(|| a.value.cmp(&b.value)),
+                None => a.value.cmp(&b.value),
+            })
+            .then(a.entity.cmp(&b.entity))
+            .then(a.tx.cmp(&b.tx))
+            .then(a.retracted.cmp(&b.retracted))
+    }
+}
 
 
 
@@ -299,6 +731,12 @@ pub mod tests {
     use queries::query::Query;
     use queries::execution::query;
     use server::TransactorService;
+    use tx::{Transactor, TxHandle, TxRaw, SYSTEM_ENTITIES};
+    use std::thread;
+    use std::time::Duration;
+    use schema::ValueType;
+    use std::sync::{Arc, Mutex};
+    use backends::KVStore;
 
     // FIXME: conn should just have a way to run a local transactor
     macro_rules! with_test_conn {
@@ -358,6 +796,8 @@ pub mod tests {
                 .iter()
                 .map(|x| TxItem::Addition(x.clone()))
                 .collect(),
+            idempotency_key: None,
+            return_datoms: false,
         }).map(|tx_result| {
                 match tx_result {
                     TxReport::Success { .. } => (),
@@ -467,6 +907,20 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_query_cartesian_product() {
+        expect_query_result(
+            parse_query("find ?a ?n where (?a name \"Bob\") (?b name ?n) order by ?n").unwrap(),
+            Relation(
+                vec![Var::new("a"), Var::new("n")],
+                vec![
+                    vec![Value::Ref(Entity(11)), Value::String("Bob".into())],
+                    vec![Value::Ref(Entity(11)), Value::String("John".into())],
+                ],
+            ),
+        );
+    }
+
     #[test]
     fn test_type_mismatch() {
         with_test_conn!(conn {
@@ -492,6 +946,1909 @@ pub mod tests {
         })
     }
 
+    #[test]
+    fn test_access_policy() {
+        use access::AccessPolicy;
+
+        with_test_conn!(conn {
+            conn.transact(parse_tx("{db:ident secret:code db:valueType db:type:string}").unwrap())
+                .unwrap();
+            conn.transact(parse_tx("add (12 secret:code \"1234\")").unwrap())
+                .unwrap();
+            conn.set_access_policy(
+                AccessPolicy::new()
+                    .deny_namespace("secret")
+                    .deny_entities(|e| e == Entity(11))
+            );
+            let db = conn.db().unwrap();
+
+            let names = query(parse_query("find ?a ?b where (?a name ?b)").unwrap(), &db).unwrap();
+            assert_eq!(names.1, vec![vec![Value::Ref(Entity(12)), Value::String("John".into())]]);
+
+            let parents = query(parse_query("find ?a ?b where (?a parent ?b)").unwrap(), &db).unwrap();
+            assert_eq!(parents.1, Vec::<Vec<Value>>::new());
+
+            let secrets = query(parse_query("find ?v where (12 ?a ?v)").unwrap(), &db).unwrap();
+            assert!(!secrets.1.contains(&vec![Value::String("1234".into())]));
+        })
+    }
+
+    #[test]
+    fn test_admin_log() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let schema_changes = query(
+                parse_query("find ?attr where (?tx db:admin:operation db:admin:schemaChange) (?tx db:admin:attribute ?attr)").unwrap(),
+                &db
+            ).unwrap();
+
+            for ident in &["name", "parent", "Hello"] {
+                let attr = *db.schema.idents.get(*ident).unwrap();
+                assert!(schema_changes.1.contains(&vec![Value::Ref(attr)]));
+            }
+
+            let reindexes = query(
+                parse_query("find ?tx where (?tx db:admin:operation db:admin:reindex)").unwrap(),
+                &db
+            ).unwrap();
+            assert_eq!(reindexes.1.len(), 1);
+
+            // Each admin op is recorded on its own transaction's
+            // entity, with a detail.
+            let ids = conn.allocate_ids(10).unwrap();
+            let db = conn.db().unwrap();
+            let q = "find ?tx ?detail where (?tx db:admin:operation {}) (?tx db:admin:detail ?detail) (?tx db:txTimestamp ?t)";
+            for op in &["db:admin:reindex", "db:admin:writerEpoch", "db:admin:allocateIds"] {
+                let ops = query(parse_query_with(q, &[Value::Ident(op.to_string())]).unwrap(), &db).unwrap();
+                assert_eq!(ops.1.len(), 1, "{}", op);
+            }
+            let allocated = query(parse_query_with(q, &[Value::Ident("db:admin:allocateIds".into())]).unwrap(), &db).unwrap();
+            assert_eq!(allocated.1[0][1], Value::String(format!("allocated ids {}..{}", ids.start, ids.end)));
+        })
+    }
+
+    #[test]
+    fn test_excise_and_collect_garbage() {
+        use std::sync::Arc;
+        use std::thread;
+        use backends::sqlite::SqliteStore;
+        use db::Db;
+        use tx::{Transactor, TxHandle};
+
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let handle = TxHandle::new(&transactor);
+        let join_handle = thread::spawn(move || transactor.run());
+
+        handle.transact(parse_tx("{db:ident name db:valueType db:type:string}").unwrap()).unwrap();
+        let bob = match handle.transact(parse_tx("{name \"Bob\"} {name \"John\"}").unwrap()).unwrap() {
+            TxReport::Success { new_entities, .. } => new_entities[0],
+            TxReport::Failure(msg) => panic!("{}", msg),
+        };
+
+        assert_eq!(handle.excise(bob).unwrap(), 1);
+        // The bootstrap indices and the ones from before the excision
+        // are garbage; the second run finds none left.
+        assert!(handle.collect_garbage().unwrap() > 0);
+        assert_eq!(handle.collect_garbage().unwrap(), 0);
+
+        // The remaining nodes still hold the whole db, and the log
+        // past the rebuilt indices has the admin ops.
+        let metadata = store.get_metadata().unwrap();
+        let mut db = Db::new(metadata.clone(), store.clone());
+        for tx in store.get_txs(metadata.indexed_seq()).unwrap() {
+            for record in tx.records {
+                db = db.add_record(record).unwrap();
+            }
+        }
+        assert_eq!(db.eav.iter().count(), db.aev.iter().count());
+        assert_eq!(db.eav.iter().count(), db.ave.iter().count());
+        assert_eq!(db.eav.iter().count(), db.tea.iter().count());
+        assert!(db.tea.iter().all(|rec| rec.entity != bob));
+
+        let names = query(parse_query("find ?name where (?e name ?name)").unwrap(), &db).unwrap();
+        assert_eq!(names.1, vec![vec![Value::String("John".into())]]);
+
+        let ops = query(
+            parse_query("find ?op where (?tx db:admin:operation ?op)").unwrap(),
+            &db
+        ).unwrap();
+        for op in &["db:admin:excise", "db:admin:gc"] {
+            assert!(ops.1.contains(&vec![Value::Ident(op.to_string())]), "{}", op);
+        }
+
+        handle.close().unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_reindex_on_request() {
+        with_test_conn!(conn {
+            assert!(conn.reindex().unwrap());
+
+            let q = "find ?tx where (?tx db:admin:operation db:admin:reindex)";
+            let mut waited = 0;
+            while conn.q(q).unwrap().1.len() < 2 {
+                assert!(waited < 1000, "the requested reindex didn't finish");
+                thread::sleep(Duration::from_millis(10));
+                waited += 1;
+            }
+        })
+    }
+
+    #[test]
+    fn test_attribute_docs() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx(
+                r#"{db:ident email db:valueType db:type:string db:doc "Primary contact address"}"#
+            ).unwrap()).unwrap();
+            let email = *conn.db().unwrap().schema.idents.get("email").unwrap();
+            conn.transact(Tx {
+                items: vec![TxItem::Addition(Fact::new(email, "Hello", "pii"))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+
+            let db = conn.db().unwrap();
+            let info = db.attribute_info("email").unwrap();
+            assert_eq!(info.value_type, Some(ValueType::String));
+            assert_eq!(info.doc, Some("Primary contact address".into()));
+            assert_eq!(info.metadata, vec![("Hello".into(), Value::String("pii".into()))]);
+            assert!(db.attributes().unwrap().contains(&info));
+
+            let doc_tx = |item: TxItem| Tx { items: vec![item], idempotency_key: None, return_datoms: false };
+            conn.transact(doc_tx(TxItem::Addition(Fact::new(email, "db:doc", "Contact address")))).unwrap();
+            conn.transact(doc_tx(TxItem::Retraction(Fact::new(email, "db:doc", "Primary contact address")))).unwrap();
+            assert_eq!(conn.db().unwrap().attribute_info("email").unwrap().doc, Some("Contact address".into()));
+
+            conn.transact(doc_tx(TxItem::Retraction(Fact::new(email, "db:doc", "Contact address")))).unwrap();
+            assert_eq!(conn.db().unwrap().attribute_info("email").unwrap().doc, None);
+        })
+    }
+
+    #[test]
+    fn test_indexed_attribute() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx("{db:ident email db:valueType db:type:string}").unwrap()).unwrap();
+            let db = conn.db().unwrap();
+            let email = *db.schema.idents.get("email").unwrap();
+            let indexed = *db.schema.idents.get("db:indexed").unwrap();
+            let set_indexed = |item: fn(Fact) -> TxItem| Tx {
+                items: vec![item(Fact::new(email, "db:indexed", Value::Boolean(true)))],
+                idempotency_key: None,
+                return_datoms: false,
+            };
+
+            conn.transact(set_indexed(TxItem::Addition)).unwrap();
+            let db = conn.db().unwrap();
+            assert!(db.schema.is_indexed(email));
+            assert!(!db.schema.is_indexed(indexed));
+
+            conn.transact(set_indexed(TxItem::Retraction)).unwrap();
+            assert!(!conn.db().unwrap().schema.is_indexed(email));
+        })
+    }
+
+    #[test]
+    fn test_attribute_alias() {
+        with_test_conn!(conn {
+            // Rename `name`, keeping the old name as an alias.
+            let name = *conn.db().unwrap().schema.idents.get("name").unwrap();
+            let Entity(e) = name;
+            conn.tx(&format!(
+                "retract ({} db:ident name) add ({} db:ident person:name) add ({} db:alias name)", e, e, e
+            )[..]).unwrap();
+
+            // Both names work in queries and transactions.
+            conn.tx(r#"add (20 name "Ann") add (20 parent 11)"#).unwrap();
+            for attribute in &["name", "person:name"] {
+                let q = format!("find ?n where (?c parent 11) (?c {} ?n)", attribute);
+                let mut names = conn.q(&q[..]).unwrap().1;
+                names.sort();
+                assert_eq!(names, vec![vec![Value::String("Ann".into())], vec![Value::String("John".into())]]);
+            }
+
+            // Output uses the new name.
+            let db = conn.db().unwrap();
+            let info = db.attribute_info("name").unwrap();
+            assert_eq!((info.entity, &info.ident[..]), (name, "person:name"));
+            assert_eq!(info.aliases, vec!["name".to_string()]);
+            assert!(db.entity(Entity(20)).unwrap().contains_key("person:name"));
+
+            // An alias can't take a name that's in use.
+            assert!(conn.tx(&format!("add ({} db:alias parent)", e)[..]).is_err());
+
+            // Once the alias is retracted, the old name is gone.
+            conn.tx(&format!("retract ({} db:alias name)", e)[..]).unwrap();
+            assert!(conn.tx(r#"add (21 name "Cy")"#).is_err());
+            assert!(conn.db().unwrap().attribute_info("person:name").unwrap().aliases.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_allowed_values() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx(
+                "{db:ident color db:valueType db:type:ident db:allowedValue color:red} \
+                 {db:ident color:red} {db:ident color:blue}"
+            ).unwrap()).unwrap();
+            let color = *conn.db().unwrap().schema.idents.get("color").unwrap();
+            conn.transact(Tx {
+                items: vec![TxItem::Addition(Fact::new(color, "db:allowedValue", Value::Ident("color:blue".into())))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+
+            conn.transact(parse_tx("add (11 color color:red)").unwrap()).unwrap();
+            match conn.transact(parse_tx("add (12 color color:green)").unwrap()).unwrap() {
+                TxReport::Failure(msg) => assert!(msg.contains("allowed values are color:blue, color:red"), "{}", msg),
+                report => panic!("expected failure, got {:?}", report),
+            }
+
+            let db = conn.db().unwrap();
+            assert_eq!(db.attribute_info("color").unwrap().allowed_values, vec!["color:blue", "color:red"]);
+        })
+    }
+
+    #[test]
+    fn test_normalizers() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx(
+                "{db:ident email db:valueType db:type:string db:normalize db:normalize:trim} \
+                 {db:ident phone db:valueType db:type:string db:normalize db:normalize:phone}"
+            ).unwrap()).unwrap();
+            let email = *conn.db().unwrap().schema.idents.get("email").unwrap();
+            conn.transact(Tx {
+                items: vec![TxItem::Addition(Fact::new(email, "db:normalize", Value::Ident("db:normalize:lowercase".into())))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+
+            conn.transact(parse_tx(r#"add (11 email " Bob@Example.COM ") add (11 phone "+1 (555) 010-9999")"#).unwrap()).unwrap();
+            let result = query(
+                parse_query(r#"find ?e ?p where (?e email "bob@example.com") (?e phone ?p)"#).unwrap(),
+                &conn.db().unwrap()
+            ).unwrap();
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(11)), Value::String("+15550109999".into())]]);
+        })
+    }
+
+    #[test]
+    fn test_nested_entity_maps() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx(
+                "{db:ident address db:valueType db:type:ref} \
+                 {db:ident street db:valueType db:type:string} \
+                 {db:ident city db:valueType db:type:string}"
+            ).unwrap()).unwrap();
+
+            let report = conn.transact(parse_tx(
+                r#"{name "Bob" address [{street "1 Main St" city "Springfield"} {city "Shelbyville"}]}"#
+            ).unwrap()).unwrap();
+            match report {
+                TxReport::Success { ref new_entities, .. } => assert_eq!(new_entities.len(), 3),
+                report => panic!("expected success, got {:?}", report),
+            }
+
+            let mut result = query(
+                parse_query(r#"find ?c where (?p name "Bob") (?p address ?a) (?a city ?c)"#).unwrap(),
+                &conn.db().unwrap()
+            ).unwrap().1;
+            result.sort();
+            assert_eq!(result, vec![
+                vec![Value::String("Shelbyville".into())],
+                vec![Value::String("Springfield".into())],
+            ]);
+
+            match conn.transact(parse_tx(r#"{name {city "Nowhere"}}"#).unwrap()).unwrap() {
+                TxReport::Failure(msg) => assert!(msg.contains("non-ref attribute name"), "{}", msg),
+                report => panic!("expected failure, got {:?}", report),
+            }
+        })
+    }
+
+    #[test]
+    fn test_vector_values() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx("{db:ident tag db:valueType db:type:string}").unwrap()).unwrap();
+            let bob = match conn.transact(parse_tx(r#"{name "Bob" tag ["c" "a" "b"]}"#).unwrap()).unwrap() {
+                TxReport::Success { new_entities, .. } => new_entities[0],
+                report => panic!("expected success, got {:?}", report),
+            };
+            conn.transact(Tx {
+                items: vec![TxItem::Retraction(Fact::new(bob, "tag", "b"))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+
+            let bob = conn.db().unwrap().entity(bob).unwrap();
+            assert_eq!(bob.get("tag"), Some(&vec![Value::String("a".into()), Value::String("c".into())]));
+            assert_eq!(bob.get("name"), Some(&vec![Value::String("Bob".into())]));
+        })
+    }
+
+    #[test]
+    fn test_graph_traversal() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx("{db:ident follows db:valueType db:type:ref}").unwrap()).unwrap();
+            conn.transact(parse_tx(
+                "add (11 follows 12) add (12 follows 13) add (13 follows 14) \
+                 add (11 follows 15) add (15 follows 14) add (14 follows 11) add (16 follows 11)"
+            ).unwrap()).unwrap();
+            let db = conn.db().unwrap();
+            let e = |ids: &[i64]| ids.iter().map(|id| Entity(*id)).collect::<Vec<_>>();
+
+            assert_eq!(db.reachable(Entity(11), "follows", 1).unwrap(), e(&[12, 15]));
+            assert_eq!(db.reachable(Entity(11), "follows", 5).unwrap(), e(&[12, 15, 13, 14]));
+
+            assert_eq!(db.shortest_path(Entity(11), Entity(14), "follows").unwrap(), Some(e(&[11, 15, 14])));
+            assert_eq!(db.shortest_path(Entity(12), Entity(15), "follows").unwrap(), Some(e(&[12, 13, 14, 11, 15])));
+            assert_eq!(db.shortest_path(Entity(11), Entity(16), "follows").unwrap(), None);
+            assert!(db.shortest_path(Entity(11), Entity(14), "name").is_err());
+        })
+    }
+
+    #[test]
+    fn test_ident_for() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let name = *db.schema.idents.get("name").unwrap();
+            assert_eq!(db.ident_for(name), Some("name"));
+            assert_eq!(db.ident_for(Entity(1_000_000)), None);
+
+            let result = query(parse_query("find ?a where (11 ?a \"Bob\")").unwrap(), &db).unwrap();
+            assert!(format!("{}", result.with_idents(&db)).contains(" name "));
+            assert!(!format!("{}", result).contains(" name "));
+
+            conn.transact(parse_tx("add (1000000 name \"Al\") add (1000001 parent 1000000)").unwrap()).unwrap();
+            let db = conn.db().unwrap();
+            let result = query(parse_query("find ?p where (1000001 parent ?p)").unwrap(), &db).unwrap();
+            assert!(!format!("{}", result.with_idents(&db)).contains(" Al "));
+            assert!(format!("{}", result.with_idents(&db).with_label("name")).contains(" Al "));
+        })
+    }
+
+    #[test]
+    fn test_csv_load() {
+        with_test_conn!(conn {
+            let csv = "name,parent\nAnn,11\n\"Smith, Joe\",\n";
+            let tx = csv_io::read_entities(&conn.db().unwrap(), &["name".into(), "parent".into()], csv.as_bytes()).unwrap();
+            assert_eq!(tx.items.len(), 2);
+            conn.transact(tx).unwrap();
+
+            let mut result = query(
+                parse_query("find ?n where (?p name ?n) (?p parent 11)").unwrap(),
+                &conn.db().unwrap()
+            ).unwrap().1;
+            result.sort();
+            assert_eq!(result, vec![vec![Value::String("Ann".into())], vec![Value::String("John".into())]]);
+            assert!(csv_io::read_entities(&conn.db().unwrap(), &["name".into(), "parent".into()], "a,b\nAnn,x\n".as_bytes()).is_err());
+        })
+    }
+
+    #[test]
+    fn test_cancelled_query() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let q = || parse_query("find ?n where (?p name ?n)").unwrap();
+            let cancel = CancelToken::new();
+            assert_eq!(query_with_cancel(q(), &db, &cancel).unwrap().1.len(), 2);
+
+            cancel.cancel();
+            match query_with_cancel(q(), &db, &cancel) {
+                Err(Error::Cancelled) => {}
+                other => panic!("expected cancellation, got {:?}", other),
+            }
+            let mut rows = query_iter_with_cancel(q(), &db, &cancel);
+            match rows.next() {
+                Some(Err(Error::Cancelled)) => {}
+                other => panic!("expected cancellation, got {:?}", other),
+            }
+            assert!(rows.next().is_none());
+        })
+    }
+
+    #[test]
+    fn test_conn_string_api() {
+        with_test_conn!(conn {
+            conn.tx(r#"add (14 name "Jane")"#).unwrap();
+            let result = conn.q(r#"find ?p where (?p name "Jane")"#).unwrap();
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(14))]]);
+
+            assert!(conn.tx(r#"add (14 nonexistent "Jane")"#).is_err());
+            assert!(conn.q("find ?p where").is_err());
+        })
+    }
+
+    #[test]
+    fn test_query_macro() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let by_name = |name: &str| query(query!("find ?p where (?p name {})", name).unwrap(), &db).unwrap().1;
+            assert_eq!(by_name("Bob"), vec![vec![Value::Ref(Entity(11))]]);
+            assert!(by_name("Bob\") (?p name ?x").is_empty());
+        })
+    }
+
+    #[test]
+    fn test_within_query() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx("{db:ident location db:valueType db:type:geo}").unwrap()).unwrap();
+            conn.transact(parse_tx(
+                "add (11 location #geo(40.7128 -74.006)) \
+                 add (12 location #geo(40.7306 -73.9866)) \
+                 add (13 location #geo(42.3601 -71.0589))"
+            ).unwrap()).unwrap();
+
+            let nearby = |conn: &mut Conn| {
+                let mut result = query(
+                    parse_query("find ?p where (within ?p location 40.72 -74.0 5000)").unwrap(),
+                    &conn.db().unwrap()
+                ).unwrap().1;
+                result.sort();
+                result
+            };
+            let expected = vec![vec![Value::Ref(Entity(11))], vec![Value::Ref(Entity(12))]];
+            assert_eq!(nearby(&mut conn), expected);
+
+            let location = *conn.db().unwrap().schema.idents.get("location").unwrap();
+            conn.transact(Tx {
+                items: vec![TxItem::Addition(Fact::new(location, "db:indexed", Value::Boolean(true)))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+            assert_eq!(nearby(&mut conn), expected);
+
+            let db = conn.db().unwrap();
+
+            let named = query(
+                parse_query("find ?n where (?p name ?n) (within ?p location 40.7128 -74.006 100)").unwrap(),
+                &db
+            ).unwrap();
+            assert_eq!(named.1, vec![vec![Value::String("Bob".into())]]);
+        })
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        with_test_conn!(conn {
+            let names = |conn: &mut Conn| {
+                let mut result = query(
+                    parse_query("find ?n where (?p name ?n) (active ?p)").unwrap(),
+                    &conn.db().unwrap()
+                ).unwrap().1;
+                result.sort();
+                result
+            };
+            assert_eq!(names(&mut conn), vec![vec![Value::String("Bob".into())], vec![Value::String("John".into())]]);
+
+            conn.transact(Tx { items: vec![TxItem::soft_delete(Entity(11))], idempotency_key: None, return_datoms: false }).unwrap();
+            assert_eq!(names(&mut conn), vec![vec![Value::String("John".into())]]);
+
+            // Built queries can use any var name, including the one the
+            // planner would otherwise pick for the db:deletedAt value.
+            let q = QueryBuilder::find(&["?_deletedAt0"])
+                .where_clause("?p", "name", var("_deletedAt0"))
+                .active("?p")
+                .build()
+                .unwrap();
+            assert_eq!(query(q, &conn.db().unwrap()).unwrap().1, vec![vec![Value::String("John".into())]]);
+
+            let deleted_at = match query(
+                parse_query("find ?t where (11 db:deletedAt ?t)").unwrap(),
+                &conn.db().unwrap()
+            ).unwrap().1[0][0] {
+                Value::Timestamp(t) => t,
+                ref v => panic!("expected a timestamp, got {:?}", v),
+            };
+            conn.transact(Tx { items: vec![TxItem::restore(Entity(11), deleted_at)], idempotency_key: None, return_datoms: false }).unwrap();
+            assert_eq!(names(&mut conn).len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        with_test_conn!(conn {
+            let keyed_tx = || {
+                let mut tx = parse_tx(r#"{name "Alice"}"#).unwrap();
+                tx.idempotency_key = Some("create-alice".into());
+                tx
+            };
+
+            let first = conn.transact(keyed_tx()).unwrap();
+            let second = conn.transact(keyed_tx()).unwrap();
+            assert_eq!(first, second);
+            match first {
+                TxReport::Success { ref new_entities, .. } => assert_eq!(new_entities.len(), 1),
+                ref report => panic!("expected success, got {:?}", report),
+            }
+
+            let alices = query(parse_query(r#"find ?a where (?a name "Alice")"#).unwrap(), &conn.db().unwrap()).unwrap();
+            assert_eq!(alices.1.len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_tx_report_datoms() {
+        with_test_conn!(conn {
+            let tx = |key: &str, return_datoms| {
+                let mut tx = parse_tx(r#"add (20 name "Ann") retract (11 name "Bob")"#).unwrap();
+                tx.idempotency_key = Some(key.into());
+                tx.return_datoms = return_datoms;
+                tx
+            };
+
+            let report = conn.transact(tx("ann", true)).unwrap();
+            let (tx_entity, datoms) = match report {
+                TxReport::Success { tx, timestamp: Some(_), datoms: Some(ref datoms), .. } => (tx, datoms.clone()),
+                ref report => panic!("expected datoms, got {:?}", report),
+            };
+            let name = conn.db().unwrap().schema.idents["name"];
+            assert!(datoms.contains(&Record::addition(Entity(20), name, "Ann", tx_entity)));
+            assert!(datoms.contains(&Record::retraction(Entity(11), name, "Bob", tx_entity)));
+
+            // A retried transaction reports what was first committed.
+            assert_eq!(conn.transact(tx("ann", true)).unwrap(), report);
+
+            match conn.transact(tx("other", false)).unwrap() {
+                TxReport::Success { timestamp: Some(_), datoms: None, .. } => {}
+                report => panic!("expected no datoms, got {:?}", report),
+            }
+        })
+    }
+
+    #[test]
+    fn test_ensure_schema() {
+        use schema::{AttributeDef, Normalizer, ValueType};
+
+        with_test_conn!(conn {
+            let mut defs = vec![
+                AttributeDef::new("email", ValueType::String).indexed().normalize(Normalizer::Lowercase),
+                AttributeDef::new("status", ValueType::Ident).allowed_value("active").allowed_value("banned"),
+                AttributeDef::new("name", ValueType::String).doc("A person's name"),
+            ];
+            assert!(conn.ensure_schema(&defs).unwrap().is_some());
+            let email = conn.db().unwrap().attribute_info("email").unwrap();
+            assert!(email.indexed);
+            assert_eq!(email.normalizers, vec![Normalizer::Lowercase]);
+            assert_eq!(conn.db().unwrap().attribute_info("name").unwrap().doc, Some("A person's name".into()));
+
+            // Once the schema matches, nothing is transacted.
+            let last_tx = conn.last_written_tx();
+            assert_eq!(conn.ensure_schema(&defs).unwrap(), None);
+            assert_eq!(conn.last_written_tx(), last_tx);
+
+            // Only what changed is transacted.
+            defs[0] = AttributeDef::new("email", ValueType::String).doc("Login email");
+            defs[1] = AttributeDef::new("status", ValueType::Ident).allowed_value("active");
+            assert!(conn.ensure_schema(&defs).unwrap().is_some());
+            let db = conn.db().unwrap();
+            let email = db.attribute_info("email").unwrap();
+            assert!(!email.indexed && email.normalizers.is_empty());
+            assert_eq!(email.doc, Some("Login email".into()));
+            assert_eq!(db.attribute_info("status").unwrap().allowed_values, vec!["active".to_string()]);
+            assert!(db.schema_changes(&defs).unwrap().is_empty());
+
+            // Incompatible changes are refused.
+            let err = conn.ensure_schema(&[AttributeDef::new("email", ValueType::Long)]).unwrap_err();
+            assert!(err.message().contains("value type"));
+            assert!(conn.ensure_schema(&[AttributeDef::new("db:type:string", ValueType::String)]).is_err());
+        })
+    }
+
+    #[test]
+    fn test_cardinality_one() {
+        use schema::{AttributeDef, Cardinality, ValueType};
+
+        with_test_conn!(conn {
+            conn.tx("{db:ident nickname db:valueType db:type:string db:cardinality db:cardinality:one}").unwrap();
+            conn.tx(r#"add (11 nickname "Bobby")"#).unwrap();
+            let before = conn.db().unwrap().basis_tx;
+            conn.tx(r#"add (11 nickname "Rob")"#).unwrap();
+            // Reasserting the current value leaves it alone.
+            conn.tx(r#"add (11 nickname "Rob")"#).unwrap();
+
+            let nicknames = |db: db::Db| db.entity(Entity(11)).unwrap()["nickname"].clone();
+            assert_eq!(nicknames(conn.db().unwrap()), vec![Value::String("Rob".into())]);
+            assert_eq!(nicknames(conn.db().unwrap().as_of(Entity(before))), vec![Value::String("Bobby".into())]);
+            assert!(conn.tx(r#"{name "Jane" nickname ["J" "Janie"]}"#).is_err());
+
+            // Declared through ensure_schema, too.
+            assert_eq!(conn.db().unwrap().attribute_info("name").unwrap().cardinality, Cardinality::Many);
+            conn.ensure_schema(&[AttributeDef::new("name", ValueType::String).cardinality_one()]).unwrap();
+            assert_eq!(conn.db().unwrap().attribute_info("name").unwrap().cardinality, Cardinality::One);
+            conn.tx(r#"add (11 name "Robert")"#).unwrap();
+            let q = "find ?n where (11 name ?n)";
+            assert_eq!(conn.q(q).unwrap().1, vec![vec![Value::String("Robert".into())]]);
+
+            conn.ensure_schema(&[AttributeDef::new("name", ValueType::String)]).unwrap();
+            conn.tx(r#"add (11 name "Bob")"#).unwrap();
+            assert_eq!(conn.q(q).unwrap().1.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_unique() {
+        use schema::{AttributeDef, ValueType};
+
+        with_test_conn!(conn {
+            conn.ensure_schema(&[AttributeDef::new("email", ValueType::String).unique()]).unwrap();
+            conn.tx(r#"add (11 email "bob@x.com")"#).unwrap();
+            let err = conn.tx(r#"{name "Other Bob" email "bob@x.com"}"#).unwrap_err().message();
+            assert!(err.contains("already belongs to entity 11"), "{}", err);
+            assert!(conn.tx(r#"add (12 email "j@x.com") add (13 email "j@x.com")"#).is_err());
+
+            // A value can move to another entity once it's retracted.
+            conn.tx(r#"retract (11 email "bob@x.com") add (12 email "bob@x.com")"#).unwrap();
+            // Unique attributes are indexed, so lookup refs work.
+            assert_eq!(conn.db().unwrap().lookup(&LookupRef::new("email", "bob@x.com")).unwrap(), Entity(12));
+            assert!(conn.db().unwrap().attribute_info("email").unwrap().unique);
+
+            // Attributes with duplicate values can't be made unique.
+            conn.tx(r#"add (13 name "Bob")"#).unwrap();
+            let err = conn.ensure_schema(&[AttributeDef::new("name", ValueType::String).unique()]).unwrap_err().message();
+            assert!(err.contains("can't make name unique"), "{}", err);
+            conn.tx(r#"retract (13 name "Bob")"#).unwrap();
+            conn.ensure_schema(&[AttributeDef::new("name", ValueType::String).unique()]).unwrap();
+            assert!(conn.tx(r#"{name "John"}"#).is_err());
+        })
+    }
+
+    #[test]
+    fn test_upsert_lookup_refs() {
+        use schema::{AttributeDef, ValueType};
+
+        with_test_conn!(conn {
+            conn.ensure_schema(&[
+                AttributeDef::new("email", ValueType::String).unique(),
+                AttributeDef::new("phone", ValueType::String).indexed(),
+            ]).unwrap();
+            let alice = match conn.tx(r#"add ([email "alice@x.com"] name "Alice")"#).unwrap() {
+                TxReport::Success { new_entities, .. } => {
+                    assert_eq!(new_entities.len(), 1);
+                    new_entities[0]
+                }
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            let db = conn.db().unwrap();
+            assert_eq!(db.entity(alice).unwrap()["name"], vec![Value::String("Alice".into())]);
+
+            // Once it exists, the lookup ref names the same entity,
+            // including later in the transaction that created it.
+            match conn.tx(r#"add ([email "alice@x.com"] parent [email "carol@x.com"]) add ([email "carol@x.com"] name "Carol")"#).unwrap() {
+                TxReport::Success { new_entities, .. } => assert_eq!(new_entities.len(), 1),
+                TxReport::Failure(msg) => panic!("{}", msg),
+            }
+            let db = conn.db().unwrap();
+            let carol = db.lookup(&LookupRef::new("email", "carol@x.com")).unwrap();
+            assert_eq!(db.entity(alice).unwrap()["parent"], vec![Value::Ref(carol)]);
+            assert_eq!(db.entity(carol).unwrap()["name"], vec![Value::String("Carol".into())]);
+
+            // Only unique attributes create entities.
+            assert!(conn.tx(r#"add ([phone "555-1234"] name "Dan")"#).is_err());
+            assert!(conn.tx(r#"retract ([email "erin@x.com"] name "Erin")"#).is_err());
+        })
+    }
+
+    #[test]
+    fn test_collation() {
+        use schema::{AttributeDef, Collation, ValueType};
+
+        with_test_conn!(conn {
+            conn.tx("{db:ident handle db:valueType db:type:string db:collation db:collation:caseInsensitive}").unwrap();
+            conn.tx(r#"{handle "carol"} {handle "Dave"} {handle "alice"} {handle "Bob"}"#).unwrap();
+            let strings = |values: &[&str]| values.iter().map(|v| vec![Value::String(v.to_string())]).collect::<Vec<_>>();
+
+            let q = "find ?h where (?e handle ?h) order by ?h";
+            assert_eq!(conn.q(q).unwrap().1, strings(&["alice", "Bob", "carol", "Dave"]));
+            let q = "find ?h where (?e handle ?h) order by ?h desc limit 2";
+            assert_eq!(conn.q(q).unwrap().1, strings(&["Dave", "carol"]));
+            let q = r#"find ?h where (?e handle ?h) (< ?h "c") order by ?h"#;
+            assert_eq!(conn.q(q).unwrap().1, strings(&["alice", "Bob"]));
+            let q = r#"find ?h where (?e handle ?h) (not ?h "BOB") order by ?h"#;
+            assert_eq!(conn.q(q).unwrap().1, strings(&["alice", "carol", "Dave"]));
+
+            // The AVET index keeps the attribute's values in the same
+            // order.
+            let handle = conn.db().unwrap().schema.resolve("handle").unwrap();
+            let avet = |db: db::Db| db.ave.iter().filter(|r| r.attribute == handle).map(|r| vec![r.value]).collect::<Vec<_>>();
+            assert_eq!(avet(conn.db().unwrap()), strings(&["alice", "Bob", "carol", "Dave"]));
+
+            // It has values, so its collation can't change.
+            assert!(conn.ensure_schema(&[AttributeDef::new("handle", ValueType::String)]).is_err());
+            conn.ensure_schema(&[AttributeDef::new("nick", ValueType::String).collation(Collation::Nfc)]).unwrap();
+            assert_eq!(conn.db().unwrap().attribute_info("nick").unwrap().collation, Collation::Nfc);
+            conn.tx("{nick \"e\u{301}\"}").unwrap();
+            let q = "find ?e where (?e nick ?n) (not ?n \"\u{e9}\")";
+            assert_eq!(conn.q(q).unwrap().1.len(), 0);
+        })
+    }
+
+    #[test]
+    fn test_string_predicates() {
+        use plan::plan_query;
+        use schema::{AttributeDef, Collation, ValueType};
+
+        with_test_conn!(conn {
+            conn.ensure_schema(&[
+                AttributeDef::new("handle", ValueType::String).indexed().collation(Collation::CaseInsensitive),
+                AttributeDef::new("code", ValueType::String).indexed(),
+                AttributeDef::new("note", ValueType::String),
+            ]).unwrap();
+            conn.tx(r#"{handle "bob" code "AB-1" note "Bonjour"} {handle "Bobby" code "ab-2" note "bonsai"}
+                       {handle "BOB" code "AB-3"} {handle "Bo" code "B"} {handle "alice"} {handle "carl"}"#).unwrap();
+            let strings = |relation: Relation| {
+                let mut values: Vec<String> = relation.1.into_iter().map(|t| t[0].to_string()).collect();
+                values.sort();
+                values
+            };
+
+            // Under its collation, handle's prefixes and matches are
+            // case-insensitive.
+            assert_eq!(strings(conn.q(r#"find ?h where (?e handle ?h) (starts-with ?h "bo")"#).unwrap()), vec![r#""BOB""#, r#""Bo""#, r#""Bobby""#, r#""bob""#]);
+            assert_eq!(strings(conn.q(r#"find ?h where (?e handle ?h) (matches-ci ?h "Bob")"#).unwrap()), vec![r#""BOB""#, r#""bob""#]);
+            assert_eq!(strings(conn.q(r#"find ?c where (?e code ?c) (starts-with ?c "AB")"#).unwrap()), vec![r#""AB-1""#, r#""AB-3""#]);
+            assert_eq!(strings(conn.q(r#"find ?c where (?e code ?c) (matches-ci ?c "ab-1")"#).unwrap()), vec![r#""AB-1""#]);
+            assert_eq!(strings(conn.q(r#"find ?n where (?e note ?n) (starts-with ?n "bon")"#).unwrap()), vec![r#""bonsai""#]);
+            assert_eq!(strings(conn.q(r#"find ?h where (?e handle ?h) (?e note ?n) (starts-with ?n "Bon")"#).unwrap()), vec![r#""bob""#]);
+
+            let db = conn.db().unwrap();
+            let plan = plan_query(parse_query(r#"find ?h where (?e handle ?h) (starts-with ?h "bo")"#).unwrap(), &db).unwrap();
+            assert!(plan.to_string().contains(r#"Scan (?e handle ?h) (starts-with ?h "bo")"#), "{}", plan);
+
+            let bobby = conn.q(r#"find ?e where (?e handle "Bobby")"#).unwrap().1[0][0].clone();
+            conn.tx(&format!(r#"retract ({} handle "Bobby")"#, bobby)[..]).unwrap();
+            assert_eq!(strings(conn.q(r#"find ?h where (?e handle ?h) (starts-with ?h "bob")"#).unwrap()), vec![r#""BOB""#, r#""bob""#]);
+        })
+    }
+
+    #[test]
+    fn test_or() {
+        with_test_conn!(conn {
+            let refs = |relation: Relation| relation.1.into_iter().map(|t| t[0].clone()).collect::<Vec<_>>();
+            let q = r#"find ?e where (or (?e name "Bob") (?e name "John"))"#;
+            assert_eq!(refs(conn.q(q).unwrap()), vec![Value::Ref(Entity(11)), Value::Ref(Entity(12))]);
+
+            // The clauses have to bind the same vars.
+            let q = r#"find ?e where (or (?e name "Bob") (?e name ?n) (?e Hello "World"))"#;
+            assert!(conn.q(q).is_err());
+
+            // Rows matching more than one clause are only returned
+            // once, and the union joins with the other clauses.
+            let q = r#"find ?e where (or (?e name "John") (?e parent 11) (?e Hello "World")) (?e name ?n)"#;
+            assert_eq!(refs(conn.q(q).unwrap()), vec![Value::Ref(Entity(12))]);
+            let q = r#"find ?p where (?c parent ?p) (or (?p name "Bob") (?p name "Alice"))"#;
+            assert_eq!(refs(conn.q(q).unwrap()), vec![Value::Ref(Entity(11))]);
+        })
+    }
+
+    #[test]
+    fn test_lookup_refs() {
+        use schema::{AttributeDef, ValueType};
+
+        with_test_conn!(conn {
+            conn.ensure_schema(&[
+                AttributeDef::new("email", ValueType::String).indexed(),
+                AttributeDef::new("team", ValueType::String).indexed(),
+                AttributeDef::new("nickname", ValueType::String),
+                AttributeDef::new("friend", ValueType::Ref),
+            ]).unwrap();
+            conn.tx(r#"{email "bob@x.com" team "a"} {email "al@x.com" team "a"}"#).unwrap();
+            conn.tx(r#"add ([email "bob@x.com"] nickname "Bob") add ([email "bob@x.com"] friend [email "al@x.com"])"#).unwrap();
+            // In tx text, `[...]` in an entity map is a vector, but
+            // maps built in code can hold lookup refs.
+            let carl: HashMap<String, TxValue> = vec![
+                ("nickname".to_string(), TxValue::from("Carl")),
+                ("friend".to_string(), TxValue::Lookup(LookupRef::new("email", "al@x.com"))),
+            ].into_iter().collect();
+            conn.transact(Tx { items: vec![TxItem::NewEntity(carl)], idempotency_key: None, return_datoms: false }).unwrap();
+            let strings = |relation: Relation| {
+                let mut values: Vec<String> = relation.1.into_iter().map(|t| t[0].to_string()).collect();
+                values.sort();
+                values
+            };
+
+            let q = r#"find ?m where ([email "bob@x.com"] friend ?f) (?f email ?m)"#;
+            assert_eq!(strings(conn.q(q).unwrap()), vec![r#""al@x.com""#]);
+            let q = r#"find ?n where (?e friend [email "al@x.com"]) (?e nickname ?n)"#;
+            assert_eq!(strings(conn.q(q).unwrap()), vec![r#""Bob""#, r#""Carl""#]);
+            let q = r#"find ?n where (?e nickname ?n) (not ?e [email "bob@x.com"])"#;
+            assert_eq!(strings(conn.q(q).unwrap()), vec![r#""Carl""#]);
+
+            conn.tx(r#"retract ([email "bob@x.com"] nickname "Bob")"#).unwrap();
+            assert_eq!(strings(conn.q(r#"find ?n where ([email "bob@x.com"] nickname ?n)"#).unwrap()), Vec::<String>::new());
+
+            // A lookup ref has to name exactly one entity, by an
+            // indexed attribute.
+            let err = conn.tx(r#"add ([email "dan@x.com"] nickname "Dan")"#).unwrap_err().message();
+            assert!(err.contains("doesn't match any entity"), "{}", err);
+            let err = conn.tx(r#"add ([team "a"] nickname "A")"#).unwrap_err().message();
+            assert!(err.contains("isn't unique"), "{}", err);
+            let err = conn.q(r#"find ?m where ([nickname "Carl"] email ?m)"#).unwrap_err().message();
+            assert!(err.contains("needs nickname to be db:indexed"), "{}", err);
+
+            // Idents name entities too, e.g. to change an attribute.
+            conn.tx(r#"add (nickname db:doc "What friends call them")"#).unwrap();
+            assert_eq!(conn.db().unwrap().attribute_info("nickname").unwrap().doc, Some("What friends call them".into()));
+
+            // Entities can be named the same ways through the library.
+            let db = conn.db().unwrap();
+            let bob = db.resolve(&EntitySpec::Lookup(LookupRef::new("email", "bob@x.com"))).unwrap();
+            assert_eq!(db.resolve(&EntitySpec::Id(bob)).unwrap(), bob);
+            assert_eq!(db.resolve(&"email".into()).unwrap(), db.schema.idents["email"]);
+            assert!(db.resolve(&"nope".into()).is_err());
+            assert_eq!(db.entity(LookupRef::new("email", "bob@x.com")).unwrap()["email"], vec![Value::String("bob@x.com".into())]);
+            assert_eq!(db.entity("email").unwrap()["db:valueType"].len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_allocate_ids() {
+        with_test_conn!(conn {
+            // Rows can refer to each other by their reserved ids.
+            let ids = conn.allocate_ids(2).unwrap();
+            let (parent, child) = (ids.start, ids.start + 1);
+            conn.tx(&format!(r#"add ({} parent {}) add ({} name "Kid")"#, child, parent, child)[..]).unwrap();
+            conn.tx(&format!(r#"add ({} name "Pa")"#, parent)[..]).unwrap();
+            let q = "find ?n where (?c name \"Kid\") (?c parent ?p) (?p name ?n)";
+            assert_eq!(conn.q(q).unwrap().1, vec![vec![Value::String("Pa".into())]]);
+
+            match conn.tx(r#"{name "Other"}"#).unwrap() {
+                TxReport::Success { tx: Entity(tx), new_entities, .. } => {
+                    assert!(tx >= ids.end && new_entities.iter().all(|&Entity(e)| e >= ids.end));
+                }
+                report => panic!("expected success, got {:?}", report),
+            }
+        })
+    }
+
+    #[test]
+    fn test_new_transactor_fences_old_one() {
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store = store_from_uri(&store_uri).unwrap();
+
+        let mut old = Transactor::new(store.clone()).unwrap();
+        let old_handle = TxHandle::new(&old);
+        let old_thread = thread::spawn(move || old.run());
+        let new = Transactor::new(store.clone()).unwrap();
+
+        match old_handle.transact(parse_tx("{db:ident name db:valueType db:type:string}").unwrap()).unwrap() {
+            TxReport::Failure(msg) => assert!(msg.contains("fenced"), "{}", msg),
+            report => panic!("expected the old transactor to be fenced, got {:?}", report),
+        }
+        assert_eq!(store.get_metadata().unwrap().epoch, 2);
+
+        old_handle.close().unwrap();
+        old_thread.join().unwrap().unwrap();
+        drop(new);
+    }
+
+    #[test]
+    fn test_conn_from_transactor() {
+        let mut context = zmq::Context::new();
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let mut server = TransactorService::new(&store_uri, &context).unwrap();
+        server.advertise_store(&store_uri);
+        let join_handle = server.listen("inproc://transactor").unwrap();
+        {
+            let mut conn = Conn::from_transactor("inproc://transactor", &context).unwrap();
+            let report = conn.transact(parse_tx("{db:ident name db:valueType db:type:string}").unwrap()).unwrap();
+            let tx = match report {
+                TxReport::Success { tx, .. } => tx,
+                report => panic!("expected success, got {:?}", report),
+            };
+
+            let info = conn.store_info().unwrap();
+            assert_eq!(info.store_uri, store_uri);
+            assert_eq!(info.basis_tx, tx.0);
+            assert!(conn.db().unwrap().schema.idents.contains_key("name"));
+
+            // Clients from before requests existed send a bare Tx.
+            let socket = context.socket(zmq::REQ).unwrap();
+            socket.connect("inproc://transactor").unwrap();
+            socket.send(rmp_serde::to_vec(&parse_tx("add (20 name \"Old\")").unwrap()).unwrap(), 0).unwrap();
+            let report: TxReport = rmp_serde::from_read_ref(&socket.recv_bytes(0).unwrap()).unwrap();
+            assert!(matches!(report, TxReport::Success { .. }), "{:?}", report);
+        }
+        server.close();
+        context.destroy().unwrap();
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_peer_rebuilds_entity_idents() {
+        use std::sync::Arc;
+        use backends::sqlite::SqliteStore;
+        use tx::Transactor;
+
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        Transactor::new(store.clone()).unwrap();
+        // Metadata stored before the schema kept its reverse map.
+        let mut metadata = store.get_metadata().unwrap();
+        metadata.schema.entity_idents = HashMap::new();
+        store.set_metadata(&metadata).unwrap();
+
+        let mut conn = Conn::open_read_only(store);
+        let db = conn.db().unwrap();
+        let db_ident = db.schema.idents["db:ident"];
+        assert_eq!(db.ident_for(db_ident), Some("db:ident"));
+    }
+
+    #[test]
+    fn test_read_only_conn() {
+        let mut context = zmq::Context::new();
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let server = TransactorService::new(&store_uri, &context).unwrap();
+        let join_handle = server.listen("inproc://transactor").unwrap();
+        {
+            let writer = test_conn(&context, &store_uri);
+            let mut reader = Conn::open_read_only(store_from_uri(&store_uri).unwrap());
+            assert!(reader.is_read_only() && !writer.is_read_only());
+            // The store isn't advertised unless the service is told to.
+            assert!(writer.store_info().is_err());
+
+            let q = "find ?n where (?p name ?n) (?c parent ?p)";
+            assert_eq!(reader.q(q).unwrap().1, vec![vec![Value::String("Bob".into())]]);
+            writer.tx(r#"add (14 name "Ann") add (14 parent 11)"#).unwrap();
+            assert_eq!(reader.q(q).unwrap().1.len(), 2);
+
+            assert!(matches!(reader.tx(r#"add (15 name "Cy")"#), Err(Error::ReadOnly)));
+            assert!(matches!(reader.store_info(), Err(Error::ReadOnly)));
+        }
+        server.close();
+        context.destroy().unwrap();
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_remote_conn() {
+        use conn::RemoteConn;
+        use server::QueryLimits;
+
+        let mut context = zmq::Context::new();
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let mut server = TransactorService::new(&store_uri, &context).unwrap();
+        server.serve_queries(QueryLimits { max_rows: 1, ..QueryLimits::default() });
+        let join_handle = server.listen("inproc://transactor").unwrap();
+        {
+            let conn = RemoteConn::new("inproc://transactor", &context).unwrap();
+            conn.transact(parse_tx("{db:ident name db:valueType db:type:string}").unwrap()).unwrap();
+            conn.transact(parse_tx(r#"{name "Ann"} {name "Ben"}"#).unwrap()).unwrap();
+
+            let result = conn.q("find ?p where (?p name {})", &["Ann".into()]).unwrap();
+            assert_eq!(result.0, vec![Var::new("p")]);
+            assert_eq!(result.1.len(), 1);
+            let too_many = conn.q("find ?n where (?p name ?n)", &[]).unwrap_err();
+            assert!(too_many.message().contains("more than 1 rows"), "{:?}", too_many);
+            assert!(conn.q("find ?n where", &[]).is_err());
+        }
+        server.close();
+        context.destroy().unwrap();
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_health_checks() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        fn get(port: u16, path: &str) -> String {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        }
+
+        let context = zmq::Context::new();
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let server = TransactorService::new(&store_uri, &context).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        server.serve_health(&format!("127.0.0.1:{}", port)).unwrap();
+
+        assert!(get(port, "/live").starts_with("HTTP/1.1 200 OK"));
+        assert!(get(port, "/ready").starts_with("HTTP/1.1 200 OK"));
+        assert!(get(port, "/metrics").starts_with("HTTP/1.1 404"));
+
+        server.shutdown(Duration::from_secs(5)).unwrap();
+        let response = get(port, "/ready");
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.ends_with("the transactor isn't running"), "{}", response);
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        with_test_conn!(conn {
+            conn.set_read_your_writes(Duration::from_secs(5));
+            let tx = match conn.transact(parse_tx(r#"add (11 name "Robert")"#).unwrap()).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                report => panic!("expected success, got {:?}", report),
+            };
+
+            let db = conn.db().unwrap();
+            let txs = query(parse_query(r#"find ?tx where (?tx db:txTimestamp ?t)"#).unwrap(), &db).unwrap();
+            assert!(txs.1.contains(&vec![Value::Ref(tx)]));
+        })
+    }
+
+    #[test]
+    fn test_db_at_least() {
+        with_test_conn!(conn {
+            let pinned = conn.db().unwrap();
+            let tx = match conn.tx(r#"add (11 name "Robert")"#).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                report => panic!("expected success, got {:?}", report),
+            };
+            assert!(conn.is_stale(&pinned));
+
+            let db = conn.db_at_least(tx.0, Duration::from_secs(5)).unwrap();
+            assert!(db.basis_tx >= tx.0);
+            assert!(!conn.is_stale(&db));
+            let q = || parse_query(r#"find ?n where (11 name ?n)"#).unwrap();
+            assert!(query(q(), &db).unwrap().1.contains(&vec![Value::String("Robert".into())]));
+            assert!(!query(q(), &pinned).unwrap().1.contains(&vec![Value::String("Robert".into())]));
+        })
+    }
+
+    #[test]
+    fn test_query_count() {
+        with_test_conn!(conn {
+            conn.tx(r#"retract (12 name "John")"#).unwrap();
+            let db = conn.db().unwrap();
+            for q in [
+                r#"find ?p where (?p name ?n)"#,
+                r#"find ?n where (11 name ?n)"#,
+                r#"find ?n where (12 name ?n)"#,
+                r#"find ?c ?n where (?c parent ?p) (?p name ?n)"#,
+                r#"find ?p where (?p name ?n) (?p parent ?q)"#,
+            ] {
+                let expected = query(parse_query(q).unwrap(), &db).unwrap().1.len();
+                assert_eq!(query_count(parse_query(q).unwrap(), &db).unwrap(), expected, "{}", q);
+            }
+            assert!(query_count(parse_query("find ?x where (?p name ?n)").unwrap(), &db).is_err());
+        })
+    }
+
+    #[test]
+    fn test_query_count_from_stats() {
+        with_test_conn!(conn {
+            conn.tx(r#"retract (12 name "John")"#).unwrap();
+            assert!(conn.reindex().unwrap());
+            let q = "find ?tx where (?tx db:admin:operation db:admin:reindex)";
+            let mut waited = 0;
+            while conn.q(q).unwrap().1.len() < 2 {
+                assert!(waited < 1000, "the requested reindex didn't finish");
+                thread::sleep(Duration::from_millis(10));
+                waited += 1;
+            }
+
+            // Counted from the statistics, which know of the retraction.
+            let names = || parse_query("find ?p where (?p name ?n)").unwrap();
+            let db = conn.db().unwrap();
+            let name = *db.schema.idents.get("name").unwrap();
+            assert_eq!(db.stats.attributes.get(&name).unwrap().retracted, Some(1));
+            assert_eq!(query_count(names(), &db).unwrap(), query(names(), &db).unwrap().1.len());
+
+            // And by reading the facts once there are some the
+            // statistics don't know of.
+            conn.tx(r#"add (12 name "Johnny")"#).unwrap();
+            let db = conn.db().unwrap();
+            assert_eq!(query_count(names(), &db).unwrap(), query(names(), &db).unwrap().1.len());
+        })
+    }
+
+    #[test]
+    fn test_with_query() {
+        with_test_conn!(conn {
+            conn.tx("{parent 11}").unwrap();
+            let db = conn.db().unwrap();
+            // Results are already bags, so naming the child doesn't
+            // change the rows.
+            let rows = |q: &str| query(parse_query(q).unwrap(), &db).unwrap().1;
+            let with = rows("find ?n with ?c where (?c parent ?p) (?p name ?n)");
+            assert_eq!(with, rows("find ?n where (?c parent ?p) (?p name ?n)"));
+            assert_eq!(with.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_query_iter() {
+        with_test_conn!(conn {
+            conn.tx("add (12 parent 13)").unwrap();
+            let db = conn.db().unwrap();
+            for q in [
+                r#"find ?p where (?p name ?n)"#,
+                r#"find ?n ?c where (?c parent ?p) (?p name ?n)"#,
+                r#"find ?n where (?p name ?n) (?p parent ?q) (not ?n "Bob")"#,
+                r#"find ?n where (?p name ?n) (exists (?p parent ?x))"#,
+                r#"find ?n where (?p name ?n) (not (?c parent ?p))"#,
+                r#"find ?n where (?p name ?n) order by ?n desc"#,
+                r#"find ?n where (?p name ?n) order by ?n limit 1"#,
+            ] {
+                let expected = query(parse_query(q).unwrap(), &db).unwrap().1;
+                let rows = query_iter(parse_query(q).unwrap(), &db).collect::<Result<Vec<_>>>().unwrap();
+                assert_eq!(rows, expected, "{}", q);
+            }
+
+            // The rows can be taken one at a time.
+            let mut rows = query_iter(parse_query("find ?n where (?p name ?n)").unwrap(), &db);
+            assert!(rows.next().unwrap().is_ok());
+
+            // An error ends the rows.
+            let mut rows = query_iter(parse_query("find ?x where (?p name ?n)").unwrap(), &db);
+            assert!(rows.next().unwrap().is_err());
+            assert!(rows.next().is_none());
+        })
+    }
+
+    #[test]
+    fn test_exists_query() {
+        with_test_conn!(conn {
+            conn.tx("add (12 parent 13)").unwrap();
+            let db = conn.db().unwrap();
+
+            // Joining on parent would give John a row per parent.
+            let result = query(parse_query("find ?n where (?p name ?n) (exists (?p parent ?x))").unwrap(), &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::String("John".into())]]);
+
+            let q = QueryBuilder::find(&["?n"]).where_clause("?p", "name", var("n")).exists("?p", "parent", Entity(11)).build().unwrap();
+            assert_eq!(query(q, &db).unwrap().1, vec![vec![Value::String("John".into())]]);
+        })
+    }
+
+    #[test]
+    fn test_not_query() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+
+            // Only John has a parent.
+            let result = query(parse_query("find ?n where (?p name ?n) (not (?p parent ?x))").unwrap(), &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::String("Bob".into())]]);
+            let result = query(parse_query("find ?n where (?p name ?n) (not (?c parent ?p))").unwrap(), &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::String("John".into())]]);
+
+            // A `not` without a clause is still the comparator.
+            let result = query(parse_query(r#"find ?n where (?p name ?n) (not ?n "Bob")"#).unwrap(), &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::String("John".into())]]);
+
+            let q = QueryBuilder::find(&["?n"]).where_clause("?p", "name", var("n")).not("?p", "parent", Entity(11)).build().unwrap();
+            assert_eq!(query(q, &db).unwrap().1, vec![vec![Value::String("Bob".into())]]);
+        })
+    }
+
+    #[test]
+    fn test_rules() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx("add (13 parent 12)").unwrap()).unwrap();
+            let db = conn.db().unwrap();
+            let rules = "rule ancestor(?a ?b) :- (?a parent ?b) \
+                         rule ancestor(?a ?c) :- (?a parent ?b) ancestor(?b ?c)";
+
+            let mut result = query(parse_query(format!("{} find ?x where ancestor(13 ?x)", rules).as_str()).unwrap(), &db).unwrap().1;
+            result.sort();
+            assert_eq!(result, vec![vec![Value::Ref(Entity(11))], vec![Value::Ref(Entity(12))]]);
+
+            // Calls join with the query's clauses like any other.
+            let q = format!("{} find ?n where (?p name ?n) ancestor(?c ?p) (?c Hello ?h)", rules);
+            let mut result = query(parse_query(q.as_str()).unwrap(), &db).unwrap().1;
+            result.sort();
+            assert_eq!(result, vec![vec![Value::String("Bob".into())], vec![Value::String("John".into())]]);
+
+            let err = query(parse_query("find ?x where ancestor(13 ?x)").unwrap(), &db).unwrap_err();
+            assert!(err.message().contains("ancestor"), "{}", err.message());
+            let err = query(parse_query(format!("{} find ?x where ancestor(?x)", rules).as_str()).unwrap(), &db).unwrap_err();
+            assert!(err.message().contains("ancestor"), "{}", err.message());
+        })
+    }
+
+    #[test]
+    fn test_collection_input() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let names = vec!["Bob".into(), "John".into(), "Nobody".into()];
+            let q = parse_query("find ?p ?name in [?name ...] where (?p name ?name)").unwrap()
+                .bind_inputs(vec![names.clone()])
+                .unwrap();
+            let mut result = query(q, &db).unwrap().1;
+            result.sort();
+            assert_eq!(result, vec![
+                vec![Value::Ref(Entity(11)), Value::String("Bob".into())],
+                vec![Value::Ref(Entity(12)), Value::String("John".into())],
+            ]);
+
+            let q = QueryBuilder::find(&["?p"]).input("?name", names).where_clause("?p", "name", var("name")).build().unwrap();
+            assert_eq!(query(q, &db).unwrap().1.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_query_with_inputs() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let q = || parse_query("find ?p in ?name where (?p name ?name)").unwrap();
+            let result = query_with_inputs(q(), vec![QueryInput::Scalar("Bob".into())], &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(11))]]);
+
+            // The value is matched as a whole, quotes and all.
+            let result = query_with_inputs(q(), vec![QueryInput::Scalar(r#"Bob") (?p name ?n"#.into())], &db).unwrap();
+            assert!(result.1.is_empty());
+
+            assert!(query_with_inputs(q(), vec![QueryInput::Collection(vec!["Bob".into()])], &db).is_err());
+            assert!(query_with_inputs(q(), vec![], &db).is_err());
+        })
+    }
+
+    #[test]
+    fn test_order_by() {
+        use plan::plan_query;
+
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let q = parse_query("find ?name where (?p name ?name) order by ?name desc").unwrap();
+            assert!(plan_query(q.clone(), &db).unwrap().to_string().contains("Sort ?name desc"));
+            assert_eq!(query(q, &db).unwrap().1, vec![
+                vec![Value::String("John".into())],
+                vec![Value::String("Bob".into())],
+            ]);
+
+            // Names are fetched in entity order, so ordering by ?p
+            // needs no sort, even though ?p isn't found.
+            let q = QueryBuilder::find(&["?name"]).where_clause("?p", "name", var("name")).order_by("?p").build().unwrap();
+            let explained = plan_query(q.clone(), &db).unwrap().to_string();
+            assert!(!explained.contains("Sort"), "{}", explained);
+            assert!(explained.contains("Fetch (?p name ?name)  [ordered by ?p ?name]"), "{}", explained);
+            assert_eq!(query(q, &db).unwrap().1, vec![
+                vec![Value::String("Bob".into())],
+                vec![Value::String("John".into())],
+            ]);
+
+            let q = parse_query("find ?p where (?p name ?name) order by ?nope").unwrap();
+            assert!(query(q, &db).is_err());
+        })
+    }
+
+    #[test]
+    fn test_top_k() {
+        use plan::plan_query;
+
+        with_test_conn!(conn {
+            conn.tx("{db:ident placed db:valueType db:type:string}").unwrap();
+            conn.tx(
+                "add (1000000 placed \"2024-03-02\") add (1000001 placed \"2024-03-05\") add (1000002 placed \"2024-03-01\") \
+                 add (1000003 placed \"2024-03-04\") add (1000004 placed \"2024-03-03\")"
+            ).unwrap();
+            let db = conn.db().unwrap();
+            let row = |e: i64, placed: &str| vec![Value::Ref(Entity(e)), Value::String(placed.into())];
+
+            let q = parse_query("find ?o ?at where (?o placed ?at) order by ?at desc limit 2").unwrap();
+            assert!(plan_query(q.clone(), &db).unwrap().to_string().contains("TopK 2 ?at desc"));
+            assert_eq!(query(q.clone(), &db).unwrap().1, vec![row(1000001, "2024-03-05"), row(1000003, "2024-03-04")]);
+            assert_eq!(query_count(q, &db).unwrap(), 2);
+
+            // Already in order by ?o, so the fetch is just cut off.
+            let q = QueryBuilder::find(&["?o", "?at"]).where_clause("?o", "placed", var("at")).order_by("?o").limit(3).build().unwrap();
+            let explained = plan_query(q.clone(), &db).unwrap().to_string();
+            assert!(explained.contains("Limit 3") && !explained.contains("Sort"), "{}", explained);
+            assert_eq!(query(q, &db).unwrap().1, vec![
+                row(1000000, "2024-03-02"),
+                row(1000001, "2024-03-05"),
+                row(1000002, "2024-03-01"),
+            ]);
+
+            let q = parse_query("find ?o where (?o placed ?at) order by ?at limit 0").unwrap();
+            assert!(query(q, &db).unwrap().1.is_empty());
+            let q = parse_query("find ?o where (?o placed ?at) order by ?nope limit 1").unwrap();
+            assert!(query(q, &db).is_err());
+        })
+    }
+
+    /// Wraps a store so that, once frozen, its readers stop seeing
+    /// new metadata and transactions, like a lagging replica.
+    struct FrozenStore {
+        store: Arc<dyn KVStore>,
+        frozen: Arc<Mutex<Option<Snapshot>>>,
+    }
+
+    /// What a `FrozenStore`'s readers see.
+    struct Snapshot {
+        metadata: Vec<u8>,
+        version: Vec<u8>,
+        last_tx: i64,
+    }
+
+    impl FrozenStore {
+        fn freeze(&self) {
+            let last_tx = self.store.get_txs(-1).unwrap().last().map_or(-1, |tx| tx.id);
+            let metadata = self.store.get("db_metadata").unwrap();
+            let version = self.store.get("db_metadata_version").unwrap();
+            *self.frozen.lock().unwrap() = Some(Snapshot { metadata, version, last_tx });
+        }
+
+        fn thaw(&self) {
+            *self.frozen.lock().unwrap() = None;
+        }
+    }
+
+    impl KVStore for FrozenStore {
+        fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.store.set(key, value)
+        }
+
+        fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+            self.store.compare_and_set(key, expected, value)
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>> {
+            match (key, &*self.frozen.lock()?) {
+                ("db_metadata", Some(snapshot)) => Ok(snapshot.metadata.clone()),
+                ("db_metadata_version", Some(snapshot)) => Ok(snapshot.version.clone()),
+                _ => self.store.get(key),
+            }
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.store.delete(key)
+        }
+
+        fn add_tx(&self, raw_tx: &TxRaw) -> Result<()> {
+            self.store.add_tx(raw_tx)
+        }
+
+        fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+            let last_tx = self.frozen.lock()?.as_ref().map_or(i64::MAX, |snapshot| snapshot.last_tx);
+            Ok(self.store.get_txs(from)?.into_iter().take_while(|tx| tx.id <= last_tx).collect())
+        }
+
+        fn stream_txs(&self, from: i64) -> backends::TxStream {
+            let (store, frozen) = (self.store.clone(), self.frozen.clone());
+            backends::TxStream::new(from, Box::new(move |after, limit| {
+                let last_tx = frozen.lock()?.as_ref().map_or(i64::MAX, |snapshot| snapshot.last_tx);
+                Ok(store.get_txs(after)?.into_iter().take_while(|tx| tx.id <= last_tx).take(limit).collect())
+            }))
+        }
+    }
+
+    #[test]
+    fn test_refresh_schema() {
+        let mut context = zmq::Context::new();
+        let store_uri = format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let server = TransactorService::new(&store_uri, &context).unwrap();
+        let join_handle = server.listen("inproc://transactor").unwrap();
+        {
+            let writer = Conn::new(store_from_uri(&store_uri).unwrap(), "inproc://transactor", &context).unwrap();
+            let store = Arc::new(FrozenStore { store: store_from_uri(&store_uri).unwrap(), frozen: Default::default() });
+            let mut reader = Conn::new(store.clone(), "inproc://transactor", &context).unwrap();
+            reader.db().unwrap();
+
+            // The reader can't see the new attribute until it asks.
+            store.freeze();
+            let report = writer.tx("{db:ident email db:valueType db:type:string}").unwrap();
+            assert!(matches!(report, TxReport::Success { schema_changed: true, .. }));
+            let report = writer.tx(r#"{email "bob@example.com"}"#).unwrap();
+            assert!(matches!(report, TxReport::Success { schema_changed: false, .. }));
+            assert!(reader.db().unwrap().attribute_info("email").is_err());
+
+            reader.refresh_schema().unwrap();
+            let db = reader.db().unwrap();
+            assert!(db.attribute_info("email").is_ok());
+            let q = "find ?e where (?e email ?v)";
+            assert_eq!(reader.q(q).unwrap().1.len(), 0);
+            store.thaw();
+            assert_eq!(reader.q(q).unwrap().1.len(), 1);
+        }
+        server.close();
+        context.destroy().unwrap();
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_relation_set_operations() {
+        let strings = |rows: &[&[&str]]| rows.iter()
+            .map(|row| row.iter().map(|s| Value::String(s.to_string())).collect())
+            .collect::<Vec<Vec<Value>>>();
+        let relation = |vars: &[&str], rows: &[&[&str]]| Relation(vars.iter().map(|v| Var::new(*v)).collect(), strings(rows));
+
+        let a = relation(&["name", "color"], &[&["Bob", "red"], &["Ann", "blue"], &["Bob", "red"]]);
+        // The same vars in another order.
+        let b = relation(&["color", "name"], &[&["blue", "Ann"], &["green", "Jo"]]);
+
+        assert_eq!(a.clone().union(b.clone()).unwrap(), relation(&["name", "color"], &[&["Bob", "red"], &["Ann", "blue"], &["Jo", "green"]]));
+        assert_eq!(a.clone().intersect(b.clone()).unwrap().1, strings(&[&["Ann", "blue"]]));
+        assert_eq!(a.clone().difference(b.clone()).unwrap().1, strings(&[&["Bob", "red"]]));
+        assert!(a.clone().union(relation(&["name"], &[])).is_err());
+        assert!(a.clone().intersect(relation(&["name", "flavor"], &[])).is_err());
+
+        let flavors = relation(&["name", "flavor"], &[&["Bob", "mint"], &["Bob", "plum"], &["Jo", "fig"]]);
+        assert_eq!(a.clone().join(flavors).unwrap(), relation(&["name", "color", "flavor"], &[
+            &["Bob", "red", "mint"], &["Bob", "red", "plum"], &["Bob", "red", "mint"], &["Bob", "red", "plum"],
+        ]));
+        let sizes = relation(&["size"], &[&["S"], &["L"]]);
+        assert_eq!(b.join(sizes).unwrap().1.len(), 4);
+    }
+
+    #[test]
+    fn test_as_of() {
+        with_test_conn!(conn {
+            let before = conn.db().unwrap().basis_tx;
+            let report = conn.tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap();
+            let renamed = match report {
+                TxReport::Success { tx, .. } => tx,
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            let jane = match conn.tx(r#"{name "Jane"}"#).unwrap() {
+                TxReport::Success { new_entities, .. } => new_entities[0],
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+
+            let names = |relation: Relation| relation.1.into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+            let strings = |names: &[&str]| names.iter().map(|n| Value::String(n.to_string())).collect::<Vec<_>>();
+            let q = "find ?n where (?p name ?n)";
+            let as_of = |tx: i64| format!("{} as of {} order by ?n", q, tx);
+            assert_eq!(names(conn.q(&as_of(before)).unwrap()), strings(&["Bob", "John"]));
+            assert_eq!(names(conn.q(&as_of(renamed.0)).unwrap()), strings(&["John", "Robert"]));
+            assert_eq!(names(conn.q(&format!("{} order by ?n", q)).unwrap()), strings(&["Jane", "John", "Robert"]));
+
+            let db = conn.db().unwrap().as_of(Entity(before));
+            assert_eq!(db.basis_tx, before);
+            assert_eq!(db.entity(Entity(11)).unwrap()["name"], strings(&["Bob"]));
+            assert!(db.entity(jane).unwrap().is_empty());
+            // Narrowing a view further can't widen it.
+            assert_eq!(db.as_of(renamed).basis_tx, before);
+        })
+    }
+
+    #[test]
+    fn test_since() {
+        with_test_conn!(conn {
+            let before = conn.db().unwrap().basis_tx;
+            let renamed = match conn.tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            conn.tx(r#"{name "Jane"}"#).unwrap();
+
+            let names = |relation: Relation| relation.1.into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+            let strings = |names: &[&str]| names.iter().map(|n| Value::String(n.to_string())).collect::<Vec<_>>();
+            let q = "find ?n where (?p name ?n)";
+            assert_eq!(names(conn.q(&format!("{} since {} order by ?n", q, before)).unwrap()),
+                       strings(&["Jane", "Robert"]));
+            assert_eq!(names(conn.q(&format!("{} since {}", q, renamed.0)).unwrap()), strings(&["Jane"]));
+            assert_eq!(names(conn.q(&format!("{} as of {} since {}", q, renamed.0, before)).unwrap()),
+                       strings(&["Robert"]));
+
+            let db = conn.db().unwrap();
+            let records = db.tx_records(renamed.0..=renamed.0).unwrap();
+            assert!(records.iter().all(|rec| rec.tx == renamed));
+            assert!(records.iter().any(|rec| rec.retracted && rec.value == Value::String("Bob".into())));
+            assert!(db.tx_records(before + 1..=db.basis_tx).unwrap().len() > records.len());
+        })
+    }
+
+    #[test]
+    fn test_history() {
+        with_test_conn!(conn {
+            let renamed = match conn.tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            let (bob, robert) = (Value::String("Bob".into()), Value::String("Robert".into()));
+
+            let db = conn.db().unwrap();
+            let q = parse_query("find ?v ?tx ?added where (11 name ?v ?tx ?added)").unwrap();
+            let rows = query(q.clone(), &db.history()).unwrap().1;
+            assert_eq!(rows.len(), 3, "{:?}", rows);
+            assert!(rows.contains(&vec![bob.clone(), Value::Ref(renamed), Value::Boolean(false)]));
+            assert!(rows.contains(&vec![robert.clone(), Value::Ref(renamed), Value::Boolean(true)]));
+            assert!(rows.iter().any(|row| row[0] == bob && row[1] != Value::Ref(renamed) && row[2] == Value::Boolean(true)));
+            assert_eq!(query(q, &db).unwrap().1, vec![vec![robert.clone(), Value::Ref(renamed), Value::Boolean(true)]]);
+
+            let retracted = conn.q("find ?v where (?p name ?v ?tx false) history").unwrap();
+            assert_eq!(retracted.1, vec![vec![bob.clone()]]);
+            let changed = conn.q(&format!("find ?v ?added where (11 name ?v {} ?added) history", renamed.0)).unwrap();
+            assert_eq!(changed.1.len(), 2);
+            assert_eq!(conn.q(&format!("find ?v where (11 name ?v {})", renamed.0)).unwrap().1, vec![vec![robert]]);
+        })
+    }
+
+    #[test]
+    fn test_tx_bounded_clauses_read_the_log() {
+        with_test_conn!(conn {
+            let before = conn.db().unwrap().basis_tx;
+            let renamed = match conn.tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            assert!(conn.reindex().unwrap());
+            let q = "find ?tx where (?tx db:admin:operation db:admin:reindex)";
+            let mut waited = 0;
+            while conn.q(q).unwrap().1.len() < 2 {
+                assert!(waited < 1000, "the requested reindex didn't finish");
+                thread::sleep(Duration::from_millis(10));
+                waited += 1;
+            }
+
+            let db = conn.db().unwrap();
+            let changed = Clause {
+                tx: Some(Term::Bound(renamed)),
+                ..Clause::new(Term::Unbound("p".into()), Term::Unbound("a".into()), Term::Unbound("v".into()))
+            };
+            assert!(db.stats.log.count > 0);
+            assert!(queries::planner::prefers_log(&changed, &(renamed.0..=renamed.0), &db.stats, &db.schema));
+            let q = format!(r#"find ?p where (?p ?a "Robert" {})"#, renamed.0);
+            assert_eq!(conn.q(&q).unwrap().1, vec![vec![Value::Ref(Entity(11))]]);
+            let q = format!(r#"find ?p where (?p ?a "Bob" {} false) history"#, renamed.0);
+            assert_eq!(conn.q(&q).unwrap().1, vec![vec![Value::Ref(Entity(11))]]);
+
+            let robert = vec![vec![Value::String("Robert".into())]];
+            assert_eq!(conn.q(&format!("find ?n where (?p name ?n {})", renamed.0)).unwrap().1, robert);
+            assert_eq!(conn.q(&format!("find ?n where (?p name ?n) since {}", before)).unwrap().1, robert);
+            let changed = conn.q(&format!("find ?n ?added where (?p name ?n {} ?added) history", renamed.0)).unwrap();
+            assert_eq!(changed.1.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_tx_data() {
+        with_test_conn!(conn {
+            let tx = parse_tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap();
+            let renamed = match conn.transact(Tx { idempotency_key: Some("rename-bob".into()), ..tx }).unwrap() {
+                TxReport::Success { tx, .. } => tx,
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+
+            let db = conn.db().unwrap();
+            let data = db.tx_data(renamed).unwrap();
+            assert_eq!(data.tx, renamed);
+            assert!(data.timestamp.is_some());
+            assert_eq!(data.annotations, vec![("db:txIdempotencyKey".to_string(), Value::String("rename-bob".into()))]);
+            let changes = |data: &db::TxData| {
+                let mut changes = data.records.iter().map(|rec| (rec.entity, rec.value.clone(), rec.retracted)).collect::<Vec<_>>();
+                changes.sort();
+                changes
+            };
+            assert_eq!(changes(&data), vec![
+                (Entity(11), Value::String("Bob".into()), true),
+                (Entity(11), Value::String("Robert".into()), false),
+            ]);
+
+            // Without a log index, it's read from the log.
+            let mut metadata = db.store.get_metadata().unwrap();
+            metadata.tea = String::new();
+            let from_log = db::Db::new(metadata, db.store.clone()).tx_data(renamed).unwrap();
+            assert_eq!(changes(&from_log), changes(&data));
+            assert_eq!(from_log.annotations, data.annotations);
+            assert!(db.tx_data(Entity(renamed.0 + 1000)).is_err());
+        })
+    }
+
+    #[test]
+    fn test_sandbox() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let mut sandbox = db.sandbox();
+            let report = sandbox.tx(r#"{name "Ann" parent 12} add (11 name "Robert") retract (11 name "Bob")"#).unwrap();
+            let ann = match report {
+                TxReport::Success { new_entities, .. } => new_entities[0],
+                TxReport::Failure(msg) => panic!("{}", msg),
+            };
+            assert_eq!(sandbox.basis_tx(), db.basis_tx);
+
+            let names = |relation: Relation| relation.1.into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+            assert_eq!(names(sandbox.q("find ?n where (?p name ?n) order by ?n").unwrap()), vec![
+                Value::String("Ann".into()),
+                Value::String("John".into()),
+                Value::String("Robert".into()),
+            ]);
+            assert_eq!(sandbox.db().entity(ann).unwrap()["parent"], vec![Value::Ref(Entity(12))]);
+            assert!(sandbox.tx("add (11 nope 1)").is_err());
+
+            // Nothing reaches the real database.
+            drop(sandbox);
+            assert_eq!(names(conn.q("find ?n where (?p name ?n) order by ?n").unwrap()), vec![
+                Value::String("Bob".into()),
+                Value::String("John".into()),
+            ]);
+        })
+    }
+
+    #[test]
+    fn test_analyze() {
+        with_test_conn!(conn {
+            conn.tx("{db:ident email db:valueType db:type:string}").unwrap();
+            conn.ensure_schema(&[schema::AttributeDef::new("bio", ValueType::String).indexed()]).unwrap();
+            conn.tx(&format!(r#"add (11 bio "{}")"#, "x".repeat(2000))).unwrap();
+            conn.tx(r#"add (11 name "Robert") retract (11 name "Bob")"#).unwrap();
+            let report = analyze::analyze(&conn.db().unwrap(), 1).unwrap();
+            let usage = |ident: &str| report.attributes.iter().find(|a| a.ident == ident).unwrap().clone();
+
+            let name = usage("name");
+            assert_eq!((name.datoms, name.stored, name.recent), (2, 4, 2));
+            assert!(name.sizes.max > 0);
+            let hello = usage("Hello");
+            assert_eq!((hello.datoms, hello.stored, hello.recent), (1, 1, 0));
+            assert_eq!(usage("db:txTimestamp").recent, 1);
+            assert_eq!(report.unused(), vec!["email"]);
+            assert!(report.recent_since.is_some());
+            assert!(report.to_string().contains("Unused: email"));
+            assert_eq!(usage("bio").max_per_entity, 1);
+            assert_eq!(report.warnings().len(), 1);
+            assert!(report.warnings()[0].starts_with("bio is indexed"));
+
+            // Queries through the conn are tracked across its dbs.
+            conn.q(r#"find ?p where (?p name "John")"#).unwrap();
+            let db = conn.db().unwrap();
+            let name = db.schema.resolve("name").unwrap();
+            assert_eq!(db.lookups.counts()[&name], lint::LookupCounts { by_value: 1, other: 0 });
+        })
+    }
+
+    #[test]
+    fn test_dump_and_load_datoms() {
+        with_test_conn!(conn {
+            let tricky = Value::String("there \"you\"\n\tsee?".into());
+            conn.transact(Tx {
+                items: vec![
+                    TxItem::Retraction(Fact::new(Entity(13), "Hello", "World")),
+                    TxItem::Addition(Fact::new(Entity(13), "Hello", tricky.clone())),
+                ],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+            let mut dump = vec![];
+            let written = datoms::write_log(&conn.db().unwrap(), &mut dump).unwrap();
+            assert!(written > 0);
+
+            // Load the dump into an empty database.
+            let store = store_from_uri(&format!("cliodb:sqlite://file:{}?mode=memory&cache=shared", Uuid::new_v4())).unwrap();
+            Transactor::new(store.clone()).unwrap();
+            let mut sandbox = Conn::open_read_only(store).db().unwrap().sandbox();
+            for tx in datoms::to_txs(datoms::DatomReader::new(&dump[..]).unwrap()) {
+                match sandbox.transact(tx.unwrap()).unwrap() {
+                    TxReport::Success { .. } => (),
+                    TxReport::Failure(msg) => panic!("{}", msg),
+                }
+            }
+
+            let q = "find ?e ?a ?v where (?e ?x ?v) (?x db:ident ?a) (< 10 ?e) (< ?e 20) order by ?e ?a";
+            assert_eq!(sandbox.q(q).unwrap(), conn.q(q).unwrap());
+            assert_eq!(sandbox.db().entity(Entity(13)).unwrap()["Hello"], vec![tricky]);
+        })
+    }
+
+    #[test]
+    fn test_find_expressions() {
+        use chrono::TimeZone;
+
+        with_test_conn!(conn {
+            conn.tx("{db:ident born db:valueType db:type:timestamp}").unwrap();
+            let born = Value::Timestamp(Utc.with_ymd_and_hms(1970, 6, 15, 12, 0, 0).unwrap());
+            conn.transact(Tx {
+                items: vec![TxItem::Addition(Fact::new(Entity(11), "born", born))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+            let db = conn.db().unwrap();
+
+            let q = parse_query(r#"find ?n (str (upper ?n) ", " ?p) (len ?n) where (?p name ?n) order by ?p"#).unwrap();
+            let Relation(vars, rows) = query(q, &db).unwrap();
+            assert_eq!(vars, vec![Var::new("n"), Var::new(r#"(str (upper ?n) ", " ?p)"#), Var::new("(len ?n)")]);
+            assert_eq!(rows, vec![
+                vec![Value::String("Bob".into()), Value::String("BOB, 11".into()), Value::Long(3)],
+                vec![Value::String("John".into()), Value::String("JOHN, 12".into()), Value::Long(4)],
+            ]);
+
+            let q = parse_query("find (year ?b) (month ?b) (day ?b) where (?p born ?b)").unwrap();
+            assert_eq!(query(q, &db).unwrap().1, vec![vec![Value::Long(1970), Value::Long(6), Value::Long(15)]]);
+
+            for invalid in &["find (nope ?n) where (?p name ?n)", "find (upper ?n ?n) where (?p name ?n)", "find (year ?n) where (?p name ?n)", "find (upper ?x) where (?p name ?n)"] {
+                assert!(query(parse_query(*invalid).unwrap(), &db).is_err(), "{} should fail", invalid);
+            }
+            assert!(parse_query_with("find (str {}) where (?p name ?n)", &["x".into()]).is_err());
+        })
+    }
+
+    #[test]
+    fn test_aggregates() {
+        use plan::plan_query;
+
+        with_test_conn!(conn {
+            conn.tx("{db:ident age db:valueType db:type:long} add (13 parent 11)").unwrap();
+            conn.transact(Tx {
+                items: [(11, 70), (12, 40), (13, 45)].iter()
+                    .map(|&(e, age)| TxItem::Addition(Fact::new(Entity(e), "age", Value::Long(age))))
+                    .collect(),
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+            let db = conn.db().unwrap();
+            let rows = |q: &str| query(parse_query(q).unwrap(), &db).map(|r| r.1);
+
+            let q = parse_query("find ?p (count ?c) where (?c parent ?p)").unwrap();
+            let Relation(vars, counts) = query(q, &db).unwrap();
+            assert_eq!(vars, vec![Var::new("p"), Var::new("(count ?c)")]);
+            assert_eq!(counts, vec![vec![Value::Ref(Entity(11)), Value::Long(2)]]);
+
+            assert_eq!(
+                rows("find (count ?p) (sum ?a) (min ?a) (max ?a) (avg ?a) where (?p age ?a)").unwrap(),
+                vec![vec![Value::Long(3), Value::Long(155), Value::Long(40), Value::Long(70), Value::Long(51)]]
+            );
+            // Grouping by a computed column, limited after aggregating.
+            assert_eq!(
+                rows("find (upper ?n) (count ?c) (max ?a) where (?c parent ?p) (?p name ?n) (?c age ?a) limit 1").unwrap(),
+                vec![vec![Value::String("BOB".into()), Value::Long(2), Value::Long(45)]]
+            );
+            assert_eq!(rows(r#"find (count ?p) where (?p name "Nobody")"#).unwrap(), Vec::<Vec<Value>>::new());
+            assert!(rows("find (sum ?n) where (?p name ?n)").is_err());
+
+            let plan = plan_query(parse_query("find ?p (count ?c) where (?c parent ?p)").unwrap(), &db).unwrap();
+            assert!(plan.to_string().contains("Aggregate (count ?c) by ?p"), "{}", plan);
+
+            // Two employees with the same salary are one tuple of
+            // salaries unless the employee keeps them apart.
+            conn.tx("{db:ident salary db:valueType db:type:long}").unwrap();
+            conn.transact(Tx {
+                items: [(11, 5000), (12, 5000)].iter()
+                    .map(|&(e, salary)| TxItem::Addition(Fact::new(Entity(e), "salary", Value::Long(salary))))
+                    .collect(),
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap();
+            let db = conn.db().unwrap();
+            let rows = |q: &str| query(parse_query(q).unwrap(), &db).map(|r| r.1);
+            assert_eq!(rows("find (sum ?s) where (?e salary ?s)").unwrap(), vec![vec![Value::Long(5000)]]);
+            assert_eq!(rows("find (sum ?s) with ?e where (?e salary ?s)").unwrap(), vec![vec![Value::Long(10000)]]);
+            assert!(rows("find (sum ?s) with ?x where (?e salary ?s)").is_err());
+            let plan = plan_query(parse_query("find (sum ?s) with ?e where (?e salary ?s)").unwrap(), &db).unwrap();
+            assert!(plan.to_string().contains("with ?e"), "{}", plan);
+        })
+    }
+
+    #[test]
+    fn test_parse_connection_uri() {
+        use conn::parse_connection_uri;
+
+        assert_eq!(
+            parse_connection_uri("cliodb://db.internal:10405?store=mysql://clio:pw@db.internal/clio?ssl=true").unwrap(),
+            ("tcp://db.internal:10405".to_string(), Some("cliodb:mysql://clio:pw@db.internal/clio?ssl=true".to_string()))
+        );
+        assert_eq!(
+            parse_connection_uri("cliodb://127.0.0.1:10405?store=cliodb:sqlite:///tmp/clio.db").unwrap().1,
+            Some("cliodb:sqlite:///tmp/clio.db".to_string())
+        );
+        assert_eq!(parse_connection_uri("cliodb://127.0.0.1:10405").unwrap().1, None);
+        assert!(parse_connection_uri("cliodb://127.0.0.1:10405?user=clio").is_err());
+        assert!(parse_connection_uri("cliodb://?store=sqlite:///tmp/clio.db").is_err());
+        assert!(parse_connection_uri("tcp://127.0.0.1:10405").is_err());
+    }
+
+    #[test]
+    fn test_named_query() {
+        with_test_conn!(conn {
+            conn.tx(r#"{db:query:name "byName" db:query:text "find ?p where (?p name {})"}"#).unwrap();
+
+            assert_eq!(conn.q_named("byName", &["Bob".into()]).unwrap().1, vec![vec![Value::Ref(Entity(11))]]);
+            assert!(conn.q_named("byName", &[]).is_err());
+            assert!(conn.q_named("nothing", &[]).is_err());
+
+            let db = conn.db().unwrap();
+            let (columns, result) = sql::execute_sql("EXECUTE byName('John')", &db).unwrap();
+            assert_eq!(columns, vec![sql::Column { name: "p".into(), value_type: Some(ValueType::Ref) }]);
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(12))]]);
+        })
+    }
+
+    #[test]
+    fn test_query_hints() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let expected = vec![vec![Value::String("John".into())]];
+            for hints in &["", "(hint fetch 1)", "(hint fetch 2) (hint ordered)", "(hint lookup 0)"] {
+                let q = parse_query(&format!("find ?n where (?p name \"Bob\") (?c parent ?p) (?c name ?n) {}", hints)[..]).unwrap();
+                assert_eq!(query(q, &db).unwrap().1, expected);
+            }
+
+            let q = parse_query("find ?n where (?c name ?n) (hint fetch 1)").unwrap();
+            assert!(query(q, &db).is_err());
+        })
+    }
+
+    #[test]
+    fn test_custom_plan() {
+        use plan::{Plan, Clause, Term, Constraint, Comparator, execute_plan, plan_query};
+
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let cancel = CancelToken::new();
+
+            // The plan the planner picks, rewritten to fetch the
+            // second clause instead of looking it up.
+            let q = parse_query("find ?n where (?p name \"Bob\") (?c parent ?p) (?c name ?n)").unwrap();
+            let rewritten = match plan_query(q.clone(), &db).unwrap() {
+                Plan::Project(inner, find) => match *inner {
+                    Plan::LookupEach(prior, name) => match *prior {
+                        Plan::LookupEach(bob, parent) => Plan::Project(
+                            Box::new(Plan::LookupEach(Box::new(Plan::Join(bob, Box::new(Plan::Fetch(parent)))), name)),
+                            find,
+                        ),
+                        other => panic!("unexpected plan {:?}", other),
+                    },
+                    other => panic!("unexpected plan {:?}", other),
+                },
+                other => panic!("unexpected plan {:?}", other),
+            };
+            assert_eq!(execute_plan(&rewritten, &db, &cancel).unwrap(), query(q, &db).unwrap());
+
+            // A plan built from scratch.
+            let names = Plan::Fetch(Clause::new(
+                Term::Unbound("e".into()),
+                Term::Bound(Ident::Name("name".into())),
+                Term::Unbound("n".into()),
+            ));
+            let constraint = Constraint {
+                comparator: Comparator::LessThan,
+                left_hand_side: Term::Unbound("n".into()),
+                right_hand_side: Term::Bound(Value::String("C".into())),
+            };
+            let plan = Plan::Project(Box::new(Plan::Constrain(Box::new(names.clone()), vec![constraint.clone()])), vec!["e".into()]);
+            assert_eq!(execute_plan(&plan, &db, &cancel).unwrap().1, vec![vec![Value::Ref(Entity(11))]]);
+
+            // Hand-built plans aren't checked, but bad ones are errors.
+            let unbound = Constraint { left_hand_side: Term::Unbound("x".into()), ..constraint };
+            assert!(execute_plan(&Plan::Constrain(Box::new(names), vec![unbound]), &db, &cancel).is_err());
+        })
+    }
+
+    #[test]
+    fn test_sql_select() {
+        with_test_conn!(conn {
+            conn.transact(parse_tx(
+                "{db:ident person:name db:valueType db:type:string} \
+                 {db:ident person:email db:valueType db:type:string}"
+            ).unwrap()).unwrap();
+            conn.transact(parse_tx(
+                r#"add (20 person:name "Ann") add (20 person:email "ann@example.com")
+                   add (21 person:name "Ben") add (21 person:email "ben@example.com")
+                   add (22 person:name "Cy")"#
+            ).unwrap()).unwrap();
+            let db = conn.db().unwrap();
+
+            let (columns, result) = sql::execute_sql("SELECT * FROM person", &db).unwrap();
+            let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+            assert_eq!(names, vec!["id", "email", "name"]);
+            assert_eq!(result.1, vec![
+                vec![Value::Ref(Entity(20)), Value::String("ann@example.com".into()), Value::String("Ann".into())],
+                vec![Value::Ref(Entity(21)), Value::String("ben@example.com".into()), Value::String("Ben".into())],
+            ]);
+
+            let (_, result) = sql::execute_sql("select name from person where email = 'ben@example.com'", &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::String("Ben".into())]]);
+
+            let (_, result) = sql::execute_sql("select id from person where name <> 'Ann' limit 1", &db).unwrap();
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(21))]]);
+
+            assert!(sql::execute_sql("select age from person", &db).is_err());
+            assert!(sql::execute_sql("select * from nobody", &db).is_err());
+        })
+    }
+
+    #[test]
+    #[cfg(feature = "parquet-export")]
+    fn test_export_attributes() {
+        with_test_conn!(conn {
+            let db = conn.db().unwrap();
+            let batch = export::attributes_to_record_batch(&db, &["name", "parent"]).unwrap();
+
+            // Bob and John, with only John having a parent.
+            assert_eq!(batch.num_rows(), 2);
+            assert_eq!(batch.num_columns(), 3);
+            assert_eq!(batch.column(2).null_count(), 1);
+
+            let mut buf = vec![];
+            export::write_attributes(&db, &["name", "parent"], &mut buf).unwrap();
+            assert!(!buf.is_empty());
+        })
+    }
+
     #[bench]
     // Parse + run a query on a small db
     fn parse_bench(b: &mut Bencher) {
@@ -521,6 +2878,8 @@ pub mod tests {
                     items: vec![
                         TxItem::Addition(Fact::new(entity, "blah", Value::Ref(entity))),
                     ],
+                    idempotency_key: None,
+                    return_datoms: false,
                 }).unwrap();
             });
         })
@@ -572,6 +2931,8 @@ pub mod tests {
 
                     conn.transact(Tx {
                         items: vec![TxItem::Addition(Fact::new(Entity(i), a, v))],
+                        idempotency_key: None,
+                        return_datoms: false,
                     }).unwrap();
                 }
 
@@ -632,7 +2993,7 @@ pub mod tests {
             let db = conn.db().unwrap();
             let q = parse_query("find ?e where (?e db:ident db:type:string)").unwrap();
             let result = query(q, &db).unwrap();
-            assert_eq!(result.1, vec![vec![Value::Ref(Entity(6))]]);
+            assert_eq!(result.1, vec![vec![Value::Ref(Entity(SYSTEM_ENTITIES.start + 5))]]);
         });
     }
 }
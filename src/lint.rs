@@ -0,0 +1,135 @@
+//! Soft limits on schema usage the engine handles badly.
+//!
+//! None of these are errors; they're logged as warnings and listed
+//! in the `clio-admin analyze` report, so they can be fixed before
+//! they hurt:
+//!
+//! - Long strings in indexed attributes, which bloat the AVET index
+//!   and slow every scan over it (`MAX_INDEXED_STRING_BYTES`). The
+//!   transactor warns as they're written.
+//! - Entities with a huge number of values for one attribute, which
+//!   make the entity slow to read (`MAX_VALUES_PER_ENTITY`). Found
+//!   by `analyze`.
+//! - Attributes that aren't indexed but are only ever queried by
+//!   value, so every query scans all of their datoms
+//!   (`VALUE_LOOKUP_THRESHOLD`). Each peer tracks its own queries in
+//!   a `LookupTracker`, and warns once per attribute.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use log::warn;
+
+use schema::{Schema, ValueType};
+use {Entity, Value};
+
+/// Strings longer than this in an indexed attribute are warned
+/// about.
+pub const MAX_INDEXED_STRING_BYTES: usize = 1024;
+
+/// Entities with more values than this for one attribute are warned
+/// about.
+pub const MAX_VALUES_PER_ENTITY: u64 = 10_000;
+
+/// An unindexed attribute is warned about once it's been queried by
+/// value this many times and never any other way.
+pub const VALUE_LOOKUP_THRESHOLD: u64 = 100;
+
+/// Warns if `value` is a long string for an indexed attribute.
+pub fn check_value(schema: &Schema, attribute: Entity, value: &Value) {
+    match *value {
+        Value::String(ref s) if s.len() > MAX_INDEXED_STRING_BYTES && schema.is_indexed(attribute) => warn!(
+            "indexed attribute {} was given a {}-byte string; long values in indexed attributes slow down the AVET index",
+            schema.ident_for(attribute).unwrap_or("?"),
+            s.len()
+        ),
+        _ => {}
+    }
+}
+
+/// How an attribute has been looked up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupCounts {
+    /// Lookups of entities by a value of the attribute.
+    pub by_value: u64,
+    /// Every other lookup naming the attribute.
+    pub other: u64,
+}
+
+/// Counts the lookups a peer makes of unindexed attributes. It's
+/// shared by every Db a Conn returns.
+#[derive(Debug, Default)]
+pub struct LookupTracker {
+    counts: Mutex<BTreeMap<Entity, LookupCounts>>,
+}
+
+impl LookupTracker {
+    /// Records a lookup of `attribute`, warning the first time it's
+    /// been looked up by value `VALUE_LOOKUP_THRESHOLD` times and
+    /// never otherwise. Indexed and ref attributes have an index for
+    /// lookups by value, so they're ignored.
+    pub fn observe(&self, schema: &Schema, attribute: Entity, by_value: bool) {
+        if schema.is_indexed(attribute) || schema.value_types.get(&attribute) == Some(&ValueType::Ref) {
+            return;
+        }
+        let mut counts = match self.counts.lock() {
+            Ok(counts) => counts,
+            Err(_) => return,
+        };
+        let count = counts.entry(attribute).or_default();
+        if by_value {
+            count.by_value += 1;
+        } else {
+            count.other += 1;
+        }
+
+        if count.by_value == VALUE_LOOKUP_THRESHOLD && count.other == 0 {
+            warn!(
+                "attribute {} is only ever queried by value, but isn't indexed, so each query scans all of its datoms; consider db:indexed",
+                schema.ident_for(attribute).unwrap_or("?")
+            );
+        }
+    }
+
+    pub fn counts(&self) -> BTreeMap<Entity, LookupCounts> {
+        self.counts.lock().map(|counts| counts.clone()).unwrap_or_default()
+    }
+
+    /// The unindexed attributes which have been queried by value
+    /// often enough, and never otherwise, to be worth indexing.
+    pub fn unindexed_value_lookups(&self, schema: &Schema) -> Vec<Entity> {
+        self.counts()
+            .into_iter()
+            .filter(|&(attr, count)| count.by_value >= VALUE_LOOKUP_THRESHOLD && count.other == 0 && !schema.is_indexed(attr))
+            .map(|(attr, _)| attr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_tracker() {
+        let schema = Schema::empty()
+            .add_ident(Entity(40), "email".into())
+            .add_value_type(Entity(40), ValueType::String)
+            .add_ident(Entity(41), "name".into())
+            .add_value_type(Entity(41), ValueType::String)
+            .add_ident(Entity(42), "id".into())
+            .add_value_type(Entity(42), ValueType::String)
+            .index_attribute(Entity(42));
+        let tracker = LookupTracker::default();
+        for _ in 0..VALUE_LOOKUP_THRESHOLD {
+            tracker.observe(&schema, Entity(40), true);
+            tracker.observe(&schema, Entity(41), true);
+            tracker.observe(&schema, Entity(42), true);
+        }
+        tracker.observe(&schema, Entity(41), false);
+
+        assert_eq!(tracker.unindexed_value_lookups(&schema), vec![Entity(40)]);
+        assert_eq!(tracker.counts()[&Entity(41)], LookupCounts { by_value: VALUE_LOOKUP_THRESHOLD, other: 1 });
+        assert!(!tracker.counts().contains_key(&Entity(42)));
+    }
+}
@@ -1,22 +1,56 @@
 use super::*;
 
-use queries::query::{Query, Term, Clause, Var, Constraint, Comparator};
+use queries::query::{Query, Term, Clause, Var, Constraint, Comparator, Within, Hints, Strategy, Order, Expression, Aggregate, Rule, RuleCall};
+use geo::GeoPoint;
 
 //// Parser
 use combine::char::{spaces, string, char, letter, digit};
-use combine::primitives::Stream;
-use combine::{Parser, ParseError, many1, between, none_of, eof};
+use combine::primitives::{Stream, ParseResult};
+use combine::{Parser, ParseError, many, many1, between, none_of, eof, optional, parser, try};
 
 pub enum Input {
     Query(Query),
     Tx(Tx),
     SampleDb,
     Dump,
+    Schema,
+    /// `\set <option> <value>`, for CLI settings.
+    Set(String, String),
+    /// `\copy (<query>) to '<file>'`: writes the results as CSV.
+    CopyTo(Query, String),
+    /// `\copy '<file>' to (<attribute>...)`: loads CSV rows as new
+    /// entities, assigning the columns to the attributes in order.
+    CopyFrom(String, Vec<String>),
+    /// `\run <name> <value>...`: runs a query stored in the db, with
+    /// the values as its parameters.
+    Run(String, Vec<Value>),
+    /// `\reindex`: asks the transactor to rebuild the indices now.
+    Reindex,
+    /// `\explain <query>`: shows the plan for a query without
+    /// running it.
+    Explain(Query),
+    /// `\dump '<file>'`: writes the tx log as datoms (see `datoms`).
+    DumpTo(String),
+    /// `\load '<file>'`: transacts the datoms in a file written by
+    /// `\dump`.
+    Load(String),
+    /// `\tx <id>`: shows what a transaction changed.
+    TxData(Entity),
 }
 
+/// Clauses and constraints are parsed along with the lookup refs in
+/// them.
 enum ClauseConstraint {
-    Constraint(Constraint),
-    Clause(Clause),
+    Constraint(Constraint, Vec<LookupRef>),
+    Clause(Clause, Vec<LookupRef>),
+    Within(Within),
+    Active(Var),
+    Exists(Clause, Vec<LookupRef>),
+    Not(Clause, Vec<LookupRef>),
+    Or(Vec<(Clause, Vec<LookupRef>)>),
+    Call(RuleCall, Vec<LookupRef>),
+    Ordered,
+    Strategy(usize, Strategy),
 }
 
 pub fn parse_input<I>(input: I) -> result::Result<Input, ParseError<I>>
@@ -27,7 +61,15 @@ where
         query_parser().map(Input::Query),
         tx_parser().map(Input::Tx),
         sample_db_parser(),
-        dump_parser()
+        dump_parser(),
+        copy_parser(),
+        datoms_parser(),
+        run_parser(),
+        reindex_parser(),
+        explain_parser(),
+        set_parser(),
+        tx_data_parser(),
+        schema_parser()
     ).parse(input)
         .map(|(r, _)| r)
 }
@@ -39,6 +81,81 @@ where
     query_parser().parse(input).map(|(r, _)| r)
 }
 
+/// Parses a query template, substituting `params` for its `{}`
+/// placeholders in order. The parameters are bound as typed values
+/// after the template is parsed, so unlike values formatted into the
+/// query text they can't change its structure.
+///
+/// Placeholders can stand for entities (which must be refs),
+/// attributes (refs, idents or strings) and values in clauses, and
+/// for either side of a constraint.
+pub fn parse_query_with(template: &str, params: &[Value]) -> Result<Query> {
+    let pieces: Vec<&str> = template.split("{}").collect();
+    if pieces.len() - 1 != params.len() {
+        return Err(format!("query has {} placeholders but {} parameters were given", pieces.len() - 1, params.len()).into());
+    }
+
+    // Each placeholder becomes a variable which can't clash with the
+    // template's own (vars are letters only, so no digits).
+    let param_var = |i: usize| {
+        let letters: String = i.to_string().chars().map(|d| (b'a' + d as u8 - b'0') as char).collect();
+        Var::new(format!("cliodbparam{}", letters))
+    };
+    let mut text = pieces[0].to_string();
+    for (i, piece) in pieces[1..].iter().enumerate() {
+        text.push_str(&format!("?{} ", param_var(i).name));
+        text.push_str(piece);
+    }
+    let mut query = parse_query(&*text).map_err(|e| format!("{}", e))?;
+
+    let param = |var: &Var| (0..params.len()).find(|i| param_var(*i) == *var).map(|i| &params[i]);
+    if query.find.iter()
+        .chain(query.expressions.iter().flat_map(|(_, e)| e.vars()))
+        .chain(query.aggregates.iter().map(|(_, a)| &a.var))
+        .chain(query.with.iter())
+        .chain(query.active.iter())
+        .chain(query.within.iter().map(|w| &w.entity))
+        .chain(query.inputs.iter().map(|i| &i.0))
+        .any(|v| param(v).is_some()) {
+        return Err("parameters can only be used in clauses and constraints".into());
+    }
+
+    for clause in query.clauses.iter_mut().chain(query.exists.iter_mut()).chain(query.not.iter_mut()).chain(query.or.iter_mut().flat_map(|cs| cs.iter_mut())) {
+        if let Term::Unbound(ref v) = clause.entity.clone() {
+            match param(v) {
+                Some(&Value::Ref(e)) => clause.entity = Term::Bound(e),
+                Some(other) => return Err(format!("parameter {} can't be used as an entity", other).into()),
+                None => {}
+            }
+        }
+        if let Term::Unbound(ref v) = clause.attribute.clone() {
+            match param(v) {
+                Some(&Value::Ref(e)) => clause.attribute = Term::Bound(Ident::Entity(e)),
+                Some(&Value::Ident(ref i)) | Some(&Value::String(ref i)) => clause.attribute = Term::Bound(Ident::Name(i.clone())),
+                Some(other) => return Err(format!("parameter {} can't be used as an attribute", other).into()),
+                None => {}
+            }
+        }
+        if let Term::Unbound(ref v) = clause.value.clone() {
+            if let Some(value) = param(v) {
+                clause.value = Term::Bound(value.clone());
+            }
+        }
+    }
+
+    for constraint in query.constraints.iter_mut() {
+        for side in [&mut constraint.left_hand_side, &mut constraint.right_hand_side] {
+            if let Term::Unbound(ref v) = side.clone() {
+                if let Some(value) = param(v) {
+                    *side = Term::Bound(value.clone());
+                }
+            }
+        }
+    }
+
+    Ok(query)
+}
+
 pub fn parse_tx<I>(input: I) -> result::Result<Tx, ParseError<I>>
 where
     I: Stream<Item = char>,
@@ -46,6 +163,14 @@ where
     tx_parser().parse(input).map(|(r, _)| r)
 }
 
+/// Parses the name of an entity: its id, its ident, or a lookup ref
+/// such as `[email "bob@x.com"]`.
+pub fn parse_entity_spec(input: &str) -> Result<EntitySpec> {
+    spaces().with(entity_spec()).skip(eof()).parse(input)
+        .map(|(spec, _)| spec)
+        .map_err(|e| format!("{}", e).into())
+}
+
 fn sample_db_parser<I>() -> impl Parser<Input = I, Output = Input>
 where
     I: combine::Stream<Item = char>,
@@ -60,6 +185,77 @@ where
     lex_string("dump").and(eof()).map(|_| Input::Dump)
 }
 
+fn schema_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    lex_string("\\schema").and(eof()).map(|_| Input::Schema)
+}
+
+fn set_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    // `\schema` shares a prefix, so don't consume it on failure.
+    let word = || many1::<String, _>(none_of(vec![' ', '\t', '\n'])).skip(spaces());
+    (try(lex_string("\\set ")), word(), word(), eof()).map(|(_, option, value, _)| Input::Set(option, value))
+}
+
+fn run_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    (try(lex_string("\\run ")), ident(), many(tx_value_lit()), eof())
+        .map(|(_, name, params, _)| Input::Run(name, params))
+}
+
+fn reindex_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    try(lex_string("\\reindex")).and(eof()).map(|_| Input::Reindex)
+}
+
+fn tx_data_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    (try(lex_string("\\tx ")), number_lit().skip(spaces()), eof()).map(|(_, tx, _)| Input::TxData(tx))
+}
+
+fn explain_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    try(lex_string("\\explain")).with(query_body()).skip(eof()).map(Input::Explain)
+}
+
+fn copy_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    let to = (between(lex_char('('), lex_char(')'), query_body()), lex_string("to"), file_path())
+        .map(|(query, _, path)| Input::CopyTo(query, path));
+    let from = (file_path(), lex_string("to"), between(lex_char('('), lex_char(')'), many1(ident())))
+        .map(|(path, _, attributes)| Input::CopyFrom(path, attributes));
+
+    try(lex_string("\\copy")).with(to.or(from)).skip(eof())
+}
+
+fn datoms_parser<I>() -> impl Parser<Input = I, Output = Input>
+where
+    I: combine::Stream<Item = char>,
+{
+    let dump = try(lex_string("\\dump")).with(file_path()).map(Input::DumpTo);
+    let load = try(lex_string("\\load")).with(file_path()).map(Input::Load);
+    dump.or(load).skip(eof())
+}
+
+/// A file path in single quotes.
+fn file_path<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = String> {
+    between(char('\''), char('\''), many1::<String, _>(none_of(vec!['\'']))).skip(spaces())
+}
+
 fn free_var<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = Var> {
     char('?')
         .and(many1(letter()))
@@ -72,10 +268,14 @@ fn comparator<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Outpu
     string(">")
         .or(string("<"))
         .or(string("not"))
+        .or(string("starts-with"))
+        .or(string("matches-ci"))
         .skip(spaces())
         .map(|s| match s {
             ">" => Comparator::GreaterThan,
             "<" => Comparator::LessThan,
+            "starts-with" => Comparator::StartsWith,
+            "matches-ci" => Comparator::MatchesCi,
             _ => Comparator::NotEqualTo,
         })
 }
@@ -85,6 +285,21 @@ fn number_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Outpu
 }
 
 
+fn float_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = f64> {
+    (optional(char('-')), many1(digit()), optional(char('.').with(many1(digit()))))
+        .map(|(sign, int, frac): (Option<char>, String, Option<String>)| {
+            let lit = format!("{}{}.{}", sign.map_or("", |_| "-"), int, frac.unwrap_or_else(|| "0".into()));
+            lit.parse().unwrap()
+        })
+        .skip(spaces())
+}
+
+/// Geo values are written `#geo(<lat> <lon>)`.
+fn geo_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = Value> {
+    (lex_string("#geo("), float_lit(), float_lit(), char(')'))
+        .map(|(_, lat, lon, _)| Value::Geo(GeoPoint::new(lat, lon)))
+}
+
 fn string_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = Value> {
     between(char('"'), char('"'), many1(none_of(vec!['\"']))).map(|s| Value::String(s))
 }
@@ -94,6 +309,13 @@ fn ident<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = S
 }
 
 fn query_parser<I>() -> impl Parser<Input = I, Output = Query>
+where
+    I: combine::Stream<Item = char>,
+{
+    query_body().and(eof()).map(|x| x.0)
+}
+
+fn query_body<I>() -> impl Parser<Input = I, Output = Query>
 where
     I: combine::Stream<Item = char>,
 {
@@ -104,71 +326,274 @@ where
 
     let entity = number_lit;
     let value = || {
-        string_lit().or(number_lit().map(|e| Value::Ref(e))).or(
+        string_lit().or(geo_lit()).or(number_lit().map(|e| Value::Ref(e))).or(
             ident().map(|i| Value::Ident(i)),
         )
     };
 
+    // A lookup ref stands in the query as a var named by its text,
+    // which is bound to the entity it names when the query is
+    // planned.
+
     // There is probably a way to DRY these out but I couldn't satisfy the type checker.
     let comparator_term = comparator().skip(spaces());
-    let entity_term = free_var()
-        .map(|x| Term::Unbound(x))
-        .or(entity().map(|x| Term::Bound(x)))
-        .skip(spaces());
-    let ident_term = free_var()
-        .map(|x| Term::Unbound(x))
-        .or(ident().map(|x| Term::Bound(Ident::Name(x))))
-        .skip(spaces());
-    let value_term = || {
+    let entity_term = || {
+        free_var()
+            .map(|x| (Term::Unbound(x), None))
+            .or(entity().map(|x| (Term::Bound(x), None)))
+            .or(lookup_ref().map(|l| (Term::Unbound(Var::new(l.to_string())), Some(l))))
+            .skip(spaces())
+    };
+    let ident_term = || {
         free_var()
             .map(|x| Term::Unbound(x))
-            .or(value().map(|x| Term::Bound(x)))
+            .or(ident().map(|x| Term::Bound(Ident::Name(x))))
+            .skip(spaces())
+    };
+    let value_term = || {
+        free_var()
+            .map(|x| (Term::Unbound(x), None))
+            .or(value().map(|x| (Term::Bound(x), None)))
+            .or(lookup_ref().map(|l| (Term::Unbound(Var::new(l.to_string())), Some(l))))
             .skip(spaces())
     };
 
     // Clause structure
-    let constraint_metadata = (comparator_term, value_term(), value_term()).map(|(c, fst, snd)| {
-        ClauseConstraint::Constraint(Constraint {
+    let constraint_metadata = (comparator_term, value_term(), value_term()).map(|(c, (fst, l1), (snd, l2))| {
+        let constraint = Constraint {
             comparator: c,
             left_hand_side: fst,
             right_hand_side: snd,
-        })
-    });
-    let clause_metadata = (entity_term, ident_term, value_term()).map(|(e, a, v)| {
-        ClauseConstraint::Clause(Clause::new(e, a, v))
+        };
+        ClauseConstraint::Constraint(constraint, l1.into_iter().chain(l2).collect())
     });
+    // A clause can go on to bind the tx of the record it matches and
+    // whether it's an assertion, `(?e name ?v ?tx ?added)`.
+    let tx_term = || {
+        free_var()
+            .map(|x| Term::Unbound(x))
+            .or(number_lit().skip(spaces()).map(|tx| Term::Bound(tx)))
+    };
+    let added_term = || {
+        free_var()
+            .map(|x| Term::Unbound(x))
+            .or(lex_string("true").map(|_| Term::Bound(true)))
+            .or(lex_string("false").map(|_| Term::Bound(false)))
+    };
+    let clause = || {
+        (entity_term(), ident_term(), value_term(), optional((tx_term(), optional(added_term()))))
+            .map(|((e, l1), a, (v, l2), history)| {
+                let clause = match history {
+                    Some((tx, Some(added))) => Clause::new(e, a, v).with_tx(tx).with_added(added),
+                    Some((tx, None)) => Clause::new(e, a, v).with_tx(tx),
+                    None => Clause::new(e, a, v),
+                };
+                (clause, l1.into_iter().chain(l2).collect())
+            })
+    };
+    let clause_metadata = clause().map(|(c, lookups)| ClauseConstraint::Clause(c, lookups));
+    let within_metadata = (lex_string("within"), free_var(), ident(), float_lit(), float_lit(), float_lit())
+        .map(|(_, var, attr, lat, lon, radius)| {
+            ClauseConstraint::Within(Within {
+                entity: var,
+                attribute: Ident::Name(attr),
+                center: GeoPoint::new(lat, lon),
+                radius: radius.round() as u64,
+            })
+        });
+    let active_metadata = lex_string("active").with(free_var()).map(ClauseConstraint::Active);
+    let exists_metadata = lex_string("exists")
+        .with(between(lex_char('('), lex_char(')'), clause()))
+        .map(|(c, lookups)| ClauseConstraint::Exists(c, lookups));
+    // `(not ?a ?b)` is a constraint, so only a clause in parens
+    // makes this a `not` clause.
+    let not_metadata = try((lex_string("not"), lex_char('(')))
+        .with(clause())
+        .skip(lex_char(')'))
+        .map(|(c, lookups)| ClauseConstraint::Not(c, lookups));
+    let or_metadata = lex_string("or")
+        .with(many1(between(lex_char('('), lex_char(')'), clause())))
+        .map(ClauseConstraint::Or);
+    let strategy = lex_string("fetch").map(|_| Strategy::Fetch)
+        .or(lex_string("lookup").map(|_| Strategy::Lookup));
+    let clause_index = many1(digit()).skip(spaces()).map(|n: String| n.parse().unwrap());
+    let hint_metadata = lex_string("hint").with(
+        lex_string("ordered").map(|_| ClauseConstraint::Ordered)
+            .or((strategy, clause_index).map(|(s, i)| ClauseConstraint::Strategy(i, s))),
+    );
     let constraint_clause = between(
         lex_char('('),
         lex_char(')'),
-        constraint_metadata.or(clause_metadata),
+        not_metadata.or(constraint_metadata).or(clause_metadata).or(within_metadata).or(active_metadata).or(exists_metadata).or(or_metadata).or(hint_metadata),
     );
+    // A name directly followed by a paren is a rule call; backtracking
+    // lets keywords like `order` and `find` through.
+    let rule_call = || {
+        (try(ident().skip(lex_char('('))), many::<Vec<_>, _>(value_term()), lex_char(')'))
+            .map(|(name, args, _)| {
+                let (args, lookups): (Vec<Term<Value>>, Vec<Option<LookupRef>>) = args.into_iter().unzip();
+                ClauseConstraint::Call(RuleCall { name, args }, Iterator::flatten(lookups.into_iter()).collect())
+            })
+    };
+    let rule_body_item = between(lex_char('('), lex_char(')'), clause())
+        .map(|(c, lookups)| ClauseConstraint::Clause(c, lookups))
+        .or(rule_call());
+    let rule_spec = (
+        try(lex_string("rule")),
+        ident(),
+        between(lex_char('('), lex_char(')'), many1(free_var().skip(spaces()))),
+        lex_string(":-"),
+        many1::<Vec<_>, _>(rule_body_item),
+    ).map(|(_, name, params, _, body)| {
+        let mut rule = Rule { name, params, clauses: vec![], calls: vec![] };
+        let mut lookups = vec![];
+        for item in body {
+            match item {
+                ClauseConstraint::Clause(c, l) => {
+                    rule.clauses.push(c);
+                    lookups.extend(l);
+                }
+                ClauseConstraint::Call(c, l) => {
+                    rule.calls.push(c);
+                    lookups.extend(l);
+                }
+                _ => unreachable!(),
+            }
+        }
+        (rule, lookups)
+    });
 
-    let find_spec = lex_string("find").and(many1(free_var())).map(|x| x.1);
-    let where_spec = lex_string("where").and(many1(constraint_clause)).map(
+    let find_spec = lex_string("find").with(many1(parser(expression))).map(|columns: Vec<Expression>| {
+        let mut find = vec![];
+        let mut expressions = vec![];
+        let mut aggregates = vec![];
+        for column in columns {
+            match column {
+                Expression::Var(var) => find.push(var),
+                expression => {
+                    // The parser only accepts letters in var names, so
+                    // this can't collide with one from the query.
+                    let var = Var::new(expression.to_string());
+                    find.push(var.clone());
+                    match Aggregate::from_expression(&expression) {
+                        Some(aggregate) => aggregates.push((var, aggregate)),
+                        None => expressions.push((var, expression)),
+                    }
+                }
+            }
+        }
+        (find, expressions, aggregates)
+    });
+    let with_spec = try(lex_string("with")).with(many1(free_var()));
+    let collection = between(lex_char('['), lex_char(']'), free_var().skip(lex_string("...")));
+    let input = collection.map(|var| (var, false)).or(free_var().map(|var| (var, true)));
+    let in_spec = lex_string("in").with(many1::<Vec<(Var, bool)>, _>(input));
+    let where_spec = lex_string("where").and(many1(constraint_clause.or(rule_call()))).map(
         |(_, clause_constraint_vec): (_, Vec<ClauseConstraint>)| {
             let mut constraints = Vec::new();
             let mut clauses = Vec::new();
+            let mut within = Vec::new();
+            let mut active = Vec::new();
+            let mut exists = Vec::new();
+            let mut not = Vec::new();
+            let mut or = Vec::new();
+            let mut calls = Vec::new();
+            let mut hints = Hints::default();
+            let mut lookups: Vec<LookupRef> = Vec::new();
+            let mut add_lookups = |refs: Vec<LookupRef>| lookups.extend(refs);
 
             for cc in clause_constraint_vec {
                 match cc {
-                    ClauseConstraint::Clause(c) => clauses.push(c),
-                    ClauseConstraint::Constraint(x) => constraints.push(x),
+                    ClauseConstraint::Clause(c, l) => {
+                        clauses.push(c);
+                        add_lookups(l);
+                    }
+                    ClauseConstraint::Constraint(x, l) => {
+                        constraints.push(x);
+                        add_lookups(l);
+                    }
+                    ClauseConstraint::Within(w) => within.push(w),
+                    ClauseConstraint::Active(v) => active.push(v),
+                    ClauseConstraint::Exists(c, l) => {
+                        exists.push(c);
+                        add_lookups(l);
+                    }
+                    ClauseConstraint::Not(c, l) => {
+                        not.push(c);
+                        add_lookups(l);
+                    }
+                    ClauseConstraint::Or(cs) => {
+                        let mut branch = vec![];
+                        for (c, l) in cs {
+                            branch.push(c);
+                            add_lookups(l);
+                        }
+                        or.push(branch);
+                    }
+                    ClauseConstraint::Call(c, l) => {
+                        calls.push(c);
+                        add_lookups(l);
+                    }
+                    ClauseConstraint::Ordered => hints.ordered = true,
+                    ClauseConstraint::Strategy(i, s) => hints.strategies.push((i, s)),
                 }
             }
 
-            (clauses, constraints)
+            (clauses, constraints, within, active, exists, not, or, calls, lookups, hints)
         },
     );
 
-    find_spec.and(where_spec)
+    let direction = lex_string("desc").map(|_| true).or(lex_string("asc").map(|_| false));
+    let order = (free_var(), optional(direction))
+        .map(|(var, descending)| Order { var, descending: descending.unwrap_or(false) });
+    let order_spec = lex_string("order").with(lex_string("by")).with(many1(order));
+    let limit_spec = lex_string("limit").with(many1(digit()).skip(spaces()))
+        .and_then(|n: String| n.parse::<usize>());
+    let as_of_spec = lex_string("as").with(lex_string("of")).with(entity().skip(spaces()));
+    let since_spec = lex_string("since").with(entity().skip(spaces()));
+    let history_spec = lex_string("history");
+
+    (many::<Vec<_>, _>(rule_spec), find_spec, optional(with_spec), optional(in_spec), where_spec, optional(as_of_spec), optional(since_spec), optional(history_spec), optional(order_spec), optional(limit_spec))
         // FIXME: add find vars
-        .map(|(find, (clauses, constraints))| Query {
-            find: find,
-            clauses: clauses,
-            constraints: constraints
+        .map(|(rules, (find, expressions, aggregates), with, inputs, (clauses, constraints, within, active, exists, not, or, calls, mut lookups, hints), as_of, since, history, order_by, limit)| {
+            let (rules, rule_lookups): (Vec<Rule>, Vec<Vec<LookupRef>>) = rules.into_iter().unzip();
+            lookups.extend(Iterator::flatten(rule_lookups.into_iter()));
+            // Each distinct lookup ref is bound once, to the var
+            // named by its text.
+            let mut lookup_vars: Vec<(Var, EntitySpec)> = vec![];
+            for l in lookups {
+                let var = Var::new(l.to_string());
+                if !lookup_vars.iter().any(|(v, _)| *v == var) {
+                    lookup_vars.push((var, EntitySpec::Lookup(l)));
+                }
+            }
+            let inputs = inputs.unwrap_or_default();
+            let scalar_inputs = inputs.iter().filter(|(_, scalar)| *scalar).map(|(var, _)| var.clone()).collect();
+            Query {
+                find: find,
+                expressions,
+                aggregates,
+                with: with.unwrap_or_default(),
+                clauses: clauses,
+                constraints: constraints,
+                within,
+                active,
+                exists,
+                not,
+                or,
+                rules,
+                calls,
+                lookups: lookup_vars,
+                inputs: inputs.into_iter().map(|(var, _)| (var, vec![])).collect(),
+                scalar_inputs,
+                hints,
+                order_by: order_by.unwrap_or_default(),
+                limit,
+                as_of,
+                since,
+                history: history.is_some(),
+            }
         })
-        .and(eof())
-        .map(|x| x.0)
 }
 
 fn lex_string<I>(s: &'static str) -> impl Parser<Input = I>
@@ -185,24 +610,88 @@ where
     char(c).skip(spaces())
 }
 
+fn tx_value_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = Value> {
+    string_lit()
+        .or(geo_lit())
+        .or(number_lit().map(|e| Value::Ref(e)))
+        .or(ident().map(|i| Value::Ident(i)))
+        .skip(spaces())
+}
+
+/// A transaction function's argument. There's no attribute to say
+/// what a number is, so it's a long rather than an entity id.
+fn tx_arg_lit<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = Value> {
+    let long = (optional(char('-')), many1(digit())).map(|(sign, digits): (Option<char>, String)| {
+        let n: i64 = digits.parse().unwrap();
+        Value::Long(if sign.is_some() { -n } else { n })
+    });
+    string_lit()
+        .or(geo_lit())
+        .or(long)
+        .or(ident().map(Value::Ident))
+        .skip(spaces())
+}
+
+/// A lookup ref, `[<attribute> <value>]`.
+fn lookup_ref<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = LookupRef> {
+    between(lex_char('['), lex_char(']'), (ident(), tx_value_lit()))
+        .map(|(attribute, value)| LookupRef::new(attribute, value))
+}
+
+/// An entity id, ident or lookup ref.
+fn entity_spec<I: combine::Stream<Item = char>>() -> impl Parser<Input = I, Output = EntitySpec> {
+    number_lit().skip(spaces()).map(EntitySpec::Id)
+        .or(ident().map(EntitySpec::Ident))
+        .or(lookup_ref().map(EntitySpec::Lookup))
+}
+
+/// Parses a column in `find`: a var, a string or number literal, or
+/// a function call `(name arg...)` whose arguments are expressions
+/// too.
+fn expression<I>(input: I) -> ParseResult<Expression, I>
+where
+    I: combine::Stream<Item = char>,
+{
+    let call = between(lex_char('('), lex_char(')'), (ident(), many(parser(expression))))
+        .map(|(name, args)| Expression::Call(name, args));
+
+    free_var().map(Expression::Var)
+        .or(call)
+        .or(string_lit().or(number_lit().map(Value::Ref)).skip(spaces()).map(Expression::Literal))
+        .parse_stream(input)
+}
+
+/// Parses an attribute value in a new entity map: a plain value, a
+/// nested entity map, or a vector of either.
+fn tx_value<I>(input: I) -> ParseResult<TxValue, I>
+where
+    I: combine::Stream<Item = char>,
+{
+    let entity_map = between(
+        lex_char('{'),
+        lex_char('}'),
+        many1::<HashMap<_, _>, _>((ident(), parser(tx_value))),
+    ).map(TxValue::Entity);
+    let vector = between(lex_char('['), lex_char(']'), many(parser(tx_value))).map(TxValue::Many);
+
+    entity_map
+        .or(vector)
+        .or(tx_value_lit().map(TxValue::Value))
+        .parse_stream(input)
+}
+
 fn tx_parser<I>() -> impl Parser<Input = I, Output = Tx>
 where
     I: combine::Stream<Item = char>,
 {
-    let entity = || number_lit().skip(spaces());
-    let value = || {
-        string_lit()
-            .or(number_lit().map(|e| Value::Ref(e)))
-            .or(ident().map(|i| Value::Ident(i)))
-            .skip(spaces())
-    };
+    let value = || tx_value_lit().map(TxValue::Value).or(lookup_ref().map(TxValue::Lookup));
 
     let fact = || {
-        between(lex_char('('), lex_char(')'), (entity(), ident(), value()))
-            .map(|f| Fact::new(f.0, f.1, f.2))
+        between(lex_char('('), lex_char(')'), (entity_spec(), ident(), value()))
+            .map(|f| LookupFact::new(f.0, f.1, f.2))
     };
 
-    let attr_pair = || (ident(), value());
+    let attr_pair = || (ident(), parser(tx_value));
     let new_entity = || {
         between(
             lex_char('{'),
@@ -211,21 +700,31 @@ where
         ).map(|x| TxItem::NewEntity(x))
     };
 
+    // Facts without lookup refs are plain additions and retractions.
     let addition = || {
-        lex_string("add")
-            .and(fact().map(|i| TxItem::Addition(i)))
-            .map(|x| x.1)
+        lex_string("add").with(fact()).map(|f| match f {
+            LookupFact { entity: EntitySpec::Id(e), attribute, value: TxValue::Value(v) } => TxItem::Addition(Fact::new(e, attribute, v)),
+            f => TxItem::LookupAddition(f),
+        })
     };
     let retraction = || {
-        lex_string("retract")
-            .and(fact().map(|i| TxItem::Retraction(i)))
-            .map(|x| x.1)
+        lex_string("retract").with(fact()).map(|f| match f {
+            LookupFact { entity: EntitySpec::Id(e), attribute, value: TxValue::Value(v) } => TxItem::Retraction(Fact::new(e, attribute, v)),
+            f => TxItem::LookupRetraction(f),
+        })
     };
 
-    let tx_item = || choice!(addition(), retraction(), new_entity());
+    // A call to a transaction function, `call (<name> <arg>...)`.
+    let function = || {
+        lex_string("call")
+            .with(between(lex_char('('), lex_char(')'), (ident(), many(tx_arg_lit()))))
+            .map(|(name, args)| TxItem::Function(name, args))
+    };
+
+    let tx_item = || choice!(addition(), retraction(), new_entity(), function());
 
     many1::<Vec<_>, _>(tx_item())
-        .map(|tx| Tx { items: tx })
+        .map(|tx| Tx { items: tx, idempotency_key: None, return_datoms: false })
         .and(eof())
         .map(|x| x.0)
 }
@@ -233,6 +732,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use queries::query::{AggregateFunction, QueryInput};
 
     #[test]
     fn test_parse_query() {
@@ -240,6 +740,9 @@ mod tests {
             parse_query("find ?a where (?a name \"Bob\") (> ?age 50) (?a age ?age)").unwrap(),
             Query {
                 find: vec![Var::new("a")],
+                expressions: vec![],
+                aggregates: vec![],
+                with: vec![],
                 clauses: vec![
                     Clause::new(
                         Term::Unbound("a".into()),
@@ -259,6 +762,22 @@ mod tests {
                         right_hand_side: Term::Bound(Value::Ref(Entity(50))),
                     },
                 ],
+                within: vec![],
+                active: vec![],
+                exists: vec![],
+                not: vec![],
+                or: vec![],
+                rules: vec![],
+                calls: vec![],
+                lookups: vec![],
+                inputs: vec![],
+                scalar_inputs: vec![],
+                hints: Hints::default(),
+                order_by: vec![],
+                limit: None,
+                as_of: None,
+                since: None,
+                history: false,
             }
         )
     }
@@ -273,15 +792,305 @@ mod tests {
                         Fact::new(Entity(0), "name", Value::String("Bob".into()))
                     ),
                 ],
+                idempotency_key: None,
+                return_datoms: false,
             }
         );
         parse_tx("{name \"Bob\" batch \"S1'17\"}").unwrap();
     }
 
+    #[test]
+    fn test_parse_nested_tx() {
+        let tx = parse_tx(r#"{name "Bob" address {street "X" city "Y"} friends [12 {name "Al"}]}"#).unwrap();
+        let address: HashMap<String, TxValue> = vec![
+            ("street".to_string(), TxValue::from("X")),
+            ("city".to_string(), TxValue::from("Y")),
+        ].into_iter().collect();
+        let friend: HashMap<String, TxValue> = vec![("name".to_string(), TxValue::from("Al"))].into_iter().collect();
+        let expected: HashMap<String, TxValue> = vec![
+            ("name".to_string(), TxValue::from("Bob")),
+            ("address".to_string(), TxValue::Entity(address)),
+            ("friends".to_string(), TxValue::Many(vec![TxValue::from(Entity(12)), TxValue::Entity(friend)])),
+        ].into_iter().collect();
+        assert_eq!(tx.items, vec![TxItem::NewEntity(expected)]);
+    }
+
+    #[test]
+    fn test_parse_tx_function() {
+        let tx = parse_tx(r#"call (db:cas 11 name "Bob" "Robert") add (12 nickname "Jo")"#).unwrap();
+        assert_eq!(tx.items[0], TxItem::Function("db:cas".into(), vec![
+            Value::Long(11),
+            Value::Ident("name".into()),
+            Value::String("Bob".into()),
+            Value::String("Robert".into()),
+        ]));
+        assert_eq!(tx.items.len(), 2);
+
+        let tx = parse_tx("call (db:inc 11 balance -5)").unwrap();
+        assert_eq!(tx.items[0], TxItem::Function("db:inc".into(), vec![
+            Value::Long(11),
+            Value::Ident("balance".into()),
+            Value::Long(-5),
+        ]));
+    }
+
+    #[test]
+    fn test_parse_lookup_refs() {
+        let tx = parse_tx(r#"add ([email "bob@x.com"] friend [email "al@x.com"]) retract (12 nickname "Bob")"#).unwrap();
+        assert_eq!(tx.items, vec![
+            TxItem::LookupAddition(LookupFact::new(
+                LookupRef::new("email", "bob@x.com"),
+                "friend",
+                TxValue::Lookup(LookupRef::new("email", "al@x.com")),
+            )),
+            TxItem::Retraction(Fact::new(Entity(12), "nickname", "Bob")),
+        ]);
+
+        let q = parse_query(r#"find ?n where ([email "bob@x.com"] friend ?f) (?f nickname ?n) (not ?f [email "bob@x.com"])"#).unwrap();
+        let var = Var::new(r#"[email "bob@x.com"]"#);
+        assert_eq!(q.lookups, vec![(var.clone(), EntitySpec::Lookup(LookupRef::new("email", "bob@x.com")))]);
+        assert_eq!(q.clauses[0].entity, Term::Unbound(var.clone()));
+        assert_eq!(q.constraints[0].right_hand_side, Term::Unbound(var));
+
+        assert_eq!(parse_entity_spec(" 12").unwrap(), EntitySpec::Id(Entity(12)));
+        assert_eq!(parse_entity_spec("color:red").unwrap(), EntitySpec::Ident("color:red".into()));
+        assert_eq!(parse_entity_spec(r#"[email "bob@x.com"]"#).unwrap(), EntitySpec::Lookup(LookupRef::new("email", "bob@x.com")));
+        assert!(parse_entity_spec("?e").is_err());
+    }
+
+    #[test]
+    fn test_parse_collection_input() {
+        let q = parse_query("find ?p in [?name ...] where (?p name ?name)").unwrap();
+        assert_eq!(q.inputs, vec![(Var::new("name"), vec![])]);
+        assert_eq!(q.find, vec![Var::new("p")]);
+
+        let q = q.bind_inputs(vec![vec!["b".into(), "a".into(), "b".into()]]).unwrap();
+        assert_eq!(q.inputs, vec![(Var::new("name"), vec!["a".into(), "b".into()])]);
+        assert!(parse_query("find ?p in [?name ...] where (?p name ?name)").unwrap().bind_inputs(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_parse_with() {
+        let q = parse_query("find ?s with ?e ?d where (?e salary ?s) (?e dept ?d)").unwrap();
+        assert_eq!(q.find, vec![Var::new("s")]);
+        assert_eq!(q.with, vec![Var::new("e"), Var::new("d")]);
+        assert!(parse_query("find ?s where (?e salary ?s)").unwrap().with.is_empty());
+        assert!(parse_query("find ?s with where (?e salary ?s)").is_err());
+        assert!(parse_query_with("find ?s with {} where (?e salary ?s)", &[Value::Long(1)]).is_err());
+    }
+
+    #[test]
+    fn test_parse_scalar_input() {
+        let q = parse_query("find ?p in ?name [?age ...] where (?p name ?name) (?p age ?age)").unwrap();
+        assert_eq!(q.inputs, vec![(Var::new("name"), vec![]), (Var::new("age"), vec![])]);
+        assert_eq!(q.scalar_inputs, vec![Var::new("name")]);
+
+        let bound = q.clone().bind(vec![QueryInput::Scalar("Bob".into()), QueryInput::Collection(vec![Value::Long(3)])]).unwrap();
+        assert_eq!(bound.inputs, vec![(Var::new("name"), vec!["Bob".into()]), (Var::new("age"), vec![Value::Long(3)])]);
+        assert!(q.clone().bind(vec![QueryInput::Collection(vec!["Bob".into()]), QueryInput::Collection(vec![])]).is_err());
+        assert!(q.clone().bind(vec![QueryInput::Scalar("Bob".into()), QueryInput::Scalar(Value::Long(3))]).is_err());
+        assert!(q.bind(vec![QueryInput::Scalar("Bob".into())]).is_err());
+    }
+
+    #[test]
+    fn test_parse_order_by() {
+        let q = parse_query("find ?p ?n where (?p name ?n) order by ?n desc ?p").unwrap();
+        assert_eq!(q.order_by, vec![Order::desc("n"), Order::asc("p")]);
+        assert!(parse_query("find ?p where (?p name ?n) order by").is_err());
+
+        let q = parse_query("find ?p where (?p name ?n) order by ?n limit 10").unwrap();
+        assert_eq!(q.limit, Some(10));
+        assert_eq!(parse_query("find ?p where (?p name ?n) limit 0").unwrap().limit, Some(0));
+        assert!(parse_query("find ?p where (?p name ?n) limit ten").is_err());
+        assert!(parse_query("find ?p where (?p name ?n) limit 1 order by ?n").is_err());
+
+        let q = parse_query("find ?p where (?p name ?n) as of 1042 order by ?n limit 1").unwrap();
+        assert_eq!((q.as_of, q.limit), (Some(Entity(1042)), Some(1)));
+        assert_eq!(parse_query("find ?p where (?p name ?n)").unwrap().as_of, None);
+        assert!(parse_query("find ?p where (?p name ?n) as of").is_err());
+        assert!(parse_query("find ?p where (?p name ?n) limit 1 as of 1042").is_err());
+
+        let q = parse_query("find ?p where (?p name ?n) as of 1042 since 1000").unwrap();
+        assert_eq!((q.as_of, q.since), (Some(Entity(1042)), Some(Entity(1000))));
+        assert!(parse_query("find ?p where (?p name ?n) since").is_err());
+
+        let q = parse_query("find ?v ?tx where (?p name ?v ?tx false) since 1000 history").unwrap();
+        assert!(q.history && !parse_query("find ?p where (?p name ?n)").unwrap().history);
+        assert_eq!(q.clauses, vec![
+            Clause::new(Term::Unbound("p".into()), Term::Bound(Ident::Name("name".into())), Term::Unbound("v".into()))
+                .with_tx(Term::Unbound("tx".into()))
+                .with_added(Term::Bound(false)),
+        ]);
+        assert_eq!(q.clauses[0].to_string(), "(?p name ?v ?tx false)");
+        let q = parse_query("find ?v where (?p name ?v 1042)").unwrap();
+        assert_eq!(q.clauses[0].tx, Some(Term::Bound(Entity(1042))));
+        assert_eq!(q.clauses[0].added, None);
+
+        match parse_input("\\explain find ?p where (?p name ?n) order by ?p") {
+            Ok(Input::Explain(q)) => assert_eq!(q.order_by, vec![Order::asc("p")]),
+            _ => panic!("expected an explain"),
+        }
+    }
+
+    #[test]
+    fn test_parse_find_expressions() {
+        let q = parse_query(r#"find ?p (str ?first " " ?last) where (?p first ?first) (?p last ?last)"#).unwrap();
+        let expression = Expression::Call("str".into(), vec![
+            Expression::Var(Var::new("first")),
+            Expression::Literal(Value::String(" ".into())),
+            Expression::Var(Var::new("last")),
+        ]);
+        assert_eq!(q.find, vec![Var::new("p"), Var::new(r#"(str ?first " " ?last)"#)]);
+        assert_eq!(q.expressions, vec![(Var::new(r#"(str ?first " " ?last)"#), expression)]);
+        assert!(parse_query("find (str ?a where (?p a ?a)").is_err());
+    }
+
+    #[test]
+    fn test_parse_aggregates() {
+        let q = parse_query("find ?a (count ?b) where (?a parent ?b)").unwrap();
+        assert_eq!(q.find, vec![Var::new("a"), Var::new("(count ?b)")]);
+        assert_eq!(q.aggregates, vec![(Var::new("(count ?b)"), Aggregate { function: AggregateFunction::Count, var: Var::new("b") })]);
+        assert!(q.expressions.is_empty());
+
+        assert!(parse_query("find (str (count ?b)) where (?a parent ?b)").unwrap().check_expressions().is_err());
+        assert!(parse_query("find (sum ?a ?b) where (?a parent ?b)").unwrap().check_expressions().is_err());
+    }
+
+    #[test]
+    fn test_parse_hints() {
+        let q = parse_query("find ?p where (?p name ?n) (?p age ?a) (hint fetch 1) (hint lookup 0) (hint ordered)").unwrap();
+        assert_eq!(q.clauses.len(), 2);
+        assert!(q.hints.ordered);
+        assert_eq!(q.hints.strategies, vec![(1, Strategy::Fetch), (0, Strategy::Lookup)]);
+        assert_eq!(q.hints.strategy(1), Some(Strategy::Fetch));
+        q.check_hints().unwrap();
+
+        let q = parse_query("find ?p where (?p name ?n) (hint fetch 3)").unwrap();
+        assert!(q.check_hints().is_err());
+    }
+
+    #[test]
+    fn test_parse_exists() {
+        let q = parse_query("find ?p where (?p name ?n) (exists (?p order ?o))").unwrap();
+        assert_eq!(q.clauses.len(), 1);
+        assert_eq!(q.exists, vec![Clause::new(
+            Term::Unbound("p".into()),
+            Term::Bound(Ident::Name("order".into())),
+            Term::Unbound("o".into()),
+        )]);
+    }
+
+    #[test]
+    fn test_parse_not() {
+        let q = parse_query("find ?p where (?p name ?n) (not (?p order ?o)) (not ?n ?o)").unwrap();
+        assert_eq!(q.not, vec![Clause::new(
+            Term::Unbound("p".into()),
+            Term::Bound(Ident::Name("order".into())),
+            Term::Unbound("o".into()),
+        )]);
+        assert_eq!(q.constraints.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let q = parse_query("rule ancestor(?a ?b) :- (?a parent ?b)
+                             rule ancestor(?a ?c) :- (?a parent ?b) ancestor(?b ?c)
+                             find ?x where ancestor(13 ?x) order by ?x").unwrap();
+        assert_eq!(q.rules.len(), 2);
+        assert_eq!(q.rules[1].params, vec![Var::new("a"), Var::new("c")]);
+        assert_eq!(q.rules[1].clauses.len(), 1);
+        assert_eq!(q.rules[1].calls[0].to_string(), "ancestor(?b ?c)");
+        assert_eq!(q.calls, vec![RuleCall {
+            name: "ancestor".into(),
+            args: vec![Term::Bound(Value::Ref(Entity(13))), Term::Unbound("x".into())],
+        }]);
+        assert_eq!(q.order_by.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_set() {
+        match parse_input("\\set resolve-refs on") {
+            Ok(Input::Set(option, value)) => assert_eq!((option.as_str(), value.as_str()), ("resolve-refs", "on")),
+            _ => panic!("expected a \\set command"),
+        }
+        assert!(matches!(parse_input("\\schema"), Ok(Input::Schema)));
+        assert!(matches!(parse_input("\\reindex"), Ok(Input::Reindex)));
+        assert!(matches!(parse_input("\\tx 1042"), Ok(Input::TxData(Entity(1042)))));
+    }
+
+    #[test]
+    fn test_parse_copy() {
+        match parse_input("\\copy (find ?n where (?p name ?n)) to 'names.csv'") {
+            Ok(Input::CopyTo(q, path)) => {
+                assert_eq!(q, parse_query("find ?n where (?p name ?n)").unwrap());
+                assert_eq!(path, "names.csv");
+            }
+            _ => panic!("expected \\copy to"),
+        }
+        match parse_input("\\copy 'people.csv' to (name email)") {
+            Ok(Input::CopyFrom(path, attributes)) => {
+                assert_eq!(path, "people.csv");
+                assert_eq!(attributes, vec!["name", "email"]);
+            }
+            _ => panic!("expected \\copy from"),
+        }
+        assert!(matches!(parse_input("\\dump 'db.datoms'"), Ok(Input::DumpTo(ref path)) if path == "db.datoms"));
+        assert!(matches!(parse_input("\\load 'db.datoms'"), Ok(Input::Load(ref path)) if path == "db.datoms"));
+    }
+
+    #[test]
+    fn test_parse_run() {
+        match parse_input("\\run people:byName \"Bob\" 12") {
+            Ok(Input::Run(name, params)) => {
+                assert_eq!(name, "people:byName");
+                assert_eq!(params, vec![Value::String("Bob".into()), Value::Ref(Entity(12))]);
+            }
+            _ => panic!("expected \\run"),
+        }
+        assert!(matches!(parse_input("\\run everyone"), Ok(Input::Run(_, ref p)) if p.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_query_with_params() {
+        let injected = "\") (?a password ?p";
+        let q = parse_query_with(
+            "find ?a where (?a name {}) (?a parent {}) (> ?age {}) (?a age ?age)",
+            &[Value::String(injected.into()), Value::Ref(Entity(12)), Value::Long(30)],
+        ).unwrap();
+        assert_eq!(q.clauses[0].value, Term::Bound(Value::String(injected.into())));
+        assert_eq!(q.clauses[1].value, Term::Bound(Value::Ref(Entity(12))));
+        assert_eq!(q.constraints[0].right_hand_side, Term::Bound(Value::Long(30)));
+        assert_eq!(q.clauses.len(), 3);
+
+        assert!(parse_query_with("find ?a where (?a name {})", &[]).is_err());
+        assert!(parse_query_with("find ?a where ({} name \"Bob\")", &[Value::String("1".into())]).is_err());
+    }
+
+    #[test]
+    fn test_parse_within() {
+        let q = parse_query("find ?p where (?p name ?n) (within ?p location 40.7 -74.0 1500)").unwrap();
+        assert_eq!(
+            q.within,
+            vec![
+                Within {
+                    entity: Var::new("p"),
+                    attribute: Ident::Name("location".into()),
+                    center: GeoPoint::new(40.7, -74.0),
+                    radius: 1500,
+                },
+            ]
+        );
+
+        parse_tx("add (0 location #geo(40.7128 -74.006))").unwrap();
+    }
+
     #[test]
     fn test_parsing_idents() {
         let q = Query {
             find: vec![Var::new("p")],
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
             clauses: vec![
                 Clause::new(
                     Term::Unbound("p".into()),
@@ -290,6 +1099,22 @@ mod tests {
                 ),
             ],
             constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
         };
 
         assert_eq!(
@@ -0,0 +1,245 @@
+//! A minimal Postgres wire-protocol (v3) frontend, so that BI tools
+//! and `psql` can query a database with the SQL subset understood by
+//! the `sql` module.
+//!
+//! Only the simple query protocol is supported, without
+//! authentication or TLS; every value is sent in text format.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{info, error};
+
+use {Error, Result, Value};
+use conn::Conn;
+use schema::ValueType;
+use sql::{execute_sql, Column};
+
+const PROTOCOL_VERSION: i32 = 196_608;
+const SSL_REQUEST_CODE: i32 = 80_877_103;
+const CANCEL_REQUEST_CODE: i32 = 80_877_102;
+
+pub struct SqlServer {
+    conn: Arc<Mutex<Conn>>,
+}
+
+impl SqlServer {
+    pub fn new(conn: Conn) -> SqlServer {
+        SqlServer { conn: Arc::new(Mutex::new(conn)) }
+    }
+
+    pub fn listen(&self, bind_address: &str) -> Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(bind_address)?;
+        info!("Listening for SQL clients on {}", bind_address);
+        Ok(self.serve(listener))
+    }
+
+    /// Accepts clients on `listener`, handling each on its own thread.
+    pub fn serve(&self, listener: TcpListener) -> thread::JoinHandle<()> {
+        let conn = self.conn.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let conn = conn.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, &conn) {
+                                error!("SQL client error: {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("failed to accept SQL client: {}", e),
+                }
+            }
+        })
+    }
+}
+
+fn read_i32<R: Read>(r: &mut R) -> io::Result<i32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+/// Reads the body of a message whose length (which includes the
+/// length field itself) has just been read.
+fn read_body<R: Read>(r: &mut R, len: i32) -> Result<Vec<u8>> {
+    if !(4..=1 << 24).contains(&len) {
+        return Err(format!("invalid message length {}", len).into());
+    }
+    let mut body = vec![0; len as usize - 4];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Reads the startup packet, declining SSL if it is requested.
+/// Returns false if the client doesn't want a session.
+fn startup(stream: &mut TcpStream) -> Result<bool> {
+    loop {
+        let len = read_i32(stream)?;
+        let body = read_body(stream, len)?;
+        if body.len() < 4 {
+            return Err("startup packet too short".into());
+        }
+        let code = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+
+        match code {
+            SSL_REQUEST_CODE => stream.write_all(b"N")?,
+            CANCEL_REQUEST_CODE => return Ok(false),
+            PROTOCOL_VERSION => return Ok(true),
+            _ => return Err(format!("unsupported protocol version {}", code).into()),
+        }
+    }
+}
+
+fn handle_client(mut stream: TcpStream, conn: &Mutex<Conn>) -> Result<()> {
+    if !startup(&mut stream)? {
+        return Ok(());
+    }
+
+    let mut out = vec![];
+    message(&mut out, b'R', &0i32.to_be_bytes());
+    parameter_status(&mut out, "server_version", "9.6.0");
+    parameter_status(&mut out, "client_encoding", "UTF8");
+    parameter_status(&mut out, "DateStyle", "ISO");
+    message(&mut out, b'Z', b"I");
+    stream.write_all(&out)?;
+
+    loop {
+        let mut tag = [0; 1];
+        if stream.read(&mut tag)? == 0 {
+            return Ok(());
+        }
+        let len = read_i32(&mut stream)?;
+        let body = read_body(&mut stream, len)?;
+
+        let mut out = vec![];
+        match tag[0] {
+            b'Q' => {
+                let sql = String::from_utf8_lossy(&body);
+                let sql = sql.trim_end_matches('\0').trim();
+                if sql.trim_end_matches(';').trim().is_empty() {
+                    message(&mut out, b'I', &[]);
+                } else {
+                    let result = conn.lock()
+                        .map_err(|_| Error::from("connection lock poisoned"))
+                        .and_then(|mut conn| conn.db())
+                        .and_then(|db| execute_sql(sql, &db));
+                    match result {
+                        Ok((columns, relation)) => {
+                            row_description(&mut out, &columns);
+                            for tuple in relation.1.iter() {
+                                data_row(&mut out, tuple);
+                            }
+                            message(&mut out, b'C', format!("SELECT {}\0", relation.1.len()).as_bytes());
+                        }
+                        Err(e) => error_response(&mut out, &e.message()),
+                    }
+                }
+                message(&mut out, b'Z', b"I");
+            }
+            b'X' => return Ok(()),
+            _ => {
+                error_response(&mut out, "only the simple query protocol is supported");
+                message(&mut out, b'Z', b"I");
+            }
+        }
+        stream.write_all(&out)?;
+    }
+}
+
+fn message(out: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    out.push(tag);
+    out.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn cstring(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+fn parameter_status(out: &mut Vec<u8>, name: &str, value: &str) {
+    let mut body = vec![];
+    cstring(&mut body, name);
+    cstring(&mut body, value);
+    message(out, b'S', &body);
+}
+
+fn error_response(out: &mut Vec<u8>, msg: &str) {
+    let mut body = vec![];
+    for &(field, value) in &[(b'S', "ERROR"), (b'V', "ERROR"), (b'C', "XX000"), (b'M', msg)] {
+        body.push(field);
+        cstring(&mut body, value);
+    }
+    body.push(0);
+    message(out, b'E', &body);
+}
+
+/// The Postgres type oid and size for a column.
+fn pg_type(value_type: &Option<ValueType>) -> (i32, i16) {
+    match *value_type {
+        None | Some(ValueType::Ref) | Some(ValueType::Long) => (20, 8),
+        Some(ValueType::Boolean) => (16, 1),
+        Some(ValueType::Timestamp) => (1184, 8),
+        Some(ValueType::String) | Some(ValueType::Ident) | Some(ValueType::Geo) => (25, -1),
+    }
+}
+
+fn row_description(out: &mut Vec<u8>, columns: &[Column]) {
+    let mut body = vec![];
+    body.extend_from_slice(&(columns.len() as i16).to_be_bytes());
+    for column in columns {
+        let (oid, size) = pg_type(&column.value_type);
+        cstring(&mut body, &column.name);
+        body.extend_from_slice(&0i32.to_be_bytes()); // table oid
+        body.extend_from_slice(&0i16.to_be_bytes()); // column number
+        body.extend_from_slice(&oid.to_be_bytes());
+        body.extend_from_slice(&size.to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+        body.extend_from_slice(&0i16.to_be_bytes()); // text format
+    }
+    message(out, b'T', &body);
+}
+
+fn text_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) | Value::Ident(ref s) => s.clone(),
+        Value::Ref(e) => e.0.to_string(),
+        Value::Long(l) => l.to_string(),
+        Value::Boolean(b) => if b { "t".into() } else { "f".into() },
+        Value::Timestamp(t) => t.to_rfc3339(),
+        Value::Geo(ref g) => format!("POINT({} {})", g.lon(), g.lat()),
+    }
+}
+
+fn data_row(out: &mut Vec<u8>, tuple: &[Value]) {
+    let mut body = vec![];
+    body.extend_from_slice(&(tuple.len() as i16).to_be_bytes());
+    for value in tuple {
+        let text = text_value(value);
+        body.extend_from_slice(&(text.len() as i32).to_be_bytes());
+        body.extend_from_slice(text.as_bytes());
+    }
+    message(out, b'D', &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Entity;
+
+    #[test]
+    fn test_data_row_encoding() {
+        let mut out = vec![];
+        data_row(&mut out, &[Value::Ref(Entity(7)), Value::Boolean(true)]);
+        assert_eq!(out, vec![
+            b'D', 0, 0, 0, 16, // tag and length
+            0, 2, // column count
+            0, 0, 0, 1, b'7',
+            0, 0, 0, 1, b't',
+        ]);
+    }
+}
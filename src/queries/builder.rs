@@ -0,0 +1,298 @@
+//! A fluent API for building queries in code. Values are passed as
+//! typed `Value`s and never parsed, so input from users can't change
+//! the structure of the query the way it can when it's formatted into
+//! a query string.
+//!
+//! ```ignore
+//! let q = QueryBuilder::find(&["?n"])
+//!     .where_clause("?p", "name", var("?n"))
+//!     .where_clause("?p", "email", user_input)
+//!     .build()?;
+//! ```
+
+use {Entity, Error, Ident, Result, Value};
+use geo::GeoPoint;
+use queries::query::{Clause, Constraint, Hints, Order, Query, Term, Var, Within};
+pub use queries::query::{Comparator, Strategy};
+
+/// Creates a variable, with or without the leading `?`. In value
+/// positions, variables have to be given this way: strings there are
+/// always values.
+pub fn var(name: &str) -> Var {
+    Var::new(name.trim_start_matches('?'))
+}
+
+/// Conversion of builder arguments into clause terms.
+///
+/// In entity and attribute positions a string starting with `?` is a
+/// variable (neither entity ids nor idents can start with one). In
+/// value positions, anything convertible into a `Value` is bound as
+/// that value, and variables must be given as a `Var`.
+pub trait IntoTerm<T> {
+    fn into_term(self) -> Result<Term<T>>;
+}
+
+impl IntoTerm<Entity> for &str {
+    fn into_term(self) -> Result<Term<Entity>> {
+        if self.starts_with('?') {
+            Ok(Term::Unbound(var(self)))
+        } else {
+            Err(format!("{:?} is not a variable; pass an Entity to bind the entity position", self).into())
+        }
+    }
+}
+
+impl IntoTerm<Entity> for Entity {
+    fn into_term(self) -> Result<Term<Entity>> {
+        Ok(Term::Bound(self))
+    }
+}
+
+impl IntoTerm<Entity> for Var {
+    fn into_term(self) -> Result<Term<Entity>> {
+        Ok(Term::Unbound(self))
+    }
+}
+
+impl IntoTerm<Ident> for &str {
+    fn into_term(self) -> Result<Term<Ident>> {
+        if self.starts_with('?') {
+            Ok(Term::Unbound(var(self)))
+        } else {
+            Ok(Term::Bound(Ident::Name(self.to_string())))
+        }
+    }
+}
+
+impl IntoTerm<Ident> for Entity {
+    fn into_term(self) -> Result<Term<Ident>> {
+        Ok(Term::Bound(Ident::Entity(self)))
+    }
+}
+
+impl IntoTerm<Ident> for Var {
+    fn into_term(self) -> Result<Term<Ident>> {
+        Ok(Term::Unbound(self))
+    }
+}
+
+impl<V: Into<Value>> IntoTerm<Value> for V {
+    fn into_term(self) -> Result<Term<Value>> {
+        Ok(Term::Bound(self.into()))
+    }
+}
+
+impl IntoTerm<Value> for Var {
+    fn into_term(self) -> Result<Term<Value>> {
+        Ok(Term::Unbound(self))
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryBuilder {
+    query: Query,
+    /// The first invalid argument, reported by `build`.
+    error: Option<Error>,
+}
+
+impl QueryBuilder {
+    /// Starts a query returning the given variables.
+    pub fn find(vars: &[&str]) -> QueryBuilder {
+        QueryBuilder {
+            query: Query {
+                find: vars.iter().map(|v| var(v)).collect(),
+                expressions: vec![],
+                aggregates: vec![],
+                with: vec![],
+                clauses: vec![],
+                constraints: vec![],
+                within: vec![],
+                active: vec![],
+                exists: vec![],
+                not: vec![],
+                or: vec![],
+                rules: vec![],
+                calls: vec![],
+                lookups: vec![],
+                inputs: vec![],
+                scalar_inputs: vec![],
+                hints: Hints::default(),
+                order_by: vec![],
+                limit: None,
+                as_of: None,
+                since: None,
+                history: false,
+            },
+            error: None,
+        }
+    }
+
+    pub fn where_clause<E, A, V>(mut self, entity: E, attribute: A, value: V) -> QueryBuilder
+        where E: IntoTerm<Entity>, A: IntoTerm<Ident>, V: IntoTerm<Value>
+    {
+        match (entity.into_term(), attribute.into_term(), value.into_term()) {
+            (Ok(e), Ok(a), Ok(v)) => self.query.clauses.push(Clause::new(e, a, v)),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => self.fail(e),
+        }
+        self
+    }
+
+    pub fn constraint<L, R>(mut self, lhs: L, comparator: Comparator, rhs: R) -> QueryBuilder
+        where L: IntoTerm<Value>, R: IntoTerm<Value>
+    {
+        match (lhs.into_term(), rhs.into_term()) {
+            (Ok(left_hand_side), Ok(right_hand_side)) => self.query.constraints.push(Constraint {
+                comparator,
+                left_hand_side,
+                right_hand_side,
+            }),
+            (Err(e), _) | (_, Err(e)) => self.fail(e),
+        }
+        self
+    }
+
+    /// Restricts `entity` to those whose geo-typed `attribute` is
+    /// within `radius` meters of `center`.
+    pub fn within(mut self, entity: &str, attribute: &str, center: GeoPoint, radius: u64) -> QueryBuilder {
+        self.query.within.push(Within {
+            entity: var(entity),
+            attribute: Ident::Name(attribute.to_string()),
+            center,
+            radius,
+        });
+        self
+    }
+
+    /// Excludes soft-deleted entities from `entity`.
+    pub fn active(mut self, entity: &str) -> QueryBuilder {
+        self.query.active.push(var(entity));
+        self
+    }
+
+    /// Binds `var` to each of `values` in turn, like
+    /// `in [?var ...]` in the query language.
+    pub fn input(mut self, var: &str, mut values: Vec<Value>) -> QueryBuilder {
+        values.sort();
+        values.dedup();
+        self.query.inputs.push((self::var(var), values));
+        self
+    }
+
+    /// Keeps only the rows for which the clause matches something,
+    /// without binding its other vars.
+    pub fn exists<E, A, V>(mut self, entity: E, attribute: A, value: V) -> QueryBuilder
+        where E: IntoTerm<Entity>, A: IntoTerm<Ident>, V: IntoTerm<Value>
+    {
+        match (entity.into_term(), attribute.into_term(), value.into_term()) {
+            (Ok(e), Ok(a), Ok(v)) => self.query.exists.push(Clause::new(e, a, v)),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => self.fail(e),
+        }
+        self
+    }
+
+    /// Keeps only the rows for which the clause matches nothing.
+    pub fn not<E, A, V>(mut self, entity: E, attribute: A, value: V) -> QueryBuilder
+        where E: IntoTerm<Entity>, A: IntoTerm<Ident>, V: IntoTerm<Value>
+    {
+        match (entity.into_term(), attribute.into_term(), value.into_term()) {
+            (Ok(e), Ok(a), Ok(v)) => self.query.not.push(Clause::new(e, a, v)),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => self.fail(e),
+        }
+        self
+    }
+
+    /// Pins the strategy for the clause added `clause`-th (from 0),
+    /// like `(hint fetch <n>)` in the query language.
+    pub fn hint(mut self, clause: usize, strategy: Strategy) -> QueryBuilder {
+        self.query.hints.strategies.push((clause, strategy));
+        self
+    }
+
+    /// Sorts the results by `var`, after any earlier sort keys, like
+    /// `order by ?var` in the query language.
+    pub fn order_by(mut self, var: &str) -> QueryBuilder {
+        self.query.order_by.push(Order::asc(self::var(var)));
+        self
+    }
+
+    /// Like `order_by`, but sorts in descending order.
+    pub fn order_by_desc(mut self, var: &str) -> QueryBuilder {
+        self.query.order_by.push(Order::desc(self::var(var)));
+        self
+    }
+
+    /// Returns at most `n` rows, the first by the `order_by` vars if
+    /// there are any, like `limit <n>` in the query language.
+    pub fn limit(mut self, n: usize) -> QueryBuilder {
+        self.query.limit = Some(n);
+        self
+    }
+
+    /// Plans the clauses in the order they were added.
+    pub fn ordered(mut self) -> QueryBuilder {
+        self.query.hints.ordered = true;
+        self
+    }
+
+    pub fn build(self) -> Result<Query> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.query.check_hints()?;
+        if self.query.find.is_empty() {
+            return Err("a query must find at least one variable".into());
+        }
+        if self.query.clauses.is_empty() && self.query.within.is_empty() {
+            return Err("a query must have at least one clause".into());
+        }
+        Ok(self.query)
+    }
+
+    fn fail(&mut self, e: Error) {
+        if self.error.is_none() {
+            self.error = Some(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_query;
+
+    #[test]
+    fn test_builder_matches_parsed_query() {
+        let built = QueryBuilder::find(&["?a"])
+            .where_clause("?a", "name", "Bob")
+            .constraint(var("age"), Comparator::GreaterThan, Entity(50))
+            .where_clause("?a", "age", var("?age"))
+            .build()
+            .unwrap();
+        assert_eq!(built, parse_query("find ?a where (?a name \"Bob\") (> ?age 50) (?a age ?age)").unwrap());
+    }
+
+    #[test]
+    fn test_strings_are_values() {
+        let q = QueryBuilder::find(&["e"])
+            .where_clause("?e", "name", "\") (?e password ?p")
+            .build()
+            .unwrap();
+        assert_eq!(q.clauses.len(), 1);
+        assert_eq!(q.clauses[0].value, Term::Bound(Value::String("\") (?e password ?p".into())));
+
+        assert!(QueryBuilder::find(&["?e"]).where_clause("12", "name", "Bob").build().is_err());
+        assert!(QueryBuilder::find(&[]).where_clause("?e", "name", "Bob").build().is_err());
+    }
+
+    #[test]
+    fn test_hints() {
+        let built = QueryBuilder::find(&["?a"])
+            .where_clause("?a", "name", var("?n"))
+            .hint(0, Strategy::Fetch)
+            .ordered()
+            .build()
+            .unwrap();
+        assert_eq!(built, parse_query("find ?a where (?a name ?n) (hint fetch 0) (hint ordered)").unwrap());
+        assert!(QueryBuilder::find(&["?a"]).where_clause("?a", "name", "Bob").hint(1, Strategy::Lookup).build().is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! The columnar form results take while a plan runs.
+//!
+//! Each var's values are kept in their own column, shared between
+//! plan steps by `Arc`, so projecting or reordering vars never copies
+//! values, and steps which rearrange rows (joins, sorts, products)
+//! work out which rows they want as indices and then gather each
+//! column once. `Relation`, the row-oriented form, is what's handed
+//! in and out of the query engine.
+//!
+//! Columns don't hold values themselves, but ids of values interned
+//! in the query's `ValuePool`, so each distinct value is stored once
+//! however many rows it ends up in, and copying or comparing a cell
+//! for equality (as joins do) never touches a string. Values are
+//! cloned out of the pool once, when the results become a `Relation`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use queries::query::Var;
+use {Relation, Value};
+
+/// A value interned in a `ValuePool`. Within a pool, equal values
+/// have equal ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ValueId(u32);
+
+/// The distinct values seen by a running query. Each is stored once,
+/// shared by its slot and its entry in the lookup table.
+#[derive(Debug, Default)]
+pub struct ValuePool {
+    values: Vec<Arc<Value>>,
+    ids: HashMap<Arc<Value>, ValueId>,
+    /// See `estimated_size`.
+    size: usize,
+}
+
+impl ValuePool {
+    pub fn new() -> ValuePool {
+        ValuePool::default()
+    }
+
+    pub fn intern(&mut self, value: Value) -> ValueId {
+        if let Some(&id) = self.ids.get(&value) {
+            return id;
+        }
+        let id = ValueId(u32::try_from(self.values.len()).expect("too many distinct values in one query"));
+        self.size += pooled_size(&value);
+        let value = Arc::new(value);
+        self.values.push(value.clone());
+        self.ids.insert(value, id);
+        id
+    }
+
+    /// Estimates the bytes of memory the pool takes up, which grows
+    /// with each distinct value interned.
+    pub fn estimated_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, id: ValueId) -> &Value {
+        &self.values[id.0 as usize]
+    }
+}
+
+/// The bytes a value takes up in a pool: its own allocation and
+/// string, and its slot and entry pointing at it.
+fn pooled_size(value: &Value) -> usize {
+    let stored = mem::size_of::<Value>() + 2 * mem::size_of::<usize>();
+    let pointers = 2 * mem::size_of::<Arc<Value>>() + mem::size_of::<ValueId>();
+    stored + pointers + match *value {
+        Value::String(ref s) | Value::Ident(ref s) => s.len(),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Columns {
+    vars: Vec<Var>,
+    columns: Vec<Arc<Vec<ValueId>>>,
+    /// The number of rows, which columns alone can't tell when there
+    /// are none.
+    len: usize,
+}
+
+impl Columns {
+    /// Columns with no vars and a single, empty row: the identity for
+    /// products and joins.
+    pub fn unit() -> Columns {
+        Columns { vars: vec![], columns: vec![], len: 1 }
+    }
+
+    /// Interns a relation's values into `pool`.
+    pub fn from_relation(relation: Relation, pool: &mut ValuePool) -> Columns {
+        let Relation(vars, tuples) = relation;
+        let rows = tuples.into_iter()
+            .map(|tuple| tuple.into_iter().map(|value| pool.intern(value)).collect())
+            .collect();
+        Columns::from_rows(vars, rows)
+    }
+
+    /// Columns of rows of interned values.
+    pub fn from_rows(vars: Vec<Var>, rows: Vec<Vec<ValueId>>) -> Columns {
+        let len = rows.len();
+        let mut columns: Vec<Vec<ValueId>> = vars.iter().map(|_| Vec::with_capacity(len)).collect();
+        for row in rows {
+            for (column, id) in columns.iter_mut().zip(row) {
+                column.push(id);
+            }
+        }
+        Columns { vars, columns: columns.into_iter().map(Arc::new).collect(), len }
+    }
+
+    /// Columns of `len` rows each, in the order of `vars`.
+    pub fn from_columns(vars: Vec<Var>, columns: Vec<Vec<ValueId>>, len: usize) -> Columns {
+        assert!(columns.iter().all(|c| c.len() == len), "columns must have `len` rows");
+        Columns { vars, columns: columns.into_iter().map(Arc::new).collect(), len }
+    }
+
+    pub fn vars(&self) -> &[Var] {
+        &self.vars
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn position(&self, var: &Var) -> Option<usize> {
+        self.vars.iter().position(|v| v == var)
+    }
+
+    pub fn column(&self, idx: usize) -> &[ValueId] {
+        &self.columns[idx]
+    }
+
+    /// The columns at `indices`, in that order. The columns are
+    /// shared, not copied.
+    pub fn select(&self, indices: &[usize]) -> Columns {
+        Columns {
+            vars: indices.iter().map(|&i| self.vars[i].clone()).collect(),
+            columns: indices.iter().map(|&i| self.columns[i].clone()).collect(),
+            len: self.len,
+        }
+    }
+
+    /// The rows at `rows`, in that order, which may repeat rows.
+    pub fn gather(&self, rows: &[usize]) -> Columns {
+        Columns {
+            vars: self.vars.clone(),
+            columns: self.columns.iter().map(|c| Arc::new(rows.iter().map(|&r| c[r]).collect())).collect(),
+            len: rows.len(),
+        }
+    }
+
+    /// Adds `other`'s columns after these. Both must have as many
+    /// rows.
+    pub fn append(mut self, other: Columns) -> Columns {
+        assert_eq!(self.len, other.len, "can't append columns of a different length");
+        self.vars.extend(other.vars);
+        self.columns.extend(other.columns);
+        self
+    }
+
+    /// Adds a column for `var`, which must have a value for each row.
+    pub fn push(&mut self, var: Var, column: Vec<ValueId>) {
+        assert_eq!(self.len, column.len(), "can't add a column of a different length");
+        self.vars.push(var);
+        self.columns.push(Arc::new(column));
+    }
+
+    /// The row at `row`, as interned values.
+    pub fn row(&self, row: usize) -> Vec<ValueId> {
+        self.columns.iter().map(|c| c[row]).collect()
+    }
+
+    /// The rows, as interned values.
+    pub fn into_rows(self) -> (Vec<Var>, Vec<Vec<ValueId>>) {
+        let rows = (0..self.len).map(|row| self.columns.iter().map(|c| c[row]).collect()).collect();
+        (self.vars, rows)
+    }
+
+    /// The rows, with their values cloned out of `pool`.
+    pub fn into_relation(self, pool: &ValuePool) -> Relation {
+        let tuples = (0..self.len)
+            .map(|row| self.columns.iter().map(|c| pool.get(c[row]).clone()).collect())
+            .collect();
+        Relation(self.vars, tuples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_columns() {
+        let mut pool = ValuePool::new();
+        let relation = Relation(vec![Var::new("a"), Var::new("b")], vec![
+            vec![Value::Long(1), Value::String("one".into())],
+            vec![Value::Long(2), Value::String("two".into())],
+            vec![Value::Long(1), Value::String("two".into())],
+        ]);
+        let columns = Columns::from_relation(relation.clone(), &mut pool);
+        assert_eq!(columns.len(), 3);
+        // Repeated values are only stored once.
+        assert_eq!(columns.column(0)[0], columns.column(0)[2]);
+        assert_eq!(columns.column(1)[1], columns.column(1)[2]);
+        assert_eq!(pool.get(columns.column(1)[0]), &Value::String("one".into()));
+        assert_eq!(columns.clone().into_relation(&pool), relation);
+
+        // Selected columns share their values.
+        let b = columns.select(&[1]);
+        assert!(Arc::ptr_eq(&b.columns[0], &columns.columns[1]));
+        assert_eq!(b.gather(&[2, 0]).into_relation(&pool).1, vec![
+            vec![Value::String("two".into())],
+            vec![Value::String("one".into())],
+        ]);
+
+        let product = Columns::unit().append(Columns::unit());
+        assert_eq!(product.into_relation(&pool), Relation(vec![], vec![vec![]]));
+        assert_eq!(Columns::from_relation(Relation(vec![], vec![]), &mut pool).len(), 0);
+    }
+}
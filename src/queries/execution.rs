@@ -1,86 +1,818 @@
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::iter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use im::{HashSet, HashMap};
 use {Result, Value, Error, Relation, Ident};
 use db::Db;
-use queries::query::{Query, Var, Clause, Term, Constraint};
-use queries::planner::{Plan};
+use schema::Collation;
+use queries::query::{Query, QueryInput, Var, Clause, Term, Constraint, Order, Expression, Aggregate, AggregateFunction, Rule, RuleCall};
+use queries::planner::{self, Plan, order_by_selectivity};
+use queries::columns::{Columns, ValueId, ValuePool};
+use queries::spill::{self, JOIN_MEMORY_BUDGET};
+
+/// A flag for stopping a running query from another thread (e.g. a
+/// Ctrl-C handler). The query checks it between fetches, and returns
+/// `Error::Cancelled` once it's set.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
 
 pub fn query(q: Query, db: &Db) -> Result<Relation> {
-    let plan = Plan::for_query(q);
-    execute_plan(&plan, db)
+    query_with_cancel(q, db, &CancelToken::new())
+}
+
+/// Like `query`, but stops early if `cancel` is cancelled.
+pub fn query_with_cancel(q: Query, db: &Db, cancel: &CancelToken) -> Result<Relation> {
+    let db = &as_of(&q, db);
+    let plan = plan_query(q, db)?;
+    execute_plan(&plan, db, cancel)
+}
+
+/// Runs `q` with its inputs bound to `inputs` (see `Query::bind`),
+/// so that values can be passed to a query without formatting them
+/// into its text.
+pub fn query_with_inputs(q: Query, inputs: Vec<QueryInput>, db: &Db) -> Result<Relation> {
+    query(q.bind(inputs)?, db)
+}
+
+/// Like `query`, but produces the result rows as they're pulled
+/// rather than collecting them into a relation first, so a large
+/// result can be printed or written out a row at a time. Lookups,
+/// constraints, semi-joins, projections and limits are pulled
+/// through a row at a time; steps which need all of their input,
+/// such as sorts, joins and aggregates, and the fetch of the first
+/// clause are still run whole. The rows come in `q.find`'s order.
+/// An error, including one planning the query, is the last item.
+pub fn query_iter(q: Query, db: &Db) -> impl Iterator<Item = Result<Vec<Value>>> {
+    query_iter_with_cancel(q, db, &CancelToken::new())
+}
+
+/// Like `query_iter`, but stops early, with `Error::Cancelled` as the
+/// last item, if `cancel` is cancelled.
+pub fn query_iter_with_cancel(q: Query, db: &Db, cancel: &CancelToken) -> impl Iterator<Item = Result<Vec<Value>>> {
+    let db = &as_of(&q, db);
+    let rows = match plan_query(q, db).and_then(|plan| rows(plan, db, cancel)) {
+        Ok((_, rows)) => rows,
+        Err(e) => Box::new(iter::once(Err(e))),
+    };
+    rows.scan(false, |failed, row| {
+        if *failed {
+            return None;
+        }
+        *failed = row.is_err();
+        Some(row)
+    })
+}
+
+/// The db a query runs against: `db` itself, or `db` as of the
+/// query's `as of` transaction and since its `since` one, and its
+/// history if the query asks for it.
+fn as_of(q: &Query, db: &Db) -> Db {
+    let db = match q.as_of {
+        Some(tx) => db.as_of(tx),
+        None => db.clone(),
+    };
+    let db = match q.since {
+        Some(tx) => db.since(tx),
+        None => db,
+    };
+    if q.history { db.history() } else { db }
+}
+
+/// Counts the rows `query` would return, without projecting or
+/// collecting them. A query of a single clause is counted straight
+/// off the index.
+pub fn query_count(q: Query, db: &Db) -> Result<usize> {
+    let db = &as_of(&q, db);
+    let plan = match plan_query(q, db)? {
+        Plan::Project(plan, projection) => {
+            let outputs = plan.outputs();
+            if projection.iter().any(|var| !outputs.contains(var)) {
+                return Err(Error::Message(format!("not all vars found in relation {:?} for projection {:?}", outputs, projection)));
+            }
+            *plan
+        }
+        plan => plan,
+    };
+
+    // Neither computing columns nor sorting changes the count.
+    let plan = match plan {
+        Plan::Compute(plan, _) => *plan,
+        plan => plan,
+    };
+    let plan = match plan {
+        Plan::Sort(plan, _) => *plan,
+        plan => plan,
+    };
+
+    match plan {
+        Plan::Fetch(ref clause) => db.count(clause),
+        ref plan => Ok(execute_columns(plan, db, &CancelToken::new(), &mut ValuePool::new())?.len()),
+    }
+}
+
+/// Plans `q` for running against `db`, as `query` does.
+pub fn plan_query(q: Query, db: &Db) -> Result<Plan> {
+    let q = resolve_lookups(q, db)?;
+    q.check_hints()?;
+    q.check_or()?;
+    q.check_rules()?;
+    q.check_expressions()?;
+    let collations = planner::collations(&q.clauses, &db.schema);
+    Ok(Plan::for_query_collated(order_by_selectivity(q, &db.stats, &db.schema), &collations))
+}
+
+/// Binds the vars standing in for the query's lookup refs (and other
+/// entity specs) to the entities they name in `db`.
+fn resolve_lookups(mut q: Query, db: &Db) -> Result<Query> {
+    let mut env = HashMap::new();
+    for (var, spec) in q.lookups.drain(..) {
+        env.insert(var, Value::Ref(db.resolve(&spec)?));
+    }
+    if env.is_empty() {
+        return Ok(q);
+    }
+
+    let substitute = |clauses: &[Clause]| clauses.iter().map(|c| c.substitute(&env)).collect::<Result<Vec<_>>>();
+    q.clauses = substitute(&q.clauses)?;
+    q.exists = substitute(&q.exists)?;
+    q.not = substitute(&q.not)?;
+    q.or = q.or.iter().map(|cs| substitute(cs)).collect::<Result<_>>()?;
+    for rule in q.rules.iter_mut() {
+        rule.clauses = substitute(&rule.clauses)?;
+    }
+    for call in q.calls.iter_mut().chain(q.rules.iter_mut().flat_map(|r| r.calls.iter_mut())) {
+        for arg in call.args.iter_mut() {
+            if let Term::Unbound(ref v) = arg.clone() {
+                if let Some(value) = env.get(v) {
+                    *arg = Term::Bound(value.clone());
+                }
+            }
+        }
+    }
+    for constraint in q.constraints.iter_mut() {
+        for side in [&mut constraint.left_hand_side, &mut constraint.right_hand_side] {
+            if let Term::Unbound(ref v) = side.clone() {
+                if let Some(value) = env.get(v) {
+                    *side = Term::Bound(value.clone());
+                }
+            }
+        }
+    }
+    Ok(q)
+}
+
+/// Runs a plan against `db`, stopping early if `cancel` is cancelled.
+pub fn execute_plan(plan: &Plan, db: &Db, cancel: &CancelToken) -> Result<Relation> {
+    let mut pool = ValuePool::new();
+    let columns = execute_columns(plan, db, cancel, &mut pool)?;
+    Ok(columns.into_relation(&pool))
 }
 
-fn execute_plan(plan: &Plan, db: &Db) -> Result<Relation> {
+/// Runs a plan as `execute_plan` does, leaving the results in
+/// columns of values interned in `pool`. Steps which stream their
+/// rows (lookups, constraints, semi-joins and limits) are collected
+/// into columns as they finish.
+fn execute_columns(plan: &Plan, db: &Db, cancel: &CancelToken, pool: &mut ValuePool) -> Result<Columns> {
+    cancel.check()?;
     match plan {
         Plan::Join(plan_a, plan_b) => {
             // join the two relations:
             // 1. determine join key (= set of overlapping variables)
             // 2. hash-join the two relations on the join key (inner join)
-            Ok(join(execute_plan(&plan_a, db)?, execute_plan(&plan_b, db)?))
-        },
-        Plan::LookupEach(prior_plan, clause) => {
-            let relation = execute_plan(prior_plan, db)?;
-
-            lookup_each(db, relation, &clause)
+            let a = execute_columns(&plan_a, db, cancel, pool)?;
+            let pool_size = pool.estimated_size();
+            let b = execute_columns(&plan_b, db, cancel, pool)?;
+            join_within(a, b, pool.estimated_size() - pool_size, JOIN_MEMORY_BUDGET)
         },
+        Plan::LookupEach(..) => collect(plan, db, cancel).map(|relation| Columns::from_relation(relation, pool)),
         Plan::Fetch(clause) => {
-            db.fetch(clause)
+            db.fetch(clause).map(|relation| Columns::from_relation(relation, pool))
+        },
+        Plan::Scan(clause, predicate) => {
+            db.scan(clause, predicate).map(|relation| Columns::from_relation(relation, pool))
+        },
+        Plan::Within(within) => {
+            db.within(within).map(|relation| Columns::from_relation(relation, pool))
+        },
+        Plan::Union(plans) => {
+            let mut relations = vec![];
+            for plan in plans {
+                relations.push(execute_columns(plan, db, cancel, pool)?);
+            }
+            union(relations)
         },
         Plan::CartesianProduct(ref plans) => {
             let mut relations = vec![];
             for plan in plans.iter() {
-                let result = execute_plan(plan, db)?;
+                let result = execute_columns(plan, db, cancel, pool)?;
                 relations.push(result);
             }
 
             Ok(cartesian_product(relations))
         },
         Plan::Project(ref plan, projection) => {
-            execute_plan(plan, db).and_then(|relation| project(relation, projection.clone()))
+            execute_columns(plan, db, cancel, pool).and_then(|columns| project(columns, projection))
+        }
+        Plan::Constrain(..) | Plan::NotExists(..) | Plan::Exists(..) => collect(plan, db, cancel).map(|relation| Columns::from_relation(relation, pool)),
+        Plan::Sort(ref plan, order) => {
+            execute_columns(plan, db, cancel, pool).and_then(|columns| sort(columns, order, pool, &planner::collations(plan.clauses(), &db.schema)))
+        }
+        Plan::Limit(ref plan, n) => {
+            let mut tuples = vec![];
+            let vars = stream(plan, db, cancel, &mut |_, tuple| {
+                if tuples.len() < *n {
+                    tuples.push(tuple);
+                }
+                Ok(tuples.len() < *n)
+            })?;
+            Ok(Columns::from_relation(Relation(vars, tuples), pool))
         }
-        Plan::Constrain(ref plan, constraints) => {
-            execute_plan(plan, db).map(|relation| constrain(relation, constraints))
+        Plan::TopK(ref plan, order, k) => top_k(plan, order, *k, db, cancel).map(|relation| Columns::from_relation(relation, pool)),
+        Plan::Compute(ref plan, expressions) => {
+            execute_columns(plan, db, cancel, pool).and_then(|columns| compute(columns, expressions, pool))
+        }
+        Plan::Aggregate(ref plan, group_by, aggregates, with) => {
+            execute_columns(plan, db, cancel, pool).and_then(|columns| aggregate(columns, group_by, aggregates, with, pool))
+        }
+        Plan::Literal(relation) => Ok(Columns::from_relation(relation.clone(), pool)),
+        Plan::Rule(rules, call) => {
+            let tuples = evaluate_rules(rules, db, cancel)?;
+            let relation = call_relation(call, tuples.get(&call.name).unwrap_or(&BTreeSet::new()));
+            Ok(Columns::from_relation(relation, pool))
         }
     }
 }
 
-fn project(relation: Relation, projection: Vec<Var>) -> Result<Relation> {
-    let Relation(vars, tuples) = relation;
+/// The tuples of each rule, found bottom-up by semi-naive
+/// evaluation. The rules without calls are evaluated first, then each
+/// round evaluates the ones with calls against the tuples the last
+/// round found, in place of one call at a time, until a round finds
+/// nothing new. Joining only against the new tuples, rather than all
+/// of them, keeps each round from finding the previous ones' tuples
+/// again.
+fn evaluate_rules(rules: &[Rule], db: &Db, cancel: &CancelToken) -> Result<BTreeMap<String, BTreeSet<Vec<Value>>>> {
+    type Tuples = BTreeMap<String, BTreeSet<Vec<Value>>>;
+    let empty = BTreeSet::new();
+    let mut total: Tuples = rules.iter().map(|rule| (rule.name.clone(), BTreeSet::new())).collect();
+
+    let mut delta: Tuples = BTreeMap::new();
+    for rule in rules.iter().filter(|rule| rule.calls.is_empty()) {
+        let tuples = evaluate_rule(rule, &[], db, cancel)?;
+        delta.entry(rule.name.clone()).or_default().extend(tuples);
+    }
+
+    while delta.values().any(|tuples| !tuples.is_empty()) {
+        for (name, tuples) in &delta {
+            total.entry(name.clone()).or_default().extend(tuples.iter().cloned());
+        }
+
+        let mut next: Tuples = BTreeMap::new();
+        for rule in rules {
+            for i in 0..rule.calls.len() {
+                let changed = match delta.get(&rule.calls[i].name) {
+                    Some(tuples) if !tuples.is_empty() => tuples,
+                    _ => continue,
+                };
+                let inputs: Vec<&BTreeSet<Vec<Value>>> = rule.calls.iter().enumerate()
+                    .map(|(j, call)| if j == i { changed } else { total.get(&call.name).unwrap_or(&empty) })
+                    .collect();
+                for tuple in evaluate_rule(rule, &inputs, db, cancel)? {
+                    if !total[&rule.name].contains(&tuple) {
+                        next.entry(rule.name.clone()).or_default().insert(tuple);
+                    }
+                }
+            }
+        }
+        delta = next;
+    }
+    Ok(total)
+}
+
+/// The tuples of a rule's params for which its body matches, given
+/// the tuples of the rules it calls, one set per call.
+fn evaluate_rule(rule: &Rule, inputs: &[&BTreeSet<Vec<Value>>], db: &Db, cancel: &CancelToken) -> Result<Vec<Vec<Value>>> {
+    let fetches = rule.clauses.iter().map(|clause| Plan::Fetch(clause.clone()));
+    let calls = rule.calls.iter().zip(inputs).map(|(call, tuples)| Plan::Literal(call_relation(call, tuples)));
+    let body = fetches.chain(calls)
+        .fold(None, |plan: Option<Plan>, next| match plan {
+            Some(plan) => Some(Plan::Join(Box::new(plan), Box::new(next))),
+            None => Some(next),
+        })
+        .ok_or_else(|| format!("rule {} has an empty body", rule.name))?;
+    let Relation(_, tuples) = execute_plan(&Plan::Project(Box::new(body), rule.params.clone()), db, cancel)?;
+    Ok(tuples)
+}
+
+/// A relation of a call's vars, from the tuples of the rule it calls
+/// which agree with its args.
+fn call_relation(call: &RuleCall, tuples: &BTreeSet<Vec<Value>>) -> Relation {
+    let vars = call.vars();
+    let rows = tuples.iter()
+        .filter_map(|tuple| {
+            let mut row: Vec<Option<&Value>> = vec![None; vars.len()];
+            for (arg, value) in call.args.iter().zip(tuple) {
+                match *arg {
+                    Term::Bound(ref bound) if bound != value => return None,
+                    Term::Bound(_) => {}
+                    Term::Unbound(ref var) => {
+                        let slot = &mut row[vars.iter().position(|v| v == var)?];
+                        match *slot {
+                            Some(seen) if seen != value => return None,
+                            _ => *slot = Some(value),
+                        }
+                    }
+                }
+            }
+            row.into_iter().map(|value| value.cloned()).collect()
+        })
+        .collect();
+    Relation(vars, rows)
+}
+
+// Projection keeps duplicate tuples, so results are bags. Aggregates
+// don't see the duplicates (see `aggregate`).
+fn project(columns: Columns, projection: &[Var]) -> Result<Columns> {
     let projected_indices = projection.iter().filter_map(|projected_var| {
-        vars.iter().position(|v| v == projected_var)
+        columns.position(projected_var)
     }).collect::<Vec<usize>>();
 
     if projected_indices.len() != projection.len() {
         // some projected var wasn't found in the relation
-        return Err(Error(format!("not all vars found in relation {:?} for projection {:?}", vars, projection)))
+        return Err(Error::Message(format!("not all vars found in relation {:?} for projection {:?}", columns.vars(), projection)))
+    }
+
+    Ok(columns.select(&projected_indices))
+}
+
+fn compute(mut columns: Columns, expressions: &[(Var, Expression)], pool: &mut ValuePool) -> Result<Columns> {
+    for (_, expression) in expressions {
+        for var in expression.vars() {
+            if columns.position(var).is_none() {
+                return Err(Error::Message(format!("expression {} uses {:?}, which isn't in the relation {:?}", expression, var, columns.vars())));
+            }
+        }
     }
 
-    Ok(Relation(
-        projection,
-        tuples.iter().map(|tuple| {
-            projected_indices.iter().map(|&idx| tuple[idx].clone()).collect()
-        }).collect()
-    ))
+    let mut computed: Vec<Vec<ValueId>> = expressions.iter().map(|_| Vec::with_capacity(columns.len())).collect();
+    for row in 0..columns.len() {
+        let values = {
+            let bindings: HashMap<&Var, &Value> = columns.vars().iter()
+                .enumerate()
+                .map(|(idx, var)| (var, pool.get(columns.column(idx)[row])))
+                .collect();
+            expressions.iter().map(|(_, e)| e.eval(&bindings)).collect::<Result<Vec<Value>>>()?
+        };
+        for (column, value) in computed.iter_mut().zip(values) {
+            column.push(pool.intern(value));
+        }
+    }
+    for ((var, _), column) in expressions.iter().zip(computed) {
+        columns.push(var.clone(), column);
+    }
+
+    Ok(columns)
+}
+
+/// The running value of an aggregate over one group's rows.
+enum Accumulator {
+    Count(i64),
+    Sum(i64),
+    Min(Value),
+    Max(Value),
+    Avg(i64, i64),
 }
 
-fn constrain(relation: Relation, constraints: &Vec<Constraint>) -> Relation {
-    //FIXME: assumes constraint is valid i.e. unbound vars in the constraint are present in the relation
-    let Relation(vars, tuples) = relation;
+impl Accumulator {
+    fn start(aggregate: &Aggregate, value: &Value) -> Result<Accumulator> {
+        let number = || match *value {
+            Value::Long(n) => Ok(n),
+            ref other => Err(Error::Message(format!("can't apply {} to {}, which isn't a number", aggregate, other))),
+        };
+        Ok(match aggregate.function {
+            AggregateFunction::Count => Accumulator::Count(1),
+            AggregateFunction::Sum => Accumulator::Sum(number()?),
+            AggregateFunction::Min => Accumulator::Min(value.clone()),
+            AggregateFunction::Max => Accumulator::Max(value.clone()),
+            AggregateFunction::Avg => Accumulator::Avg(number()?, 1),
+        })
+    }
 
-    let out_tuples = tuples.into_iter().filter(|tuple| {
-        let bindings: HashMap<&Var, &Value> = vars.iter().zip(tuple.iter()).collect();
-        constraints.iter().all(|constraint| constraint.satisfied_by(&bindings))
-    }).collect();
+    fn add(&mut self, aggregate: &Aggregate, value: &Value) -> Result<()> {
+        let overflow = || Error::Message(format!("{} overflowed", aggregate));
+        match (self, Accumulator::start(aggregate, value)?) {
+            (Accumulator::Count(n), _) => *n += 1,
+            (Accumulator::Sum(sum), Accumulator::Sum(n)) => *sum = sum.checked_add(n).ok_or_else(overflow)?,
+            (Accumulator::Avg(sum, count), Accumulator::Avg(n, _)) => {
+                *sum = sum.checked_add(n).ok_or_else(overflow)?;
+                *count += 1;
+            }
+            (Accumulator::Min(min), _) if value < min => *min = value.clone(),
+            (Accumulator::Max(max), _) if value > max => *max = value.clone(),
+            _ => {}
+        }
+        Ok(())
+    }
 
-    Relation(vars, out_tuples)
+    fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(n) | Accumulator::Sum(n) => Value::Long(n),
+            Accumulator::Min(value) | Accumulator::Max(value) => value,
+            Accumulator::Avg(sum, count) => Value::Long(sum.div_euclid(count)),
+        }
+    }
 }
 
-fn lookup_each(db: &Db, relation: Relation, clause: &Clause) -> Result<Relation> {
+/// Groups the relation's rows by the `group_by` vars, giving a row
+/// per group with those vars' values and then the aggregates. With
+/// no rows, there are no groups, so the result is empty even when
+/// nothing is grouped by.
+///
+/// As in Datomic, the aggregates see the set of tuples of the
+/// grouped and aggregated vars, so rows which only differ in other
+/// vars count once unless those vars are given as `with`.
+fn aggregate(columns: Columns, group_by: &[Var], aggregates: &[(Var, Aggregate)], with: &[Var], pool: &mut ValuePool) -> Result<Columns> {
+    let position = |var: &Var| columns.position(var)
+        .ok_or_else(|| Error::Message(format!("can't aggregate by {:?}, which isn't in the relation {:?}", var, columns.vars())));
+    let key_indices = group_by.iter().map(&position).collect::<Result<Vec<usize>>>()?;
+    let value_indices = aggregates.iter().map(|(_, a)| position(&a.var)).collect::<Result<Vec<usize>>>()?;
+    let mut distinct_indices = with.iter().map(&position).collect::<Result<Vec<usize>>>()?;
+    distinct_indices.extend(key_indices.iter().chain(&value_indices));
+    let mut seen: HashSet<Vec<ValueId>> = HashSet::new();
+
+    // The first row of each group, and the group's accumulators.
+    let mut first_rows: Vec<usize> = vec![];
+    let mut groups: Vec<Vec<Accumulator>> = vec![];
+    let mut group_indices: HashMap<Vec<ValueId>, usize> = HashMap::new();
+    for row in 0..columns.len() {
+        if seen.insert(key_for_row(&columns, &distinct_indices, row)).is_some() {
+            continue;
+        }
+        let key = key_for_row(&columns, &key_indices, row);
+        match group_indices.get(&key) {
+            Some(&i) => {
+                for ((accumulator, (_, aggregate)), &idx) in groups[i].iter_mut().zip(aggregates).zip(&value_indices) {
+                    accumulator.add(aggregate, pool.get(columns.column(idx)[row]))?;
+                }
+            }
+            None => {
+                let accumulators = aggregates.iter().zip(&value_indices)
+                    .map(|((_, aggregate), &idx)| Accumulator::start(aggregate, pool.get(columns.column(idx)[row])))
+                    .collect::<Result<Vec<Accumulator>>>()?;
+                group_indices.insert(key, groups.len());
+                first_rows.push(row);
+                groups.push(accumulators);
+            }
+        }
+    }
+
+    let mut output = columns.select(&key_indices).gather(&first_rows);
+    let mut finished: Vec<Vec<ValueId>> = aggregates.iter().map(|_| Vec::with_capacity(groups.len())).collect();
+    for accumulators in groups {
+        for (column, accumulator) in finished.iter_mut().zip(accumulators) {
+            column.push(pool.intern(accumulator.finish()));
+        }
+    }
+    for ((var, _), column) in aggregates.iter().zip(finished) {
+        output.push(var.clone(), column);
+    }
+    Ok(output)
+}
+
+/// The positions of the order's vars in `vars`, with whether each
+/// is descending and the collation to compare it by.
+fn sort_keys(vars: &[Var], order: &[Order], collations: &HashMap<Var, Collation>) -> Result<Vec<(usize, bool, Collation)>> {
+    let mut keys = vec![];
+    for o in order {
+        match vars.iter().position(|v| *v == o.var) {
+            Some(idx) => keys.push((idx, o.descending, collations.get(&o.var).cloned().unwrap_or(Collation::Binary))),
+            None => return Err(Error::Message(format!("can't order by {:?}, which isn't in the relation {:?}", o.var, vars))),
+        }
+    }
+    Ok(keys)
+}
+
+fn sort(columns: Columns, order: &[Order], pool: &ValuePool, collations: &HashMap<Var, Collation>) -> Result<Columns> {
+    let keys = sort_keys(columns.vars(), order, collations)?;
+
+    let mut rows: Vec<usize> = (0..columns.len()).collect();
+    rows.sort_by(|&a, &b| {
+        for &(idx, descending, collation) in &keys {
+            let column = columns.column(idx);
+            let ordering = collation.compare(pool.get(column[a]), pool.get(column[b]));
+            if ordering != cmp::Ordering::Equal {
+                return if descending { ordering.reverse() } else { ordering };
+            }
+        }
+        cmp::Ordering::Equal
+    });
+
+    Ok(columns.gather(&rows))
+}
+
+/// A value in a row's sort key, ordered so that a row which should
+/// come first compares less.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Asc(Value),
+    Desc(Reverse<Value>),
+}
+
+/// Keeps the first `k` rows of the plan in the given order. The rows
+/// are kept in a max-heap as they're produced, so a row is dropped as
+/// soon as `k` better ones have been seen; ties keep the order they
+/// were produced in, as with a (stable) sort.
+fn top_k(plan: &Plan, order: &[Order], k: usize, db: &Db, cancel: &CancelToken) -> Result<Relation> {
+    let collations = planner::collations(plan.clauses(), &db.schema);
+    let mut keys: Option<Vec<(usize, bool, Collation)>> = None;
+    let mut heap: BinaryHeap<(Vec<SortValue>, usize, Vec<Value>)> = BinaryHeap::new();
+    let mut produced = 0;
+    let vars = stream(plan, db, cancel, &mut |vars, tuple| {
+        if keys.is_none() {
+            keys = Some(sort_keys(vars, order, &collations)?);
+        }
+        let key = keys.as_ref().unwrap().iter().map(|&(idx, descending, collation)| {
+            if descending {
+                SortValue::Desc(Reverse(collation.key(&tuple[idx])))
+            } else {
+                SortValue::Asc(collation.key(&tuple[idx]))
+            }
+        }).collect();
+        let row = (key, produced, tuple);
+        produced += 1;
+
+        if heap.len() < k {
+            heap.push(row);
+        } else if heap.peek().is_some_and(|worst| row < *worst) {
+            heap.pop();
+            heap.push(row);
+        }
+        Ok(true)
+    })?;
+    sort_keys(&vars, order, &collations)?;
+
+    let tuples = heap.into_sorted_vec().into_iter().map(|(_, _, tuple)| tuple).collect();
+    Ok(Relation(vars, tuples))
+}
+
+/// Takes each row a plan produces, given the plan's vars, and says
+/// whether to carry on.
+type Emit<'a> = dyn FnMut(&[Var], Vec<Value>) -> Result<bool> + 'a;
+
+/// Runs a plan, handing its rows to `emit` as they're produced rather
+/// than collecting them, and returns its vars. Lookups, constraints
+/// and semi-joins pass each row along as they find it, so a limit
+/// above them can stop them early, and a top-k only holds the rows
+/// it keeps.
+fn stream(plan: &Plan, db: &Db, cancel: &CancelToken, emit: &mut Emit) -> Result<Vec<Var>> {
+    match plan {
+        Plan::LookupEach(prior_plan, clause) => {
+            let relation = execute_plan(prior_plan, db, cancel)?;
+
+            lookup_each(db, relation, clause, cancel, emit)
+        }
+        Plan::Constrain(plan, constraints) => {
+            let collations = planner::collations(plan.clauses(), &db.schema);
+            let mut checked = false;
+            let vars = stream(plan, db, cancel, &mut |vars, tuple| {
+                if !checked {
+                    check_constraint_vars(vars, constraints)?;
+                    checked = true;
+                }
+                let satisfied = {
+                    let bindings: HashMap<&Var, &Value> = vars.iter().zip(tuple.iter()).collect();
+                    constraints.iter().all(|constraint| constraint.satisfied_by(&bindings, &collations))
+                };
+                if satisfied {
+                    emit(vars, tuple)
+                } else {
+                    Ok(true)
+                }
+            })?;
+            check_constraint_vars(&vars, constraints)?;
+            Ok(vars)
+        }
+        Plan::NotExists(plan, clause) => semi_join(plan, clause, false, db, cancel, emit),
+        Plan::Exists(plan, clause) => semi_join(plan, clause, true, db, cancel, emit),
+        plan => {
+            let Relation(vars, tuples) = execute_plan(plan, db, cancel)?;
+            for tuple in tuples {
+                if !emit(&vars, tuple)? {
+                    break;
+                }
+            }
+            Ok(vars)
+        }
+    }
+}
+
+/// Runs a plan with `stream`, collecting its rows.
+fn collect(plan: &Plan, db: &Db, cancel: &CancelToken) -> Result<Relation> {
+    let mut tuples = vec![];
+    let vars = stream(plan, db, cancel, &mut |_, tuple| {
+        tuples.push(tuple);
+        Ok(true)
+    })?;
+    Ok(Relation(vars, tuples))
+}
+
+/// A plan's rows, produced as they're pulled.
+type Rows = Box<dyn Iterator<Item = Result<Vec<Value>>>>;
+
+/// Builds an iterator over the plan's rows, for `query_iter`, and
+/// returns it with the plan's vars. The steps `stream` passes rows
+/// along from, as well as projections and limits, pull their rows
+/// from the iterator below; any other step is run with
+/// `execute_plan` and its rows handed out from the relation.
+fn rows(plan: Plan, db: &Db, cancel: &CancelToken) -> Result<(Vec<Var>, Rows)> {
+    cancel.check()?;
+    match plan {
+        Plan::LookupEach(prior_plan, clause) => {
+            let (in_vars, in_rows) = rows(*prior_plan, db, cancel)?;
+            // The clause's other vars are bound by the fetch, in the
+            // order `Db::fetch` gives them.
+            let mut out_vars = in_vars.clone();
+            out_vars.extend(clause.unbound_vars().into_iter().filter(|var| !in_vars.contains(var)));
+
+            let (db, cancel) = (db.clone(), cancel.clone());
+            let rows = in_rows.flat_map(move |row| -> Rows {
+                let looked_up = row.and_then(|tuple| {
+                    cancel.check()?;
+                    let binding: HashMap<Var, Value> = in_vars.iter().cloned().zip(tuple.iter().cloned()).collect();
+                    let Relation(_, new_tuples) = db.fetch(&clause.substitute(&binding)?)?;
+                    Ok(new_tuples.into_iter().map(move |new_tuple| {
+                        let mut out_tuple = tuple.clone();
+                        out_tuple.extend(new_tuple);
+                        Ok(out_tuple)
+                    }))
+                });
+                match looked_up {
+                    Ok(rows) => Box::new(rows),
+                    Err(e) => Box::new(iter::once(Err(e))),
+                }
+            });
+            Ok((out_vars, Box::new(rows)))
+        }
+        Plan::Constrain(plan, constraints) => {
+            let collations = planner::collations(plan.clauses(), &db.schema);
+            let (vars, rows) = rows(*plan, db, cancel)?;
+            check_constraint_vars(&vars, &constraints)?;
+
+            let rows_vars = vars.clone();
+            let rows = rows.filter(move |row| match row {
+                Ok(tuple) => {
+                    let bindings: HashMap<&Var, &Value> = rows_vars.iter().zip(tuple.iter()).collect();
+                    constraints.iter().all(|constraint| constraint.satisfied_by(&bindings, &collations))
+                }
+                Err(_) => true,
+            });
+            Ok((vars, Box::new(rows)))
+        }
+        Plan::NotExists(plan, clause) => semi_join_rows(*plan, clause, false, db, cancel),
+        Plan::Exists(plan, clause) => semi_join_rows(*plan, clause, true, db, cancel),
+        Plan::Project(plan, projection) => {
+            let (vars, rows) = rows(*plan, db, cancel)?;
+            let projected_indices = projection.iter().filter_map(|projected_var| {
+                vars.iter().position(|var| var == projected_var)
+            }).collect::<Vec<usize>>();
+            if projected_indices.len() != projection.len() {
+                return Err(Error::Message(format!("not all vars found in relation {:?} for projection {:?}", vars, projection)));
+            }
+
+            let rows = rows.map(move |row| row.map(|tuple| projected_indices.iter().map(|&i| tuple[i].clone()).collect()));
+            Ok((projection, Box::new(rows)))
+        }
+        Plan::Limit(plan, n) => {
+            let (vars, rows) = rows(*plan, db, cancel)?;
+            Ok((vars, Box::new(rows.take(n))))
+        }
+        plan => {
+            let Relation(vars, tuples) = execute_plan(&plan, db, cancel)?;
+            Ok((vars, Box::new(tuples.into_iter().map(Ok))))
+        }
+    }
+}
+
+/// `semi_join`, for `rows`.
+fn semi_join_rows(plan: Plan, clause: Clause, exists: bool, db: &Db, cancel: &CancelToken) -> Result<(Vec<Var>, Rows)> {
+    let (vars, rows) = rows(plan, db, cancel)?;
+    let indices = shared_vars(&clause, &vars)?;
+
+    let (db, cancel, rows_vars) = (db.clone(), cancel.clone(), vars.clone());
+    let mut matched: HashMap<Vec<Value>, bool> = HashMap::new();
+    let rows = rows.filter_map(move |row| {
+        let tuple = match row {
+            Ok(tuple) => tuple,
+            Err(e) => return Some(Err(e)),
+        };
+        let has_match = (|| {
+            cancel.check()?;
+            let key: Vec<Value> = indices.iter().map(|&i| tuple[i].clone()).collect();
+            if let Some(&has_match) = matched.get(&key) {
+                return Ok(has_match);
+            }
+            let binding: HashMap<Var, Value> = rows_vars.iter().cloned().zip(tuple.iter().cloned()).collect();
+            let has_match = db.count(&clause.substitute(&binding)?)? > 0;
+            matched.insert(key, has_match);
+            Ok(has_match)
+        })();
+        match has_match {
+            Ok(has_match) if has_match == exists => Some(Ok(tuple)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    });
+    Ok((vars, Box::new(rows)))
+}
+
+fn check_constraint_vars(vars: &[Var], constraints: &[Constraint]) -> Result<()> {
+    for constraint in constraints {
+        for term in &[&constraint.left_hand_side, &constraint.right_hand_side] {
+            if let Term::Unbound(var) = term {
+                if !vars.contains(var) {
+                    return Err(Error::Message(format!("constraint {:?} uses {:?}, which isn't in the relation {:?}", constraint, var, vars)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the tuples for which the clause, bound with the tuple's
+/// values, matches something (if `exists`) or nothing (if not).
+/// Each distinct binding of the shared vars is only looked up once.
+fn semi_join(plan: &Plan, clause: &Clause, exists: bool, db: &Db, cancel: &CancelToken, emit: &mut Emit) -> Result<Vec<Var>> {
+    let mut indices: Option<Vec<usize>> = None;
+    let mut matched: HashMap<Vec<Value>, bool> = HashMap::new();
+    let vars = stream(plan, db, cancel, &mut |vars, tuple| {
+        cancel.check()?;
+        if indices.is_none() {
+            indices = Some(shared_vars(clause, vars)?);
+        }
+        let key: Vec<Value> = indices.as_ref().unwrap().iter().map(|&i| tuple[i].clone()).collect();
+        let has_match = match matched.get(&key) {
+            Some(&has_match) => has_match,
+            None => {
+                let binding: HashMap<Var, Value> = vars.iter().cloned().zip(tuple.iter().cloned()).collect();
+                let has_match = db.count(&clause.substitute(&binding)?)? > 0;
+                matched.insert(key, has_match);
+                has_match
+            }
+        };
+        if has_match == exists {
+            emit(vars, tuple)
+        } else {
+            Ok(true)
+        }
+    })?;
+    shared_vars(clause, &vars)?;
+    Ok(vars)
+}
+
+/// The positions in `vars` of the clause's vars, which a semi-join
+/// looks up each distinct binding of.
+fn shared_vars(clause: &Clause, vars: &[Var]) -> Result<Vec<usize>> {
+    let shared: Vec<usize> = clause.unbound_vars()
+        .iter()
+        .filter_map(|v| vars.iter().position(|var| var == v))
+        .collect();
+    if shared.is_empty() {
+        return Err(Error::Message(format!("clause {:?} shares no vars with the relation {:?}", clause, vars)));
+    }
+    Ok(shared)
+}
+
+fn lookup_each(db: &Db, relation: Relation, clause: &Clause, cancel: &CancelToken, emit: &mut Emit) -> Result<Vec<Var>> {
     // for each binding in the relation, bind the clause and fetch matching records
     // then, use results to build a new output relation including new vars which the clause binds
     let Relation(in_vars, in_tuples) = relation;
 
     if in_tuples.len() == 0 {
-        return Ok(Relation(in_vars, in_tuples));
+        return Ok(in_vars);
     }
 
     let entity_index: Option<usize> = match clause.entity {
@@ -102,174 +834,194 @@ fn lookup_each(db: &Db, relation: Relation, clause: &Clause) -> Result<Relation>
         let entity = if let Some(entity_val) = entity {
             match entity_val {
                 Value::Ref(e) => Some(e),
-                other_value => return Err(Error(format!["Attempted to bind non-entity {:?} in entity position for clause {:?}", other_value, clause]))
+                other_value => return Err(Error::Message(format!["Attempted to bind non-entity {:?} in entity position for clause {:?}", other_value, clause]))
             }
         } else { None };
 
         let attribute = if let Some(attr_val) = attribute {
             match attr_val {
                 Value::Ref(e) => Some(Ident::Entity(e)),
-                other_value => return Err(Error(format!["Attempted to bind non-entity {:?} in attribute position for clause {:?}", other_value, clause]))
+                other_value => return Err(Error::Message(format!["Attempted to bind non-entity {:?} in attribute position for clause {:?}", other_value, clause]))
             }
         } else { None };
 
-        Ok(Clause::new(
-            entity.map_or(clause.entity.clone(), |e|  Term::Bound(e)),
-            attribute.map_or(clause.attribute.clone(), |a| Term::Bound(a)),
-            value.map_or(clause.value.clone(), |v| Term::Bound(v))
-        ))
+        Ok(Clause {
+            entity: entity.map_or(clause.entity.clone(), |e|  Term::Bound(e)),
+            attribute: attribute.map_or(clause.attribute.clone(), |a| Term::Bound(a)),
+            value: value.map_or(clause.value.clone(), |v| Term::Bound(v)),
+            ..clause.clone()
+        })
+    }
+
+    // The tx and added terms are rarely used, so they're bound
+    // through `Clause::substitute`.
+    let mut history_indices: Vec<(Var, usize)> = vec![];
+    if let Some(Term::Unbound(ref var)) = clause.tx {
+        history_indices.extend(in_vars.iter().position(|v| v == var).map(|idx| (var.clone(), idx)));
+    }
+    if let Some(Term::Unbound(ref var)) = clause.added {
+        history_indices.extend(in_vars.iter().position(|v| v == var).map(|idx| (var.clone(), idx)));
     }
 
     let substitute_clause = |tuple: &Vec<Value>| {
-        bind_clause(
+        let bound = bind_clause(
             clause,
             entity_index.map(|idx| tuple[idx].clone()),
             attribute_index.map(|idx| tuple[idx].clone()),
             value_index.map(|idx| tuple[idx].clone()),
-        )
+        )?;
+        if history_indices.is_empty() {
+            return Ok(bound);
+        }
+        let binding: HashMap<Var, Value> = history_indices.iter().map(|(var, idx)| (var.clone(), tuple[*idx].clone())).collect();
+        bound.substitute(&binding)
     };
 
     // New vars will be set by the first query. Every subsequent query
     // should return the same set of out vars.
     let mut new_vars: Option<Vec<Var>> = None;
-    let mut out_tuples: Vec<Vec<Value>> = vec![];
+    let mut out_vars = in_vars.clone();
     for tuple in in_tuples {
+        cancel.check()?;
         let sub_clause = substitute_clause(&tuple)?;
         let Relation(new_var_results, new_tuples) = db.fetch(&sub_clause)?;
 
+        if new_vars.is_none() {
+            out_vars.extend(new_var_results.iter().cloned());
+        }
         new_vars.get_or_insert_with(|| new_var_results.clone());
         assert_eq!(new_vars.clone().unwrap(), new_var_results);
 
         for new_tuple in new_tuples {
             let mut out_tuple = tuple.clone();
             out_tuple.extend(new_tuple);
-            out_tuples.push(out_tuple);
+            if !emit(&out_vars, out_tuple)? {
+                return Ok(out_vars);
+            }
         }
     }
 
-    let mut out_vars = in_vars.clone();
-    out_vars.extend(new_vars.unwrap());
-    Ok(Relation(out_vars, out_tuples))
+    Ok(out_vars)
 }
 
-/// Implements the cartesian product of relations, none of which
-/// should share fields (otherwise they should be joined).
-/// Horribly inefficient implementation!
-fn cartesian_product(relations: Vec<Relation>) -> Relation {
-    relations.iter().fold(Relation(vec![], vec!()), |acc, relation| {
-        let Relation(old_vars, old_vals) = acc;
-        let Relation(new_vars, new_vals) = relation;
-
-        let mut next_vars = vec![];
-        next_vars.extend(old_vars);
-        next_vars.extend(new_vars.clone());
-
-        let mut next_vals = vec![];
-        for old_val in old_vals {
-            for new_val in new_vals {
-                let mut tuple = old_val.clone();
-                tuple.extend(new_val.clone());
-                next_vals.push(tuple);
+/// The distinct rows of the relations, in the first one's var order
+/// and in the order they're first seen.
+fn union(relations: Vec<Columns>) -> Result<Columns> {
+    let vars = match relations.first() {
+        Some(first) => first.vars().to_vec(),
+        None => return Ok(Columns::from_rows(vec![], vec![])),
+    };
+    let mut seen: HashSet<Vec<ValueId>> = HashSet::new();
+    let mut rows = vec![];
+    for columns in relations {
+        let indices = vars.iter().map(|var| columns.position(var)).collect::<Option<Vec<usize>>>();
+        match indices {
+            Some(ref indices) if indices.len() == columns.vars().len() => {
+                let (_, tuples) = columns.select(indices).into_rows();
+                for tuple in tuples {
+                    if seen.insert(tuple.clone()).is_none() {
+                        rows.push(tuple);
+                    }
+                }
             }
+            _ => return Err(Error::Message(format!("can't union relations of {:?} and {:?}", vars, columns.vars()))),
         }
+    }
+    Ok(Columns::from_rows(vars, rows))
+}
 
-        Relation(next_vars, next_vals)
+/// Implements the cartesian product of relations, none of which
+/// should share fields (otherwise they should be joined).
+fn cartesian_product(relations: Vec<Columns>) -> Columns {
+    relations.into_iter().fold(Columns::unit(), |acc, relation| {
+        let (acc_rows, rows): (Vec<usize>, Vec<usize>) = (0..acc.len())
+            .flat_map(|i| (0..relation.len()).map(move |j| (i, j)))
+            .unzip();
+        acc.gather(&acc_rows).append(relation.gather(&rows))
     })
 }
 
 
 /// Implements the natural join between relations, outputting one
 /// tuple for each combination of tuples in the two relations which
-/// match on all overlapping variables.
-fn join(rel_a: Relation, rel_b: Relation) -> Relation {
-    // The join key is a vector of vars in both a and b, ordered as they are in a.
-    let join_key: Vec<Var> = derive_join_key(&rel_a, &rel_b);
-    let output_key = derive_output_key(&rel_a, &rel_b);
-
-    let rel_a_vars = rel_a.0.iter().cloned().collect::<HashSet<Var>>();
-    let rel_b_out_indices = rel_b.0.iter().enumerate()
-        .filter(|(_idx, var)| !rel_a_vars.contains(var))
-        .map(|(idx, _var)| idx)
-        .collect::<Vec<usize>>();
+/// match on all overlapping variables. The output has `rel_a`'s vars
+/// and then the rest of `rel_b`'s, and keeps the order of `rel_a`'s
+/// tuples.
+///
+/// `rel_b` is hashed on the join key. If it's estimated to need more
+/// than `JOIN_MEMORY_BUDGET` bytes, the join spills to disk instead
+/// (see `spill`).
+pub(crate) fn join(rel_a: Relation, rel_b: Relation) -> Result<Relation> {
+    let mut pool = ValuePool::new();
+    let a = Columns::from_relation(rel_a, &mut pool);
+    let pool_size = pool.estimated_size();
+    let b = Columns::from_relation(rel_b, &mut pool);
+    Ok(join_within(a, b, pool.estimated_size() - pool_size, JOIN_MEMORY_BUDGET)?.into_relation(&pool))
+}
 
-    let rel_b_map = hash_relation(&join_key, rel_b);
+/// Joins as `join` does, spilling if `b`, whose values grew the pool
+/// by `b_pool_growth` bytes, needs more than `budget` bytes.
+pub(crate) fn join_within(a: Columns, b: Columns, b_pool_growth: usize, budget: usize) -> Result<Columns> {
+    // The join key is a vector of vars in both a and b, ordered as they are in a.
+    let join_key: Vec<Var> = derive_join_key(a.vars(), b.vars());
 
-    let project = |mut tuple_a: Vec<Value>, tuple_b: &Vec<Value>| {
-        for idx in rel_b_out_indices.iter() {
-            let val = tuple_b[*idx].clone();
-            tuple_a.push(val);
-        }
+    // Spilling partitions by the join key, so it can't help a
+    // cartesian product.
+    if !join_key.is_empty() && spill::estimated_size(&b, b_pool_growth) > budget {
+        return spill::grace_join(a, b, &join_key, budget);
+    }
 
-        tuple_a
-    };
+    let a_vars: HashSet<&Var> = a.vars().iter().collect();
+    let b_out: Vec<usize> = (0..b.vars().len()).filter(|&idx| !a_vars.contains(&b.vars()[idx])).collect();
+    let a_keys = key_indices(&join_key, a.vars());
+    let b_keys = key_indices(&join_key, b.vars());
 
-    // Join tuples in a and b on matching join key
-    let rel_a_key_indices = key_indices(&join_key, &rel_a);
-    let joined: Vec<Vec<Value>> = rel_a.1.iter().fold(vec![], |mut output, tuple_a| {
-        if let Some(matches) = rel_b_map.get(&key_for_tuple(&rel_a_key_indices, &tuple_a)) {
-            for tuple_b in matches {
-                output.push(project(tuple_a.clone(), tuple_b));
+    // Hash b's rows by their join key, then pair each of a's rows
+    // with the rows of b matching its key.
+    let mut hashed: HashMap<Vec<ValueId>, Vec<usize>> = HashMap::new();
+    for row in 0..b.len() {
+        hashed.entry(key_for_row(&b, &b_keys, row)).or_insert(vec![]).push(row);
+    }
+    let mut rows_a = vec![];
+    let mut rows_b = vec![];
+    for row in 0..a.len() {
+        if let Some(matches) = hashed.get(&key_for_row(&a, &a_keys, row)) {
+            for &row_b in matches {
+                rows_a.push(row);
+                rows_b.push(row_b);
             }
         }
+    }
 
-        output
-    });
-
-    Relation(output_key, joined)
+    Ok(a.gather(&rows_a).append(b.select(&b_out).gather(&rows_b)))
 }
 
 /// The join key is a vector containing the vars in both relations a
 /// and b, ordered as they are in relation a.
-fn derive_join_key(a: &Relation, b: &Relation) -> Vec<Var> {
-    let b_vars_set: HashSet<Var> = b.0.iter().cloned().collect();
+fn derive_join_key(a: &[Var], b: &[Var]) -> Vec<Var> {
+    let b_vars_set: HashSet<&Var> = b.iter().collect();
 
     // The join key is a vector of vars in both a and b, ordered as they are in a.
-    a.0.iter()
-        .filter(|var| b_vars_set.contains(&var))
+    a.iter()
+        .filter(|var| b_vars_set.contains(var))
         .cloned().collect()
 }
 
-/// The output key is a vector containing the union of vars in
-/// relations a and b ordered as vars in a as they are ordered in a,
-/// followed by vars only in b as they are (relatively) ordered in b.
-fn derive_output_key(a: &Relation, b: &Relation) -> Vec<Var> {
-    let a_vars_set: HashSet<Var> = a.0.iter().cloned().collect();
-    a.0.iter().cloned()
-        .chain(b.0.iter().filter(|var| !a_vars_set.contains(&var)).cloned())
-        .collect()
-}
-
-fn key_indices(join_key: &Vec<Var>, relation: &Relation) -> Vec<usize> {
+pub(crate) fn key_indices(join_key: &[Var], vars: &[Var]) -> Vec<usize> {
     join_key.iter().map(|ref key_var| {
-        relation.0.iter().position(|ref var| var == key_var)
+        vars.iter().position(|ref var| var == key_var)
             .expect("Join key variable not found in relation")
     }).collect()
 }
 
-fn key_for_tuple(key_indices: &Vec<usize>, tuple: &Vec<Value>) -> Vec<Value> {
+/// The values of a row at `key_indices`. Interned values are equal
+/// when their ids are, so the ids will do as a key.
+fn key_for_row(columns: &Columns, key_indices: &[usize], row: usize) -> Vec<ValueId> {
     key_indices.iter()
-        .map(|&idx| tuple[idx].clone())
+        .map(|&idx| columns.column(idx)[row])
         .collect()
 }
 
-fn hash_relation(
-    join_key: &Vec<Var>,
-    relation: Relation
-) -> HashMap<Vec<Value>, Vec<Vec<Value>>> {
-    let indices: Vec<usize> = key_indices(join_key, &relation);
-    // Build hashmap of join key -> tuple in rel b
-    relation.1.into_iter().fold(
-        HashMap::new(),
-        |mut m: HashMap<Vec<Value>, Vec<Vec<Value>>>, tuple| {
-            {
-                let entry = m.entry(key_for_tuple(&indices, &tuple)).or_insert(vec![]);
-                (*entry).push(tuple);
-            }
-            m
-        }
-    )
-}
-
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
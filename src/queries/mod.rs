@@ -1,3 +1,6 @@
 pub mod query;
 pub mod planner;
 pub mod execution;
+pub mod columns;
+pub mod spill;
+pub mod builder;
@@ -1,5 +1,11 @@
-use queries::query::{Var, Clause, Query, Constraint};
+use queries::query::{Var, Clause, Query, Constraint, Comparator, Within, Term, Strategy, Order, Expression, Aggregate, Rule, RuleCall};
+use {Ident, Relation, Value};
+use stats::{Stats, AttributeStats};
+use schema::{Collation, Schema};
 use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use im::HashMap;
+use std::fmt;
 ///! The query planner converts a query into an execution plan. In the
 ///! future it will be possible to improve the performance of queries
 ///! by using heuristics to decide between possible execution plans,
@@ -75,6 +81,40 @@ use std::collections::HashSet;
 ///! It would be better not to require the user to order query clauses
 ///! like this, but in the absence of a more sophisticated planner it
 ///! at least offers some control over performance.
+///!
+///! Once the indices have been rebuilt, the database has statistics
+///! about each attribute's values (see the `stats` module), and
+///! `order_by_selectivity` uses them to put the clauses in an order
+///! like the above before planning: it estimates how many facts each
+///! clause would produce, including the effect of range constraints
+///! on its value, and repeatedly picks the cheapest clause connected
+///! to the vars bound so far. Before the first reindex there are no
+///! statistics, and clauses are planned in the order given.
+///!
+///! A clause bounded to a range of transactions, by `since` or by its
+///! own transaction term, is read from their part of the log index
+///! when that has fewer records than the clause's index would read
+///! (see `prefers_log`).
+///!
+///! Where the estimates are wrong, hints in the query override them:
+///! `(hint ordered)` keeps the clauses in the order written, and
+///! `(hint fetch 2)` or `(hint lookup 2)` pins the strategy for the
+///! third clause (see `Hints`).
+///!
+///! Results are sorted for `order by` only when they don't already
+///! come out in that order. A fetch returns its rows in the order of
+///! the index it scans, which (whichever index it is) is by the
+///! clause's vars in entity, attribute, value order: fetching
+///! `(?e name ?n)` gives rows ordered by `?e` and then `?n`. Lookups,
+///! joins, constraints and projections keep the order of the relation
+///! they start from, so `order by ?e` needs no sort as long as the
+///! first clause planned is one with `?e` as its first var. `\explain`
+///! shows the order of each step, and whether a sort is needed.
+///!
+///! With a `limit` as well, rows already in order are cut off by a
+///! `Limit`, which stops the lookups and filters below it once it has
+///! enough. Otherwise a `TopK` keeps the best rows seen so far in a
+///! heap as they're produced, instead of sorting them all.
 
 /// A representation of an execution plan for answering a query or
 /// a part of one.  It consists of either a simple fetch or a way of
@@ -83,10 +123,49 @@ use std::collections::HashSet;
 pub enum Plan {
     Join(Box<Plan>, Box<Plan>),
     Fetch(Clause),
+    /// A fetch of the clause's facts whose values satisfy a string
+    /// predicate on them, such as `(starts-with ?name "bo")`. Where
+    /// the attribute's index keeps the matching values together,
+    /// only their range of it is read (see `Db::scan`).
+    Scan(Clause, Constraint),
+    /// A radius query: an index range scan over the geohash cells
+    /// covering the search area, refined by distance.
+    Within(Within),
     LookupEach(Box<Plan>, Clause),
+    /// The distinct rows of any of the plans, which all have the same
+    /// vars, for an `or`.
+    Union(Vec<Plan>),
     CartesianProduct(Vec<Box<Plan>>),
     Project(Box<Plan>, Vec<Var>),
-    Constrain(Box<Plan>, Vec<Constraint>)
+    Constrain(Box<Plan>, Vec<Constraint>),
+    /// Sorts the relation. The planner leaves this out when the
+    /// relation is already in order.
+    Sort(Box<Plan>, Vec<Order>),
+    /// Keeps the first rows of a relation which is already in order,
+    /// stopping the steps below it once it has them.
+    Limit(Box<Plan>, usize),
+    /// Keeps the first rows of the relation in the given order,
+    /// holding only that many while the steps below produce the rest.
+    TopK(Box<Plan>, Vec<Order>, usize),
+    /// Adds a column for each expression, bound to its var.
+    Compute(Box<Plan>, Vec<(Var, Expression)>),
+    /// Groups the rows by the given vars, with a row for each group
+    /// of its values for those vars and the aggregates, in the order
+    /// the groups are first seen. Rows with the same values for the
+    /// grouped vars, the aggregated vars and the last vars (the
+    /// query's `with`) are only aggregated once.
+    Aggregate(Box<Plan>, Vec<Var>, Vec<(Var, Aggregate)>, Vec<Var>),
+    /// Keeps only the tuples for which the clause, bound with the
+    /// tuple's values, matches nothing.
+    NotExists(Box<Plan>, Clause),
+    /// Keeps only the tuples for which the clause, bound with the
+    /// tuple's values, matches something.
+    Exists(Box<Plan>, Clause),
+    /// A relation given in the query, for a collection input.
+    Literal(Relation),
+    /// The tuples of the rule matching a call, worked out from the
+    /// query's rules (see `execution::evaluate_rules`).
+    Rule(Vec<Rule>, RuleCall),
 }
 
 impl Plan {
@@ -97,7 +176,8 @@ impl Plan {
                 .union(&plan_b.outputs())
                 .cloned()
                 .collect(),
-            &Fetch(ref clause) => clause.unbound_vars().clone().into_iter().collect(),
+            &Fetch(ref clause) | &Scan(ref clause, _) => clause.unbound_vars().clone().into_iter().collect(),
+            Within(within) => vec![within.entity.clone()].into_iter().collect(),
             &LookupEach(ref plan, ref clause) => plan.outputs()
                 .union(&clause.unbound_vars().clone().into_iter().collect())
                 .cloned()
@@ -106,13 +186,129 @@ impl Plan {
                 .iter()
                 .flat_map(|p| p.outputs().clone())
                 .collect(),
+            Union(plans) => plans.iter().flat_map(|p| p.outputs()).collect(),
             &Project(ref _plan, ref projection) => projection.iter().cloned().collect(),
-            &Constrain(ref plan, _) => plan.outputs()
+            &Constrain(ref plan, _) => plan.outputs(),
+            Sort(plan, _) | Limit(plan, _) | TopK(plan, _, _) => plan.outputs(),
+            Compute(plan, expressions) => plan.outputs()
+                .into_iter()
+                .chain(expressions.iter().map(|(var, _)| var.clone()))
+                .collect(),
+            Aggregate(_, group_by, aggregates, _) => group_by.iter()
+                .chain(aggregates.iter().map(|(var, _)| var))
+                .cloned()
+                .collect(),
+            NotExists(plan, _) | Exists(plan, _) => plan.outputs(),
+            Literal(Relation(vars, _)) => vars.iter().cloned().collect(),
+            Rule(_, call) => call.vars().into_iter().collect(),
+        }
+    }
+
+    /// The clauses whose matches make up the plan's rows.
+    pub fn clauses(&self) -> Vec<&Clause> {
+        use self::Plan::*;
+        match self {
+            Fetch(clause) | Scan(clause, _) => vec![clause],
+            LookupEach(plan, clause) => plan.clauses().into_iter().chain(Some(clause)).collect(),
+            Join(a, b) => a.clauses().into_iter().chain(b.clauses()).collect(),
+            CartesianProduct(plans) => plans.iter().flat_map(|p| p.clauses()).collect(),
+            Union(plans) => plans.iter().flat_map(|p| p.clauses()).collect(),
+            Project(plan, _) | Constrain(plan, _) | Sort(plan, _) | Limit(plan, _) | TopK(plan, _, _) | Compute(plan, _)
+                | Aggregate(plan, _, _, _) | NotExists(plan, _) | Exists(plan, _) => plan.clauses(),
+            Within(_) | Literal(_) | Rule(..) => vec![],
+        }
+    }
+
+    /// The vars the plan's rows are known to be sorted by (ascending,
+    /// the first var first), from the index order of the fetch it
+    /// starts with.
+    pub fn sorted_by(&self) -> Vec<Var> {
+        use self::Plan::*;
+        match self {
+            Fetch(clause) => clause.unbound_vars(),
+            // Which index is scanned depends on the schema.
+            Scan(..) | Within(_) | Union(_) | Rule(..) => vec![],
+            Join(plan, _) | LookupEach(plan, _) | Constrain(plan, _) | NotExists(plan, _) | Exists(plan, _) | Limit(plan, _) | Compute(plan, _) => plan.sorted_by(),
+            CartesianProduct(plans) => plans.first().map(|p| p.sorted_by()).unwrap_or_default(),
+            Project(plan, projection) => plan.sorted_by().into_iter().take_while(|v| projection.contains(v)).collect(),
+            // Groups come in the order of their first rows.
+            Aggregate(plan, group_by, _, _) => plan.sorted_by().into_iter().take_while(|v| group_by.contains(v)).collect(),
+            Sort(_, order) | TopK(_, order, _) => order.iter().take_while(|o| !o.descending).map(|o| o.var.clone()).collect(),
+            Literal(Relation(vars, tuples)) => {
+                if tuples.windows(2).all(|w| w[0] <= w[1]) {
+                    vars.clone()
+                } else {
+                    vec![]
+                }
+            }
+        }
+    }
+
+    /// Whether the plan's rows are already sorted by `order`.
+    pub fn is_sorted_by(&self, order: &[Order]) -> bool {
+        let sorted_by = self.sorted_by();
+        order.len() <= sorted_by.len() && order.iter().zip(sorted_by).all(|(o, var)| !o.descending && o.var == var)
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        use self::Plan::*;
+        let list = |items: Vec<String>| items.join(" ");
+        let (step, children): (String, Vec<&Plan>) = match self {
+            Fetch(clause) => (format!("Fetch {}", clause), vec![]),
+            Scan(clause, predicate) => (format!("Scan {} {}", clause, predicate), vec![]),
+            Within(w) => (format!("Within {} {:?} {}m of {}", w.entity, w.attribute, w.radius, w.center), vec![]),
+            Join(a, b) => ("Join".into(), vec![a, b]),
+            LookupEach(plan, clause) => (format!("LookupEach {}", clause), vec![plan]),
+            CartesianProduct(plans) => ("CartesianProduct".into(), plans.iter().map(|p| &**p).collect()),
+            Union(plans) => ("Union".into(), plans.iter().collect()),
+            Project(plan, projection) => (format!("Project {}", list(projection.iter().map(|v| v.to_string()).collect())), vec![plan]),
+            Constrain(plan, constraints) => (format!("Constrain {}", list(constraints.iter().map(|c| c.to_string()).collect())), vec![plan]),
+            Sort(plan, order) => (format!("Sort {}", list(order.iter().map(|o| o.to_string()).collect())), vec![plan]),
+            Limit(plan, n) => (format!("Limit {}", n), vec![plan]),
+            TopK(plan, order, n) => (format!("TopK {} {}", n, list(order.iter().map(|o| o.to_string()).collect())), vec![plan]),
+            Compute(plan, expressions) => (format!("Compute {}", list(expressions.iter().map(|(_, e)| e.to_string()).collect())), vec![plan]),
+            Aggregate(plan, group_by, aggregates, with) => (
+                format!(
+                    "Aggregate {} by {}{}",
+                    list(aggregates.iter().map(|(_, a)| a.to_string()).collect()),
+                    list(group_by.iter().map(|v| v.to_string()).collect()),
+                    if with.is_empty() { String::new() } else { format!(" with {}", list(with.iter().map(|v| v.to_string()).collect())) }
+                ),
+                vec![plan],
+            ),
+            NotExists(plan, clause) => (format!("NotExists {}", clause), vec![plan]),
+            Exists(plan, clause) => (format!("Exists {}", clause), vec![plan]),
+            Literal(Relation(vars, tuples)) => (format!("Literal {} ({} rows)", list(vars.iter().map(|v| v.to_string()).collect()), tuples.len()), vec![]),
+            Rule(_, call) => (format!("Rule {}", call), vec![]),
+        };
+
+        write!(f, "{:width$}{}", "", step, width = depth * 2)?;
+        let sorted_by = self.sorted_by();
+        if !sorted_by.is_empty() {
+            write!(f, "  [ordered by {}]", list(sorted_by.iter().map(|v| v.to_string()).collect()))?;
+        }
+        writeln!(f)?;
+        for child in children {
+            child.fmt_indented(f, depth + 1)?;
         }
+        Ok(())
     }
 
     pub fn for_query(q: Query) -> Plan {
-        let final_relations = q.clauses.iter().fold(vec![], |relations, clause| {
+        Plan::for_query_collated(q, &HashMap::new())
+    }
+
+    /// Plans the query as `for_query` does, given the collations of
+    /// its vars (see `collations`). Rows come out of the indexes in
+    /// byte-wise order, so ordering by a collated var always sorts.
+    pub fn for_query_collated(q: Query, collations: &HashMap<Var, Collation>) -> Plan {
+        // Collection inputs start out as relations of their own, so
+        // clauses using their vars become a lookup per value.
+        let inputs: Vec<Plan> = q.inputs
+            .iter()
+            .map(|(var, values)| Plan::Literal(Relation(vec![var.clone()], values.iter().map(|v| vec![v.clone()]).collect())))
+            .collect();
+        let final_relations = q.clauses.iter().enumerate().fold(inputs, |relations, (i, clause)| {
             // Cases to care about:
             //
             // 1. Some unbound vars in clause match at least one relation.
@@ -132,7 +328,14 @@ impl Plan {
                 .cloned()
                 .partition(|r| overlaps(&clause, &r));
 
-            if overlapping.len() > 0 {
+            if !overlapping.is_empty() && q.hints.strategy(i) == Some(Strategy::Fetch) {
+                // Hinted: fetch the clause on its own and join it
+                // with the relations it overlaps.
+                let mut joined = vec![fetch(clause, &q.constraints)];
+                joined.extend(overlapping);
+                non_overlapping.push(join(joined));
+                non_overlapping
+            } else if overlapping.len() > 0 {
                 // add clause to relation
                 let prior_rel = overlapping[0].clone();
                 let mut outputs: HashSet<Var> = HashSet::new();
@@ -149,13 +352,52 @@ impl Plan {
                 non_overlapping.push(join(overlapping));
                 non_overlapping
             } else {
-                non_overlapping.push(
-                    Plan::Fetch(clause.clone())
-                );
+                non_overlapping.push(fetch(clause, &q.constraints));
                 non_overlapping
             }
         });
 
+        // Each `or` is a union of its clauses' fetches, joined with
+        // any relation which already binds its vars.
+        let final_relations = q.or.iter().fold(final_relations, |relations, alternatives| {
+            let union = Plan::Union(alternatives.iter().map(|clause| fetch(clause, &q.constraints)).collect());
+            let (overlapping, mut non_overlapping): (Vec<Plan>, Vec<Plan>) = relations
+                .into_iter()
+                .partition(|r| !r.outputs().is_disjoint(&union.outputs()));
+
+            let mut joined = vec![union];
+            joined.extend(overlapping);
+            non_overlapping.push(join(joined));
+            non_overlapping
+        });
+
+        // Rule calls are leaves too, joined with any relation which
+        // shares a var with them.
+        let final_relations = q.calls.iter().fold(final_relations, |relations, call| {
+            let rule = Plan::Rule(q.rules.clone(), call.clone());
+            let (overlapping, mut non_overlapping): (Vec<Plan>, Vec<Plan>) = relations
+                .into_iter()
+                .partition(|r| !r.outputs().is_disjoint(&rule.outputs()));
+
+            let mut joined = vec![rule];
+            joined.extend(overlapping);
+            non_overlapping.push(join(joined));
+            non_overlapping
+        });
+
+        // Radius predicates are leaves like fetches, joined with any
+        // relation which already binds their entity var.
+        let final_relations = q.within.iter().fold(final_relations, |relations, within| {
+            let (overlapping, mut non_overlapping): (Vec<Plan>, Vec<Plan>) = relations
+                .into_iter()
+                .partition(|r| r.outputs().contains(&within.entity));
+
+            let mut joined = vec![Plan::Within(within.clone())];
+            joined.extend(overlapping);
+            non_overlapping.push(join(joined));
+            non_overlapping
+        });
+
         // TODO: it's fine for correctness to just apply constraints
         // at the end, but it would be better for performance to apply
         // them as soon as the bindings they require are satisfied as
@@ -166,11 +408,252 @@ impl Plan {
             final_relations
         };
 
-        if constrained_relations.len() == 1 {
-            Plan::Project(Box::new(constrained_relations[0].clone()), q.find)
+        let combined = if constrained_relations.len() == 1 {
+            constrained_relations[0].clone()
+        } else {
+            Plan::CartesianProduct(constrained_relations.into_iter().map(|r| Box::new(r)).collect())
+        };
+
+        // `(active ?e)` is sugar for "?e has no db:deletedAt". Queries
+        // built in code can name vars anything, so the value var is
+        // picked to be one the plan doesn't already output.
+        let filtered = q.active.into_iter().fold(combined, |plan, var| {
+            let outputs = plan.outputs();
+            let deleted_at = (0..)
+                .map(|i| Var::new(format!("_deletedAt{}", i)))
+                .find(|candidate| !outputs.contains(candidate) && *candidate != var)
+                .unwrap();
+            let deleted = Clause::new(
+                Term::Unbound(var),
+                Term::Bound(Ident::Name("db:deletedAt".into())),
+                Term::Unbound(deleted_at),
+            );
+            Plan::NotExists(Box::new(plan), deleted)
+        });
+        let filtered = q.exists.into_iter().fold(filtered, |plan, clause| {
+            Plan::Exists(Box::new(plan), clause)
+        });
+        let filtered = q.not.into_iter().fold(filtered, |plan, clause| {
+            Plan::NotExists(Box::new(plan), clause)
+        });
+
+        // Aggregates group by the other columns, computed ones
+        // included, so those are worked out for every row first.
+        let aggregates = q.aggregates;
+        let (filtered, expressions) = if aggregates.is_empty() {
+            (filtered, q.expressions)
+        } else {
+            let group_by = q.find
+                .iter()
+                .filter(|var| !aggregates.iter().any(|(aggregate, _)| aggregate == *var))
+                .cloned()
+                .collect();
+            let computed = if q.expressions.is_empty() {
+                filtered
+            } else {
+                Plan::Compute(Box::new(filtered), q.expressions)
+            };
+            (Plan::Aggregate(Box::new(computed), group_by, aggregates, q.with), vec![])
+        };
+
+        // A limit on unordered results keeps the first rows found.
+        let collated = q.order_by.iter().any(|o| collations.contains_key(&o.var));
+        let in_order = q.order_by.is_empty() || (!collated && filtered.is_sorted_by(&q.order_by));
+        let sorted = match q.limit {
+            Some(n) if in_order => Plan::Limit(Box::new(filtered), n),
+            Some(n) => Plan::TopK(Box::new(filtered), q.order_by, n),
+            None if in_order => filtered,
+            None => Plan::Sort(Box::new(filtered), q.order_by),
+        };
+
+        // Computed columns are added last, so they're only worked out
+        // for the rows which are kept.
+        let computed = if expressions.is_empty() {
+            sorted
         } else {
-            Plan::Project(Box::new(Plan::CartesianProduct(constrained_relations.into_iter().map(|r| Box::new(r)).collect())), q.find)
+            Plan::Compute(Box::new(sorted), expressions)
+        };
+
+        Plan::Project(Box::new(computed), q.find)
+    }
+}
+
+/// Shows the plan as a tree of steps, each with the vars its rows are
+/// ordered by, for `\explain`.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// The collations of the vars the clauses bind to values of
+/// attributes with one, which constraints and ordering compare those
+/// vars by.
+pub fn collations<'a, I: IntoIterator<Item = &'a Clause>>(clauses: I, schema: &Schema) -> HashMap<Var, Collation> {
+    clauses
+        .into_iter()
+        .filter_map(|clause| {
+            let attribute = match clause.attribute {
+                Term::Bound(Ident::Entity(e)) => Some(e),
+                Term::Bound(Ident::Name(ref name)) => schema.resolve(name),
+                Term::Unbound(_) => None,
+            };
+            match (attribute.and_then(|a| schema.collations.get(&a)), &clause.value) {
+                (Some(collation), Term::Unbound(var)) => Some((var.clone(), *collation)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Reorders the query's clauses greedily by their estimated number
+/// of results, preferring clauses which share a var with the ones
+/// already picked so the plan doesn't fall back to cartesian
+/// products. Ties keep the order given. Clauses hinted to be looked
+/// up wait until they share a var with the ones picked, if they can.
+/// Without statistics, or with `(hint ordered)`, the query is
+/// returned unchanged.
+pub fn order_by_selectivity(mut q: Query, stats: &Stats, schema: &Schema) -> Query {
+    if stats.is_empty() || q.hints.ordered {
+        return q;
+    }
+
+    let mut bound: HashSet<Var> = q.inputs.iter().map(|(var, _)| var.clone()).collect();
+    let mut remaining: Vec<(usize, Clause)> = std::mem::take(&mut q.clauses).into_iter().enumerate().collect();
+    let mut order = vec![];
+    while !remaining.is_empty() {
+        let connected = |clause: &Clause| clause.unbound_vars().iter().any(|var| bound.contains(var));
+        let lookup = |i: usize| q.hints.strategy(i) == Some(Strategy::Lookup);
+        let any_connected = remaining.iter().any(|(_, c)| connected(c));
+        let any_unhinted = remaining.iter().any(|&(i, _)| !lookup(i));
+        let mut best: Option<(usize, f64)> = None;
+        for (pos, (i, clause)) in remaining.iter().enumerate() {
+            if (any_connected && !connected(clause)) || (!any_connected && any_unhinted && lookup(*i)) {
+                continue;
+            }
+            let estimate = estimate(clause, &bound, &q.constraints, stats, schema);
+            if best.is_none_or(|(_, b)| estimate < b) {
+                best = Some((pos, estimate));
+            }
         }
+
+        let (i, clause) = remaining.remove(best.unwrap().0);
+        bound.extend(clause.unbound_vars());
+        order.push(i);
+        q.clauses.push(clause);
+    }
+
+    // Hints refer to clauses by position, so they follow them.
+    for hint in q.hints.strategies.iter_mut() {
+        if let Some(pos) = order.iter().position(|&i| i == hint.0) {
+            hint.0 = pos;
+        }
+    }
+    q
+}
+
+/// Estimates how many facts `clause` matches, per binding of the vars
+/// in `bound` it uses. With its entity bound, that's roughly the
+/// chance of a match, assuming entities have one value each.
+/// Attributes without statistics were created since the last
+/// reindex, so few facts can have them.
+fn estimate(clause: &Clause, bound: &HashSet<Var>, constraints: &[Constraint], stats: &Stats, schema: &Schema) -> f64 {
+    let attribute = match clause.attribute {
+        Term::Bound(Ident::Entity(e)) => Some(e),
+        Term::Bound(Ident::Name(ref name)) => schema.resolve(name),
+        Term::Unbound(_) => None,
+    };
+    let attr_stats = match attribute {
+        Some(a) => match stats.attributes.get(&a) {
+            Some(s) => s,
+            None => return 0.0,
+        },
+        None => return stats.attributes.values().map(|s| s.count as f64).sum(),
+    };
+
+    let estimate = match clause.value {
+        Term::Bound(ref value) => attr_stats.estimate_equal(value),
+        Term::Unbound(ref var) if bound.contains(var) => attr_stats.average_per_value(),
+        Term::Unbound(ref var) => attr_stats.count as f64 * range_fraction(attr_stats, var, constraints),
+    };
+    let entity_bound = match clause.entity {
+        Term::Bound(_) => true,
+        Term::Unbound(ref var) => bound.contains(var),
+    };
+    let estimate = if entity_bound && attr_stats.count > 0 {
+        estimate / attr_stats.count as f64
+    } else {
+        estimate
+    };
+    // A bound transaction has only so many records.
+    match clause.tx {
+        Some(Term::Bound(_)) if stats.log.count > 0 => estimate.min(stats.log.average_per_value()),
+        _ => estimate,
+    }
+}
+
+/// Whether to read `clause`'s facts from the part of the log index
+/// for the transactions `txs`, rather than from the index its shape
+/// picks (see `Db::fetch`): true when the log has fewer records in
+/// the range than that index would read. Without statistics for the
+/// log, only clauses which would scan the whole EAVT index read it.
+pub fn prefers_log(clause: &Clause, txs: &RangeInclusive<i64>, stats: &Stats, schema: &Schema) -> bool {
+    let attribute = match clause.attribute {
+        Term::Bound(Ident::Entity(e)) => Some(e),
+        Term::Bound(Ident::Name(ref name)) => schema.resolve(name),
+        Term::Unbound(_) => None,
+    };
+    if stats.log.count == 0 {
+        return attribute.is_none() && matches!(clause.entity, Term::Unbound(_));
+    }
+
+    let read = match (attribute, &clause.value) {
+        // Values of other types are only grouped together by the
+        // AVET index, for attributes which are indexed.
+        (Some(a), Term::Bound(value)) if !matches!(value, Value::Ref(_)) && !schema.is_indexed(a) => {
+            stats.attributes.get(&a).map_or(0.0, |s| s.count as f64)
+        }
+        // The index is read for every transaction's facts.
+        _ => estimate(&Clause { tx: None, ..clause.clone() }, &HashSet::new(), &[], stats, schema),
+    };
+    stats.estimate_log(txs) < read
+}
+
+/// The fraction of an attribute's values left by the `<` and `>`
+/// constraints comparing `var` with a constant.
+fn range_fraction(stats: &AttributeStats, var: &Var, constraints: &[Constraint]) -> f64 {
+    let is_var = |term: &Term<Value>| match term {
+        Term::Unbound(v) => v == var,
+        Term::Bound(_) => false,
+    };
+    constraints.iter().fold(1.0, |fraction, constraint| {
+        // Whether the constraint keeps the values below the constant.
+        let (value, below) = match (&constraint.left_hand_side, &constraint.right_hand_side) {
+            (lhs, Term::Bound(value)) if is_var(lhs) => (value, constraint.comparator == Comparator::LessThan),
+            (Term::Bound(value), rhs) if is_var(rhs) => (value, constraint.comparator == Comparator::GreaterThan),
+            _ => return fraction,
+        };
+        fraction * match constraint.comparator {
+            Comparator::NotEqualTo | Comparator::StartsWith | Comparator::MatchesCi => 1.0,
+            _ if below => stats.fraction_below(value),
+            _ => 1.0 - stats.fraction_below(value),
+        }
+    })
+}
+
+/// Fetches the clause's facts, scanning for them when a constraint
+/// is a string predicate on the clause's value (and its entity isn't
+/// known, or the EAVT index would be better). A scan doesn't bind
+/// the clause's `tx` and `added` terms, so clauses with them are
+/// always fetched.
+fn fetch(clause: &Clause, constraints: &[Constraint]) -> Plan {
+    let scannable = |var: &Var| match (&clause.entity, &clause.attribute, &clause.value) {
+        (Term::Unbound(_), Term::Bound(_), Term::Unbound(v)) => v == var && clause.tx.is_none() && clause.added.is_none(),
+        _ => false,
+    };
+    match constraints.iter().find(|c| c.string_predicate().is_some_and(|(var, _)| scannable(var))) {
+        Some(predicate) => Plan::Scan(clause.clone(), predicate.clone()),
+        None => Plan::Fetch(clause.clone()),
     }
 }
 
@@ -210,9 +693,12 @@ mod tests {
     use proptest::strategy::Strategy;
 
     use {Entity, Value, Ident};
-    use queries::query::{Query, Clause, Term};
+    use queries::query::{Query, Clause, Term, Constraint, Comparator, Hints, Order};
+    use queries::query::Strategy as ClauseStrategy;
     use queries::query::Term::{Bound, Unbound};
-    use queries::planner::{Plan};
+    use queries::planner::{Plan, order_by_selectivity, prefers_log};
+    use stats::{Stats, AttributeStats};
+    use schema::Schema;
 
     #[test]
     fn test_plan_single_clause() {
@@ -220,8 +706,27 @@ mod tests {
         let find = vec!["a".into(), "b".into()];
         let query = Query {
             find: find.clone(),
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
             clauses: vec![clause.clone()],
             constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
         };
         let plan = Plan::for_query(query);
         assert_eq!(
@@ -230,6 +735,63 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_plan_order_by() {
+        let clause_a = Clause::new(Unbound("a".into()), Bound(Ident::Entity(Entity(1))), Unbound("b".into()));
+        let clause_b = Clause::new(Unbound("b".into()), Bound(Ident::Entity(Entity(2))), Unbound("c".into()));
+        let query = |order_by| Query {
+            find: vec!["c".into()],
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
+            clauses: vec![clause_a.clone(), clause_b.clone()],
+            constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by,
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
+        };
+        let lookup = Plan::LookupEach(Box::new(Plan::Fetch(clause_a.clone())), clause_b.clone());
+
+        // The lookups keep the fetch's order, by ?a then ?b.
+        assert_eq!(lookup.sorted_by(), vec!["a".into(), "b".into()]);
+        for order_by in [vec![Order::asc("a")], vec![Order::asc("a"), Order::asc("b")]] {
+            assert_eq!(Plan::for_query(query(order_by)), Plan::Project(Box::new(lookup.clone()), vec!["c".into()]));
+        }
+
+        for order_by in [vec![Order::asc("b")], vec![Order::desc("a")], vec![Order::asc("a"), Order::asc("c")]] {
+            assert_eq!(
+                Plan::for_query(query(order_by.clone())),
+                Plan::Project(Box::new(Plan::Sort(Box::new(lookup.clone()), order_by)), vec!["c".into()])
+            );
+        }
+
+        let limited = |order_by, limit| Query { limit: Some(limit), ..query(order_by) };
+        assert_eq!(
+            Plan::for_query(limited(vec![Order::asc("a")], 5)),
+            Plan::Project(Box::new(Plan::Limit(Box::new(lookup.clone()), 5)), vec!["c".into()])
+        );
+        assert_eq!(
+            Plan::for_query(limited(vec![Order::desc("c")], 5)),
+            Plan::Project(Box::new(Plan::TopK(Box::new(lookup.clone()), vec![Order::desc("c")], 5)), vec!["c".into()])
+        );
+
+        // Projecting away ?a loses the order.
+        assert_eq!(Plan::Project(Box::new(lookup), vec!["b".into()]).sorted_by(), vec![]);
+    }
+
     #[test]
     fn test_plan_fetch_and_lookup() {
         let clause_a = Clause::new(Unbound("a".into()), Bound(Ident::Entity(Entity(1))), Unbound("b".into()));
@@ -237,8 +799,27 @@ mod tests {
         let find = vec!["a".into(), "b".into(), "c".into()];
         let query = Query {
             find: find.clone(),
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
             clauses: vec![clause_a.clone(), clause_b.clone()],
             constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
         };
         let fetch_plan = Plan::Fetch(clause_a);
         assert_eq!(
@@ -342,8 +923,27 @@ mod tests {
         let find = vec!["a".into(), "b".into(), "c".into(), "d".into()];
         let query = Query {
             find: find.clone(),
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
             clauses: vec![clause_a.clone(), clause_b.clone(), clause_c.clone()],
             constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
         };
         let fetch_plan_a = Plan::Fetch(clause_a);
         let fetch_plan_b = Plan::Fetch(clause_b);
@@ -353,4 +953,197 @@ mod tests {
             Plan::Project(Box::new(Plan::Join(Box::new(lookup_plan), Box::new(fetch_plan_b))), find)
         );
     }
+
+    #[test]
+    fn test_order_by_selectivity() {
+        // Attribute 1 (name) has a distinct value per entity; attribute
+        // 2 (age) has values 0..100 spread over 10000 entities.
+        let mut stats = Stats::default();
+        stats.attributes.insert(Entity(1), AttributeStats {
+            count: 10000,
+            distinct: 10000,
+            boundaries: (0..50).map(|i| Value::String(format!("{:03}", i * 200))).collect(),
+            depth: 200,
+            retracted: None,
+        });
+        stats.attributes.insert(Entity(2), AttributeStats {
+            count: 10000,
+            distinct: 100,
+            boundaries: (0..50).map(|i| Value::Long(i * 2)).collect(),
+            depth: 200,
+            retracted: None,
+        });
+        let age = Clause::new(Unbound("e".into()), Bound(Ident::Entity(Entity(2))), Unbound("age".into()));
+        let name = Clause::new(Unbound("e".into()), Bound(Ident::Entity(Entity(1))), Bound(Value::String("Bob".into())));
+        let friend = Clause::new(Unbound("f".into()), Bound(Ident::Entity(Entity(3))), Unbound("e".into()));
+        let query = |clauses: Vec<Clause>, constraints| Query {
+            find: vec!["e".into()],
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
+            clauses,
+            constraints,
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
+        };
+
+        // Without stats, the given order is kept.
+        let q = query(vec![age.clone(), name.clone()], vec![]);
+        assert_eq!(order_by_selectivity(q.clone(), &Stats::default(), &Schema::empty()), q);
+
+        // The bound name is the most selective, and the new attribute
+        // 3 has no facts as of the last reindex.
+        let q = query(vec![age.clone(), name.clone(), friend.clone()], vec![]);
+        assert_eq!(order_by_selectivity(q, &stats, &Schema::empty()).clauses, vec![friend, name.clone(), age.clone()]);
+
+        // A narrow range on age beats a name which isn't bound.
+        let any_name = Clause::new(Unbound("e".into()), Bound(Ident::Entity(Entity(1))), Unbound("name".into()));
+        let young = Constraint {
+            comparator: Comparator::LessThan,
+            left_hand_side: Unbound("age".into()),
+            right_hand_side: Bound(Value::Long(4)),
+        };
+        let q = query(vec![any_name.clone(), age.clone()], vec![young]);
+        assert_eq!(order_by_selectivity(q, &stats, &Schema::empty()).clauses, vec![age, any_name]);
+    }
+
+    #[test]
+    fn test_prefers_log() {
+        let mut stats = Stats::default();
+        stats.attributes.insert(Entity(1), AttributeStats {
+            count: 10000,
+            distinct: 10000,
+            boundaries: (0..50).map(|i| Value::String(format!("{:03}", i * 200))).collect(),
+            depth: 200,
+            retracted: None,
+        });
+        let names = Clause::new(Unbound("e".into()), Bound(Ident::Entity(Entity(1))), Unbound("n".into()));
+        let anything = Clause::new(Unbound("e".into()), Unbound("a".into()), Unbound("v".into()));
+        let recent = 1990..=i64::MAX;
+
+        // Without statistics for the log, only a clause which would
+        // scan everything reads it.
+        assert!(!prefers_log(&names, &recent, &stats, &Schema::empty()));
+        assert!(prefers_log(&anything, &recent, &stats, &Schema::empty()));
+
+        // 20 records in each of transactions 1000..2000.
+        stats.log = AttributeStats {
+            count: 20000,
+            distinct: 1000,
+            boundaries: (0..50).map(|i| Value::Ref(Entity(1000 + i * 20))).collect(),
+            depth: 400,
+            retracted: None,
+        };
+        assert!(prefers_log(&names, &recent, &stats, &Schema::empty()));
+        assert!(!prefers_log(&names, &(1000..=i64::MAX), &stats, &Schema::empty()));
+        let in_tx = Clause { tx: Some(Bound(Entity(1500))), ..names.clone() };
+        assert!(prefers_log(&in_tx, &(1500..=1500), &stats, &Schema::empty()));
+        // An entity's facts are quicker to read from EAVT.
+        let bobs = Clause::new(Bound(Entity(5)), Bound(Ident::Entity(Entity(1))), Unbound("n".into()));
+        assert!(!prefers_log(&bobs, &recent, &stats, &Schema::empty()));
+
+        // A clause in a bound transaction is estimated to match only
+        // as many facts as the transaction has.
+        let query = |clauses: Vec<Clause>| Query {
+            find: vec!["e".into()],
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
+            clauses,
+            constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
+        };
+        let named = Clause::new(Unbound("e".into()), Bound(Ident::Entity(Entity(1))), Bound(Value::String("Bob".into())));
+        let changed = in_tx;
+        let q = query(vec![changed.clone(), names.clone()]);
+        assert_eq!(order_by_selectivity(q, &stats, &Schema::empty()).clauses, vec![changed.clone(), names]);
+        let q = query(vec![changed.clone(), named.clone()]);
+        assert_eq!(order_by_selectivity(q, &stats, &Schema::empty()).clauses, vec![named, changed]);
+    }
+
+    #[test]
+    fn test_plan_hints() {
+        let name = Clause::new(Unbound("a".into()), Bound(Ident::Entity(Entity(1))), Unbound("b".into()));
+        let age = Clause::new(Unbound("a".into()), Bound(Ident::Entity(Entity(2))), Unbound("c".into()));
+        let find = vec!["a".into()];
+        let mut query = Query {
+            find: find.clone(),
+            expressions: vec![],
+            aggregates: vec![],
+            with: vec![],
+            clauses: vec![name.clone(), age.clone()],
+            constraints: vec![],
+            within: vec![],
+            active: vec![],
+            exists: vec![],
+            not: vec![],
+            or: vec![],
+            rules: vec![],
+            calls: vec![],
+            lookups: vec![],
+            inputs: vec![],
+            scalar_inputs: vec![],
+            hints: Hints::default(),
+            order_by: vec![],
+            limit: None,
+            as_of: None,
+            since: None,
+            history: false,
+        };
+        assert_eq!(
+            Plan::for_query(query.clone()),
+            Plan::Project(Box::new(Plan::LookupEach(Box::new(Plan::Fetch(name.clone())), age.clone())), find.clone())
+        );
+
+        query.hints.strategies.push((1, ClauseStrategy::Fetch));
+        assert_eq!(
+            Plan::for_query(query.clone()),
+            Plan::Project(Box::new(Plan::Join(Box::new(Plan::Fetch(age.clone())), Box::new(Plan::Fetch(name.clone())))), find)
+        );
+
+        // Hints follow their clause when the clauses are reordered;
+        // `ordered` keeps them as written.
+        let mut stats = Stats::default();
+        stats.attributes.insert(Entity(1), AttributeStats { count: 100, distinct: 100, boundaries: vec![], depth: 1, retracted: None });
+        stats.attributes.insert(Entity(2), AttributeStats { count: 10, distinct: 10, boundaries: vec![], depth: 1, retracted: None });
+        let ordered = order_by_selectivity(query.clone(), &stats, &Schema::empty());
+        assert_eq!(ordered.clauses, vec![age.clone(), name.clone()]);
+        assert_eq!(ordered.hints.strategies, vec![(0, ClauseStrategy::Fetch)]);
+
+        query.hints.ordered = true;
+        assert_eq!(order_by_selectivity(query.clone(), &stats, &Schema::empty()), query);
+
+        // A clause hinted to be looked up isn't picked first.
+        query.hints = Hints { ordered: false, strategies: vec![(1, ClauseStrategy::Lookup)] };
+        assert_eq!(order_by_selectivity(query, &stats, &Schema::empty()).clauses, vec![name, age]);
+    }
 }
@@ -1,16 +1,416 @@
-use im::HashMap;
+use std::cmp;
+use std::fmt;
 
-use {Entity, Value, Result, Ident};
+use chrono::Datelike;
+use im::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+
+use {Entity, EntitySpec, Value, Result, Ident};
+use geo::GeoPoint;
+use schema::Collation;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Query {
     pub find: Vec<Var>,
+    /// Columns in `find` computed from the other vars, such as
+    /// `(year ?at)`, each found as the var named by its text.
+    pub expressions: Vec<(Var, Expression)>,
+    /// Columns in `find` which aggregate the rows sharing the other
+    /// columns' values, such as `(count ?b)`, each found as the var
+    /// named by its text.
+    pub aggregates: Vec<(Var, Aggregate)>,
+    /// Vars which keep rows apart when aggregating, written
+    /// `with ?e` after `find`. Aggregates see each distinct tuple of
+    /// the `find` vars and these once, so e.g. two people with the
+    /// same salary are only summed separately `with` the person.
+    pub with: Vec<Var>,
     pub clauses: Vec<Clause>,
     pub constraints: Vec<Constraint>,
+    pub within: Vec<Within>,
+    /// Vars which must be bound to entities that haven't been soft
+    /// deleted, written `(active ?e)`.
+    pub active: Vec<Var>,
+    /// Clauses which must match something for a row to be kept,
+    /// written `(exists (?e a ?v))`. Vars they don't share with the
+    /// rest of the query aren't bound in the results.
+    pub exists: Vec<Clause>,
+    /// Clauses which must match nothing for a row to be kept,
+    /// written `(not (?e parent ?p))`, e.g. to find entities missing
+    /// an attribute. Vars they don't share with the rest of the query
+    /// can take any value.
+    pub not: Vec<Clause>,
+    /// Alternatives, written `(or (?e name "Bob") (?e name "John"))`:
+    /// a row matches if any of the clauses does. The clauses in each
+    /// must use the same vars.
+    pub or: Vec<Vec<Clause>>,
+    /// Rules the query defines, written before `find` (see `Rule`).
+    pub rules: Vec<Rule>,
+    /// Calls of the query's rules in its clauses, written
+    /// `ancestor(?a ?b)`.
+    pub calls: Vec<RuleCall>,
+    /// Entities named in the clauses and constraints by something
+    /// other than their id, such as lookup refs, written
+    /// `[email "bob@x.com"]`. Each stands in the query as the var
+    /// named by its text, which is bound to the entity (see
+    /// `Db::resolve`) when the query is planned.
+    pub lookups: Vec<(Var, EntitySpec)>,
+    /// Vars bound to a collection of values, declared as
+    /// `in [?name ...]`, or to a single value, declared as
+    /// `in ?name`, and given their values by `bind` (or
+    /// `bind_inputs`). The query runs once per value, as a union.
+    pub inputs: Vec<(Var, Vec<Value>)>,
+    /// The vars of the `inputs` declared as a single value.
+    pub scalar_inputs: Vec<Var>,
+    /// Hints overriding the planner's choices, written `(hint ...)`.
+    pub hints: Hints,
+    /// How to sort the results, written `order by ?a ?b desc` after
+    /// the clauses. The vars needn't be among those found.
+    pub order_by: Vec<Order>,
+    /// The most rows to return, written `limit <n>` at the end. With
+    /// `order by`, these are the first rows in that order.
+    pub limit: Option<usize>,
+    /// Runs the query against the db as it was just after this
+    /// transaction (see `Db::as_of`), written `as of <tx>` after the
+    /// clauses.
+    pub as_of: Option<Entity>,
+    /// Runs the query against only the facts asserted and retracted
+    /// after this transaction (see `Db::since`), written `since <tx>`
+    /// after the clauses.
+    pub since: Option<Entity>,
+    /// Runs the query against every assertion and retraction (see
+    /// `Db::history`), written `history` after the clauses.
+    pub history: bool,
+}
+
+/// The value given for one of a query's inputs (see `Query::bind`).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum QueryInput {
+    /// For an input declared as `in ?name`.
+    Scalar(Value),
+    /// For an input declared as `in [?name ...]`.
+    Collection(Vec<Value>),
+}
+
+impl Query {
+    /// Binds the query's inputs, in the order they were declared, to
+    /// the given values: a scalar for each input declared as
+    /// `in ?name`, and a collection for each declared as
+    /// `in [?name ...]`. Being bound as typed values rather than
+    /// formatted into the query text, they can't change its
+    /// structure.
+    pub fn bind(self, inputs: Vec<QueryInput>) -> Result<Query> {
+        if inputs.len() != self.inputs.len() {
+            return Err(format!("query has {} inputs but {} values were given", self.inputs.len(), inputs.len()).into());
+        }
+        let mut collections = vec![];
+        for ((var, _), input) in self.inputs.iter().zip(inputs) {
+            match (self.scalar_inputs.contains(var), input) {
+                (true, QueryInput::Scalar(value)) => collections.push(vec![value]),
+                (false, QueryInput::Collection(values)) => collections.push(values),
+                (true, QueryInput::Collection(_)) => return Err(format!("input ?{} takes a single value, not a collection", var.name).into()),
+                (false, QueryInput::Scalar(_)) => return Err(format!("input ?{} takes a collection, not a single value", var.name).into()),
+            }
+        }
+        self.bind_inputs(collections)
+    }
+
+    /// Binds the query's inputs, in the order they were declared, to
+    /// the given collections of values, whether or not they were
+    /// declared as collections.
+    pub fn bind_inputs(mut self, collections: Vec<Vec<Value>>) -> Result<Query> {
+        if collections.len() != self.inputs.len() {
+            return Err(format!("query has {} inputs but {} collections were given", self.inputs.len(), collections.len()).into());
+        }
+        for (input, mut values) in self.inputs.iter_mut().zip(collections) {
+            // Inputs are sets; a repeated value would repeat rows.
+            values.sort();
+            values.dedup();
+            input.1 = values;
+        }
+        Ok(self)
+    }
+
+    /// Checks that the expressions call functions which exist, with
+    /// the right number of arguments.
+    pub fn check_expressions(&self) -> Result<()> {
+        for (_, expression) in &self.expressions {
+            expression.check()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the clauses in each `or` use the same vars, so
+    /// every row of their union has a value for each.
+    pub fn check_or(&self) -> Result<()> {
+        for alternatives in &self.or {
+            let vars = |clause: &Clause| clause.unbound_vars().into_iter().collect::<HashSet<Var>>();
+            let first = &alternatives[0];
+            if let Some(other) = alternatives.iter().find(|clause| vars(clause) != vars(first)) {
+                return Err(format!("clauses in an or must use the same vars, but {} and {} don't", first, other).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every rule called is defined, with as many params
+    /// as it's given args, and that the params of each rule are all
+    /// bound by its body.
+    pub fn check_rules(&self) -> Result<()> {
+        let arity = |name: &str| self.rules.iter().find(|r| r.name == name).map(|r| r.params.len());
+        for rule in &self.rules {
+            if arity(&rule.name) != Some(rule.params.len()) {
+                return Err(format!("rule {} is defined with different numbers of params", rule.name).into());
+            }
+            let bound: HashSet<Var> = rule.clauses.iter()
+                .flat_map(|c| c.unbound_vars())
+                .chain(rule.calls.iter().flat_map(|c| c.vars()))
+                .collect();
+            if let Some(param) = rule.params.iter().find(|p| !bound.contains(*p)) {
+                return Err(format!("param {} of rule {} isn't bound by its body", param, rule.name).into());
+            }
+        }
+        for call in self.calls.iter().chain(self.rules.iter().flat_map(|r| r.calls.iter())) {
+            match arity(&call.name) {
+                None => return Err(format!("no rule named {}", call.name).into()),
+                Some(n) if n != call.args.len() => {
+                    return Err(format!("rule {} takes {} args, but {} were given", call.name, n, call.args.len()).into());
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the hints refer to clauses the query has.
+    pub fn check_hints(&self) -> Result<()> {
+        for &(clause, _) in &self.hints.strategies {
+            if clause >= self.clauses.len() {
+                return Err(format!("hint refers to clause {} but the query has {} clauses", clause, self.clauses.len()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the facts matching a clause are found.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Strategy {
+    /// Fetch all the facts matching the clause and join them with
+    /// the results so far, written `(hint fetch <n>)`.
+    Fetch,
+    /// Look up the facts matching the clause for each result so far,
+    /// written `(hint lookup <n>)`. The planner does this anyway for
+    /// clauses sharing a var with earlier ones; the hint keeps it
+    /// from moving the clause ahead of them.
+    Lookup,
+}
+
+/// Hints for when the planner's estimates are wrong.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Hints {
+    /// Plan the clauses in the order they're written, rather than by
+    /// selectivity, written `(hint ordered)`.
+    pub ordered: bool,
+    /// Strategies for clauses, by their position in the query
+    /// counting from 0.
+    pub strategies: Vec<(usize, Strategy)>,
+}
+
+impl Hints {
+    /// The strategy pinned for the clause at `clause`, if any. A
+    /// later hint for the same clause wins.
+    pub fn strategy(&self, clause: usize) -> Option<Strategy> {
+        self.strategies.iter().rev().find(|&&(i, _)| i == clause).map(|&(_, s)| s)
+    }
+}
+
+/// A computed column in `find`: a var, a literal, or a call to one
+/// of a few pure functions, such as `(str ?first " " ?last)`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expression {
+    Var(Var),
+    Literal(Value),
+    Call(String, Vec<Expression>),
+}
+
+/// The functions expressions can call, with how many arguments each
+/// takes (`None` for any number):
+///
+/// - `str` joins its arguments as text;
+/// - `upper`, `lower` and `len` (in characters) take a string;
+/// - `year`, `month` and `day` take a timestamp, in UTC.
+const FUNCTIONS: &[(&str, Option<usize>)] = &[
+    ("str", None),
+    ("upper", Some(1)),
+    ("lower", Some(1)),
+    ("len", Some(1)),
+    ("year", Some(1)),
+    ("month", Some(1)),
+    ("day", Some(1)),
+];
+
+impl Expression {
+    pub fn vars(&self) -> Vec<&Var> {
+        match self {
+            Expression::Var(var) => vec![var],
+            Expression::Literal(_) => vec![],
+            Expression::Call(_, args) => args.iter().flat_map(|arg| arg.vars()).collect(),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if let Expression::Call(name, args) = self {
+            if AggregateFunction::from_name(name).is_some() {
+                return Err(format!("{} takes a single var, and can't be used inside another expression", name).into());
+            }
+            match FUNCTIONS.iter().find(|f| f.0 == name) {
+                None => return Err(format!("unknown function {}", name).into()),
+                Some(&(_, Some(arity))) if arity != args.len() => {
+                    return Err(format!("{} takes {} argument(s) but was given {}", name, arity, args.len()).into());
+                }
+                Some(_) => {}
+            }
+            for arg in args {
+                arg.check()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn eval(&self, binding: &HashMap<&Var, &Value>) -> Result<Value> {
+        let (name, args) = match self {
+            Expression::Var(var) => {
+                return binding.get(var).map(|v| (*v).clone()).ok_or_else(|| format!("{} isn't bound", var).into());
+            }
+            Expression::Literal(value) => return Ok(value.clone()),
+            Expression::Call(name, args) => (name, args),
+        };
+        let args = args.iter().map(|arg| arg.eval(binding)).collect::<Result<Vec<Value>>>()?;
+
+        let text = |value: &Value| match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        match (name.as_str(), args.as_slice()) {
+            ("str", args) => Ok(Value::String(args.iter().map(text).collect())),
+            ("upper", [Value::String(s)]) => Ok(Value::String(s.to_uppercase())),
+            ("lower", [Value::String(s)]) => Ok(Value::String(s.to_lowercase())),
+            ("len", [Value::String(s)]) => Ok(Value::Long(s.chars().count() as i64)),
+            ("year", [Value::Timestamp(t)]) => Ok(Value::Long(t.year() as i64)),
+            ("month", [Value::Timestamp(t)]) => Ok(Value::Long(t.month() as i64)),
+            ("day", [Value::Timestamp(t)]) => Ok(Value::Long(t.day() as i64)),
+            _ => Err(format!("can't apply {} to {}", name, args.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ")).into()),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Var(var) => write!(f, "{}", var),
+            Expression::Literal(value) => write!(f, "{}", value),
+            Expression::Call(name, args) => {
+                write!(f, "({}", name)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// The functions aggregate columns can use.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AggregateFunction {
+    /// The number of rows.
+    Count,
+    /// The total of the values, which must be numbers.
+    Sum,
+    Min,
+    Max,
+    /// The mean of the values, which must be numbers, rounded down.
+    Avg,
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<AggregateFunction> {
+        match name {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            "avg" => Some(AggregateFunction::Avg),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Avg => "avg",
+        }
+    }
+}
+
+/// An aggregate column in `find`, such as `(count ?b)`: the rows are
+/// grouped by the query's other columns, and the function applied to
+/// the values of `var` in each group. Rows aren't deduplicated first,
+/// so `count` counts every match.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    pub var: Var,
+}
+
+impl Aggregate {
+    /// The aggregate a call in `find` such as `(count ?b)` stands
+    /// for, if it's one.
+    pub fn from_expression(expression: &Expression) -> Option<Aggregate> {
+        match expression {
+            Expression::Call(name, args) => match (AggregateFunction::from_name(name), args.as_slice()) {
+                (Some(function), [Expression::Var(var)]) => Some(Aggregate { function, var: var.clone() }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({} {})", self.function.name(), self.var)
+    }
+}
+
+/// A sort key for query results.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Order {
+    pub var: Var,
+    pub descending: bool,
+}
+
+impl Order {
+    pub fn asc<V: Into<Var>>(var: V) -> Order {
+        Order { var: var.into(), descending: false }
+    }
+
+    pub fn desc<V: Into<Var>>(var: V) -> Order {
+        Order { var: var.into(), descending: true }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.var, if self.descending { " desc" } else { "" })
+    }
 }
 
 /// A free logic variable
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Var {
     pub name: String,
 }
@@ -23,6 +423,12 @@ impl Var {
     }
 }
 
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "?{}", self.name)
+    }
+}
+
 impl<T: Into<String>> From<T> for Var {
     fn from(x: T) -> Self {
         Var { name: x.into() }
@@ -34,6 +440,14 @@ pub struct Clause {
     pub entity: Term<Entity>,
     pub attribute: Term<Ident>,
     pub value: Term<Value>,
+    /// The transaction of the matching record, written as a fourth
+    /// term, `(?e name ?v ?tx)`.
+    pub tx: Option<Term<Entity>>,
+    /// Whether the matching record is an assertion rather than a
+    /// retraction, written as a fifth term, `(?e name ?v ?tx ?added)`.
+    /// Retractions are only matched in a history view (see
+    /// `Db::history`); otherwise it's always true.
+    pub added: Option<Term<bool>>,
 }
 
 impl Clause {
@@ -42,9 +456,19 @@ impl Clause {
             entity: e,
             attribute: a,
             value: v,
+            tx: None,
+            added: None,
         }
     }
 
+    pub fn with_tx(self, tx: Term<Entity>) -> Clause {
+        Clause { tx: Some(tx), ..self }
+    }
+
+    pub fn with_added(self, added: Term<bool>) -> Clause {
+        Clause { added: Some(added), ..self }
+    }
+
     pub fn unbound_vars(&self) -> Vec<Var> {
         let mut unbound: Vec<Var> = vec![];
 
@@ -60,6 +484,14 @@ impl Clause {
             unbound.push(v_var.clone());
         }
 
+        if let Some(Term::Unbound(ref tx_var)) = self.tx {
+            unbound.push(tx_var.clone());
+        }
+
+        if let Some(Term::Unbound(ref added_var)) = self.added {
+            unbound.push(added_var.clone());
+        }
+
         return unbound;
     }
 
@@ -103,7 +535,56 @@ impl Clause {
             }
         };
 
-        Ok(Clause::new(entity, attribute, value))
+        let tx = match self.tx {
+            Some(Term::Unbound(ref var)) => match env.get(var) {
+                Some(&Value::Ref(tx)) => Some(Term::Bound(tx)),
+                Some(_) => return Err("type mismatch".into()),
+                None => self.tx.clone(),
+            },
+            _ => self.tx.clone(),
+        };
+
+        let added = match self.added {
+            Some(Term::Unbound(ref var)) => match env.get(var) {
+                Some(&Value::Boolean(added)) => Some(Term::Bound(added)),
+                Some(_) => return Err("type mismatch".into()),
+                None => self.added.clone(),
+            },
+            _ => self.added.clone(),
+        };
+
+        Ok(Clause { entity, attribute, value, tx, added })
+    }
+}
+
+/// Writes the clause as it would appear in a query.
+impl fmt::Display for Clause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entity = match self.entity {
+            Term::Bound(e) => e.0.to_string(),
+            Term::Unbound(ref var) => var.to_string(),
+        };
+        let attribute = match self.attribute {
+            Term::Bound(Ident::Name(ref name)) => name.clone(),
+            Term::Bound(Ident::Entity(e)) => e.0.to_string(),
+            Term::Unbound(ref var) => var.to_string(),
+        };
+        let value = match self.value {
+            Term::Bound(ref v) => v.to_string(),
+            Term::Unbound(ref var) => var.to_string(),
+        };
+        write!(f, "({} {} {}", entity, attribute, value)?;
+        match self.tx {
+            Some(Term::Bound(tx)) => write!(f, " {}", tx.0)?,
+            Some(Term::Unbound(ref var)) => write!(f, " {}", var)?,
+            None => {}
+        }
+        match self.added {
+            Some(Term::Bound(added)) => write!(f, " {}", added)?,
+            Some(Term::Unbound(ref var)) => write!(f, " {}", var)?,
+            None => {}
+        }
+        write!(f, ")")
     }
 }
 
@@ -114,12 +595,93 @@ pub enum Term<T> {
     Unbound(Var),
 }
 
-/// A comparator is <, > or !=.
+/// A radius predicate, `(within ?e location <lat> <lon> <radius>)`,
+/// matching entities whose geo-typed attribute lies within `radius`
+/// meters of `center`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Within {
+    pub entity: Var,
+    pub attribute: Ident,
+    pub center: GeoPoint,
+    pub radius: u64,
+}
+
+/// A rule, written `rule ancestor(?a ?b) :- (?a parent ?b)`: its
+/// tuples are the values of its params for which every clause in its
+/// body matches. Several rules with the same name are alternatives,
+/// and a body can call rules, including the one it defines, so
+/// `rule ancestor(?a ?c) :- (?a parent ?b) ancestor(?b ?c)` extends
+/// the first to every ancestor.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub params: Vec<Var>,
+    pub clauses: Vec<Clause>,
+    pub calls: Vec<RuleCall>,
+}
+
+/// A call of a rule, written `ancestor(?a 12)`, matching the rule's
+/// tuples whose values agree with the args.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RuleCall {
+    pub name: String,
+    pub args: Vec<Term<Value>>,
+}
+
+impl RuleCall {
+    /// The vars among the args, each once, in order.
+    pub fn vars(&self) -> Vec<Var> {
+        let mut vars: Vec<Var> = vec![];
+        for arg in &self.args {
+            if let Term::Unbound(ref var) = *arg {
+                if !vars.contains(var) {
+                    vars.push(var.clone());
+                }
+            }
+        }
+        vars
+    }
+}
+
+impl fmt::Display for RuleCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|arg| match *arg {
+            Term::Bound(ref v) => v.to_string(),
+            Term::Unbound(ref var) => var.to_string(),
+        }).collect();
+        write!(f, "{}({})", self.name, args.join(" "))
+    }
+}
+
+/// A comparator is <, > or !=, or a string predicate.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Comparator {
     GreaterThan,
     LessThan,
     NotEqualTo,
+    /// `(starts-with ?name "bo")`: the left side is a string
+    /// beginning with the right, once both are collated.
+    StartsWith,
+    /// `(matches-ci ?name "bob")`: the sides are strings equal but
+    /// for case, whatever the collation.
+    MatchesCi,
+}
+
+impl Comparator {
+    /// Whether `lhs` and `rhs` compare as the comparator requires,
+    /// under `collation`.
+    pub fn holds(&self, collation: Collation, lhs: &Value, rhs: &Value) -> bool {
+        match *self {
+            Comparator::GreaterThan => collation.compare(lhs, rhs) == cmp::Ordering::Greater,
+            Comparator::LessThan => collation.compare(lhs, rhs) == cmp::Ordering::Less,
+            Comparator::NotEqualTo => collation.compare(lhs, rhs) != cmp::Ordering::Equal,
+            Comparator::StartsWith => collation.starts_with(lhs, rhs),
+            Comparator::MatchesCi => match (lhs, rhs) {
+                (Value::String(_), Value::String(_)) => Collation::CaseInsensitive.compare(lhs, rhs) == cmp::Ordering::Equal,
+                _ => false,
+            },
+        }
+    }
 }
 
 /// A constraint differs from a clause in that it cannot add new items
@@ -132,8 +694,27 @@ pub struct Constraint {
     pub right_hand_side: Term<Value>,
 }
 
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let term = |t: &Term<Value>| match *t {
+            Term::Bound(ref v) => v.to_string(),
+            Term::Unbound(ref var) => var.to_string(),
+        };
+        let comparator = match self.comparator {
+            Comparator::GreaterThan => ">",
+            Comparator::LessThan => "<",
+            Comparator::NotEqualTo => "not",
+            Comparator::StartsWith => "starts-with",
+            Comparator::MatchesCi => "matches-ci",
+        };
+        write!(f, "({} {} {})", comparator, term(&self.left_hand_side), term(&self.right_hand_side))
+    }
+}
+
 impl Constraint {
-    pub fn satisfied_by(&self, binding: &HashMap<&Var, &Value>) -> bool {
+    /// Whether the bound values satisfy the constraint, comparing
+    /// them by the collation of its first var which has one.
+    pub fn satisfied_by(&self, binding: &HashMap<&Var, &Value>, collations: &HashMap<Var, Collation>) -> bool {
         let lhs_value = match self.left_hand_side {
             Term::Bound(ref val) => val,
             Term::Unbound(ref var) => binding[var],
@@ -143,10 +724,26 @@ impl Constraint {
             Term::Unbound(ref var) => binding[var],
         };
 
-        match self.comparator {
-            Comparator::GreaterThan => lhs_value > rhs_value,
-            Comparator::LessThan => lhs_value < rhs_value,
-            Comparator::NotEqualTo => lhs_value != rhs_value,
+        let collation = [&self.left_hand_side, &self.right_hand_side]
+            .iter()
+            .filter_map(|term| match **term {
+                Term::Unbound(ref var) => collations.get(var),
+                Term::Bound(_) => None,
+            })
+            .next()
+            .cloned()
+            .unwrap_or(Collation::Binary);
+        self.comparator.holds(collation, lhs_value, rhs_value)
+    }
+
+    /// The var and string this constraint matches the var's values
+    /// against, if it's a string predicate which can be answered by
+    /// scanning part of an index, such as `(starts-with ?name "bo")`.
+    pub fn string_predicate(&self) -> Option<(&Var, &str)> {
+        match (self.comparator, &self.left_hand_side, &self.right_hand_side) {
+            (Comparator::StartsWith, Term::Unbound(var), Term::Bound(Value::String(s)))
+                | (Comparator::MatchesCi, Term::Unbound(var), Term::Bound(Value::String(s))) => Some((var, s)),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,302 @@
+//! A grace hash join, for joins whose hashed side is too large to
+//! hold in memory.
+//!
+//! Both relations are split into partitions by a hash of their join
+//! key and written to temporary files, so matching tuples always
+//! land in the same partition, and only one partition of the hashed
+//! relation has to be in memory at a time. The first relation's
+//! tuples are numbered as they're written, so the output can be put
+//! back in their order, which the planner relies on.
+//!
+//! Each input is written out a tuple at a time and then dropped, and
+//! each partition's output goes to a file of its own, so the join
+//! holds one partition at a time besides its result. The output files
+//! are merged by tuple number into the result.
+//!
+//! A single join key matching more tuples than the budget still ends
+//! up in one partition, which is hashed in memory regardless.
+//!
+//! Tuples are written as the ids of their values in the query's
+//! `ValuePool`, which stays in memory, so the size of a join's input
+//! only depends on how many tuples and vars it has.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use rmp_serde;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use im::HashMap;
+
+use queries::columns::{Columns, ValueId};
+use queries::execution::key_indices;
+use queries::query::Var;
+use Result;
+
+/// The estimated size, in bytes, above which the hashed side of a
+/// join is spilled to disk.
+pub const JOIN_MEMORY_BUDGET: usize = 512 * 1024 * 1024;
+
+/// The most partitions a join is split into, which bounds the files
+/// open at once.
+const MAX_PARTITIONS: usize = 256;
+
+/// Estimates the bytes of memory `columns` would take up as rows of
+/// value ids, plus `pool_growth`, what the query's `ValuePool` grew
+/// by while they were produced, for the values new to them.
+pub fn estimated_size(columns: &Columns, pool_growth: usize) -> usize {
+    let row = mem::size_of::<Vec<ValueId>>() + columns.vars().len() * mem::size_of::<ValueId>();
+    row.saturating_mul(columns.len()).saturating_add(pool_growth)
+}
+
+/// Joins two relations on `join_key` as `execution::join` does,
+/// hashing one partition of `b` at a time.
+pub(crate) fn grace_join(a: Columns, b: Columns, join_key: &[Var], budget: usize) -> Result<Columns> {
+    let keys = JoinKeys::new(join_key, a.vars(), b.vars());
+    let vars: Vec<Var> = a.vars().iter().cloned().chain(keys.b_out.iter().map(|&idx| b.vars()[idx].clone())).collect();
+
+    // Twice as many partitions as would just fit, since the hash
+    // won't split the tuples evenly. The pool isn't partitioned, so
+    // only the tuples count.
+    let partitions = estimated_size(&b, 0)
+        .div_ceil(budget.max(1))
+        .saturating_mul(2)
+        .clamp(2, MAX_PARTITIONS);
+
+    let dir = SpillDir::create()?;
+    let files_b = dir.write_partitions(
+        "b",
+        partitions,
+        (0..b.len()).map(|row| {
+            let tuple = b.row(row);
+            (key_for_tuple(&keys.b, &tuple), tuple)
+        }),
+    )?;
+    drop(b);
+    let files_a = dir.write_partitions(
+        "a",
+        partitions,
+        (0..a.len()).map(|row| {
+            let tuple = a.row(row);
+            (key_for_tuple(&keys.a, &tuple), (row as u64, tuple))
+        }),
+    )?;
+    drop(a);
+
+    let mut outputs = vec![];
+    for (idx, (file_a, file_b)) in files_a.iter().zip(&files_b).enumerate() {
+        let mut hashed: HashMap<Vec<ValueId>, Vec<Vec<ValueId>>> = HashMap::new();
+        read_partition(file_b, |tuple: Vec<ValueId>| {
+            hashed.entry(key_for_tuple(&keys.b, &tuple)).or_insert(vec![]).push(tuple);
+            Ok(())
+        })?;
+        // a's partition is in tuple order, and so is its output.
+        let path = dir.0.join(format!("out-{}", idx));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        read_partition(file_a, |(i, tuple_a): (u64, Vec<ValueId>)| {
+            for tuple_b in hashed.get(&key_for_tuple(&keys.a, &tuple_a)).into_iter().flatten() {
+                write_item(&mut writer, &(i, keys.combine(&tuple_a, tuple_b)))?;
+            }
+            Ok(())
+        })?;
+        writer.flush()?;
+        outputs.push(path);
+    }
+
+    merge_outputs(vars, &outputs)
+}
+
+/// Merges the output files of a join's partitions, each in order of
+/// the first relation's tuple numbers, into columns in that order.
+fn merge_outputs(vars: Vec<Var>, paths: &[PathBuf]) -> Result<Columns> {
+    let mut readers = paths.iter().map(PartitionReader::open).collect::<Result<Vec<_>>>()?;
+    // The next tuple of each file, and a heap of their numbers.
+    let mut next: Vec<Option<Vec<ValueId>>> = vec![None; readers.len()];
+    let mut heap = BinaryHeap::new();
+    for (idx, reader) in readers.iter_mut().enumerate() {
+        if let Some((i, tuple)) = reader.next_item::<(u64, Vec<ValueId>)>()? {
+            next[idx] = Some(tuple);
+            heap.push(Reverse((i, idx)));
+        }
+    }
+
+    let mut columns: Vec<Vec<ValueId>> = vars.iter().map(|_| vec![]).collect();
+    let mut len = 0;
+    while let Some(Reverse((_, idx))) = heap.pop() {
+        let tuple = next[idx].take().unwrap();
+        for (column, id) in columns.iter_mut().zip(tuple) {
+            column.push(id);
+        }
+        len += 1;
+        if let Some((i, tuple)) = readers[idx].next_item::<(u64, Vec<ValueId>)>()? {
+            next[idx] = Some(tuple);
+            heap.push(Reverse((i, idx)));
+        }
+    }
+    Ok(Columns::from_columns(vars, columns, len))
+}
+
+/// Where the join key is in the tuples of each side of a join, and
+/// which of the second side's columns are added to the output.
+struct JoinKeys {
+    a: Vec<usize>,
+    b: Vec<usize>,
+    b_out: Vec<usize>,
+}
+
+impl JoinKeys {
+    fn new(join_key: &[Var], vars_a: &[Var], vars_b: &[Var]) -> JoinKeys {
+        let b_out = (0..vars_b.len()).filter(|&idx| !vars_a.contains(&vars_b[idx])).collect();
+        JoinKeys { a: key_indices(join_key, vars_a), b: key_indices(join_key, vars_b), b_out }
+    }
+
+    /// The output tuple for matching tuples of a and b.
+    fn combine(&self, tuple_a: &[ValueId], tuple_b: &[ValueId]) -> Vec<ValueId> {
+        let mut tuple = tuple_a.to_vec();
+        tuple.extend(self.b_out.iter().map(|&idx| tuple_b[idx]));
+        tuple
+    }
+}
+
+fn key_for_tuple(key_indices: &[usize], tuple: &[ValueId]) -> Vec<ValueId> {
+    key_indices.iter().map(|&idx| tuple[idx]).collect()
+}
+
+fn partition(key: &[ValueId], partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % partitions as u64) as usize
+}
+
+/// A temporary directory for partition files, removed when dropped.
+struct SpillDir(PathBuf);
+
+impl SpillDir {
+    fn create() -> Result<SpillDir> {
+        let path = env::temp_dir().join(format!("cliodb-join-{}", Uuid::new_v4()));
+        fs::create_dir(&path)?;
+        Ok(SpillDir(path))
+    }
+
+    /// Writes each item to the partition file for its key, returning
+    /// the files in partition order.
+    fn write_partitions<T, I>(&self, name: &str, partitions: usize, items: I) -> Result<Vec<PathBuf>>
+    where
+        T: Serialize,
+        I: Iterator<Item = (Vec<ValueId>, T)>,
+    {
+        let paths: Vec<PathBuf> = (0..partitions).map(|i| self.0.join(format!("{}-{}", name, i))).collect();
+        let mut writers = paths.iter()
+            .map(|path| File::create(path).map(BufWriter::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        for (key, item) in items {
+            write_item(&mut writers[partition(&key, partitions)], &item)?;
+        }
+        for mut writer in writers {
+            writer.flush()?;
+        }
+        Ok(paths)
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Appends an item to a spill file. Each item is length-prefixed, so
+/// the end of the file can be told from a truncated item.
+fn write_item<T: Serialize, W: Write>(writer: &mut W, item: &T) -> Result<()> {
+    let bytes = rmp_serde::to_vec(item)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads the items of a spill file back, in the order they were
+/// written.
+struct PartitionReader {
+    reader: BufReader<File>,
+    bytes: Vec<u8>,
+}
+
+impl PartitionReader {
+    fn open(path: &PathBuf) -> Result<PartitionReader> {
+        Ok(PartitionReader { reader: BufReader::new(File::open(path)?), bytes: vec![] })
+    }
+
+    fn next_item<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.bytes.resize(u32::from_le_bytes(len) as usize, 0);
+        self.reader.read_exact(&mut self.bytes)?;
+        Ok(Some(rmp_serde::from_slice(&self.bytes)?))
+    }
+}
+
+/// Calls `f` with each item in a partition file, in the order they
+/// were written, stopping at the first error.
+fn read_partition<T, F>(path: &PathBuf, mut f: F) -> Result<()>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<()>,
+{
+    let mut reader = PartitionReader::open(path)?;
+    while let Some(item) = reader.next_item()? {
+        f(item)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use queries::columns::ValuePool;
+    use queries::execution::join_within;
+    use {Entity, Relation, Value};
+
+    #[test]
+    fn test_grace_join_matches_hash_join() {
+        let vars = |names: &[&str]| names.iter().map(|n| Var::new(*n)).collect::<Vec<Var>>();
+        let mut pool = ValuePool::new();
+        let people = Columns::from_relation(Relation(vars(&["p", "name"]), (0..500).rev()
+            .map(|i| vec![Value::Ref(Entity(i % 40)), Value::String(format!("person {}", i))])
+            .collect()), &mut pool);
+        let pets = Columns::from_relation(Relation(vars(&["pet", "p"]), (0..300)
+            .map(|i| vec![Value::String(format!("pet {}", i)), Value::Ref(Entity(i % 50))])
+            .collect()), &mut pool);
+
+        let in_memory = join_within(people.clone(), pets.clone(), 0, usize::MAX).unwrap().into_relation(&pool);
+        let spilled = join_within(people, pets, 0, 1000).unwrap().into_relation(&pool);
+        assert_eq!(in_memory.0, vars(&["p", "name", "pet"]));
+        assert_eq!(in_memory.1.len(), 500 * 6);
+        assert_eq!(spilled, in_memory);
+    }
+
+    #[test]
+    fn test_estimated_size() {
+        let mut pool = ValuePool::new();
+        let tuples = vec![vec![Value::String("x".repeat(100)), Value::Long(1)]; 1000];
+        let columns = Columns::from_relation(Relation(vec![Var::new("s"), Var::new("n")], tuples), &mut pool);
+        let pool_growth = pool.estimated_size();
+        // Repeated values don't count against the budget again.
+        assert!(pool_growth > 100 && pool_growth < 2 * 1000, "{}", pool_growth);
+        let size = estimated_size(&columns, pool_growth);
+        assert!(size > 8 * 1000 && size < 100 * 1000, "{}", size);
+        assert_eq!(estimated_size(&Columns::from_relation(Relation(vec![], vec![]), &mut pool), 0), 0);
+    }
+}
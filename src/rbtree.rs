@@ -23,14 +23,14 @@ struct RBTreeNode<T> {
 /// NOT enforce invariants in the base case (leaving that to `balance`
 /// in the recursive cases), and it does not color the root of the
 /// tree black.
-fn ins<T: ::std::fmt::Debug, C>(tree: Child<T>, x: T, comparator: C) -> Arc<RBTreeNode<T>>
+fn ins<T: ::std::fmt::Debug, C>(tree: Child<T>, x: T, comparator: &C) -> Arc<RBTreeNode<T>>
 where
     T: Ord + Clone,
-    C: Comparator<Item = T> + Copy,
+    C: Comparator<Item = T>,
 {
     match tree {
         Some(ref t) => {
-            match C::compare(&x, &t.item) {
+            match comparator.compare(&x, &t.item) {
                 Ordering::Less => {
                     balance(Arc::new(RBTreeNode::new(
                         t.color,
@@ -213,7 +213,7 @@ impl<T: ::std::fmt::Debug + Ord + Clone> RBTreeNode<T> {
     }
 }
 
-impl<T: ::std::fmt::Debug + Ord + Clone, C: Comparator<Item = T> + Copy> RBTree<T, C> {
+impl<T: ::std::fmt::Debug + Ord + Clone, C: Comparator<Item = T>> RBTree<T, C> {
     pub fn new(comparator: C) -> RBTree<T, C> {
         RBTree {
             root: None,
@@ -226,11 +226,15 @@ impl<T: ::std::fmt::Debug + Ord + Clone, C: Comparator<Item = T> + Copy> RBTree<
         self.size
     }
 
+    pub fn with_comparator(&self, comparator: C) -> RBTree<T, C> {
+        RBTree { comparator, ..self.clone() }
+    }
+
     pub fn insert(&self, x: T) -> RBTree<T, C> {
         let tree = RBTree {
-            root: Some(ins(self.root.clone(), x, self.comparator).make_black()),
+            root: Some(ins(self.root.clone(), x, &self.comparator).make_black()),
             size: self.size + 1,
-            comparator: self.comparator,
+            comparator: self.comparator.clone(),
         };
         tree
     }
@@ -250,11 +254,17 @@ impl<T: ::std::fmt::Debug + Ord + Clone, C: Comparator<Item = T> + Copy> RBTree<
     }
 
     pub fn range_from(&self, start: T) -> Iter<T> {
+        self.range_from_by(|item| self.comparator.compare(item, &start))
+    }
+
+    /// Iterates from the first item `seek` doesn't order before the
+    /// range, for ranges that don't start at an item.
+    pub fn range_from_by<F: Fn(&T) -> Ordering>(&self, seek: F) -> Iter<T> {
         let mut stack = Vec::new();
         let mut node = self.root.clone();
 
         while let Some(node_ptr) = node.clone() {
-            match C::compare(&node_ptr.item, &start) {
+            match seek(&node_ptr.item) {
                 Ordering::Greater => {
                     node = node_ptr.left.clone();
                     stack.push(node_ptr);
@@ -368,7 +378,7 @@ mod tests {
         impl Comparator for RevComparator {
             type Item = i64;
 
-            fn compare(a: &i64, b: &i64) -> Ordering {
+            fn compare(&self, a: &i64, b: &i64) -> Ordering {
                 b.cmp(a) // backwards!
             }
         }
@@ -0,0 +1,220 @@
+//! When the transactor rebuilds its indices.
+//!
+//! Novelty (facts added since the last reindex) lives in memory and
+//! is merged into every index scan, so queries slow down as it grows.
+//! A `ReindexPolicy` rebuilds the indices once novelty reaches a hard
+//! limit, or earlier if queries have slowed down noticeably since the
+//! last rebuild, or during a low-traffic window given by a cron-style
+//! `Schedule`. A reindex can also be requested at any time with
+//! `TxHandle::reindex` (`\reindex` in the CLI).
+//!
+//! A rebuild rewrites the durable indices, so a bug in it could lose
+//! data once the metadata points at the new ones. With `verify` set,
+//! the transactor first compares the rebuilt indices with the old,
+//! datom by datom.
+
+use std::time::Duration;
+
+use chrono::prelude::{DateTime, Datelike, Timelike, Utc};
+
+use Result;
+
+/// Decides when to rebuild the indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReindexPolicy {
+    /// Reindex whenever novelty reaches this many records.
+    pub max_novelty: usize,
+    /// Reindexes for slow queries or in scheduled windows only
+    /// happen once novelty reaches this many records, since small
+    /// rebuilds aren't worth their writes.
+    pub min_novelty: usize,
+    /// Reindex when recent query latency reaches this multiple of
+    /// the latency just after the last reindex.
+    pub latency_factor: Option<f64>,
+    /// Minutes (UTC) in which to reindex.
+    pub schedule: Option<Schedule>,
+    /// Read back each rebuilt index and compare it with the one it
+    /// replaces before switching to it, abandoning the rebuild if
+    /// they differ. This doubles the reads of a reindex.
+    pub verify: bool,
+}
+
+impl Default for ReindexPolicy {
+    fn default() -> ReindexPolicy {
+        ReindexPolicy {
+            max_novelty: 100_000,
+            min_novelty: 10_000,
+            latency_factor: Some(2.0),
+            schedule: None,
+            verify: false,
+        }
+    }
+}
+
+/// How many query latencies are averaged to get the baseline after
+/// each reindex.
+const BASELINE_SAMPLES: u32 = 16;
+
+/// The weight of each new latency in the recent average.
+const RECENT_WEIGHT: f64 = 0.1;
+
+/// Tracks query latency since the last reindex: a baseline from the
+/// first queries after it, and a moving average of recent ones.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyTracker {
+    samples: u32,
+    baseline: f64,
+    recent: f64,
+}
+
+impl LatencyTracker {
+    pub fn observe(&mut self, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        if self.samples < BASELINE_SAMPLES {
+            self.samples += 1;
+            self.baseline += (latency - self.baseline) / self.samples as f64;
+            self.recent = self.baseline;
+        } else {
+            self.recent += (latency - self.recent) * RECENT_WEIGHT;
+        }
+    }
+
+    /// Whether recent queries take at least `factor` times as long as
+    /// those just after the last reindex.
+    pub fn degraded(&self, factor: f64) -> bool {
+        self.samples == BASELINE_SAMPLES && self.recent >= self.baseline * factor
+    }
+
+    pub fn reset(&mut self) {
+        *self = LatencyTracker::default();
+    }
+}
+
+/// A cron-style schedule: five space-separated fields for the
+/// minute, hour, day of the month, month and day of the week (0 or 7
+/// is Sunday). Each field is `*`, a number, a range `a-b`, a step
+/// `*/n` or `a-b/n`, or a comma-separated list of those. As in cron,
+/// if both day fields are restricted, a day matching either matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Schedule> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("schedule {:?} should have 5 fields", spec).into());
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Sunday is both 0 and 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether the minute containing `time` is in the schedule.
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let has = |set: u64, n: u32| set & (1 << n) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        has(self.minutes, time.minute()) && has(self.hours, time.hour()) && has(self.months, time.month()) && day_matches
+    }
+}
+
+/// Parses one field of a schedule into a bit set of the values it
+/// allows.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let number = |s: &str| -> Result<u32> {
+        match s.parse::<u32>() {
+            Ok(n) if n >= min && n <= max => Ok(n),
+            _ => Err(format!("invalid value {:?} in schedule field {:?} (expected {}-{})", s, field, min, max).into()),
+        }
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => match part[i + 1..].parse::<u32>() {
+                Ok(step) if step > 0 => (&part[..i], step),
+                _ => return Err(format!("invalid step in schedule field {:?}", field).into()),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (number(&range[..i])?, number(&range[i + 1..])?)
+        } else {
+            let n = number(range)?;
+            (n, n)
+        };
+        if start > end {
+            return Err(format!("invalid range {:?} in schedule field {:?}", range, field).into());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_schedule() {
+        // 2:30 on Sunday, 2024-03-03.
+        let sunday = Utc.with_ymd_and_hms(2024, 3, 3, 2, 30, 0).unwrap();
+        assert!(Schedule::parse("30 2 * * *").unwrap().matches(&sunday));
+        assert!(Schedule::parse("*/15 1-3 * * 0").unwrap().matches(&sunday));
+        assert!(Schedule::parse("* * * * 7").unwrap().matches(&sunday));
+        assert!(!Schedule::parse("* * * * 1-5").unwrap().matches(&sunday));
+        assert!(!Schedule::parse("0,45 * * * *").unwrap().matches(&sunday));
+        // Either restricted day field can match.
+        assert!(Schedule::parse("* * 1 * 0").unwrap().matches(&sunday));
+        assert!(!Schedule::parse("* * 1 3 *").unwrap().matches(&sunday));
+
+        for invalid in &["* * * *", "60 * * * *", "5-1 * * * *", "*/0 * * * *", "a * * * *"] {
+            assert!(Schedule::parse(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_latency_tracker() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..BASELINE_SAMPLES {
+            tracker.observe(Duration::from_millis(10));
+        }
+        assert!(!tracker.degraded(2.0));
+        for _ in 0..50 {
+            tracker.observe(Duration::from_millis(30));
+        }
+        assert!(tracker.degraded(2.0));
+        tracker.reset();
+        assert!(!tracker.degraded(2.0));
+    }
+}
@@ -0,0 +1,181 @@
+//! Continuous backup of a database to a secondary store, which may
+//! use a different backend than the primary.
+//!
+//! A `Replicator` copies transactions from the primary's log as
+//! they're appended, and after each reindex copies the new index
+//! segments and then the metadata pointing at them. The secondary's
+//! metadata is only ever replaced once everything it refers to is in
+//! place, so at any moment the secondary holds a consistent database:
+//! the indices as of some reindex plus the log since (or part of it).
+//!
+//! To cut over to the secondary:
+//!
+//! 1. Stop the primary's transactor, so the log stops growing.
+//! 2. Run a final `sync` (`clio-replicate --once`), which copies the
+//!    rest of the log and the latest metadata.
+//! 3. Start a transactor on the secondary. It claims a new epoch, so
+//!    were the old transactor to come back pointed at the secondary,
+//!    it couldn't write.
+//!
+//! Transactions committed on the primary after the last sync are
+//! lost if the primary is, so the sync interval bounds the data lost.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use {Record, Result};
+use backends::KVStore;
+use durable_tree::copy_tree;
+
+/// What one `Replicator::sync` copied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub txs_copied: usize,
+    pub segments_copied: usize,
+    /// The version of the metadata copied, if it had changed.
+    pub metadata_version: Option<u64>,
+}
+
+pub struct Replicator {
+    primary: Arc<dyn KVStore>,
+    secondary: Arc<dyn KVStore>,
+    /// The log position of the last transaction copied.
+    last_seq: i64,
+    /// The version of the last metadata copied.
+    metadata_version: u64,
+}
+
+impl Replicator {
+    /// Creates a replicator which picks up after whatever the
+    /// secondary already holds, so a restarted replicator doesn't
+    /// copy everything again.
+    pub fn new(primary: Arc<dyn KVStore>, secondary: Arc<dyn KVStore>) -> Result<Replicator> {
+        let (indexed_seq, metadata_version) = match secondary.get_metadata() {
+            Ok(metadata) => (metadata.indexed_seq(), metadata.version),
+            Err(_) => (0, 0),
+        };
+        let last_seq = secondary.get_txs(indexed_seq)?.last().map_or(indexed_seq, |tx| tx.seq);
+
+        Ok(Replicator {
+            primary,
+            secondary,
+            last_seq,
+            metadata_version,
+        })
+    }
+
+    /// Copies everything written to the primary since the last sync.
+    pub fn sync(&mut self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+
+        // The metadata is read before the log, so the log copied
+        // below reaches at least as far as the indices.
+        let metadata = self.primary.get_metadata_if_newer(self.metadata_version)?;
+
+        for tx in self.primary.get_txs(self.last_seq)? {
+            self.secondary.add_tx(&tx)?;
+            self.last_seq = tx.seq;
+            report.txs_copied += 1;
+        }
+
+        if let Some(metadata) = metadata {
+            // The log index is missing until the transactor backfills it.
+            for root in [&metadata.eav, &metadata.ave, &metadata.aev, &metadata.vae, &metadata.tea].iter().filter(|root| !root.is_empty()) {
+                report.segments_copied += copy_tree::<Record>(root, &*self.primary, &*self.secondary)?;
+            }
+            self.secondary.set_metadata(&metadata)?;
+            self.metadata_version = metadata.version;
+            report.metadata_version = Some(metadata.version);
+        }
+
+        Ok(report)
+    }
+
+    /// Syncs every `interval`, forever. Failed syncs are logged and
+    /// retried on the next round.
+    pub fn run(&mut self, interval: Duration) {
+        loop {
+            match self.sync() {
+                Ok(ref report) if *report == SyncReport::default() => {}
+                Ok(report) => info!("Replicated {:?}", report),
+                Err(e) => warn!("Replication failed, retrying: {:?}", e),
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use {Entity, Fact, Tx, TxItem, Value};
+    use backends::mem::MemStore;
+    use backends::sqlite::SqliteStore;
+    use db::Db;
+    use tx::{Transactor, TxHandle};
+
+    fn transact(handle: &TxHandle, entity: i64, doc: &str) {
+        handle.transact(Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(entity), "db:doc", doc))],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+    }
+
+    fn datoms(store: &Arc<dyn KVStore>) -> Vec<Record> {
+        let metadata = store.get_metadata().unwrap();
+        let indexed_seq = metadata.indexed_seq();
+        let mut db = Db::new(metadata, store.clone());
+        for tx in store.get_txs(indexed_seq).unwrap() {
+            for record in tx.records {
+                db = db.add_record(record).unwrap();
+            }
+        }
+        db.eav.iter().collect()
+    }
+
+    fn start(store: &Arc<dyn KVStore>) -> (TxHandle, thread::JoinHandle<()>) {
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let handle = TxHandle::new(&transactor);
+        (handle, thread::spawn(move || transactor.run().unwrap()))
+    }
+
+    #[test]
+    fn test_replicate_and_cut_over() {
+        let primary: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let secondary: Arc<dyn KVStore> = Arc::new(MemStore::new());
+
+        let (handle, transactor) = start(&primary);
+        transact(&handle, 1000, "first");
+        let mut replicator = Replicator::new(primary.clone(), secondary.clone()).unwrap();
+        let report = replicator.sync().unwrap();
+        assert!(report.txs_copied > 0 && report.segments_copied > 0);
+        assert_eq!(datoms(&secondary), datoms(&primary));
+
+        // Only what's new is copied.
+        transact(&handle, 1001, "second");
+        let report = replicator.sync().unwrap();
+        assert_eq!((report.txs_copied, report.segments_copied), (1, 0));
+        handle.close().unwrap();
+        transactor.join().unwrap();
+
+        // A restarted replicator resumes where the last one stopped.
+        let report = Replicator::new(primary.clone(), secondary.clone()).unwrap().sync().unwrap();
+        assert_eq!(report.txs_copied, 0);
+        assert_eq!(datoms(&secondary), datoms(&primary));
+
+        // The secondary can take over.
+        let (handle, transactor) = start(&secondary);
+        transact(&handle, 1002, "third");
+        handle.close().unwrap();
+        transactor.join().unwrap();
+        let after = datoms(&secondary);
+        assert!(datoms(&primary).iter().all(|r| after.contains(r)));
+        assert!(after.iter().any(|r| r.value == Value::String("third".into())));
+    }
+}
@@ -0,0 +1,121 @@
+//! Throwaway databases for tests and previews.
+//!
+//! `Db::sandbox` forks a db at its basis into a `Sandbox`, which
+//! applies transactions to its own in-memory copy instead of sending
+//! them to the transactor. Nothing a sandbox does is written to the
+//! store or seen by anyone else, and it's all discarded when the
+//! sandbox is dropped, so it suits integration tests that shouldn't
+//! leave data behind and showing users what a change would do before
+//! they commit it.
+//!
+//! Transactions run through the same code the transactor uses, so
+//! they fail in a sandbox when they'd be rejected for real, except
+//! for checks made by the transactor's `TxListener`s, which don't run
+//! here. Only the built-in transaction functions can be called unless
+//! others are registered with `Sandbox::add_function`. Idempotency
+//! keys are ignored.
+
+use chrono::prelude::Utc;
+
+use db::Db;
+use functions::TxFunctions;
+use parser::{parse_query, parse_tx};
+use queries::execution;
+use tx::apply_items;
+use {Entity, Fact, Relation, Result, Tx, TxItem, TxReport, Value};
+
+/// Entities created in a sandbox are numbered from here, far above
+/// any id a transactor will hand out, so they can't collide with
+/// entities created for real after the fork.
+const FIRST_ID: i64 = 1 << 62;
+
+/// A private, in-memory fork of a db. See the module docs.
+#[derive(Clone)]
+pub struct Sandbox {
+    db: Db,
+    next_id: i64,
+    functions: TxFunctions,
+}
+
+impl Sandbox {
+    pub fn new(db: Db) -> Sandbox {
+        Sandbox { db, next_id: FIRST_ID, functions: TxFunctions::new() }
+    }
+
+    /// The db with every transaction made in the sandbox so far.
+    pub fn db(&self) -> Db {
+        self.db.clone()
+    }
+
+    /// The id of the last real transaction the sandbox includes.
+    pub fn basis_tx(&self) -> i64 {
+        self.db.basis_tx
+    }
+
+    /// Registers a transaction function, as `Transactor::add_function`
+    /// does.
+    pub fn add_function<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&Db, &[Value]) -> Result<Vec<TxItem>> + Send + Sync + 'static,
+    {
+        self.functions.register(name, function);
+    }
+
+    /// Applies a transaction to the sandbox. As with `Conn::transact`,
+    /// a transaction that can't be applied is reported as a
+    /// `TxReport::Failure`, and leaves the sandbox unchanged.
+    pub fn transact(&mut self, tx: Tx) -> Result<TxReport> {
+        let next_id = &mut self.next_id;
+        let mut get_id = || {
+            *next_id += 1;
+            *next_id - 1
+        };
+
+        let tx_entity = Entity(get_id());
+        let timestamp = Utc::now();
+        let mut records = vec![];
+        let mut new_entities = vec![];
+        let items = tx.items;
+        let functions = &self.functions;
+        let applied = self.db
+            .add(Fact::new(tx_entity, "db:txTimestamp", Value::Timestamp(timestamp)), tx_entity)
+            .and_then(|(db, record)| {
+                records.push(record);
+                apply_items(db, items, tx_entity, functions, &mut get_id, &mut records, &mut new_entities)
+            });
+
+        match applied {
+            Ok(mut db) => {
+                // The sandbox's transactions aren't in the log, so
+                // they don't move the basis.
+                db.basis_tx = self.db.basis_tx;
+                db.latest_tx = self.db.latest_tx;
+                let schema_changed = records.iter().any(|r| db.schema.is_schema_attribute(r.attribute));
+                self.db = db;
+                Ok(TxReport::Success {
+                    tx: tx_entity,
+                    new_entities,
+                    reindex: None,
+                    timestamp: Some(timestamp),
+                    datoms: if tx.return_datoms { Some(records) } else { None },
+                    schema_changed,
+                })
+            }
+            Err(e) => Ok(TxReport::Failure(format!("{:?}", e))),
+        }
+    }
+
+    /// Parses and applies a transaction. Unlike `transact`, a
+    /// transaction that can't be applied is returned as an error.
+    pub fn tx(&mut self, tx: &str) -> Result<TxReport> {
+        match self.transact(parse_tx(tx)?)? {
+            TxReport::Failure(msg) => Err(msg.into()),
+            report => Ok(report),
+        }
+    }
+
+    /// Parses and runs a query against the sandbox.
+    pub fn q(&self, query: &str) -> Result<Relation> {
+        execution::query(parse_query(query)?, &self.db)
+    }
+}
@@ -1,6 +1,27 @@
+use std::cmp::Ordering;
+
 use serde::{Serialize, Deserialize};
 use im::{HashMap, HashSet};
-use super::{Entity};
+use unicode_normalization::UnicodeNormalization;
+use super::{Entity, Value};
+
+/// Attributes which describe other attributes. Asserting or
+/// retracting any of these is a schema change, and the transactor
+/// records it in the `db:admin` namespace.
+pub const SCHEMA_ATTRIBUTES: &[&str] = &[
+    "db:ident",
+    "db:valueType",
+    "db:indexed",
+    "db:doc",
+    "db:allowedValue",
+    "db:normalize",
+    "db:noHistory",
+    "db:alias",
+    "db:encrypted",
+    "db:cardinality",
+    "db:collation",
+    "db:unique",
+];
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ValueType {
@@ -9,50 +30,506 @@ pub enum ValueType {
     Ref,
     Timestamp,
     Boolean,
-    Long
+    Long,
+    Geo,
+}
+
+impl ValueType {
+    /// Returns the type for an ident used as the value of
+    /// `db:valueType`, e.g. `db:type:string`.
+    pub fn from_ident(ident: &str) -> Option<ValueType> {
+        match ident {
+            "db:type:string" => Some(ValueType::String),
+            "db:type:ident" => Some(ValueType::Ident),
+            "db:type:ref" => Some(ValueType::Ref),
+            "db:type:timestamp" => Some(ValueType::Timestamp),
+            "db:type:boolean" => Some(ValueType::Boolean),
+            "db:type:long" => Some(ValueType::Long),
+            "db:type:geo" => Some(ValueType::Geo),
+            _ => None,
+        }
+    }
+
+    pub fn ident(&self) -> &'static str {
+        match *self {
+            ValueType::String => "db:type:string",
+            ValueType::Ident => "db:type:ident",
+            ValueType::Ref => "db:type:ref",
+            ValueType::Timestamp => "db:type:timestamp",
+            ValueType::Boolean => "db:type:boolean",
+            ValueType::Long => "db:type:long",
+            ValueType::Geo => "db:type:geo",
+        }
+    }
+}
+
+/// Built-in normalizers which can be attached to string attributes
+/// with `db:normalize`. The transactor applies them to asserted and
+/// retracted values before they are stored, so that e.g. unique
+/// constraints and joins aren't defeated by stray whitespace.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Normalizer {
+    Trim,
+    Lowercase,
+    /// Strips formatting from phone numbers, keeping the digits
+    /// and a leading `+`.
+    Phone,
 }
 
+impl Normalizer {
+    pub fn from_ident(ident: &str) -> Option<Normalizer> {
+        match ident {
+            "db:normalize:trim" => Some(Normalizer::Trim),
+            "db:normalize:lowercase" => Some(Normalizer::Lowercase),
+            "db:normalize:phone" => Some(Normalizer::Phone),
+            _ => None,
+        }
+    }
+
+    pub fn ident(&self) -> &'static str {
+        match *self {
+            Normalizer::Trim => "db:normalize:trim",
+            Normalizer::Lowercase => "db:normalize:lowercase",
+            Normalizer::Phone => "db:normalize:phone",
+        }
+    }
+
+    pub fn apply(&self, s: &str) -> String {
+        match *self {
+            Normalizer::Trim => s.trim().to_string(),
+            Normalizer::Lowercase => s.to_lowercase(),
+            Normalizer::Phone => {
+                let s = s.trim();
+                let prefix = if s.starts_with('+') { "+" } else { "" };
+                let digits: String = s.chars().filter(|c| c.is_ascii_digit()).collect();
+                format!("{}{}", prefix, digits)
+            }
+        }
+    }
+}
+
+/// How many values an entity can have for an attribute, declared
+/// with `db:cardinality`. Attributes are cardinality-many unless
+/// declared otherwise; asserting a new value of a cardinality-one
+/// attribute retracts the entity's old one.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Cardinality {
     One,
     Many,
 }
 
+impl Cardinality {
+    pub fn from_ident(ident: &str) -> Option<Cardinality> {
+        match ident {
+            "db:cardinality:one" => Some(Cardinality::One),
+            "db:cardinality:many" => Some(Cardinality::Many),
+            _ => None,
+        }
+    }
+
+    pub fn ident(&self) -> &'static str {
+        match *self {
+            Cardinality::One => "db:cardinality:one",
+            Cardinality::Many => "db:cardinality:many",
+        }
+    }
+}
+
+/// How an attribute's string values are compared, declared with
+/// `db:collation`. Constraints and ordering in queries compare by the
+/// collation, and so does the AVET index, breaking ties byte-wise so
+/// that distinct values stay distinct. Attributes are compared
+/// byte-wise unless declared otherwise.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Collation {
+    Binary,
+    /// Compares strings as if they were lowercase.
+    CaseInsensitive,
+    /// Compares strings in Unicode normalization form C, so that
+    /// composed and decomposed forms of a character are equal.
+    Nfc,
+}
+
+impl Collation {
+    pub fn from_ident(ident: &str) -> Option<Collation> {
+        match ident {
+            "db:collation:binary" => Some(Collation::Binary),
+            "db:collation:caseInsensitive" => Some(Collation::CaseInsensitive),
+            "db:collation:nfc" => Some(Collation::Nfc),
+            _ => None,
+        }
+    }
+
+    pub fn ident(&self) -> &'static str {
+        match *self {
+            Collation::Binary => "db:collation:binary",
+            Collation::CaseInsensitive => "db:collation:caseInsensitive",
+            Collation::Nfc => "db:collation:nfc",
+        }
+    }
+
+    /// Compares two values. Only strings are collated; anything else
+    /// is compared as usual.
+    pub fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        match (self, a, b) {
+            (Collation::CaseInsensitive, Value::String(a), Value::String(b)) => {
+                a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))
+            }
+            (Collation::Nfc, Value::String(a), Value::String(b)) => a.nfc().cmp(b.nfc()),
+            _ => a.cmp(b),
+        }
+    }
+
+    /// Whether `value` is a string beginning with the string
+    /// `prefix`, once both are collated.
+    pub fn starts_with(&self, value: &Value, prefix: &Value) -> bool {
+        match (self.key(value), self.key(prefix)) {
+            (Value::String(value), Value::String(prefix)) => value.starts_with(&prefix),
+            _ => false,
+        }
+    }
+
+    /// A value which orders among other keys as `value` does under
+    /// `compare`, for sorting by.
+    pub fn key(&self, value: &Value) -> Value {
+        match (self, value) {
+            (Collation::CaseInsensitive, Value::String(s)) => Value::String(s.chars().flat_map(char::to_lowercase).collect()),
+            (Collation::Nfc, Value::String(s)) => Value::String(s.nfc().collect()),
+            _ => value.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Schema {
     pub idents: HashMap<String, Entity>,
+    /// The reverse of `idents`, for rendering refs to ident
+    /// entities. Schemas stored before it existed have it rebuilt
+    /// by `with_entity_idents`.
+    #[serde(default)]
+    pub entity_idents: HashMap<Entity, String>,
     pub value_types: HashMap<Entity, ValueType>,
     pub cardinalities: HashMap<Entity, Cardinality>,
     pub indexed: HashSet<Entity>,
+    #[serde(default)]
+    pub docs: HashMap<Entity, String>,
+    /// Enum-style constraints: the idents an attribute's values are
+    /// restricted to, declared with `db:allowedValue`.
+    #[serde(default)]
+    pub allowed_values: HashMap<Entity, HashSet<String>>,
+    /// Normalizers to run on an attribute's values, in the order
+    /// they were declared with `db:normalize`.
+    #[serde(default)]
+    pub normalizers: HashMap<Entity, Vec<Normalizer>>,
+    /// Attributes declared with `db:noHistory`, whose superseded
+    /// values are dropped from the durable indexes on reindex.
+    #[serde(default)]
+    pub no_history: HashSet<Entity>,
+    /// Other names for attributes, declared with `db:alias`, such as
+    /// the old name of a renamed attribute. They resolve to the
+    /// attribute like its ident does, but output uses the ident.
+    #[serde(default)]
+    pub aliases: HashMap<String, Entity>,
+    /// Attributes declared with `db:encrypted`, whose values are
+    /// stored encrypted (see the `encryption` module).
+    #[serde(default)]
+    pub encrypted: HashSet<Entity>,
+    /// Collations declared with `db:collation`.
+    #[serde(default)]
+    pub collations: HashMap<Entity, Collation>,
+    /// Attributes declared with `db:unique`, each of whose values can
+    /// belong to only one entity.
+    #[serde(default)]
+    pub unique: HashSet<Entity>,
+}
+
+/// A description of a single attribute, as returned by the schema
+/// introspection API (`Db::attributes`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeInfo {
+    pub entity: Entity,
+    pub ident: String,
+    pub value_type: Option<ValueType>,
+    pub indexed: bool,
+    pub doc: Option<String>,
+    pub allowed_values: Vec<String>,
+    pub normalizers: Vec<Normalizer>,
+    pub no_history: bool,
+    pub encrypted: bool,
+    pub cardinality: Cardinality,
+    pub collation: Collation,
+    pub unique: bool,
+    /// Other names the attribute can be referred to by, sorted.
+    pub aliases: Vec<String>,
+    /// Any other facts asserted about the attribute entity, as
+    /// (attribute ident, value) pairs.
+    pub metadata: Vec<(String, Value)>,
+}
+
+/// The desired definition of an attribute, for
+/// `Conn::ensure_schema`. Properties left at their defaults are
+/// unset: no index, no doc, no allowed values and no normalizers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeDef {
+    pub ident: String,
+    pub value_type: ValueType,
+    pub indexed: bool,
+    pub doc: Option<String>,
+    pub allowed_values: Vec<String>,
+    pub normalizers: Vec<Normalizer>,
+    pub no_history: bool,
+    pub encrypted: bool,
+    pub cardinality: Cardinality,
+    pub collation: Collation,
+    pub unique: bool,
+}
+
+impl AttributeDef {
+    pub fn new<S: Into<String>>(ident: S, value_type: ValueType) -> AttributeDef {
+        AttributeDef {
+            ident: ident.into(),
+            value_type,
+            indexed: false,
+            doc: None,
+            allowed_values: vec![],
+            normalizers: vec![],
+            no_history: false,
+            encrypted: false,
+            cardinality: Cardinality::Many,
+            collation: Collation::Binary,
+            unique: false,
+        }
+    }
+
+    pub fn indexed(mut self) -> AttributeDef {
+        self.indexed = true;
+        self
+    }
+
+    pub fn doc<S: Into<String>>(mut self, doc: S) -> AttributeDef {
+        self.doc = Some(doc.into());
+        self
+    }
+
+    pub fn allowed_value<S: Into<String>>(mut self, value: S) -> AttributeDef {
+        self.allowed_values.push(value.into());
+        self
+    }
+
+    pub fn normalize(mut self, normalizer: Normalizer) -> AttributeDef {
+        self.normalizers.push(normalizer);
+        self
+    }
+
+    pub fn no_history(mut self) -> AttributeDef {
+        self.no_history = true;
+        self
+    }
+
+    pub fn encrypted(mut self) -> AttributeDef {
+        self.encrypted = true;
+        self
+    }
+
+    pub fn cardinality_one(mut self) -> AttributeDef {
+        self.cardinality = Cardinality::One;
+        self
+    }
+
+    pub fn collation(mut self, collation: Collation) -> AttributeDef {
+        self.collation = collation;
+        self
+    }
+
+    pub fn unique(mut self) -> AttributeDef {
+        self.unique = true;
+        self
+    }
 }
 
 impl Schema {
     pub fn add_ident(&self, entity: Entity, identifier: String) -> Schema {
         let mut new = self.clone();
+        new.entity_idents.insert(entity, identifier.clone());
         new.idents.insert(identifier, entity);
         new
     }
 
+    /// Fills in `entity_idents` if it's missing, as it is in schemas
+    /// stored before it was added.
+    pub fn with_entity_idents(mut self) -> Schema {
+        if self.entity_idents.is_empty() && !self.idents.is_empty() {
+            self.entity_idents = self.idents.iter().map(|(ident, entity)| (*entity, ident.clone())).collect();
+        }
+        self
+    }
+
+    /// Removes a retracted ident, e.g. the old name of a renamed
+    /// attribute.
+    pub fn remove_ident(&self, entity: Entity, identifier: &str) -> Schema {
+        let mut new = self.clone();
+        if new.idents.get(identifier) == Some(&entity) {
+            new.idents.remove(identifier);
+        }
+        if new.entity_idents.get(&entity).map(|i| i.as_str()) == Some(identifier) {
+            new.entity_idents.remove(&entity);
+        }
+        new
+    }
+
+    /// Returns the entity named by an ident or an alias.
+    pub fn resolve(&self, name: &str) -> Option<Entity> {
+        self.idents.get(name).or_else(|| self.aliases.get(name)).cloned()
+    }
+
+    pub fn add_alias(&self, entity: Entity, alias: String) -> Schema {
+        let mut new = self.clone();
+        new.aliases.insert(alias, entity);
+        new
+    }
+
+    pub fn remove_alias(&self, entity: Entity, alias: &str) -> Schema {
+        let mut new = self.clone();
+        if new.aliases.get(alias) == Some(&entity) {
+            new.aliases.remove(alias);
+        }
+        new
+    }
+
+    pub fn ident_for(&self, entity: Entity) -> Option<&str> {
+        self.entity_idents.get(&entity).map(|ident| ident.as_str())
+    }
+
     pub fn add_cardinality(&self, entity: Entity, cardinality: Cardinality) -> Schema {
         let mut new = self.clone();
         new.cardinalities.insert(entity, cardinality);
         new
     }
 
+    pub fn remove_cardinality(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.cardinalities.remove(entity);
+        new
+    }
+
+    pub fn cardinality(&self, entity: Entity) -> Cardinality {
+        self.cardinalities.get(&entity).cloned().unwrap_or(Cardinality::Many)
+    }
+
+    pub fn add_collation(&self, entity: Entity, collation: Collation) -> Schema {
+        let mut new = self.clone();
+        new.collations.insert(entity, collation);
+        new
+    }
+
+    pub fn remove_collation(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.collations.remove(entity);
+        new
+    }
+
+    pub fn collation(&self, entity: Entity) -> Collation {
+        self.collations.get(&entity).cloned().unwrap_or(Collation::Binary)
+    }
+
     pub fn add_value_type(&self, entity: Entity, value_type: ValueType) -> Schema {
         let mut new = self.clone();
         new.value_types.insert(entity, value_type);
         new
     }
 
+    pub fn add_doc(&self, entity: Entity, doc: String) -> Schema {
+        let mut new = self.clone();
+        new.docs.insert(entity, doc);
+        new
+    }
+
+    pub fn remove_doc(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.docs.remove(entity);
+        new
+    }
+
+    pub fn add_allowed_value(&self, entity: Entity, value: String) -> Schema {
+        let mut new = self.clone();
+        new.allowed_values.entry(entity).or_default().insert(value);
+        new
+    }
+
+    pub fn remove_allowed_value(&self, entity: Entity, value: &str) -> Schema {
+        let mut new = self.clone();
+        let now_empty = match new.allowed_values.get_mut(&entity) {
+            Some(values) => {
+                values.remove(value);
+                values.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            new.allowed_values.remove(&entity);
+        }
+        new
+    }
+
+    /// Returns the sorted allowed values for an attribute, or None
+    /// if the attribute is unconstrained.
+    pub fn allowed_values(&self, entity: Entity) -> Option<Vec<String>> {
+        self.allowed_values.get(&entity).map(|values| {
+            let mut values = values.iter().cloned().collect::<Vec<_>>();
+            values.sort();
+            values
+        })
+    }
+
+    pub fn add_normalizer(&self, entity: Entity, normalizer: Normalizer) -> Schema {
+        let mut new = self.clone();
+        let normalizers = new.normalizers.entry(entity).or_default();
+        if !normalizers.contains(&normalizer) {
+            normalizers.push(normalizer);
+        }
+        new
+    }
+
+    pub fn remove_normalizer(&self, entity: Entity, normalizer: Normalizer) -> Schema {
+        let mut new = self.clone();
+        let now_empty = match new.normalizers.get_mut(&entity) {
+            Some(normalizers) => {
+                normalizers.retain(|n| *n != normalizer);
+                normalizers.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            new.normalizers.remove(&entity);
+        }
+        new
+    }
+
+    /// Runs the attribute's normalizers over a value. Only string
+    /// values are affected.
+    pub fn normalize(&self, entity: Entity, value: Value) -> Value {
+        match (self.normalizers.get(&entity), value) {
+            (Some(normalizers), Value::String(s)) => {
+                Value::String(normalizers.iter().fold(s, |s, n| n.apply(&s)))
+            }
+            (_, value) => value,
+        }
+    }
+
     pub fn index_attribute(&self, entity: Entity) -> Schema {
         let mut new = self.clone();
         new.indexed.insert(entity);
         new
     }
 
+    pub fn is_schema_attribute(&self, entity: Entity) -> bool {
+        SCHEMA_ATTRIBUTES.iter().any(|ident| self.idents.get(*ident) == Some(&entity))
+    }
+
+    /// Unique attributes are always indexed, since adding a value
+    /// means looking up whether another entity already has it.
     pub fn is_indexed(&self, entity: Entity) -> bool {
-        self.indexed.contains(&entity)
+        self.indexed.contains(&entity) || self.unique.contains(&entity)
     }
 
     pub fn add_indexed(&self, entity: Entity) -> Schema {
@@ -67,12 +544,65 @@ impl Schema {
         new
     }
 
+    pub fn add_no_history(&self, entity: Entity) -> Schema {
+        let mut new = self.clone();
+        new.no_history.insert(entity);
+        new
+    }
+
+    pub fn remove_no_history(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.no_history.remove(entity);
+        new
+    }
+
+    pub fn add_encrypted(&self, entity: Entity) -> Schema {
+        let mut new = self.clone();
+        new.encrypted.insert(entity);
+        new
+    }
+
+    pub fn remove_encrypted(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.encrypted.remove(entity);
+        new
+    }
+
+    pub fn is_encrypted(&self, entity: Entity) -> bool {
+        self.encrypted.contains(&entity)
+    }
+
+    pub fn add_unique(&self, entity: Entity) -> Schema {
+        let mut new = self.clone();
+        new.unique.insert(entity);
+        new
+    }
+
+    pub fn remove_unique(&self, entity: &Entity) -> Schema {
+        let mut new = self.clone();
+        new.unique.remove(entity);
+        new
+    }
+
+    pub fn is_unique(&self, entity: Entity) -> bool {
+        self.unique.contains(&entity)
+    }
+
     pub fn empty() -> Schema {
         Schema {
             idents: HashMap::new(),
+            entity_idents: HashMap::new(),
             value_types: HashMap::new(),
             cardinalities: HashMap::new(),
             indexed: HashSet::new(),
+            docs: HashMap::new(),
+            allowed_values: HashMap::new(),
+            normalizers: HashMap::new(),
+            no_history: HashSet::new(),
+            aliases: HashMap::new(),
+            encrypted: HashSet::new(),
+            collations: HashMap::new(),
+            unique: HashSet::new(),
         }
     }
 }
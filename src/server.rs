@@ -1,13 +1,87 @@
+use std::io::{BufRead, BufReader, Write};
+use std::iter;
+use std::net::TcpListener;
+use std::result;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use zmq;
 use rmp_serde;
 use log::{info, error};
+use serde::{Serialize, Deserialize};
 
-use {Result, Tx};
+use {Relation, Result, Tx, Value};
+use backends::KVStore;
 use conn::store_from_uri;
+use parser::parse_query_with;
+use queries::execution::{query_iter_with_cancel, CancelToken};
 use tx::{TxHandle, Transactor};
 
+/// The version of the request protocol spoken by `listen`, returned
+/// in `StoreInfo` so clients can refuse a transactor they don't
+/// understand.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long `listen` waits for a request before checking for the
+/// replies of queries answered in the meantime.
+const QUERY_REPLY_POLL_MS: i64 = 5;
+
+/// A request to the transactor. Clients from before requests existed
+/// send a bare `Tx`, which is still accepted as a `Transact`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// Answered with a `TxReport`.
+    Transact(Tx),
+    /// Answered with a `std::result::Result<StoreInfo, String>`.
+    GetStoreInfo,
+    /// Runs a query, with parameters as for `parse_query_with`,
+    /// against the transactor's latest db, for clients which can't
+    /// reach the store. Answered with a
+    /// `std::result::Result<Relation, String>`.
+    Query(String, Vec<Value>),
+    /// Reserves a block of entity ids (see
+    /// `Transactor::allocate_ids`). Answered with a
+    /// `std::result::Result<Range<i64>, String>`.
+    AllocateIds(u64),
+    /// Starts a reindex unless one is running (see
+    /// `TxHandle::reindex`). Answered with a
+    /// `std::result::Result<bool, String>`: whether one was started.
+    Reindex,
+    /// Answered with a `std::result::Result<(i64, Schema), String>`:
+    /// the transactor's latest transaction and the schema as of it,
+    /// for clients whose replay of the log is behind.
+    GetSchema,
+}
+
+/// Bounds on the queries a transactor runs for clients, since they
+/// share its time with transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryLimits {
+    /// Queries running longer than this are cancelled.
+    pub timeout: Duration,
+    /// Queries returning more rows than this fail rather than send
+    /// them.
+    pub max_rows: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> QueryLimits {
+        QueryLimits { timeout: Duration::from_secs(5), max_rows: 10_000 }
+    }
+}
+
+/// What a client needs to read the database, so that the
+/// transactor's address is the only thing it has to be configured
+/// with.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StoreInfo {
+    pub store_uri: String,
+    pub protocol_version: u32,
+    /// The last committed transaction when the request was answered.
+    pub basis_tx: i64,
+}
+
 /// Run a 0MQ-based server to accept transaction requests and process
 /// them. Because it uses 0MQ sockets to abstract over the transport
 /// medium, it can be used for both in-process and networked
@@ -17,35 +91,124 @@ pub struct TransactorService {
     tx_handle: TxHandle,
     context: zmq::Context,
     tx_join_handle: thread::JoinHandle<Result<()>>,
+    store: Arc<dyn KVStore>,
+    /// The store URI handed out to clients, if any.
+    advertised_store: Option<String>,
+    /// Set if the service runs queries for clients.
+    query_limits: Option<QueryLimits>,
 }
 
 impl TransactorService {
     pub fn new(store_uri: &str, context: &zmq::Context) -> Result<TransactorService> {
         let kvstore = store_from_uri(store_uri)?;
+        TransactorService::with_transactor(Transactor::new(kvstore)?, context)
+    }
 
-        let mut transactor = Transactor::new(kvstore)?;
+    /// Serves an already-created transactor, e.g. one with listeners
+    /// registered.
+    pub fn with_transactor(mut transactor: Transactor, context: &zmq::Context) -> Result<TransactorService> {
         let tx_handle = TxHandle::new(&transactor);
+        let store = transactor.store();
 
         let join_handle = thread::spawn(move || transactor.run());
 
-        Ok(TransactorService { tx_handle, context: context.clone(), tx_join_handle: join_handle })
+        Ok(TransactorService {
+            tx_handle,
+            context: context.clone(),
+            tx_join_handle: join_handle,
+            store,
+            advertised_store: None,
+            query_limits: None,
+        })
+    }
+
+    /// Runs queries sent by clients (see `RemoteConn`), within
+    /// `limits`. Otherwise they're refused.
+    pub fn serve_queries(&mut self, limits: QueryLimits) {
+        self.query_limits = Some(limits);
+    }
+
+    /// Sets the store URI returned to clients which ask the
+    /// transactor where the store is. Store URIs can hold
+    /// credentials, so none is handed out unless this is called, and
+    /// it needn't be the URI the service was created with, e.g. to
+    /// give clients read-only credentials.
+    pub fn advertise_store(&mut self, store_uri: &str) {
+        self.advertised_store = Some(store_uri.to_string());
+    }
+
+    fn store_info(tx_handle: &TxHandle, advertised_store: &Option<String>) -> result::Result<StoreInfo, String> {
+        let store_uri = advertised_store.clone().ok_or("the transactor doesn't advertise its store")?;
+        let basis_tx = tx_handle.latest_tx().map_err(|e| e.message())?;
+        Ok(StoreInfo { store_uri, protocol_version: PROTOCOL_VERSION, basis_tx })
+    }
+
+    fn run_query(tx_handle: &TxHandle, limits: Option<QueryLimits>, text: &str, params: &[Value]) -> Result<Relation> {
+        let limits = limits.ok_or("the transactor doesn't serve queries")?;
+        let query = parse_query_with(text, params)?;
+        let db = tx_handle.current_db()?;
+
+        // The query runs on its own thread so that it can be
+        // abandoned (and cancelled) once it's out of time. Only one
+        // row more than the limit is pulled, to tell that it's over.
+        let cancel = CancelToken::new();
+        let (result_send, result_recv) = mpsc::channel();
+        let query_cancel = cancel.clone();
+        let max_rows = limits.max_rows;
+        thread::spawn(move || {
+            let vars = query.find.clone();
+            let rows = query_iter_with_cancel(query, &db, &query_cancel).take(max_rows + 1).collect::<Result<Vec<_>>>();
+            result_send.send(rows.map(|rows| Relation(vars, rows)))
+        });
+        let started = Instant::now();
+        let relation = match result_recv.recv_timeout(limits.timeout) {
+            Ok(result) => result?,
+            Err(_) => {
+                cancel.cancel();
+                return Err(format!("query ran longer than {} ms", limits.timeout.as_millis()).into());
+            }
+        };
+        tx_handle.observe_query_latency(started.elapsed())?;
+
+        if relation.1.len() > limits.max_rows {
+            return Err(format!("query returned more than {} rows", limits.max_rows).into());
+        }
+        Ok(relation)
     }
 
     pub fn listen(&self, bind_address: &str) -> Result<thread::JoinHandle<()>> {
         let tx_handle = self.tx_handle.clone();
+        let advertised_store = self.advertised_store.clone();
+        let query_limits = self.query_limits;
         let context = self.context.clone();
         let addr = bind_address.to_string();
-        let socket = context.socket(zmq::REP)?;
+        let socket = context.socket(zmq::ROUTER)?;
         socket.bind(&addr)?;
         info!("Listening on {}", addr);
 
         Ok(thread::spawn(move || {
-            // TODO: support multiple simultaneous transactions using zmq::ROUTER socket
-            // or an asynchronous transaction mechanism
+            // Queries are answered from threads of their own, so that
+            // a slow one doesn't hold up transactions. Their replies
+            // come back here to be sent, along with the envelope
+            // addressing the client, as only this thread may use the
+            // socket.
+            let (reply_send, reply_recv) = mpsc::channel::<(Vec<Vec<u8>>, Vec<u8>)>();
             // FIXME: less unwrapping!
             loop {
-                let msg = match socket.recv_bytes(0) {
-                    Ok(msg) => msg,
+                for (envelope, reply) in reply_recv.try_iter() {
+                    socket.send_multipart(envelope.into_iter().chain(iter::once(reply)), 0).unwrap();
+                }
+                match socket.poll(zmq::POLLIN, QUERY_REPLY_POLL_MS) {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(zmq::Error::ETERM) => break,
+                    Err(e) => {
+                        error!("unexpected error polling for requests: {}", e);
+                        break;
+                    }
+                }
+                let mut envelope = match socket.recv_multipart(0) {
+                    Ok(frames) => frames,
                     Err(zmq::Error::ETERM) => {
                         break;
                     },
@@ -54,9 +217,82 @@ impl TransactorService {
                         break;
                     }
                 };
-                let tx_request: Tx = rmp_serde::from_read_ref(&msg).unwrap();
-                let result = tx_handle.transact(tx_request).unwrap();
-                socket.send(rmp_serde::to_vec(&result).unwrap(), 0).unwrap();
+                let msg = envelope.pop().unwrap();
+                let request = rmp_serde::from_read_ref(&msg)
+                    .or_else(|_| rmp_serde::from_read_ref(&msg).map(Request::Transact))
+                    .unwrap();
+                let reply = match request {
+                    Request::Transact(tx) => rmp_serde::to_vec(&tx_handle.transact(tx).unwrap()),
+                    Request::GetStoreInfo => rmp_serde::to_vec(&TransactorService::store_info(&tx_handle, &advertised_store)),
+                    Request::Query(text, params) => {
+                        let (tx_handle, reply_send) = (tx_handle.clone(), reply_send.clone());
+                        thread::spawn(move || {
+                            let result = TransactorService::run_query(&tx_handle, query_limits, &text, &params);
+                            let reply = rmp_serde::to_vec(&result.map_err(|e| e.message())).unwrap();
+                            let _ = reply_send.send((envelope, reply));
+                        });
+                        continue;
+                    }
+                    Request::AllocateIds(n) => rmp_serde::to_vec(&tx_handle.allocate_ids(n).map_err(|e| e.message())),
+                    Request::Reindex => rmp_serde::to_vec(&tx_handle.reindex().map_err(|e| e.message())),
+                    Request::GetSchema => {
+                        let schema = tx_handle.current_db().map(|db| (db.basis_tx, db.schema));
+                        rmp_serde::to_vec(&schema.map_err(|e| e.message()))
+                    }
+                };
+                socket.send_multipart(envelope.into_iter().chain(iter::once(reply.unwrap())), 0).unwrap();
+            }
+        }))
+    }
+
+    /// Whether the service can take transactions: the store's
+    /// metadata can be read and the transactor is running.
+    fn check_ready(tx_handle: &TxHandle, store: &dyn KVStore) -> Result<()> {
+        store.get_metadata().map_err(|e| format!("can't read the store's metadata: {}", e.message()))?;
+        tx_handle.latest_tx().map_err(|_| "the transactor isn't running")?;
+        Ok(())
+    }
+
+    /// Answers HTTP health checks at `bind_address` (e.g.
+    /// `0.0.0.0:8080`), for container orchestrators. `GET /live` is
+    /// 200 while the process is up, and `GET /ready` is 200 once the
+    /// service can take transactions and 503 otherwise.
+    pub fn serve_health(&self, bind_address: &str) -> Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(bind_address).map_err(|e| format!("can't bind {}: {}", bind_address, e))?;
+        let tx_handle = self.tx_handle.clone();
+        let store = self.store.clone();
+        info!("Serving health checks on {}", bind_address);
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("unexpected error accepting a health check: {}", e);
+                        continue;
+                    }
+                };
+                // Only the request line matters, and a client that
+                // never sends one mustn't hold up the others.
+                let mut request_line = String::new();
+                let read = stream.set_read_timeout(Some(Duration::from_secs(1)))
+                    .and_then(|_| BufReader::new(&stream).read_line(&mut request_line));
+                if read.is_err() {
+                    continue;
+                }
+                let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                    ["GET", "/live"] => ("200 OK", "live".to_string()),
+                    ["GET", "/ready"] => match TransactorService::check_ready(&tx_handle, &*store) {
+                        Ok(()) => ("200 OK", "ready".to_string()),
+                        Err(e) => ("503 Service Unavailable", e.message()),
+                    },
+                    _ => ("404 Not Found", "not found".to_string()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body,
+                );
             }
         }))
     }
@@ -65,4 +301,19 @@ impl TransactorService {
         self.tx_handle.close().unwrap();
         self.tx_join_handle.join().unwrap().unwrap();
     }
+
+    /// Stops the transactor once it has committed the transactions
+    /// already sent to it. Fails if that takes longer than `grace`,
+    /// leaving the transactor to be killed with the process.
+    pub fn shutdown(self, grace: Duration) -> Result<()> {
+        self.tx_handle.close()?;
+        let (done_send, done_recv) = mpsc::channel();
+        let join_handle = self.tx_join_handle;
+        thread::spawn(move || done_send.send(join_handle.join()));
+        match done_recv.recv_timeout(grace) {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("the transactor panicked while stopping".into()),
+            Err(_) => Err(format!("the transactor didn't stop within {} ms", grace.as_millis()).into()),
+        }
+    }
 }
@@ -0,0 +1,483 @@
+//! Translates a restricted subset of SQL into datalog queries, for
+//! the Postgres wire-protocol frontend.
+//!
+//! Each attribute namespace is exposed as a table whose columns are
+//! the attributes in that namespace, plus an `id` column holding the
+//! entity: the attributes `person:name` and `person:email` make up
+//! the table `person` with columns `id`, `name` and `email`. The
+//! supported statements look like
+//!
+//! ```text
+//! SELECT name, email FROM person WHERE name = 'Bob' AND age > 30 LIMIT 10
+//! ```
+//!
+//! `SELECT *` selects `id` and every attribute in the namespace.
+//! Since a row is produced by joining on the entity, entities which
+//! lack any of the selected or filtered attributes don't appear.
+//!
+//! Queries stored in the database run with `EXECUTE`, passing their
+//! parameters in order:
+//!
+//! ```text
+//! EXECUTE people:byName('Bob')
+//! ```
+
+use {Entity, Ident, Relation, Result, Value};
+use db::Db;
+use schema::ValueType;
+use queries::query::{Clause, Comparator, Constraint, Hints, Order, Query, Term, Var};
+use queries::execution::query;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Select {
+    /// None for `SELECT *`.
+    pub columns: Option<Vec<String>>,
+    pub table: String,
+    pub conditions: Vec<Condition>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Condition {
+    pub column: String,
+    pub op: Op,
+    pub literal: Literal,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Literal {
+    String(String),
+    Number(i64),
+    Boolean(bool),
+}
+
+/// A column of a translated query's result, with the value type of
+/// the attribute it comes from (None for `id`).
+#[derive(Debug, PartialEq, Clone)]
+pub struct Column {
+    pub name: String,
+    pub value_type: Option<ValueType>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Word(String),
+    Str(String),
+    Number(i64),
+    Symbol(String),
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = sql.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ';' {
+            chars.next();
+        } else if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Word(word));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::new();
+            number.push(c);
+            chars.next();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let n = number.parse().map_err(|_| format!("invalid number: {}", number))?;
+            tokens.push(Token::Number(n));
+        } else if c == '\'' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    // '' is an escaped quote
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        s.push('\'');
+                    }
+                    Some('\'') => break,
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string literal".into()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            chars.next();
+            let mut symbol = c.to_string();
+            if let Some(&next) = chars.peek() {
+                if (c == '<' && (next == '>' || next == '=')) || (c == '!' && next == '=') || (c == '>' && next == '=') {
+                    symbol.push(next);
+                    chars.next();
+                }
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Tokens {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Tokens {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) => w.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected {}, found {:?}", keyword, self.tokens.get(self.pos)).into())
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(w)) => Ok(w),
+            other => Err(format!("expected an identifier, found {:?}", other).into()),
+        }
+    }
+}
+
+pub fn parse_select(sql: &str) -> Result<Select> {
+    let mut tokens = Tokens { tokens: tokenize(sql)?, pos: 0 };
+
+    tokens.expect_keyword("select")?;
+    let columns = if tokens.tokens.get(tokens.pos) == Some(&Token::Symbol("*".into())) {
+        tokens.pos += 1;
+        None
+    } else {
+        let mut columns = vec![tokens.identifier()?];
+        while tokens.tokens.get(tokens.pos) == Some(&Token::Symbol(",".into())) {
+            tokens.pos += 1;
+            columns.push(tokens.identifier()?);
+        }
+        Some(columns)
+    };
+
+    tokens.expect_keyword("from")?;
+    let table = tokens.identifier()?;
+
+    let mut conditions = vec![];
+    if tokens.peek_keyword("where") {
+        tokens.pos += 1;
+        loop {
+            let column = tokens.identifier()?;
+            let op = match tokens.next() {
+                Some(Token::Symbol(ref s)) if s == "=" => Op::Eq,
+                Some(Token::Symbol(ref s)) if s == "<>" || s == "!=" => Op::NotEq,
+                Some(Token::Symbol(ref s)) if s == "<" => Op::Lt,
+                Some(Token::Symbol(ref s)) if s == ">" => Op::Gt,
+                other => return Err(format!("unsupported operator {:?}", other).into()),
+            };
+            let literal = match tokens.next() {
+                Some(Token::Str(s)) => Literal::String(s),
+                Some(Token::Number(n)) => Literal::Number(n),
+                Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("true") => Literal::Boolean(true),
+                Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("false") => Literal::Boolean(false),
+                other => return Err(format!("expected a literal, found {:?}", other).into()),
+            };
+            conditions.push(Condition { column, op, literal });
+
+            if tokens.peek_keyword("and") {
+                tokens.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    let mut limit = None;
+    if tokens.peek_keyword("limit") {
+        tokens.pos += 1;
+        match tokens.next() {
+            Some(Token::Number(n)) if n >= 0 => limit = Some(n as usize),
+            other => return Err(format!("invalid limit {:?}", other).into()),
+        }
+    }
+
+    match tokens.next() {
+        None => Ok(Select { columns, table, conditions, limit }),
+        Some(token) => Err(format!("unexpected {:?}", token).into()),
+    }
+}
+
+fn attribute(db: &Db, table: &str, column: &str) -> Result<(Entity, ValueType)> {
+    let ident = format!("{}:{}", table, column);
+    let entity = db.schema.resolve(&ident)
+        .ok_or_else(|| format!("column {} does not exist in table {}", column, table))?;
+    let value_type = db.schema.value_types.get(&entity)
+        .cloned()
+        .ok_or_else(|| format!("{} is not an attribute", ident))?;
+    Ok((entity, value_type))
+}
+
+fn literal_value(literal: &Literal, value_type: &ValueType) -> Result<Value> {
+    match (literal, value_type) {
+        (Literal::String(s), ValueType::String) => Ok(Value::String(s.clone())),
+        (Literal::String(s), ValueType::Ident) => Ok(Value::Ident(s.clone())),
+        (Literal::Number(n), ValueType::Long) => Ok(Value::Long(*n)),
+        (Literal::Number(n), ValueType::Ref) => Ok(Value::Ref(Entity(*n))),
+        (Literal::Boolean(b), ValueType::Boolean) => Ok(Value::Boolean(*b)),
+        (literal, value_type) => Err(format!("{:?} can't be compared with a {:?} column", literal, value_type).into()),
+    }
+}
+
+/// Translates a select statement into a query against `db`, returning
+/// the query and a description of its result columns.
+pub fn translate(select: &Select, db: &Db) -> Result<(Query, Vec<Column>)> {
+    let prefix = format!("{}:", select.table);
+    let column_names = match select.columns {
+        Some(ref columns) => columns.clone(),
+        None => {
+            let mut columns: Vec<String> = db.schema.idents
+                .iter()
+                .filter(|(ident, entity)| ident.starts_with(&prefix) && db.schema.value_types.contains_key(entity))
+                .map(|(ident, _)| ident[prefix.len()..].to_string())
+                .collect();
+            if columns.is_empty() {
+                return Err(format!("table {} does not exist", select.table).into());
+            }
+            columns.sort();
+            columns.insert(0, "id".into());
+            columns
+        }
+    };
+
+    let id = Var::new("id");
+    let mut clauses = vec![];
+    let mut constraints = vec![];
+    let mut columns = vec![];
+
+    for name in column_names.iter() {
+        if name == "id" {
+            columns.push(Column { name: name.clone(), value_type: None });
+            continue;
+        }
+
+        let (attr, value_type) = attribute(db, &select.table, name)?;
+        clauses.push(Clause::new(
+            Term::Unbound(id.clone()),
+            Term::Bound(Ident::Entity(attr)),
+            Term::Unbound(Var::new(name.clone())),
+        ));
+        columns.push(Column { name: name.clone(), value_type: Some(value_type) });
+    }
+
+    for condition in select.conditions.iter() {
+        if condition.column == "id" {
+            return Err("filtering on id is not supported".into());
+        }
+
+        let (attr, value_type) = attribute(db, &select.table, &condition.column)?;
+        let value = literal_value(&condition.literal, &value_type)?;
+
+        if condition.op == Op::Eq {
+            clauses.push(Clause::new(Term::Unbound(id.clone()), Term::Bound(Ident::Entity(attr)), Term::Bound(value)));
+            continue;
+        }
+
+        // Other comparisons need the column bound to a var; a
+        // dedicated one keeps this independent of the select list.
+        let var = Var::new(format!("where:{}", condition.column));
+        clauses.push(Clause::new(Term::Unbound(id.clone()), Term::Bound(Ident::Entity(attr)), Term::Unbound(var.clone())));
+        let comparator = match condition.op {
+            Op::Lt => Comparator::LessThan,
+            Op::Gt => Comparator::GreaterThan,
+            _ => Comparator::NotEqualTo,
+        };
+        constraints.push(Constraint {
+            comparator,
+            left_hand_side: Term::Unbound(var),
+            right_hand_side: Term::Bound(value),
+        });
+    }
+
+    if clauses.is_empty() {
+        return Err("select at least one column other than id".into());
+    }
+
+    // Clauses which bind a value to a literal go first, so the
+    // planner starts from the most selective ones.
+    clauses.sort_by_key(|c| match c.value {
+        Term::Bound(_) => 0,
+        Term::Unbound(_) => 1,
+    });
+
+    // Rows are sorted by each column in turn, so that a limit picks
+    // out the same rows every time.
+    let find: Vec<Var> = columns.iter().map(|c| Var::new(c.name.clone())).collect();
+    let order_by = find.iter().cloned().map(Order::asc).collect();
+    let query = Query {
+        find,
+        expressions: vec![],
+        aggregates: vec![],
+        with: vec![],
+        clauses,
+        constraints,
+        within: vec![],
+        active: vec![],
+        exists: vec![],
+        not: vec![],
+        or: vec![],
+        rules: vec![],
+        calls: vec![],
+        lookups: vec![],
+        inputs: vec![],
+        scalar_inputs: vec![],
+        hints: Hints::default(),
+        order_by,
+        limit: select.limit,
+        as_of: None,
+        since: None,
+        history: false,
+    };
+
+    Ok((query, columns))
+}
+
+/// Parses an `EXECUTE` statement into the query name and its
+/// parameters. Numbers are passed as refs, as in query text.
+pub fn parse_execute(sql: &str) -> Result<(String, Vec<Value>)> {
+    let mut tokens = Tokens { tokens: tokenize(sql)?, pos: 0 };
+
+    tokens.expect_keyword("execute")?;
+    let mut name = tokens.identifier()?;
+    while tokens.tokens.get(tokens.pos) == Some(&Token::Symbol(":".into())) {
+        tokens.pos += 1;
+        name.push(':');
+        name.push_str(&tokens.identifier()?);
+    }
+
+    let mut params = vec![];
+    if tokens.tokens.get(tokens.pos) == Some(&Token::Symbol("(".into())) {
+        tokens.pos += 1;
+        if tokens.tokens.get(tokens.pos) == Some(&Token::Symbol(")".into())) {
+            tokens.pos += 1;
+        } else {
+            loop {
+                params.push(match tokens.next() {
+                    Some(Token::Str(s)) => Value::String(s),
+                    Some(Token::Number(n)) => Value::Ref(Entity(n)),
+                    Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("true") => Value::Boolean(true),
+                    Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("false") => Value::Boolean(false),
+                    other => return Err(format!("expected a literal, found {:?}", other).into()),
+                });
+                match tokens.next() {
+                    Some(Token::Symbol(ref s)) if s == "," => continue,
+                    Some(Token::Symbol(ref s)) if s == ")" => break,
+                    other => return Err(format!("expected , or ), found {:?}", other).into()),
+                }
+            }
+        }
+    }
+
+    match tokens.next() {
+        None => Ok((name, params)),
+        Some(token) => Err(format!("unexpected {:?}", token).into()),
+    }
+}
+
+/// Runs a named query. The column types aren't declared anywhere,
+/// so they're taken from the first row (text if there are no rows).
+fn execute_named(sql: &str, db: &Db) -> Result<(Vec<Column>, Relation)> {
+    let (name, params) = parse_execute(sql)?;
+    let Relation(vars, mut tuples) = query(db.named_query(&name, &params)?, db)?;
+    tuples.sort();
+
+    let columns = vars
+        .iter()
+        .enumerate()
+        .map(|(i, var)| Column {
+            name: var.name.clone(),
+            value_type: Some(tuples.first().map_or(ValueType::String, |t| t[i].value_type())),
+        })
+        .collect();
+
+    Ok((columns, Relation(vars, tuples)))
+}
+
+/// Parses, translates and runs a select or execute statement.
+pub fn execute_sql(sql: &str, db: &Db) -> Result<(Vec<Column>, Relation)> {
+    if (Tokens { tokens: tokenize(sql)?, pos: 0 }).peek_keyword("execute") {
+        return execute_named(sql, db);
+    }
+
+    let select = parse_select(sql)?;
+    let (q, columns) = translate(&select, db)?;
+    let relation = query(q, db)?;
+
+    Ok((columns, relation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_select() {
+        assert_eq!(
+            parse_select("SELECT name, email FROM person WHERE name = 'O''Brien' AND age > 30 LIMIT 5;").unwrap(),
+            Select {
+                columns: Some(vec!["name".into(), "email".into()]),
+                table: "person".into(),
+                conditions: vec![
+                    Condition { column: "name".into(), op: Op::Eq, literal: Literal::String("O'Brien".into()) },
+                    Condition { column: "age".into(), op: Op::Gt, literal: Literal::Number(30) },
+                ],
+                limit: Some(5),
+            }
+        );
+
+        assert_eq!(parse_select("select * from person").unwrap().columns, None);
+        assert!(parse_select("select name from person where").is_err());
+        assert!(parse_select("delete from person").is_err());
+    }
+
+    #[test]
+    fn test_parse_execute() {
+        assert_eq!(
+            parse_execute("EXECUTE people:byName('Bob', 12, true);").unwrap(),
+            ("people:byName".to_string(), vec!["Bob".into(), Value::Ref(Entity(12)), Value::Boolean(true)])
+        );
+        assert_eq!(parse_execute("execute everyone").unwrap(), ("everyone".to_string(), vec![]));
+        assert_eq!(parse_execute("execute everyone()").unwrap(), ("everyone".to_string(), vec![]));
+        assert!(parse_execute("execute people('Bob'").is_err());
+    }
+}
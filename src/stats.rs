@@ -0,0 +1,210 @@
+//! Per-attribute statistics for the query planner, computed from the
+//! AVET index whenever the indices are rebuilt and saved with the
+//! database metadata.
+//!
+//! The log index's transactions get statistics of their own, so the
+//! planner can tell how many records a range of transactions has.
+//!
+//! Statistics describe the durable indices as of the last reindex,
+//! so they don't account for novelty, and they count every datom in
+//! the index, including values which have since been retracted. The
+//! planner only uses them to compare clauses against each other, for
+//! which rough numbers are good enough.
+
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use serde::{Serialize, Deserialize};
+
+use {Entity, Record, Value};
+
+/// The most buckets kept in an attribute's histogram. Building the
+/// histogram doubles the bucket depth whenever it would exceed this.
+pub const MAX_BUCKETS: usize = 64;
+
+/// Statistics about the values of one attribute.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct AttributeStats {
+    /// The number of datoms with the attribute.
+    pub count: u64,
+    /// The number of distinct values among them.
+    pub distinct: u64,
+    /// An equi-depth histogram: every `depth`-th value, in order.
+    pub boundaries: Vec<Value>,
+    pub depth: u64,
+    /// How many of the datoms are retractions, each of which cancels
+    /// one of the others. Unknown in statistics from before it was
+    /// counted.
+    #[serde(default)]
+    pub retracted: Option<u64>,
+}
+
+impl AttributeStats {
+    /// Estimates how many datoms have exactly `value`. Values which
+    /// span several histogram buckets are known to be common; for
+    /// the rest, values are assumed to be evenly distributed.
+    pub fn estimate_equal(&self, value: &Value) -> f64 {
+        let buckets = self.boundaries.iter().filter(|b| *b == value).count() as u64;
+        if buckets > 1 {
+            (buckets * self.depth) as f64
+        } else {
+            self.average_per_value()
+        }
+    }
+
+    /// The average number of datoms per distinct value.
+    pub fn average_per_value(&self) -> f64 {
+        if self.distinct == 0 {
+            0.0
+        } else {
+            self.count as f64 / self.distinct as f64
+        }
+    }
+
+    /// Estimates the fraction of datoms whose value is less than `value`.
+    pub fn fraction_below(&self, value: &Value) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let below = self.boundaries.partition_point(|b| b < value) as u64 * self.depth;
+        (below as f64 / self.count as f64).min(1.0)
+    }
+}
+
+/// Statistics for every attribute in the durable indices, keyed by
+/// the attribute's entity. Empty until the first reindex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Stats {
+    pub attributes: BTreeMap<Entity, AttributeStats>,
+    /// Statistics about the log index, with each record's transaction
+    /// as its value. Empty in metadata from before it was kept.
+    #[serde(default)]
+    pub log: AttributeStats,
+}
+
+impl Stats {
+    /// Computes statistics in one pass over records sorted by
+    /// attribute and then value, as they are in the AVET index.
+    pub fn from_sorted<I: Iterator<Item = Record>>(records: I) -> Stats {
+        let mut attributes: BTreeMap<Entity, AttributeStats> = BTreeMap::new();
+        let mut current: Option<(Entity, Histogram)> = None;
+
+        for record in records {
+            if current.as_ref().map(|c| c.0) != Some(record.attribute) {
+                if let Some((attribute, histogram)) = current.take() {
+                    attributes.insert(attribute, histogram.stats);
+                }
+                let mut histogram = Histogram::new();
+                histogram.stats.retracted = Some(0);
+                current = Some((record.attribute, histogram));
+            }
+            let histogram = &mut current.as_mut().unwrap().1;
+            if record.retracted {
+                *histogram.stats.retracted.as_mut().unwrap() += 1;
+            }
+            histogram.push(record.value);
+        }
+
+        if let Some((attribute, histogram)) = current {
+            attributes.insert(attribute, histogram.stats);
+        }
+        Stats { attributes, log: AttributeStats::default() }
+    }
+
+    /// Adds statistics about the log index, from its records in
+    /// order of transaction.
+    pub fn with_log<I: Iterator<Item = Record>>(self, records: I) -> Stats {
+        let mut histogram = Histogram::new();
+        for record in records {
+            histogram.push(Value::Ref(record.tx));
+        }
+        Stats { log: histogram.stats, ..self }
+    }
+
+    /// Estimates how many records the log index has for the
+    /// transactions `txs`: at least as many as an average one.
+    pub fn estimate_log(&self, txs: &RangeInclusive<i64>) -> f64 {
+        let below = |tx: i64| self.log.fraction_below(&Value::Ref(Entity(tx)));
+        let end = if *txs.end() == i64::MAX { 1.0 } else { below(txs.end() + 1) };
+        (self.log.count as f64 * (end - below(*txs.start()))).max(self.log.average_per_value())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+}
+
+/// Builds an attribute's statistics from its values, in order.
+struct Histogram {
+    stats: AttributeStats,
+    in_bucket: u64,
+    last_value: Option<Value>,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            stats: AttributeStats { depth: 1, ..AttributeStats::default() },
+            in_bucket: 0,
+            last_value: None,
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        let stats = &mut self.stats;
+        stats.count += 1;
+        if self.last_value.as_ref() != Some(&value) {
+            stats.distinct += 1;
+        }
+        self.in_bucket += 1;
+        if self.in_bucket == stats.depth {
+            stats.boundaries.push(value.clone());
+            self.in_bucket = 0;
+            if stats.boundaries.len() > MAX_BUCKETS {
+                // Keep every second boundary. The values after
+                // the dropped last one start the next bucket.
+                stats.boundaries = stats.boundaries.iter().skip(1).step_by(2).cloned().collect();
+                self.in_bucket = stats.depth;
+                stats.depth *= 2;
+            }
+        }
+        self.last_value = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(attribute: i64, values: &[i64]) -> Vec<Record> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(e, &v)| Record::addition(Entity(e as i64), Entity(attribute), Value::Long(v), Entity(1)))
+            .collect()
+    }
+
+    #[test]
+    fn test_stats_from_sorted() {
+        let mut values: Vec<i64> = (0..1000).collect();
+        values.extend(vec![2000; 1000]);
+        let mut all = records(10, &values);
+        all.extend(records(11, &[1, 1, 2]));
+        let stats = Stats::from_sorted(all.into_iter());
+
+        let a = &stats.attributes[&Entity(10)];
+        assert_eq!(a.count, 2000);
+        assert_eq!(a.distinct, 1001);
+        assert!(a.boundaries.len() <= MAX_BUCKETS);
+        assert_eq!(a.boundaries.len() as u64, a.count / a.depth);
+
+        // Half the values are below 1000, and half are 2000.
+        assert!((a.fraction_below(&Value::Long(1000)) - 0.5).abs() < 0.05);
+        assert!((a.estimate_equal(&Value::Long(2000)) - 1000.0).abs() < 100.0);
+        assert!(a.estimate_equal(&Value::Long(5)) < 3.0);
+
+        let b = &stats.attributes[&Entity(11)];
+        assert_eq!((b.count, b.distinct), (3, 2));
+        assert_eq!(b.boundaries, vec![Value::Long(1), Value::Long(1), Value::Long(2)]);
+    }
+}
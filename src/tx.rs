@@ -1,61 +1,212 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ops::Range;
 use std::sync::Arc;
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Sender, SyncSender, Receiver};
 use std::thread;
 use std::time::Duration;
+use std::sync::mpsc::RecvTimeoutError;
 
-use log::{debug, info, warn, error};
-use chrono::prelude::Utc;
+use log::{debug, error, info, warn};
+use chrono::prelude::{DateTime, Utc};
+use itertools::Itertools;
+use im::HashMap;
 
 use backends::KVStore;
-use db::{Db, DbMetadata};
-use schema::{Schema, ValueType};
-use {Tx, TxReport, Entity, Record, Value, TxItem, Result, Fact};
+use durable_tree;
+use db::{Db, DbMetadata, drop_history};
+use schema::{Cardinality, Schema, ValueType};
+use durable_tree::{RebuildProgress, Compactor};
+use stats::Stats;
+use reindex::{ReindexPolicy, LatencyTracker};
+use backfill;
+use functions::TxFunctions;
+use encryption::Keyring;
+use {Error, Tx, TxReport, ReindexStatus, Entity, Record, Value, TxItem, TxValue, Result, Fact, Ident, EntitySpec, LookupFact};
+use queries::query::{Clause, Term};
+
+/// A validated transaction that hasn't been committed yet, as seen
+/// by a `TxListener`.
+pub struct PendingTx<'a> {
+    pub tx_entity: Entity,
+    /// The records the transaction will append, including those
+    /// derived by listeners that ran before this one.
+    pub records: &'a [Record],
+    pub db_before: &'a Db,
+    pub db_after: &'a Db,
+}
+
+/// Observes each transaction before it's committed. A listener can
+/// veto the transaction by returning an error, which fails it as if
+/// it were invalid, or derive more facts from it (e.g. to maintain a
+/// denormalized counter) by returning items to apply as part of the
+/// same transaction.
+///
+/// A transaction seen by `before_commit` can still fail: a later
+/// listener can veto it, or the writes for its group can fail. A
+/// listener which keeps state should only keep changes to it once
+/// `after_commit` is called, and drop them in `on_abort`.
+pub trait TxListener: Send {
+    fn before_commit(&mut self, tx: &PendingTx) -> Result<Vec<TxItem>>;
+
+    /// Called once the transaction `tx_entity` is in the log.
+    fn after_commit(&mut self, _tx_entity: Entity) {}
+
+    /// Called when the transaction `tx_entity`, which this listener's
+    /// `before_commit` has seen, won't be committed.
+    fn on_abort(&mut self, _tx_entity: Entity) {}
+}
 
 pub struct Transactor {
     next_id: i64,
+    /// Ids below this are reserved in the stored metadata, so a
+    /// restarted transactor starts after them even if it crashed
+    /// before saving which of them it had handed out.
+    id_limit: i64,
     current_db: Db,
     store: Arc<dyn KVStore>,
     latest_tx: i64,
     last_indexed_tx: i64,
+    /// The log positions of `latest_tx` and `last_indexed_tx`.
+    latest_seq: i64,
+    last_indexed_seq: i64,
+    /// The writer epoch claimed by this transactor. It's stamped on
+    /// every transaction it appends, and the store refuses appends
+    /// from a transactor whose epoch has been superseded.
+    epoch: u64,
 
-    /// Interactions with a running transactor happen over an event
-    /// channel.
+    /// Interactions with a running transactor happen over two
+    /// channels: a bounded one for transactions, which applies
+    /// backpressure to clients, and an unbounded one for control
+    /// events, which are handled before any queued transactions.
     recv: Receiver<Event>,
-    send: Sender<Event>,
+    send: SyncSender<Event>,
+    control: ControlSender,
+    control_recv: Receiver<Control>,
 
     /// While asynchronously rebuilding the durable indices, it's
     /// necessary to keep track of transactions which will need to be
     /// added to the rebuilt indices' in-memory trees before swapping
     /// over.
     catchup_txs: Option<Vec<TxRaw>>,
+    /// The last tx included in the checkpoint being rebuilt, which
+    /// becomes `last_indexed_tx` once the rebuilt indices are in use.
+    rebuild_checkpoint_tx: i64,
+    rebuild_checkpoint_seq: i64,
+    /// Progress of the rebuild in flight, if any.
+    reindex_progress: Option<Arc<RebuildProgress>>,
     throttled: bool,
+
+    /// The roots of the durable indices replaced since the transactor
+    /// started, whose nodes `collect_garbage` deletes unless the
+    /// current indices still use them.
+    retired_roots: Vec<String>,
+
+    /// Run in registration order on every transaction.
+    listeners: Vec<Box<dyn TxListener>>,
+    functions: TxFunctions,
+    reindex_policy: ReindexPolicy,
+    latency: LatencyTracker,
+    /// The last minute checked against the reindex schedule, so a
+    /// scheduled minute only starts one reindex.
+    last_scheduled_minute: Option<i64>,
+    /// Set when the metadata couldn't be saved after a log append
+    /// went through. The appended txs are durable, so they're still
+    /// reported as committed, but the transactor stops rather than
+    /// keep writing on top of stale metadata; a restart recovers
+    /// from the log.
+    halted: Option<Error>,
+    /// The metadata as this transactor last read or wrote it,
+    /// serialized. A save only goes through if the store still holds
+    /// it, so once another transactor has saved (as it does when it
+    /// starts), this one can't overwrite it.
+    saved_metadata: Option<Vec<u8>>,
 }
 
-/// Represents any input that might need to be given to a
-/// running transactor. Usually this would be a transaction to
-/// process, but any other interrupts that require linearization
-/// (e.g. swapping over to a new index) would be delivered through the
-/// same channel.
+/// The number of transactions which can be queued before
+/// `TxHandle::transact` blocks.
+const TX_QUEUE_CAPACITY: usize = 1024;
+
+/// How often an idle transactor checks whether to reindex, e.g.
+/// because a scheduled window has started.
+const REINDEX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Novelty beyond which transactions are throttled while a reindex
+/// is running.
+const THROTTLE_NOVELTY: usize = 1_000_000;
+
+/// The most queued transactions committed together by one log
+/// append and metadata save.
+const MAX_GROUP_SIZE: usize = 64;
+
+/// How many entity ids the transactor reserves in the metadata at a
+/// time.
+const ID_BLOCK: i64 = 1000;
+
+/// How many times the metadata save after a log append is tried, and
+/// the wait before the first retry (doubled for each one after).
+const METADATA_SAVE_ATTEMPTS: u32 = 3;
+const METADATA_SAVE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How deeply transaction functions may call each other, through the
+/// items they return, before the transaction is failed.
+const MAX_FUNCTION_DEPTH: usize = 32;
+
+/// Represents a transaction for a running transactor to process.
 enum Event {
     Tx(Tx, Sender<TxReport>),
-    RebuiltIndex(Db),
+    /// Wakes the transactor to handle a control event.
+    Wake,
+}
+
+/// Interrupts which require linearization with transactions but
+/// shouldn't wait behind them (e.g. swapping over to a new index).
+enum Control {
+    /// The rebuilt indices, or why they can't be used.
+    RebuiltIndex(Result<Db>),
+    Excise(Entity, Sender<Result<usize>>),
+    CollectGarbage(Sender<Result<usize>>),
+    ReindexStatus(Sender<Option<ReindexStatus>>),
+    LatestTx(Sender<i64>),
+    CurrentDb(Sender<Db>),
+    AllocateIds(u64, Sender<Result<Range<i64>>>),
+    QueryLatency(Duration),
+    Reindex(Sender<bool>),
     Stop,
 }
 
+#[derive(Clone)]
+struct ControlSender {
+    control: Sender<Control>,
+    wake: SyncSender<Event>,
+}
+
+impl ControlSender {
+    fn send(&self, control: Control) -> Result<()> {
+        self.control.send(control)?;
+        // If the queue is full the transactor is busy and will see
+        // the control event before the next transaction anyway.
+        let _ = self.wake.try_send(Event::Wake);
+        Ok(())
+    }
+}
+
 /// TxHandle is a wrapper over Transactor that provides a thread-safe
 /// interface for submitting transactions and receiving their results,
 /// abstracting away the implementation of the thread-safety.
 #[derive(Clone)]
 pub struct TxHandle {
-    chan: Sender<Event>,
+    chan: SyncSender<Event>,
+    control: ControlSender,
 }
 
 impl TxHandle {
     pub fn new(transactor: &Transactor) -> TxHandle {
         let chan = transactor.send.clone();
+        let control = transactor.control.clone();
 
-        TxHandle { chan }
+        TxHandle { chan, control }
     }
 
     pub fn transact(&self, tx: Tx) -> Result<TxReport> {
@@ -67,79 +218,283 @@ impl TxHandle {
         }
     }
 
+    /// Removes all of an entity's records from the indices, returning
+    /// how many were removed.
+    pub fn excise(&self, entity: Entity) -> Result<usize> {
+        let (result_send, result_recv) = mpsc::channel();
+        self.control.send(Control::Excise(entity, result_send))?;
+        result_recv.recv()?
+    }
+
+    /// Deletes the index nodes which are no longer in use, returning
+    /// how many were deleted.
+    pub fn collect_garbage(&self) -> Result<usize> {
+        let (result_send, result_recv) = mpsc::channel();
+        self.control.send(Control::CollectGarbage(result_send))?;
+        result_recv.recv()?
+    }
+
+    /// Returns the progress of the index rebuild in flight, if any.
+    pub fn reindex_status(&self) -> Result<Option<ReindexStatus>> {
+        let (status_send, status_recv) = mpsc::channel();
+        self.control.send(Control::ReindexStatus(status_send))?;
+        Ok(status_recv.recv()?)
+    }
+
+    /// Returns the id of the last transaction the transactor
+    /// committed.
+    pub fn latest_tx(&self) -> Result<i64> {
+        let (tx_send, tx_recv) = mpsc::channel();
+        self.control.send(Control::LatestTx(tx_send))?;
+        Ok(tx_recv.recv()?)
+    }
+
+    /// Returns the transactor's db as of its latest transaction.
+    pub fn current_db(&self) -> Result<Db> {
+        let (db_send, db_recv) = mpsc::channel();
+        self.control.send(Control::CurrentDb(db_send))?;
+        Ok(db_recv.recv()?)
+    }
+
+    /// Reserves `n` entity ids. See `Transactor::allocate_ids`.
+    pub fn allocate_ids(&self, n: u64) -> Result<Range<i64>> {
+        let (ids_send, ids_recv) = mpsc::channel();
+        self.control.send(Control::AllocateIds(n, ids_send))?;
+        ids_recv.recv()?
+    }
+
+    /// Reports how long a query took, for the reindex policy.
+    pub fn observe_query_latency(&self, latency: Duration) -> Result<()> {
+        self.control.send(Control::QueryLatency(latency))
+    }
+
+    /// Starts a reindex now, unless one is already running. Returns
+    /// whether it started one.
+    pub fn reindex(&self) -> Result<bool> {
+        let (started_send, started_recv) = mpsc::channel();
+        self.control.send(Control::Reindex(started_send))?;
+        Ok(started_recv.recv()?)
+    }
+
     pub fn close(&self) -> Result<()>{
-        Ok(self.chan.send(Event::Stop)?)
+        self.control.send(Control::Stop)
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct TxRaw {
+    /// The transaction's position in the log. Each transaction
+    /// appended is one past the last, whatever entity ids were
+    /// handed out in between.
+    pub seq: i64,
+    /// The id of the transaction's entity.
     pub id: i64,
+    pub epoch: u64,
     pub records: Vec<Record>,
 }
 
+/// Drops log entries written by a fenced-off writer, i.e. entries
+/// whose epoch is older than that of an entry before them in the
+/// log. `epoch` is the newest epoch seen so far, and is updated as
+/// entries are read.
+pub fn drop_fenced_txs(txs: Vec<TxRaw>, epoch: &mut u64) -> Vec<TxRaw> {
+    txs.into_iter()
+        .filter(|tx| {
+            if tx.epoch < *epoch {
+                warn!("ignoring tx {} from fenced writer epoch {} (current epoch {})", tx.id, tx.epoch, epoch);
+                return false;
+            }
+            *epoch = tx.epoch;
+            true
+        })
+        .collect()
+}
+
+/// Checks the log entries about to be replayed onto the checkpoint
+/// described by `metadata`, so that a transactor doesn't go on
+/// writing after a half-written checkpoint or log.
+fn check_replay(metadata: &DbMetadata, novelty: &[TxRaw]) -> Result<()> {
+    if metadata.next_id <= metadata.last_indexed_tx {
+        return Err(inconsistent(format!(
+            "next id {} isn't past the last indexed tx {}", metadata.next_id, metadata.last_indexed_tx
+        )));
+    }
+    let mut last_seq = metadata.indexed_seq();
+    for tx in novelty {
+        if tx.seq <= last_seq {
+            return Err(inconsistent(format!("tx {} is at log position {}, after position {}", tx.id, tx.seq, last_seq)));
+        }
+        for record in &tx.records {
+            if record.tx != Entity(tx.id) {
+                return Err(inconsistent(format!("tx {} has a record from tx {}", tx.id, record.tx.0)));
+            }
+        }
+        last_seq = tx.seq;
+    }
+    Ok(())
+}
+
+/// Checks that every ident in the replayed schema resolves to the
+/// entity it names, and that `attributes`, those of the replayed
+/// records, and every attribute with a value type have idents.
+fn check_schema(schema: &Schema, attributes: &HashSet<Entity>) -> Result<()> {
+    for (entity, ident) in &schema.entity_idents {
+        if schema.idents.get(ident) != Some(entity) {
+            return Err(inconsistent(format!("ident {} doesn't resolve to entity {}", ident, entity.0)));
+        }
+    }
+    for attribute in schema.value_types.keys() {
+        if schema.ident_for(*attribute).is_none() {
+            return Err(inconsistent(format!("attribute {} has a value type but no ident", attribute.0)));
+        }
+    }
+    for attribute in attributes {
+        if schema.ident_for(*attribute).is_none() {
+            return Err(inconsistent(format!("the log has records of attribute {}, which has no ident", attribute.0)));
+        }
+    }
+    Ok(())
+}
+
+fn inconsistent(problem: String) -> Error {
+    format!("refusing to start the transactor, the db is inconsistent: {}", problem).into()
+}
+
+/// What a transaction committed, for its report.
+struct Committed {
+    tx: Entity,
+    new_entities: Vec<Entity>,
+    timestamp: Option<DateTime<Utc>>,
+    datoms: Option<Vec<Record>>,
+    schema_changed: bool,
+}
+
 impl Transactor {
     /// Creates a transactor by retrieving the database metadata from
     /// the store (if it exists already) or creating the metadata for
     /// a new database (if no metadata is present in the store).
+    /// Indices missing from older metadata are backfilled first (see
+    /// `backfill`). Fails if the log after the last checkpoint
+    /// doesn't replay onto it consistently.
     pub fn new(store: Arc<dyn KVStore>) -> Result<Transactor> {
-        let (send, recv) = mpsc::channel();
+        let (send, recv) = mpsc::sync_channel(TX_QUEUE_CAPACITY);
+        let (control_send, control_recv) = mpsc::channel();
+        let control = ControlSender { control: control_send, wake: send.clone() };
 
-        match store.get_metadata() {
-            Ok(metadata) => {
+        match store.get("db_metadata") {
+            Ok(mut saved_metadata) => {
+                let mut metadata = DbMetadata::from_stored(&saved_metadata)?;
+                let backfilled = metadata.missing_indices();
+                for name in backfilled.iter() {
+                    info!("Backfilling the {} index...", name);
+                    metadata = backfill::backfill(store.clone(), name)?;
+                    // As `compare_and_set_metadata` wrote it.
+                    saved_metadata = metadata.to_stored()?;
+                }
                 let mut next_id = metadata.next_id;
                 let last_id = metadata.last_indexed_tx;
-                let mut latest_tx = last_id;
+                let last_seq = metadata.indexed_seq();
+                let (mut latest_tx, mut latest_seq) = (last_id, last_seq);
+                let mut epoch = metadata.epoch;
+                let novelty = drop_fenced_txs(store.get_txs(last_seq)?, &mut epoch);
+                check_replay(&metadata, &novelty)?;
                 let mut db = Db::new(metadata, store.clone());
-                let novelty = store.get_txs(last_id)?;
+                let mut attributes = HashSet::new();
                 for tx in novelty {
+                    // Logs written before ids were reserved ahead of
+                    // use can have ids past the stored next id.
                     for record in tx.records {
+                        attributes.insert(record.attribute);
                         let Entity(e) = record.entity;
-                        if e > next_id {
+                        if e >= next_id {
                             next_id = e + 1;
                         }
                         db = db.add_record(record)?;
                     }
 
+                    next_id = next_id.max(tx.id + 1);
                     latest_tx = tx.id;
+                    latest_seq = tx.seq;
                 }
+                check_schema(&db.schema, &attributes)?;
 
-                Ok(Transactor {
+                let mut tx = Transactor {
                     next_id,
+                    id_limit: next_id,
                     store: store.clone(),
                     latest_tx: latest_tx,
                     last_indexed_tx: last_id,
+                    latest_seq,
+                    last_indexed_seq: last_seq,
+                    epoch: epoch + 1,
                     current_db: db,
                     send,
                     recv,
+                    control: control.clone(),
+                    control_recv,
                     catchup_txs: None,
+                    rebuild_checkpoint_tx: -1,
+                    rebuild_checkpoint_seq: 0,
+                    reindex_progress: None,
                     throttled: false,
-                })
+                    retired_roots: vec![],
+                    listeners: vec![],
+                    functions: TxFunctions::new(),
+                    reindex_policy: ReindexPolicy::default(),
+                    latency: LatencyTracker::default(),
+                    last_scheduled_minute: None,
+                    halted: None,
+                    saved_metadata: Some(saved_metadata),
+                };
+
+                save_metadata(&tx.current_db, tx.id_limit, tx.last_indexed_tx, tx.last_indexed_seq, tx.epoch, &mut tx.saved_metadata)?;
+                tx.claim_epoch()?;
+                for name in backfilled {
+                    tx.record_admin_op("db:admin:backfill", format!("backfilled the {} index", name))?;
+                }
+                Ok(tx)
             }
             // FIXME: this should happen if metadata is None, not on error
             Err(_) => {
                 let (current_db, next_id) = create_db(store.clone())?;
                 let mut tx = Transactor {
                     next_id,
+                    id_limit: next_id,
                     store: store,
                     latest_tx: 0,
                     last_indexed_tx: -1,
+                    latest_seq: 0,
+                    last_indexed_seq: 0,
+                    epoch: 1,
                     current_db,
                     send,
                     recv,
+                    control: control.clone(),
+                    control_recv,
                     catchup_txs: None,
+                    rebuild_checkpoint_tx: -1,
+                    rebuild_checkpoint_seq: 0,
+                    reindex_progress: None,
                     throttled: false,
+                    retired_roots: vec![],
+                    listeners: vec![],
+                    functions: TxFunctions::new(),
+                    reindex_policy: ReindexPolicy::default(),
+                    latency: LatencyTracker::default(),
+                    last_scheduled_minute: None,
+                    halted: None,
+                    saved_metadata: None,
                 };
 
-                save_metadata(&tx.current_db, tx.next_id, tx.last_indexed_tx)?;
+                save_metadata(&tx.current_db, tx.id_limit, tx.last_indexed_tx, tx.last_indexed_seq, tx.epoch, &mut tx.saved_metadata)?;
 
                 // We need to persist the bootstrapping data because
                 // it's not in the transaction log.
                 // FIXME: unwind this from the channel communication code
                 // which isn't needed here
                 tx.rebuild_indices();
-                match tx.recv.recv().unwrap() {
-                    Event::RebuiltIndex(new_db) => {
+                match tx.control_recv.recv().unwrap() {
+                    Control::RebuiltIndex(new_db) => {
                         tx.switch_to_rebuilt_indexes(new_db)?;
                     },
                     // no one can send messages on this channel before
@@ -148,48 +503,164 @@ impl Transactor {
                     // rebuild is complete
                     _ => unreachable!()
                 }
+                tx.claim_epoch()?;
                 Ok(tx)
             }
         }
     }
 
+    /// Replaces the default reindex policy.
+    pub fn set_reindex_policy(&mut self, policy: ReindexPolicy) {
+        self.reindex_policy = policy;
+    }
+
+    /// Encrypts the values of `db:encrypted` attributes with
+    /// `keyring`. Transactions asserting such values fail until one is
+    /// set.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.current_db = self.current_db.with_keyring(Arc::new(keyring));
+    }
+
+    /// The store the transactor commits to.
+    pub(crate) fn store(&self) -> Arc<dyn KVStore> {
+        self.store.clone()
+    }
+
+    /// Starts a reindex if the policy calls for one and none is
+    /// running.
+    fn maybe_reindex(&mut self) {
+        if self.catchup_txs.is_some() {
+            return;
+        }
+
+        let policy = &self.reindex_policy;
+        let novelty = self.current_db.mem_index_size();
+        let now = Utc::now();
+        let minute = now.timestamp() / 60;
+        let scheduled = self.last_scheduled_minute != Some(minute)
+            && policy.schedule.as_ref().is_some_and(|s| s.matches(&now));
+        self.last_scheduled_minute = Some(minute);
+
+        let reason = if novelty >= policy.max_novelty {
+            "novelty limit reached"
+        } else if novelty < policy.min_novelty {
+            return;
+        } else if policy.latency_factor.is_some_and(|f| self.latency.degraded(f)) {
+            "queries slowed down"
+        } else if scheduled {
+            "scheduled"
+        } else {
+            return;
+        };
+        info!("Reindexing with {} records of novelty: {}", novelty, reason);
+        self.rebuild_indices();
+    }
+
+    /// Registers a listener to run on every transaction processed
+    /// after this call, after any listeners already registered.
+    pub fn add_listener<L: TxListener + 'static>(&mut self, listener: L) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    /// Registers a transaction function that transactions can call
+    /// as `name`, replacing any function with that name, built in or
+    /// not.
+    pub fn add_function<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&Db, &[Value]) -> Result<Vec<TxItem>> + Send + Sync + 'static,
+    {
+        self.functions.register(name, function);
+    }
+
     /// Builds a new set of durable indices by combining the existing
     /// durable indices and the in-memory indices.
     fn rebuild_indices(&mut self) -> () {
         info!("Rebuilding indices...");
         let checkpoint = self.current_db.clone();
-        let send = self.send.clone();
+        let verify = self.reindex_policy.verify;
+        let control = self.control.clone();
         self.catchup_txs = Some(Vec::new());
+        self.rebuild_checkpoint_tx = self.latest_tx;
+        self.rebuild_checkpoint_seq = self.latest_seq;
+
+        let records_total = checkpoint.eav.mem_index_size()
+            + checkpoint.ave.mem_index_size()
+            + checkpoint.aev.mem_index_size()
+            + checkpoint.vae.mem_index_size()
+            + checkpoint.tea.mem_index_size();
+        let progress = Arc::new(RebuildProgress::new(records_total));
+        self.reindex_progress = Some(progress.clone());
 
         thread::spawn(move || {
+            let old = checkpoint.clone();
             let Db {
                 eav,
                 ave,
                 aev,
                 vae,
+                tea,
                 ..
             } = checkpoint;
 
-            let new_ave_handle = thread::spawn(move || ave.rebuild());
-            let new_aev_handle = thread::spawn(move || aev.rebuild());
-            let new_vae_handle = thread::spawn(move || vae.rebuild());
-            let new_eav = eav.rebuild();
+            // Superseded values of db:noHistory attributes are
+            // dropped from the leaves we rewrite.
+            let no_history = checkpoint.schema.no_history.clone();
+            let compact: Option<Compactor<Record>> = if no_history.is_empty() {
+                None
+            } else {
+                Some(Arc::new(move |records| drop_history(records, &no_history)))
+            };
+
+            let (ave_progress, aev_progress, vae_progress) = (progress.clone(), progress.clone(), progress.clone());
+            let (ave_compact, aev_compact, vae_compact) = (compact.clone(), compact.clone(), compact.clone());
+            let new_ave_handle = thread::spawn(move || ave.rebuild_with_progress(&ave_progress, ave_compact.as_ref()));
+            let new_aev_handle = thread::spawn(move || aev.rebuild_with_progress(&aev_progress, aev_compact.as_ref()));
+            let new_vae_handle = thread::spawn(move || vae.rebuild_with_progress(&vae_progress, vae_compact.as_ref()));
+            // The log index keeps every record, like the log: a
+            // fact's records aren't together in it to be compacted.
+            let tea_progress = progress.clone();
+            let new_tea_handle = thread::spawn(move || tea.rebuild_with_progress(&tea_progress, None));
+            let new_eav = eav.rebuild_with_progress(&progress, compact.as_ref());
             let new_ave = new_ave_handle.join().unwrap();
             let new_aev = new_aev_handle.join().unwrap();
             let new_vae = new_vae_handle.join().unwrap();
+            let new_tea = new_tea_handle.join().unwrap();
+            let stats = Stats::from_sorted(new_ave.iter()).with_log(new_tea.iter());
 
-            send.send(Event::RebuiltIndex(Db {
+            let new_db = Db {
                 eav: new_eav,
                 ave: new_ave,
                 aev: new_aev,
                 vae: new_vae,
+                tea: new_tea,
                 schema: checkpoint.schema.clone(),
                 store: checkpoint.store.clone(),
-            }))
+                access: None,
+                keyring: checkpoint.keyring.clone(),
+                basis_tx: checkpoint.basis_tx,
+                latest_tx: checkpoint.latest_tx,
+                stats: Arc::new(stats),
+                lookups: checkpoint.lookups.clone(),
+                as_of: None,
+                since: None,
+                history: false,
+            };
+            let rebuilt = if verify {
+                info!("Verifying rebuilt indices...");
+                check_same_datoms(&old, &new_db).map(|()| new_db)
+            } else {
+                Ok(new_db)
+            };
+            control.send(Control::RebuiltIndex(rebuilt))
         });
     }
 
-    fn switch_to_rebuilt_indexes(&mut self, new_db: Db) -> Result<()> {
+    fn switch_to_rebuilt_indexes(&mut self, new_db: Result<Db>) -> Result<()> {
+        let new_db = match new_db {
+            Ok(db) => db,
+            Err(e) => return self.abandon_rebuild(e),
+        };
+
         // First, replay the catchup transactions into the new DB.
         // (This function should never be called when catchup_txs is
         // None.)
@@ -200,7 +671,8 @@ impl Transactor {
         // they are added and then just swap that in, but that would
         // require some big changes to the index api exposing its
         // externals. Worthwhile?)
-        info!("Replaying {} transactions on rebuilt indices...", self.catchup_txs.as_ref().map_or(0, |v| v.len()));
+        let num_catchup_txs = self.catchup_txs.as_ref().map_or(0, |v| v.len());
+        info!("Replaying {} transactions on rebuilt indices...", num_catchup_txs);
         let mut final_db = new_db;
         let catchup_txs = std::mem::replace(&mut self.catchup_txs, None);
         for tx in catchup_txs.unwrap() {
@@ -209,9 +681,24 @@ impl Transactor {
             }
         }
 
+        // The catchup txs are only in the rebuilt db's in-memory
+        // indices, so peers have to keep replaying them from the log.
         info!("Switching over to rebuilt indices.");
-        save_metadata(&final_db, self.next_id, self.latest_tx)?;
+        self.last_indexed_tx = self.rebuild_checkpoint_tx;
+        self.last_indexed_seq = self.rebuild_checkpoint_seq;
+        save_metadata(&final_db, self.id_limit, self.last_indexed_tx, self.last_indexed_seq, self.epoch, &mut self.saved_metadata)?;
+        self.retired_roots.extend(durable_roots(&self.current_db));
         self.current_db = final_db;
+        self.latency.reset();
+
+        let detail = match self.reindex_progress.take().map(|p| p.status()) {
+            Some(status) => format!(
+                "rebuilt indices ({} records, {} segments written in {}ms), replaying {} transactions",
+                status.records_processed, status.segments_written, status.elapsed_ms, num_catchup_txs
+            ),
+            None => format!("rebuilt indices, replaying {} transactions", num_catchup_txs),
+        };
+        self.record_admin_op("db:admin:reindex", detail)?;
 
         // If the mem index filled up during the rebuild, we need to
         // immediately kick off another.
@@ -224,13 +711,174 @@ impl Transactor {
         Ok(())
     }
 
-    fn process_tx(&mut self, tx: Tx) -> Result<Vec<Entity>> {
+    /// Carries on with the current indices after a rebuild failed
+    /// verification. Its segments are left unreferenced in the store,
+    /// and the next reindex starts over.
+    fn abandon_rebuild(&mut self, error: Error) -> Result<()> {
+        error!("Abandoning rebuilt indices: {}", error.message());
+        self.catchup_txs = None;
+        self.reindex_progress = None;
+        self.throttled = false;
+        self.record_admin_op("db:admin:reindex", format!("abandoned rebuilt indices: {}", error.message()))
+    }
+
+    /// Returns what was committed by the transaction with the given
+    /// idempotency key, if there is one. `pending` holds the
+    /// transactions applied but not yet written in this group.
+    fn committed_with_key(&self, key: &str, return_datoms: bool, pending: &[TxRaw]) -> Result<Option<Committed>> {
+        // Databases created before idempotency keys existed can't
+        // have any committed keyed transactions.
+        if !self.current_db.schema.idents.contains_key("db:txIdempotencyKey") {
+            return Ok(None);
+        }
+
+        let keyed_tx = Clause::new(
+            Term::Unbound("tx".into()),
+            Term::Bound(Ident::Name("db:txIdempotencyKey".into())),
+            Term::Bound(Value::String(key.into())),
+        );
+        let tx_entity = match self.current_db.fetch(&keyed_tx)?.1.first().map(|t| t[0].clone()) {
+            Some(Value::Ref(e)) => e,
+            _ => return Ok(None),
+        };
+
+        let new_entities = Clause::new(
+            Term::Bound(tx_entity),
+            Term::Bound(Ident::Name("db:txNewEntity".into())),
+            Term::Unbound("e".into()),
+        );
+        let mut entities = self.current_db.fetch(&new_entities)?.1
+            .into_iter()
+            .filter_map(|t| match t[0] {
+                Value::Ref(e) => Some(e),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // New entities are allocated in increasing order.
+        entities.sort();
+
+        let timestamp = Clause::new(
+            Term::Bound(tx_entity),
+            Term::Bound(Ident::Name("db:txTimestamp".into())),
+            Term::Unbound("t".into()),
+        );
+        let timestamp = match self.current_db.fetch(&timestamp)?.1.first().map(|t| &t[0]) {
+            Some(&Value::Timestamp(t)) => Some(t),
+            _ => None,
+        };
+
+        let schema_change = Clause::new(
+            Term::Bound(tx_entity),
+            Term::Bound(Ident::Name("db:admin:operation".into())),
+            Term::Bound(Value::Ident("db:admin:schemaChange".into())),
+        );
+        let schema_changed = self.current_db.schema.idents.contains_key("db:admin:operation")
+            && !self.current_db.fetch(&schema_change)?.1.is_empty();
+
+        // The datoms are read back from the log entry.
+        let datoms = if return_datoms {
+            match pending.iter().find(|raw| raw.id == tx_entity.0) {
+                Some(raw) => Some(raw.records.clone()),
+                None => match self.store.get_tx(tx_entity.0)? {
+                    Some(raw) => Some(raw.records),
+                    None => return Err(format!("tx {} is missing from the log", tx_entity.0).into()),
+                },
+            }
+        } else {
+            None
+        };
+
+        Ok(Some(Committed { tx: tx_entity, new_entities: entities, timestamp, datoms, schema_changed }))
+    }
+
+    /// Applies and commits a single transaction, returning what it
+    /// committed.
+    #[cfg(test)]
+    fn process_tx(&mut self, tx: Tx) -> Result<Committed> {
+        self.process_txs(vec![tx]).pop().unwrap()
+    }
+
+    /// Applies a group of transactions one after another, then
+    /// commits them together with one log append and one metadata
+    /// save, so that queued transactions share the cost of the
+    /// writes. Each transaction is valid or not on its own, but if
+    /// the writes fail, every transaction in the group fails.
+    fn process_txs(&mut self, txs: Vec<Tx>) -> Vec<Result<Committed>> {
+        let db_before = self.current_db.clone();
+        let mut pending = vec![];
+        let results: Vec<Result<Committed>> = txs.into_iter().map(|tx| self.apply_tx(tx, vec![], &mut pending)).collect();
+        self.commit_group(db_before, &pending, results)
+    }
+
+    /// Commits the log entries in `pending`, which were applied to
+    /// `db_before` with the given results. The applied txs are only
+    /// rolled back if they never made it into the log.
+    fn commit_group(&mut self, db_before: Db, pending: &[TxRaw], results: Vec<Result<Committed>>) -> Vec<Result<Committed>> {
+        if pending.is_empty() {
+            return results;
+        }
+
+        match self.reserve_ids(&db_before).and_then(|()| self.commit(pending)) {
+            Ok(()) => {
+                for raw in pending {
+                    for listener in self.listeners.iter_mut() {
+                        listener.after_commit(Entity(raw.id));
+                    }
+                }
+                results
+            }
+            Err(e) => {
+                self.current_db = db_before;
+                for raw in pending {
+                    for listener in self.listeners.iter_mut() {
+                        listener.on_abort(Entity(raw.id));
+                    }
+                }
+                results.into_iter().map(|result| result.and(Err(e.clone()))).collect()
+            }
+        }
+    }
+
+    /// Applies a transaction to the current db, adding its log entry
+    /// to `pending` for `commit` to write. `annotations` are asserted
+    /// about the transaction's entity, as (attribute, value) pairs.
+    /// If it fails, the listeners which saw it are told it's aborted.
+    fn apply_tx(&mut self, tx: Tx, annotations: Vec<(&str, Value)>, pending: &mut Vec<TxRaw>) -> Result<Committed> {
         debug!("processing tx {:?}", tx);
+        if let Some(ref key) = tx.idempotency_key {
+            if let Some(committed) = self.committed_with_key(key, tx.return_datoms, pending)? {
+                info!("tx with idempotency key {:?} already committed", key);
+                return Ok(committed);
+            }
+        }
+
+        let tx_entity = Entity(self.get_id());
+        let mut listeners_ran = 0;
+        let result = self.apply_new_tx(tx, tx_entity, annotations, pending, &mut listeners_ran);
+        if result.is_err() {
+            for listener in self.listeners[..listeners_ran].iter_mut() {
+                listener.on_abort(tx_entity);
+            }
+        }
+        result
+    }
+
+    /// Applies a transaction which hasn't been committed before as
+    /// `tx_entity`, counting the listeners which have seen it in
+    /// `listeners_ran`.
+    fn apply_new_tx(
+        &mut self,
+        tx: Tx,
+        tx_entity: Entity,
+        annotations: Vec<(&str, Value)>,
+        pending: &mut Vec<TxRaw>,
+        listeners_ran: &mut usize,
+    ) -> Result<Committed> {
         let mut new_entities = vec![];
-        let tx_id = self.get_id();
-        let tx_entity = Entity(tx_id);
         let mut raw_tx = TxRaw {
-            id: tx_id,
+            seq: self.latest_seq + 1 + pending.len() as i64,
+            id: tx_entity.0,
+            epoch: self.epoch,
             records: vec![],
         };
 
@@ -247,51 +895,123 @@ impl Transactor {
             }
         }
 
-        let tx_timestamp = Value::Timestamp(Utc::now());
+        let now = Utc::now();
+        let tx_timestamp = Value::Timestamp(now);
         let mut db_after = add!(&self.current_db, tx_entity, "db:txTimestamp".to_string(), tx_timestamp, tx_entity);
-        for item in tx.items {
-            match item {
-                TxItem::Addition(f) => {
-                    db_after = add!(&db_after, f.entity, f.attribute, f.value, tx_entity);
-                }
-                TxItem::NewEntity(ht) => {
-                    let entity = Entity(self.get_id());
-                    for (k, v) in ht {
-                        db_after = add!(&db_after, entity, k, v, tx_entity);
-                    }
-                    new_entities.push(entity);
-                }
-                TxItem::Retraction(f) => {
-                    let (nextdb, record) = db_after.retract(Fact::new(f.entity, f.attribute, f.value), tx_entity)?;
-                    db_after = nextdb;
-                    raw_tx.records.push(record);
-                }
+        for (attribute, value) in annotations {
+            db_after = add!(&db_after, tx_entity, attribute, value, tx_entity);
+        }
+        // Items derived by a listener are applied like the client's,
+        // and are seen by the listeners after it.
+        let mut items = tx.items;
+        let functions = self.functions.clone();
+        loop {
+            db_after = apply_items(
+                db_after,
+                ::std::mem::take(&mut items),
+                tx_entity,
+                &functions,
+                &mut || self.get_id(),
+                &mut raw_tx.records,
+                &mut new_entities,
+            )?;
+
+            if *listeners_ran == self.listeners.len() {
+                break;
             }
+            *listeners_ran += 1;
+            items = self.listeners[*listeners_ran - 1].before_commit(&PendingTx {
+                tx_entity,
+                records: &raw_tx.records,
+                db_before: &self.current_db,
+                db_after: &db_after,
+            })?;
         }
 
-        // FIXME: Race condition. If adding the tx completes but
-        // saving the metadata does not, the tx log will be polluted.
-        self.store.add_tx(&raw_tx)?;
-        self.latest_tx = raw_tx.id;
-        if let Some(txs) = self.catchup_txs.as_mut() {
-            txs.push(raw_tx.clone());
+        if let Some(key) = tx.idempotency_key {
+            db_after = add!(&db_after, tx_entity, "db:txIdempotencyKey", key, tx_entity);
+            for entity in new_entities.iter() {
+                db_after = add!(&db_after, tx_entity, "db:txNewEntity", *entity, tx_entity);
+            }
+        }
+
+        let schema_changed = raw_tx.records.iter().any(|rec| db_after.schema.is_schema_attribute(rec.attribute));
+
+        // Schema changes are annotated on the transaction entity in
+        // the db:admin namespace, so the history of structural
+        // changes can be queried like any other data.
+        if db_after.schema.idents.contains_key("db:admin:operation") {
+            let changed_attributes = raw_tx.records
+                .iter()
+                .filter(|rec| db_after.schema.is_schema_attribute(rec.attribute))
+                .map(|rec| rec.entity)
+                .unique()
+                .collect::<Vec<_>>();
+
+            if !changed_attributes.is_empty() {
+                let op = Value::Ident("db:admin:schemaChange".into());
+                db_after = add!(&db_after, tx_entity, "db:admin:operation", op, tx_entity);
+            }
+
+            for attr in changed_attributes {
+                db_after = add!(&db_after, tx_entity, "db:admin:attribute", attr, tx_entity);
+            }
         }
 
-        save_metadata(&db_after, self.next_id, self.last_indexed_tx)?;
+        let datoms = if tx.return_datoms {
+            Some(raw_tx.records.clone())
+        } else {
+            None
+        };
+
+        pending.push(raw_tx);
         self.current_db = db_after;
 
-        if self.current_db.mem_index_size() > 100_000 {
-            match self.catchup_txs {
-                Some(_) => {
-                    if !self.throttled && self.current_db.mem_index_size() > 1_000_000 {
-                        warn!(
-                            "Mem limit high water mark surpassed during reindexing -- throttling transactions."
-                        );
-                        self.throttled = true;
-                    }
+        Ok(Committed { tx: tx_entity, new_entities, timestamp: Some(now), datoms, schema_changed })
+    }
+
+    /// Makes sure the stored metadata reserves every id handed out,
+    /// reserving another block if it doesn't, before any of them are
+    /// written to the log. `db` is the committed db, whose metadata
+    /// is saved with the reservation.
+    fn reserve_ids(&mut self, db: &Db) -> Result<()> {
+        if self.next_id <= self.id_limit {
+            return Ok(());
+        }
+        let id_limit = self.next_id + ID_BLOCK;
+        save_metadata(db, id_limit, self.last_indexed_tx, self.last_indexed_seq, self.epoch, &mut self.saved_metadata)?;
+        self.id_limit = id_limit;
+        Ok(())
+    }
+
+    /// Writes the log entries of the transactions applied since the
+    /// last commit, then the metadata. Only a failed log append fails
+    /// the commit: once it goes through the transactions are durable,
+    /// and a metadata save that keeps failing halts the transactor
+    /// instead.
+    fn commit(&mut self, pending: &[TxRaw]) -> Result<()> {
+        self.store.add_txs(pending)?;
+        self.latest_tx = pending[pending.len() - 1].id;
+        self.latest_seq = pending[pending.len() - 1].seq;
+        if let Some(txs) = self.catchup_txs.as_mut() {
+            txs.extend(pending.iter().cloned());
+        }
+
+        if let Err(e) = self.save_metadata_with_retries() {
+            error!("couldn't save metadata after appending through tx {}, halting: {:?}", self.latest_tx, e);
+            self.halted = Some(e);
+        }
+
+        match self.catchup_txs {
+            Some(_) => {
+                if !self.throttled && self.current_db.mem_index_size() > THROTTLE_NOVELTY {
+                    warn!(
+                        "Mem limit high water mark surpassed during reindexing -- throttling transactions."
+                    );
+                    self.throttled = true;
                 }
-                None => self.rebuild_indices(),
             }
+            None => self.maybe_reindex(),
         }
 
         if self.throttled {
@@ -299,7 +1019,139 @@ impl Transactor {
             thread::sleep(Duration::from_millis(1000));
         }
 
-        Ok(new_entities)
+        Ok(())
+    }
+
+    /// Saves the metadata, retrying failures other than a conflict
+    /// with another writer, which a retry can't get past.
+    fn save_metadata_with_retries(&mut self) -> Result<()> {
+        let mut backoff = METADATA_SAVE_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match save_metadata(&self.current_db, self.id_limit, self.last_indexed_tx, self.last_indexed_seq, self.epoch, &mut self.saved_metadata) {
+                Err(e @ Error::Conflict { .. }) => return Err(e),
+                Err(e) if attempt < METADATA_SAVE_ATTEMPTS => {
+                    warn!("metadata save attempt {} failed, retrying: {:?}", attempt, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Transacts a record of an administrative operation performed
+    /// by the transactor itself (as opposed to one submitted by a
+    /// client), so that it shows up in the db:admin namespace.
+    fn record_admin_op(&mut self, op: &str, detail: String) -> Result<()> {
+        // Databases created before the db:admin attributes existed
+        // can't record admin operations.
+        if !self.current_db.schema.idents.contains_key("db:admin:operation") {
+            return Ok(());
+        }
+
+        let annotations = vec![
+            ("db:admin:operation", Value::Ident(op.into())),
+            ("db:admin:detail", Value::String(detail)),
+        ];
+        let db_before = self.current_db.clone();
+        let mut pending = vec![];
+        let tx = Tx { items: vec![], idempotency_key: None, return_datoms: false };
+        let result = self.apply_tx(tx, annotations, &mut pending);
+        self.commit_group(db_before, &pending, vec![result]).pop().unwrap()?;
+        Ok(())
+    }
+
+    /// Removes all of `entity`'s records from the indices, returning
+    /// how many were removed. The tx log is append-only, so it keeps
+    /// them; the rebuilt indices are saved as covering the whole log,
+    /// so they aren't replayed back in.
+    fn excise(&mut self, entity: Entity) -> Result<usize> {
+        if self.catchup_txs.is_some() {
+            return Err("can't excise while the indices are being rebuilt".into());
+        }
+        if self.current_db.schema.idents.values().any(|e| *e == entity) {
+            return Err(format!("can't excise entity {}, which has an ident", entity.0).into());
+        }
+
+        let removed = self.current_db.eav.iter().filter(|rec| rec.entity == entity).count();
+        let excised = self.current_db.excise(entity)?;
+        save_metadata(&excised, self.id_limit, self.latest_tx, self.latest_seq, self.epoch, &mut self.saved_metadata)?;
+        self.last_indexed_tx = self.latest_tx;
+        self.last_indexed_seq = self.latest_seq;
+        self.retired_roots.extend(durable_roots(&self.current_db));
+        self.current_db = excised;
+
+        self.record_admin_op(
+            "db:admin:excise",
+            format!("excised {} records of entity {}", removed, entity.0),
+        )?;
+        Ok(removed)
+    }
+
+    /// Deletes the nodes of the retired indices which the current
+    /// indices don't share, returning how many were deleted. Peers
+    /// still reading a Db from before the indices were replaced can't
+    /// load the deleted nodes, so this only runs on request.
+    fn collect_garbage(&mut self) -> Result<usize> {
+        if self.catchup_txs.is_some() {
+            return Err("can't collect garbage while the indices are being rebuilt".into());
+        }
+
+        let mut seen = HashSet::new();
+        for root in durable_roots(&self.current_db) {
+            let keys = durable_tree::node_keys::<Record>(self.store.clone(), &root, &seen)?;
+            seen.extend(keys);
+        }
+
+        // If this fails partway, the rest of the garbage is left in
+        // place rather than retried from roots which may be gone.
+        let retired = std::mem::take(&mut self.retired_roots);
+        let mut garbage = vec![];
+        for root in retired {
+            let keys = durable_tree::node_keys::<Record>(self.store.clone(), &root, &seen)?;
+            seen.extend(keys.iter().cloned());
+            garbage.extend(keys);
+        }
+
+        for key in garbage.iter() {
+            self.store.delete(key)?;
+        }
+        forget_nodes(&self.current_db, &garbage);
+
+        self.record_admin_op(
+            "db:admin:gc",
+            format!("deleted {} index nodes", garbage.len()),
+        )?;
+        Ok(garbage.len())
+    }
+
+    /// Appends a transaction in this transactor's epoch, so that the
+    /// store fences off any previous writer straight away instead of
+    /// at this transactor's first client transaction.
+    fn claim_epoch(&mut self) -> Result<()> {
+        info!("Claiming writer epoch {}", self.epoch);
+        let detail = format!("writer epoch {} started", self.epoch);
+        self.record_admin_op("db:admin:writerEpoch", detail)
+    }
+
+    /// Reserves a block of `n` entity ids which the transactor won't
+    /// hand out itself, so that a bulk import can assign them to rows
+    /// up front and refer to them across transactions. The block is
+    /// saved in the metadata before it's returned, so it survives a
+    /// restart of the transactor.
+    pub fn allocate_ids(&mut self, n: u64) -> Result<Range<i64>> {
+        let start = self.next_id;
+        let end = i64::try_from(n).ok()
+            .and_then(|n| start.checked_add(n))
+            .ok_or_else(|| format!("can't allocate {} ids", n))?;
+        let id_limit = self.id_limit.max(end);
+        save_metadata(&self.current_db, id_limit, self.last_indexed_tx, self.last_indexed_seq, self.epoch, &mut self.saved_metadata)?;
+        self.next_id = end;
+        self.id_limit = id_limit;
+        self.record_admin_op("db:admin:allocateIds", format!("allocated ids {}..{}", start, end))?;
+        Ok(start..end)
     }
 
     fn get_id(&mut self) -> i64 {
@@ -312,70 +1164,464 @@ impl Transactor {
     /// transactions and other events.
     pub fn run(&mut self) -> Result<()> {
         loop {
-            match self.recv.recv().unwrap() {
+            if let Some(e) = self.halted.take() {
+                return Err(e);
+            }
+
+            // Control events preempt queued transactions.
+            while let Ok(control) = self.control_recv.try_recv() {
+                match control {
+                    Control::RebuiltIndex(new_db) => {
+                        self.switch_to_rebuilt_indexes(new_db)?;
+                    }
+                    Control::Excise(entity, cb_chan) => {
+                        let _ = cb_chan.send(self.excise(entity));
+                    }
+                    Control::CollectGarbage(cb_chan) => {
+                        let _ = cb_chan.send(self.collect_garbage());
+                    }
+                    Control::ReindexStatus(cb_chan) => {
+                        let _ = cb_chan.send(self.reindex_progress.as_ref().map(|p| p.status()));
+                    }
+                    Control::LatestTx(cb_chan) => {
+                        let _ = cb_chan.send(self.latest_tx);
+                    }
+                    Control::CurrentDb(cb_chan) => {
+                        let _ = cb_chan.send(self.current_db.clone());
+                    }
+                    Control::AllocateIds(n, cb_chan) => {
+                        let _ = cb_chan.send(self.allocate_ids(n));
+                    }
+                    Control::QueryLatency(latency) => {
+                        self.latency.observe(latency);
+                    }
+                    Control::Reindex(cb_chan) => {
+                        let idle = self.catchup_txs.is_none();
+                        if idle {
+                            info!("Reindexing on request");
+                            self.rebuild_indices();
+                        }
+                        let _ = cb_chan.send(idle);
+                    }
+                    Control::Stop => return Ok(()),
+                }
+            }
+
+            let event = match self.recv.recv_timeout(REINDEX_CHECK_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.maybe_reindex();
+                    continue;
+                }
+                // The transactor holds a sender itself.
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            };
+            match event {
                 Event::Tx(tx, cb_chan) => {
-                    // TODO: check for more txs & batch them.
-                    // Ignoring the result because it's not important
-                    // for correctness whether or not the client
-                    // receives the response.
-                    let _ = match self.process_tx(tx) {
-                        Ok(new_entities) => cb_chan.send(TxReport::Success { new_entities }),
-                        Err(e) => cb_chan.send(TxReport::Failure(format!("{:?}", e)))
+                    // Transactions queued behind this one are
+                    // committed with it, up to a limit. A wake means
+                    // a control event is waiting, so it ends the group.
+                    let mut txs = vec![tx];
+                    let mut cb_chans = vec![cb_chan];
+                    while txs.len() < MAX_GROUP_SIZE {
+                        match self.recv.try_recv() {
+                            Ok(Event::Tx(tx, cb_chan)) => {
+                                txs.push(tx);
+                                cb_chans.push(cb_chan);
+                            }
+                            Ok(Event::Wake) | Err(_) => break,
+                        }
+                    }
+
+                    let results = self.process_txs(txs);
+                    let reindex = if self.throttled {
+                        self.reindex_progress.as_ref().map(|p| p.status())
+                    } else {
+                        None
                     };
+                    for (result, cb_chan) in results.into_iter().zip(cb_chans) {
+                        // Ignoring the result because it's not
+                        // important for correctness whether or not
+                        // the client receives the response.
+                        let _ = match result {
+                            Ok(Committed { tx, new_entities, timestamp, datoms, schema_changed }) => {
+                                cb_chan.send(TxReport::Success {
+                                    tx,
+                                    new_entities,
+                                    reindex: reindex.clone(),
+                                    timestamp,
+                                    datoms,
+                                    schema_changed,
+                                })
+                            }
+                            Err(e) => cb_chan.send(TxReport::Failure(format!("{:?}", e)))
+                        };
+                    }
                 }
-                Event::RebuiltIndex(new_db) => {
-                    self.switch_to_rebuilt_indexes(new_db)?;
-                },
-                Event::Stop => break
+                Event::Wake => {}
             }
         }
+    }
+}
 
-        Ok(())
+/// Fails for the entities `create_db` made, which transactions can't
+/// add or retract facts about.
+fn check_not_system(entity: Entity) -> Result<()> {
+    if is_system_entity(entity) {
+        return Err(format!("entity {} is built in and can't be changed", entity.0).into());
+    }
+    Ok(())
+}
+
+/// Applies the items of a transaction to `db`, adding the records
+/// they create to `records`. New entities get ids from `next_id`.
+/// Adding a value of a cardinality-one attribute first retracts the
+/// entity's old value, and adding its current value does nothing.
+pub(crate) fn apply_items(
+    mut db: Db,
+    items: Vec<TxItem>,
+    tx_entity: Entity,
+    functions: &TxFunctions,
+    next_id: &mut dyn FnMut() -> i64,
+    records: &mut Vec<Record>,
+    new_entities: &mut Vec<Entity>,
+) -> Result<Db> {
+    let add = |mut db: Db, fact: Fact, records: &mut Vec<Record>| -> Result<Db> {
+        check_not_system(fact.entity)?;
+        let superseded = match db.superseded_by(&fact)? {
+            Some(superseded) => superseded,
+            None => return Ok(db),
+        };
+        for old in superseded {
+            let (next_db, record) = db.retract(old, tx_entity)?;
+            db = next_db;
+            records.push(record);
+        }
+        let (next_db, record) = db.add(fact, tx_entity)?;
+        records.push(record);
+        Ok(next_db)
+    };
+
+    // The items a function returns are applied in its place, before
+    // the items after it, each with the depth of calls it came from.
+    let mut pending: Vec<(TxItem, usize)> = items.into_iter().rev().map(|item| (item, 0)).collect();
+    while let Some((item, depth)) = pending.pop() {
+        match item {
+            TxItem::Addition(f) => {
+                db = add(db, f, records)?;
+            }
+            TxItem::NewEntity(map) => {
+                let mut facts = vec![];
+                expand_new_entity(map, &db, &mut facts, new_entities, next_id)?;
+                for f in facts {
+                    db = add(db, f, records)?;
+                }
+            }
+            TxItem::Retraction(f) => {
+                check_not_system(f.entity)?;
+                let (next_db, record) = db.retract(f, tx_entity)?;
+                db = next_db;
+                records.push(record);
+            }
+            TxItem::LookupAddition(f) => {
+                db = upsert_lookups(db, &f, tx_entity, next_id, records, new_entities)?;
+                let f = resolve_lookups(&db, f)?;
+                db = add(db, f, records)?;
+            }
+            TxItem::LookupRetraction(f) => {
+                let f = resolve_lookups(&db, f)?;
+                check_not_system(f.entity)?;
+                let (next_db, record) = db.retract(f, tx_entity)?;
+                db = next_db;
+                records.push(record);
+            }
+            TxItem::Function(name, args) => {
+                if depth == MAX_FUNCTION_DEPTH {
+                    return Err(format!("transaction functions nested more than {} deep calling {}", MAX_FUNCTION_DEPTH, name).into());
+                }
+                let items = functions.call(&name, &db, &args)?;
+                pending.extend(items.into_iter().rev().map(|item| (item, depth + 1)));
+            }
+        }
+    }
+    Ok(db)
+}
+
+/// Creates the entities named by lookup refs in an addition which are
+/// on unique attributes but don't match an entity yet, each with the
+/// looked-up value, so that `resolve_lookups` finds them.
+fn upsert_lookups(
+    mut db: Db,
+    fact: &LookupFact,
+    tx_entity: Entity,
+    next_id: &mut dyn FnMut() -> i64,
+    records: &mut Vec<Record>,
+    new_entities: &mut Vec<Entity>,
+) -> Result<Db> {
+    let mut lookups = vec![];
+    if let EntitySpec::Lookup(ref lookup) = fact.entity {
+        lookups.push(lookup);
+    }
+    if let TxValue::Lookup(ref lookup) = fact.value {
+        if is_ref_attribute(&db.schema, &fact.attribute) {
+            lookups.push(lookup);
+        }
+    }
+
+    for lookup in lookups {
+        let unique = db.schema.resolve(&lookup.attribute).is_some_and(|attr| db.schema.is_unique(attr));
+        if !unique || db.lookup(lookup).is_ok() {
+            continue;
+        }
+        let entity = Entity(next_id());
+        new_entities.push(entity);
+        let (next_db, record) = db.add(Fact::new(entity, lookup.attribute.clone(), lookup.value.clone()), tx_entity)?;
+        db = next_db;
+        records.push(record);
+    }
+    Ok(db)
+}
+
+/// Resolves the idents and lookup refs in a fact against `db`, which
+/// includes the transaction's earlier items.
+fn resolve_lookups(db: &Db, fact: LookupFact) -> Result<Fact> {
+    let entity = db.resolve(&fact.entity)?;
+    let value = match fact.value {
+        TxValue::Value(v) => v,
+        TxValue::Lookup(ref lookup) if is_ref_attribute(&db.schema, &fact.attribute) => Value::Ref(db.lookup(lookup)?),
+        TxValue::Lookup(lookup) => {
+            return Err(format!("lookup ref {} given for non-ref attribute {}", lookup, fact.attribute).into());
+        }
+        TxValue::Entity(_) | TxValue::Many(_) => {
+            return Err(format!("only a value or lookup ref can be added to {} outside an entity map", fact.attribute).into());
+        }
+    };
+    Ok(Fact::new(entity, fact.attribute, value))
+}
+
+/// Allocates an entity for a new entity map and collects its facts,
+/// recursively creating any entities nested under its ref
+/// attributes. Every created entity is added to `new_entities`,
+/// parents before their children.
+fn expand_new_entity(
+    map: HashMap<String, TxValue>,
+    db: &Db,
+    facts: &mut Vec<Fact>,
+    new_entities: &mut Vec<Entity>,
+    next_id: &mut dyn FnMut() -> i64,
+) -> Result<Entity> {
+    let entity = Entity(next_id());
+    new_entities.push(entity);
+    let schema = &db.schema;
+
+    for (attribute, value) in map {
+        // A vector is asserted as one fact per element, which only
+        // makes sense for cardinality-many attributes.
+        let values = match value {
+            TxValue::Many(ref values) if values.len() > 1 && is_cardinality_one(schema, &attribute) => {
+                return Err(format!("several values given for cardinality-one attribute {}", attribute).into());
+            }
+            TxValue::Many(values) => values,
+            value => vec![value],
+        };
+
+        for value in values {
+            let value = match value {
+                TxValue::Value(v) => v,
+                TxValue::Entity(nested) => {
+                    if !is_ref_attribute(schema, &attribute) {
+                        return Err(format!("nested entity given for non-ref attribute {}", attribute).into());
+                    }
+                    Value::Ref(expand_new_entity(nested, db, facts, new_entities, next_id)?)
+                }
+                TxValue::Lookup(lookup) => {
+                    if !is_ref_attribute(schema, &attribute) {
+                        return Err(format!("lookup ref {} given for non-ref attribute {}", lookup, attribute).into());
+                    }
+                    Value::Ref(db.lookup(&lookup)?)
+                }
+                TxValue::Many(_) => {
+                    return Err(format!("nested vector given for attribute {}", attribute).into());
+                }
+            };
+            facts.push(Fact::new(entity, attribute.clone(), value));
+        }
     }
+
+    Ok(entity)
+}
+
+fn is_cardinality_one(schema: &Schema, attribute: &str) -> bool {
+    schema.resolve(attribute).is_some_and(|a| schema.cardinality(a) == Cardinality::One)
+}
+
+fn is_ref_attribute(schema: &Schema, attribute: &str) -> bool {
+    schema.resolve(attribute)
+        .and_then(|a| schema.value_types.get(&a))
+        == Some(&ValueType::Ref)
+}
+
+/// Checks that two dbs contain the same datoms, in every index,
+/// reporting the first datom missing from or duplicated in `actual`.
+/// The indices are streamed side by side, so this works on dbs too
+/// big for memory.
+fn check_same_datoms(expected: &Db, actual: &Db) -> Result<()> {
+    fn check<I: Iterator<Item = Record>>(index: &str, expected: I, actual: I) -> Result<()> {
+        let mut expected = expected.peekable();
+        let mut actual = actual.peekable();
+        loop {
+            match (expected.next(), actual.next()) {
+                (None, None) => return Ok(()),
+                (Some(e), Some(a)) if e == a => continue,
+                (Some(e), Some(a)) => {
+                    return if expected.peek() == Some(&a) {
+                        Err(format!("{} index is missing datom {:?}", index, e).into())
+                    } else {
+                        Err(format!("{} index has unexpected datom {:?} (expected {:?})", index, a, e).into())
+                    };
+                }
+                (Some(e), None) => return Err(format!("{} index is missing datom {:?}", index, e).into()),
+                (None, Some(a)) => return Err(format!("{} index has unexpected datom {:?}", index, a).into()),
+            }
+        }
+    }
+
+    let no_history = &expected.schema.no_history;
+    check("eav", logical_datoms(expected.eav.iter(), no_history), logical_datoms(actual.eav.iter(), no_history))?;
+    check("ave", logical_datoms(expected.ave.iter(), no_history), logical_datoms(actual.ave.iter(), no_history))?;
+    check("aev", logical_datoms(expected.aev.iter(), no_history), logical_datoms(actual.aev.iter(), no_history))?;
+    check("vae", logical_datoms(expected.vae.iter(), no_history), logical_datoms(actual.vae.iter(), no_history))?;
+    check("tea", expected.tea.iter(), actual.tea.iter())
+}
+
+/// The datoms of an index as far as a rebuild has to preserve them:
+/// every record, except that the records of each value of a
+/// `db:noHistory` attribute are replaced by one assertion (with tx 0)
+/// for each time it's currently asserted, since the rebuild is free
+/// to drop their history (see `drop_history`).
+fn logical_datoms<'a, I: Iterator<Item = Record> + 'a>(
+    records: I,
+    no_history: &'a im::HashSet<Entity>,
+) -> Box<dyn Iterator<Item = Record> + 'a> {
+    let mut records = records.peekable();
+    Box::new(Iterator::flatten(::std::iter::from_fn(move || {
+        let first = records.next()?;
+        if !no_history.contains(&first.attribute) {
+            return Some(vec![first]);
+        }
+        let mut asserted: i64 = if first.retracted { -1 } else { 1 };
+        let same_value = |r: &Record| r.entity == first.entity && r.attribute == first.attribute && r.value == first.value;
+        while let Some(r) = records.next_if(same_value) {
+            asserted += if r.retracted { -1 } else { 1 };
+        }
+        let current = Record::addition(first.entity, first.attribute, first.value.clone(), Entity(0));
+        Some(vec![current; asserted.max(0) as usize])
+    })))
 }
 
 /// Saves the db metadata (index root nodes, entity ID state) to
 /// storage, when implemented by the storage backend (i.e. when
-/// not using in-memory storage).
-fn save_metadata(db: &Db, next_id: i64, last_indexed_tx: i64) -> Result<()> {
+/// not using in-memory storage). `saved` is the metadata as the
+/// caller last read or wrote it (`None` if there's none yet); the
+/// save fails with `Error::Conflict` if another writer has changed
+/// it since, and otherwise replaces it with what's written.
+fn save_metadata(
+    db: &Db,
+    next_id: i64,
+    last_indexed_tx: i64,
+    last_indexed_seq: i64,
+    epoch: u64,
+    saved: &mut Option<Vec<u8>>,
+) -> Result<()> {
+    let version = match *saved {
+        Some(ref serialized) => DbMetadata::from_stored(serialized)?.version + 1,
+        None => 1,
+    };
+
     let metadata = DbMetadata {
         next_id,
         last_indexed_tx,
+        last_indexed_seq: Some(last_indexed_seq),
+        epoch,
+        version,
         schema: db.schema.clone(),
         eav: db.eav.durable_root(),
         aev: db.aev.durable_root(),
         ave: db.ave.durable_root(),
         vae: db.vae.durable_root(),
+        tea: db.tea.durable_root(),
+        stats: (*db.stats).clone(),
+        // The transactor's db includes every transaction it has
+        // committed.
+        latest_tx: db.basis_tx,
     };
 
-    db.store.set_metadata(&metadata)?;
+    *saved = Some(db.store.compare_and_set_metadata(saved.as_deref(), &metadata)?);
     Ok(())
 }
 
+/// The roots of the durable indices `db` reads from.
+fn durable_roots(db: &Db) -> Vec<String> {
+    vec![
+        db.eav.durable_root(),
+        db.ave.durable_root(),
+        db.aev.durable_root(),
+        db.vae.durable_root(),
+        db.tea.durable_root(),
+    ]
+}
+
+/// Drops deleted `keys` from the node caches of `db`'s indices, which
+/// new trees are built through.
+fn forget_nodes(db: &Db, keys: &[String]) {
+    db.eav.forget(keys);
+    db.ave.forget(keys);
+    db.aev.forget(keys);
+    db.vae.forget(keys);
+    db.tea.forget(keys);
+}
+
+/// The ids of the built-in idents' entities. They're kept apart from
+/// the ids handed out for data, so adding an ident doesn't take an id
+/// that data may already use.
+pub const SYSTEM_ENTITIES: Range<i64> = (1 << 61)..(1 << 61) + 1024;
+
+/// The first id handed out for data. Lower ids, other than the
+/// bootstrap transaction's, are never handed out, so ids picked by
+/// hand there (as in examples and test fixtures) can't collide with
+/// allocated ones.
+pub const FIRST_ID: i64 = 1024;
+
+/// Whether `entity` is one `create_db` made: a built-in ident, or the
+/// transaction that created them.
+pub fn is_system_entity(entity: Entity) -> bool {
+    entity.0 == 0 || SYSTEM_ENTITIES.contains(&entity.0)
+}
+
 fn create_db(store: Arc<dyn KVStore>) -> Result<(Db, i64)> {
-    use {EAVT, AVET, VAET, AEVT};
+    use {EAVT, AVET, VAET, AEVT, TEAV};
     use durable_tree;
 
     let eav_root = durable_tree::DurableTree::create(store.clone(), EAVT)?.root;
-    let ave_root = durable_tree::DurableTree::create(store.clone(), AVET)?.root;
+    let ave_root = durable_tree::DurableTree::create(store.clone(), AVET::default())?.root;
     let aev_root = durable_tree::DurableTree::create(store.clone(), AEVT)?.root;
     let vae_root = durable_tree::DurableTree::create(store.clone(), VAET)?.root;
-
-    let mut next_id = 0;
-    let mut get_next_id = || {
-        let result = next_id;
-        next_id += 1;
-        return result;
-    };
+    let tea_root = durable_tree::DurableTree::create(store.clone(), TEAV)?.root;
 
     let metadata = DbMetadata {
         next_id: 0,
         last_indexed_tx: 0,
+        last_indexed_seq: Some(0),
+        epoch: 0,
+        version: 0,
         schema: Schema::empty(),
         eav: eav_root,
         ave: ave_root,
         aev: aev_root,
         vae: vae_root,
+        tea: tea_root,
+        stats: Stats::default(),
+        latest_tx: 0,
     };
 
     let idents = &[
@@ -388,6 +1634,32 @@ fn create_db(store: Arc<dyn KVStore>) -> Result<(Db, i64)> {
         "db:type:timestamp",
         "db:type:ref",
         "db:type:boolean",
+        "db:type:geo",
+        "db:deletedAt",
+        "db:txIdempotencyKey",
+        "db:txNewEntity",
+        "db:admin:operation",
+        "db:admin:attribute",
+        "db:admin:detail",
+        "db:doc",
+        "db:allowedValue",
+        "db:normalize",
+        "db:normalize:trim",
+        "db:normalize:lowercase",
+        "db:normalize:phone",
+        "db:noHistory",
+        "db:alias",
+        "db:query:name",
+        "db:query:text",
+        "db:encrypted",
+        "db:cardinality",
+        "db:cardinality:one",
+        "db:cardinality:many",
+        "db:collation",
+        "db:collation:binary",
+        "db:collation:caseInsensitive",
+        "db:collation:nfc",
+        "db:unique",
     ];
 
     let value_types = &[
@@ -395,10 +1667,31 @@ fn create_db(store: Arc<dyn KVStore>) -> Result<(Db, i64)> {
         ("db:valueType", "db:type:ident"),
         ("db:txTimestamp", "db:type:timestamp"),
         ("db:indexed", "db:type:boolean"),
+        ("db:admin:operation", "db:type:ident"),
+        ("db:admin:attribute", "db:type:ref"),
+        ("db:admin:detail", "db:type:string"),
+        ("db:doc", "db:type:string"),
+        ("db:allowedValue", "db:type:ident"),
+        ("db:deletedAt", "db:type:timestamp"),
+        ("db:txIdempotencyKey", "db:type:string"),
+        ("db:txNewEntity", "db:type:ref"),
+        ("db:normalize", "db:type:ident"),
+        ("db:noHistory", "db:type:boolean"),
+        ("db:alias", "db:type:ident"),
+        ("db:query:name", "db:type:string"),
+        ("db:query:text", "db:type:string"),
+        ("db:encrypted", "db:type:boolean"),
+        ("db:cardinality", "db:type:ident"),
+        ("db:collation", "db:type:ident"),
+        ("db:unique", "db:type:boolean"),
     ];
 
-    let initial_tx_entity = Entity(get_next_id());
-    let ident_entities = idents.iter().map(|i| (i, Entity(get_next_id()))).collect::<Vec<_>>();
+    // Idempotency keys are looked up on every keyed transaction, and
+    // named queries on every run.
+    let indexed = &["db:txIdempotencyKey", "db:query:name"];
+
+    let initial_tx_entity = Entity(0);
+    let ident_entities = idents.iter().zip(SYSTEM_ENTITIES).map(|(i, e)| (i, Entity(e))).collect::<Vec<_>>();
 
     let mut db = Db::new(metadata, store);
 
@@ -429,9 +1722,846 @@ fn create_db(store: Arc<dyn KVStore>) -> Result<(Db, i64)> {
         facts.push((entity_for_ident(name), entity_for_ident(&"db:valueType"), Value::Ident((*value_type).into())));
     }
 
+    for name in indexed {
+        facts.push((entity_for_ident(name), entity_for_ident(&"db:indexed"), Value::Boolean(true)));
+    }
+
+    // An attribute has one cardinality and one collation, so
+    // declaring another replaces the old.
+    let cardinality = entity_for_ident(&"db:cardinality");
+    for name in &["db:cardinality", "db:collation"] {
+        facts.push((entity_for_ident(name), cardinality, Value::Ident(Cardinality::One.ident().into())));
+    }
+
     db = facts.into_iter().fold(db, move |db, (e, a, v)| {
         db.add_record(Record::addition(e, a, v, initial_tx_entity)).unwrap()
     });
 
-    Ok((db, get_next_id()))
+    Ok((db, FIRST_ID))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use backends::TxStream;
+    use conn::Conn;
+    use backends::sqlite::SqliteStore;
+    use rmp_serde;
+    use uuid::Uuid;
+    use {parse_query_with, parse_tx};
+    use queries::execution::query;
+
+    fn transact(transactor: &mut Transactor, entity: i64, name: &str) {
+        transactor.process_tx(Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(entity), "db:doc", name))],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+    }
+
+    /// The db a peer would see: the durable indices named in the
+    /// stored metadata plus a replay of the log after them.
+    fn peer_db(store: &Arc<dyn KVStore>) -> Db {
+        let metadata = store.get_metadata().unwrap();
+        let indexed_seq = metadata.indexed_seq();
+        let mut db = Db::new(metadata, store.clone());
+        for tx in store.get_txs(indexed_seq).unwrap() {
+            for record in tx.records {
+                db = db.add_record(record).unwrap();
+            }
+        }
+        db
+    }
+
+    #[test]
+    fn test_reindex_with_catchup_txs_preserves_datoms() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+
+        transact(&mut transactor, 1000, "before");
+        transactor.rebuild_indices();
+        // These land while the rebuild is in flight, so they have to
+        // be replayed onto the rebuilt indices.
+        transact(&mut transactor, 1001, "during");
+        transact(&mut transactor, 1002, "during");
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+        let (before_switch, seq_before_switch) = (transactor.current_db.clone(), transactor.latest_seq);
+
+        match transactor.control_recv.recv().unwrap() {
+            Control::RebuiltIndex(new_db) => transactor.switch_to_rebuilt_indexes(new_db).unwrap(),
+            _ => unreachable!(),
+        }
+        // Queries served after the switch must see exactly what they
+        // saw before it: nothing dropped by the rebuild, and nothing
+        // both rebuilt and replayed. The only new datoms are the ones
+        // the switch itself logs.
+        let mut expected = before_switch;
+        for tx in store.get_txs(seq_before_switch).unwrap() {
+            for record in tx.records {
+                expected = expected.add_record(record).unwrap();
+            }
+        }
+        check_same_datoms(&expected, &transactor.current_db).unwrap();
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+
+        transact(&mut transactor, 1003, "after");
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_reindex_drops_no_history_values() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        transactor.set_reindex_policy(ReindexPolicy { verify: true, ..ReindexPolicy::default() });
+
+        let attr = Entity(1000);
+        let counter = Entity(1001);
+        transactor.process_tx(Tx {
+            items: vec![
+                TxItem::Addition(Fact::new(attr, "db:ident", Value::Ident("counter".into()))),
+                TxItem::Addition(Fact::new(attr, "db:valueType", Value::Ident("db:type:long".into()))),
+                TxItem::Addition(Fact::new(attr, "db:noHistory", Value::Boolean(true))),
+            ],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        for (old, new) in [(None, 1), (Some(1), 2), (Some(2), 3)] {
+            let mut items = vec![TxItem::Addition(Fact::new(counter, "counter", Value::Long(new)))];
+            if let Some(old) = old {
+                items.push(TxItem::Retraction(Fact::new(counter, "counter", Value::Long(old))));
+            }
+            transactor.process_tx(Tx { items, idempotency_key: None, return_datoms: false }).unwrap();
+        }
+        assert!(transactor.current_db.attribute_info("counter").unwrap().no_history);
+
+        transactor.rebuild_indices();
+        match transactor.control_recv.recv().unwrap() {
+            Control::RebuiltIndex(new_db) => transactor.switch_to_rebuilt_indexes(new_db).unwrap(),
+            _ => unreachable!(),
+        }
+
+        let values: Vec<(Value, bool)> = transactor.current_db.eav.iter()
+            .filter(|r| r.attribute == attr)
+            .map(|r| (r.value, r.retracted))
+            .collect();
+        assert_eq!(values, vec![(Value::Long(3), false)]);
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_abandons_rebuild_failing_verification() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        transact(&mut transactor, 1000, "before");
+        let indexed = store.get_metadata().unwrap();
+
+        transactor.rebuild_indices();
+        let new_db = match transactor.control_recv.recv().unwrap() {
+            Control::RebuiltIndex(new_db) => new_db.unwrap(),
+            _ => unreachable!(),
+        };
+        // A rebuild which invented a datom.
+        let doc = new_db.schema.idents["db:doc"];
+        let corrupt = new_db.add_record(Record::addition(Entity(1000), doc, Value::String("bogus".into()), Entity(1))).unwrap();
+        let error = check_same_datoms(&transactor.current_db, &corrupt).unwrap_err();
+        assert!(error.message().contains("unexpected datom"), "{}", error.message());
+
+        transactor.switch_to_rebuilt_indexes(Err(error)).unwrap();
+        assert!(transactor.catchup_txs.is_none());
+        assert_eq!(store.get_metadata().unwrap().eav, indexed.eav);
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_reindex_computes_stats() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+
+        for (entity, name) in [(1000, "a"), (1001, "b"), (1002, "b")] {
+            transact(&mut transactor, entity, name);
+        }
+
+        transactor.rebuild_indices();
+        match transactor.control_recv.recv().unwrap() {
+            Control::RebuiltIndex(new_db) => transactor.switch_to_rebuilt_indexes(new_db).unwrap(),
+            _ => unreachable!(),
+        }
+
+        let db = peer_db(&store);
+        let doc = db.schema.idents["db:doc"];
+        let stats = &db.stats.attributes[&doc];
+        assert!(stats.count >= 3);
+        assert!(stats.distinct >= 2);
+        assert!(stats.fraction_below(&Value::String("b".into())) < 1.0);
+    }
+
+    #[test]
+    fn test_encrypted_attribute() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let keyring = Keyring::new([7; 32]);
+        transactor.set_keyring(keyring.clone());
+
+        let ssn = Entity(1000);
+        let person = Entity(2000);
+        transactor.process_tx(Tx {
+            items: vec![
+                TxItem::Addition(Fact::new(ssn, "db:ident", Value::Ident("ssn".into()))),
+                TxItem::Addition(Fact::new(ssn, "db:valueType", Value::Ident("db:type:string".into()))),
+                TxItem::Addition(Fact::new(ssn, "db:encrypted", Value::Boolean(true))),
+            ],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        let assert_ssn = |transactor: &mut Transactor, item: fn(Fact) -> TxItem, value: &str| {
+            transactor.process_tx(Tx {
+                items: vec![item(Fact::new(person, "ssn", value))],
+                idempotency_key: None,
+                return_datoms: false,
+            })
+        };
+        assert_ssn(&mut transactor, TxItem::Addition, "123-45-6789").unwrap();
+
+        // The store only ever sees ciphertext.
+        let stored: Vec<Value> = store.get_txs(0).unwrap().into_iter()
+            .flat_map(|tx| tx.records)
+            .filter(|r| r.attribute == ssn)
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(stored.len(), 1);
+        assert_ne!(stored[0], Value::String("123-45-6789".into()));
+
+        // A peer without the key sees the stored value; with it, the
+        // plaintext, which it can also look up by.
+        let peer = peer_db(&store);
+        assert_eq!(peer.entity(person).unwrap()["ssn"], stored);
+        let peer = peer.with_keyring(Arc::new(keyring));
+        assert_eq!(peer.entity(person).unwrap()["ssn"], vec![Value::String("123-45-6789".into())]);
+        let q = parse_query_with("find ?p where (?p ssn {})", &["123-45-6789".into()]).unwrap();
+        assert_eq!(query(q, &peer).unwrap().1, vec![vec![Value::Ref(person)]]);
+
+        assert_ssn(&mut transactor, TxItem::Retraction, "123-45-6789").unwrap();
+        assert!(transactor.current_db.entity(person).unwrap().get("ssn").is_none());
+
+        // Without a key, encrypted values can't be written, and
+        // attributes with values can't be switched to encryption.
+        let mut keyless = Transactor::new(store.clone()).unwrap();
+        assert!(assert_ssn(&mut keyless, TxItem::Addition, "987-65-4321").is_err());
+        assert!(keyless.process_tx(Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(SYSTEM_ENTITIES.start), "db:encrypted", Value::Boolean(true)))],
+            idempotency_key: None,
+            return_datoms: false,
+        }).is_err());
+    }
+
+    /// Vetoes docs saying "forbidden", and keeps a count of the docs
+    /// asserted so far on entity 2000. A transaction's docs are only
+    /// counted once it's committed.
+    struct DocCounter {
+        count: i64,
+        pending: Vec<(Entity, i64)>,
+    }
+
+    impl TxListener for DocCounter {
+        fn before_commit(&mut self, tx: &PendingTx) -> Result<Vec<TxItem>> {
+            let doc = tx.db_after.schema.idents["db:doc"];
+            let docs = tx.records.iter().filter(|r| r.attribute == doc && !r.retracted).collect::<Vec<_>>();
+            if docs.iter().any(|r| r.value == Value::String("forbidden".into())) {
+                return Err("forbidden doc".into());
+            }
+            if docs.is_empty() {
+                return Ok(vec![]);
+            }
+
+            let count = self.count + self.pending.iter().map(|p| p.1).sum::<i64>();
+            let mut items = vec![];
+            if count > 0 {
+                items.push(TxItem::Retraction(Fact::new(Entity(2000), "docCount", Value::Long(count))));
+            }
+            self.pending.push((tx.tx_entity, docs.len() as i64));
+            items.push(TxItem::Addition(Fact::new(Entity(2000), "docCount", Value::Long(count + docs.len() as i64))));
+            Ok(items)
+        }
+
+        fn after_commit(&mut self, tx_entity: Entity) {
+            self.count += self.pending.iter().filter(|p| p.0 == tx_entity).map(|p| p.1).sum::<i64>();
+            self.pending.retain(|p| p.0 != tx_entity);
+        }
+
+        fn on_abort(&mut self, tx_entity: Entity) {
+            self.pending.retain(|p| p.0 != tx_entity);
+        }
+    }
+
+    /// Vetoes docs saying "vetoed", after the listeners before it
+    /// have seen them.
+    struct LateVeto;
+
+    impl TxListener for LateVeto {
+        fn before_commit(&mut self, tx: &PendingTx) -> Result<Vec<TxItem>> {
+            if tx.records.iter().any(|r| r.value == Value::String("vetoed".into())) {
+                return Err("vetoed doc".into());
+            }
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn test_listeners_veto_and_derive_facts() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        transactor.process_tx(Tx {
+            items: vec![
+                TxItem::Addition(Fact::new(Entity(1999), "db:ident", Value::Ident("docCount".into()))),
+                TxItem::Addition(Fact::new(Entity(1999), "db:valueType", Value::Ident("db:type:long".into()))),
+            ],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        transactor.add_listener(DocCounter { count: 0, pending: vec![] });
+        transactor.add_listener(LateVeto);
+
+        transact(&mut transactor, 1000, "one");
+        transact(&mut transactor, 1001, "two");
+        for doc in &["forbidden", "vetoed"] {
+            assert!(transactor.process_tx(Tx {
+                items: vec![TxItem::Addition(Fact::new(Entity(1002), "db:doc", *doc))],
+                idempotency_key: None,
+                return_datoms: false,
+            }).is_err());
+        }
+
+        let counter = transactor.current_db.entity(Entity(2000)).unwrap();
+        assert_eq!(counter.get("docCount"), Some(&vec![Value::Long(2)]));
+        assert!(transactor.current_db.entity(Entity(1002)).unwrap().is_empty());
+        // The vetoed doc wasn't counted.
+        transact(&mut transactor, 1003, "three");
+        let counter = transactor.current_db.entity(Entity(2000)).unwrap();
+        assert_eq!(counter.get("docCount"), Some(&vec![Value::Long(3)]));
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_tx_functions() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let run = |transactor: &mut Transactor, items: Vec<TxItem>| {
+            transactor.process_tx(Tx { items, idempotency_key: None, return_datoms: false })
+        };
+        let call = |name: &str, args: Vec<Value>| TxItem::Function(name.into(), args);
+        run(&mut transactor, vec![
+            TxItem::Addition(Fact::new(Entity(1000), "db:ident", Value::Ident("balance".into()))),
+            TxItem::Addition(Fact::new(Entity(1000), "db:valueType", Value::Ident("db:type:long".into()))),
+            TxItem::Addition(Fact::new(Entity(1000), "db:cardinality", Value::Ident("db:cardinality:one".into()))),
+        ]).unwrap();
+
+        let account = Entity(2000);
+        let balance = |transactor: &Transactor| transactor.current_db.entity(account).unwrap().get("balance").cloned();
+        let args = |values: &[Value]| {
+            let mut args = vec![Value::Ref(account), Value::Ident("balance".into())];
+            args.extend(values.iter().cloned());
+            args
+        };
+        run(&mut transactor, vec![call("db:inc", args(&[Value::Long(5)]))]).unwrap();
+        // Each call sees the items before it.
+        run(&mut transactor, vec![call("db:inc", args(&[Value::Long(5)])), call("db:inc", args(&[Value::Long(-2)]))]).unwrap();
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(8)]));
+
+        assert!(run(&mut transactor, vec![call("db:cas", args(&[Value::Long(7), Value::Long(0)]))]).is_err());
+        run(&mut transactor, vec![call("db:cas", args(&[Value::Long(8), Value::Long(0)]))]).unwrap();
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(0)]));
+
+        // Numbers in the tx syntax's calls are longs.
+        run(&mut transactor, parse_tx("call (db:inc 2000 balance -5)").unwrap().items).unwrap();
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(-5)]));
+        run(&mut transactor, parse_tx("call (db:cas 2000 balance -5 0)").unwrap().items).unwrap();
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(0)]));
+
+        transactor.add_function("withdraw", |db, args| {
+            let current = match db.entity(Entity(2000))?.get("balance").map(|v| &v[0]) {
+                Some(&Value::Long(n)) => n,
+                _ => 0,
+            };
+            match args {
+                [Value::Long(n)] if *n <= current => Ok(vec![
+                    TxItem::Function("db:inc".into(), vec![Value::Ref(Entity(2000)), Value::Ident("balance".into()), Value::Long(-n)]),
+                ]),
+                _ => Err("insufficient funds".into()),
+            }
+        });
+        run(&mut transactor, vec![call("db:inc", args(&[Value::Long(10)])), call("withdraw", vec![Value::Long(4)])]).unwrap();
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(6)]));
+        assert!(run(&mut transactor, vec![call("withdraw", vec![Value::Long(7)])]).is_err());
+        assert!(run(&mut transactor, vec![call("deposit", vec![Value::Long(7)])]).is_err());
+        assert_eq!(balance(&transactor), Some(vec![Value::Long(6)]));
+
+        // A function which calls itself forever fails its transaction.
+        transactor.add_function("forever", |_, args| Ok(vec![TxItem::Function("forever".into(), args.to_vec())]));
+        match run(&mut transactor, vec![call("forever", vec![])]) {
+            Err(e) => assert!(e.message().contains("nested more than 32 deep"), "{:?}", e),
+            Ok(_) => panic!("endless calls succeeded"),
+        }
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_computed_attribute() {
+        use computed::ComputedAttribute;
+
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let mut items = vec![];
+        for (e, ident) in [(1000, "first"), (1001, "last"), (1002, "fullName")] {
+            items.push(TxItem::Addition(Fact::new(Entity(e), "db:ident", Value::Ident(ident.into()))));
+            items.push(TxItem::Addition(Fact::new(Entity(e), "db:valueType", Value::Ident("db:type:string".into()))));
+        }
+        transactor.process_tx(Tx { items, idempotency_key: None, return_datoms: false }).unwrap();
+        transactor.add_listener(ComputedAttribute::new("fullName", &["first", "last"], |values| {
+            match (values.get("first").map(|v| &v[0]), values.get("last").map(|v| &v[0])) {
+                (Some(Value::String(first)), Some(Value::String(last))) => Some(Value::String(format!("{} {}", first, last))),
+                _ => None,
+            }
+        }));
+
+        let person = Entity(2000);
+        let full_name = |transactor: &Transactor| transactor.current_db.entity(person).unwrap().get("fullName").cloned();
+        transactor.process_tx(Tx {
+            items: vec![TxItem::Addition(Fact::new(person, "first", "Ada"))],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        assert_eq!(full_name(&transactor), None);
+
+        transactor.process_tx(Tx {
+            items: vec![TxItem::Addition(Fact::new(person, "last", "Byron"))],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        assert_eq!(full_name(&transactor), Some(vec![Value::String("Ada Byron".into())]));
+
+        let tx = transactor.process_tx(Tx {
+            items: vec![
+                TxItem::Retraction(Fact::new(person, "last", "Byron")),
+                TxItem::Addition(Fact::new(person, "last", "Lovelace")),
+            ],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap().tx;
+        assert_eq!(full_name(&transactor), Some(vec![Value::String("Ada Lovelace".into())]));
+        let full_name_attr = transactor.current_db.schema.idents["fullName"];
+        let derived: Vec<(Value, bool)> = transactor.current_db.eav.iter()
+            .filter(|r| r.attribute == full_name_attr && r.tx == tx)
+            .map(|r| (r.value, r.retracted))
+            .collect();
+        assert_eq!(derived.len(), 2);
+        assert!(derived.contains(&(Value::String("Ada Lovelace".into()), false)));
+        check_same_datoms(&transactor.current_db, &peer_db(&store)).unwrap();
+    }
+
+    #[test]
+    fn test_allocated_ids_survive_restart() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let ids = transactor.allocate_ids(100).unwrap();
+        assert_eq!(ids.end - ids.start, 100);
+        drop(transactor);
+
+        // A restarted transactor doesn't hand out the reserved ids.
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let mut entity = HashMap::new();
+        entity.insert("db:doc".to_string(), TxValue::from("new"));
+        let committed = transactor.process_tx(Tx {
+            items: vec![TxItem::NewEntity(entity)],
+            idempotency_key: None,
+            return_datoms: false,
+        }).unwrap();
+        let Entity(tx) = committed.tx;
+        assert!(tx >= ids.end && committed.new_entities.iter().all(|&Entity(e)| e >= ids.end));
+        assert!(transactor.allocate_ids(0).unwrap().start > tx);
+
+        assert!(transactor.allocate_ids(u64::MAX).is_err());
+
+        // The log's positions don't skip the allocated ids.
+        let seqs: Vec<i64> = store.get_txs(-1).unwrap().iter().map(|tx| tx.seq).collect();
+        assert_eq!(seqs, (1..=seqs.len() as i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reads_metadata_without_log_positions() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        Transactor::new(store.clone()).unwrap();
+        let m = store.get_metadata().unwrap();
+
+        // The metadata as written before `last_indexed_seq` existed.
+        let old = (m.next_id, 7, m.schema.clone(), m.eav.clone(), m.ave.clone(), m.aev.clone(), m.vae.clone(), m.epoch, m.version, m.stats.clone(), m.latest_tx);
+        store.set("db_metadata", &rmp_serde::to_vec(&old).unwrap()).unwrap();
+        let metadata = store.get_metadata().unwrap();
+        assert_eq!((metadata.last_indexed_seq, metadata.indexed_seq()), (None, 7));
+    }
+
+    #[test]
+    fn test_refuses_to_start_on_inconsistent_log() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        transact(&mut transactor, 1000, "one");
+        drop(transactor);
+        let last = store.get_txs(-1).unwrap().pop().unwrap();
+
+        // A record of an attribute whose schema tx never made it
+        // into the log.
+        let id = last.id + 10;
+        store.add_tx(&TxRaw {
+            seq: last.seq + 1,
+            id,
+            epoch: last.epoch,
+            records: vec![Record::addition(Entity(1000), Entity(id + 1), "x", Entity(id))],
+        }).unwrap();
+        let err = Transactor::new(store.clone()).err().unwrap();
+        assert!(err.message().contains("attribute") && err.message().contains("no ident"), "{}", err.message());
+
+        // A record stamped with some other tx.
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        drop(Transactor::new(store.clone()).unwrap());
+        let last = store.get_txs(-1).unwrap().pop().unwrap();
+        let mut tx = last.clone();
+        tx.seq += 1;
+        tx.id += 10;
+        store.add_tx(&tx).unwrap();
+        let err = Transactor::new(store.clone()).err().unwrap();
+        assert!(err.message().contains(&format!("has a record from tx {}", last.id)), "{}", err.message());
+    }
+
+    #[test]
+    fn test_ids_are_reserved_ahead_of_use() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let reserved: DbMetadata = store.get_metadata().unwrap();
+        let mut new_entity = || {
+            let mut entity = HashMap::new();
+            entity.insert("db:doc".to_string(), TxValue::from("new"));
+            transactor.process_tx(Tx {
+                items: vec![TxItem::NewEntity(entity)],
+                idempotency_key: None,
+                return_datoms: false,
+            }).unwrap()
+        };
+        let committed: Vec<Committed> = (0..10).map(|_| new_entity()).collect();
+
+        // Had the transactor crashed before saving the metadata
+        // again, a restart would still start after every id it
+        // handed out.
+        for c in &committed {
+            assert!(c.tx.0 < reserved.next_id && c.new_entities.iter().all(|e| e.0 < reserved.next_id));
+        }
+        assert_eq!(store.get_metadata().unwrap().next_id, reserved.next_id);
+    }
+
+    #[test]
+    fn test_metadata_save_conflicts_after_another_transactor_starts() {
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let mut first = Transactor::new(store.clone()).unwrap();
+        let _second = Transactor::new(store.clone()).unwrap();
+
+        match first.allocate_ids(10) {
+            Err(Error::Conflict { ref key }) => assert_eq!(key, "db_metadata"),
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+        assert!(matches!(first.save_metadata_with_retries(), Err(Error::Conflict { .. })));
+    }
+
+    #[test]
+    fn test_system_entities_cant_be_changed() {
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        let mut transactor = Transactor::new(store).unwrap();
+        let db_ident = transactor.current_db.schema.idents["db:ident"];
+        assert!(SYSTEM_ENTITIES.contains(&db_ident.0));
+        let mut process = |item: TxItem| transactor.process_tx(Tx {
+            items: vec![item],
+            idempotency_key: None,
+            return_datoms: false,
+        });
+
+        assert!(process(TxItem::Addition(Fact::new(db_ident, "db:doc", "mine"))).is_err());
+        assert!(process(TxItem::Retraction(Fact::new(db_ident, "db:ident", Value::Ident("db:ident".into())))).is_err());
+        assert!(process(TxItem::Addition(Fact::new(Entity(0), "db:doc", "mine"))).is_err());
+
+        // Ids below the first allocated one are free for hand-picked
+        // entities, and allocation starts above them.
+        let committed = process(TxItem::Addition(Fact::new(Entity(11), "db:doc", "mine"))).unwrap();
+        assert!(committed.tx.0 >= FIRST_ID);
+    }
+
+    /// A store whose tx log reads only return transactions up to
+    /// `visible`, like an eventually consistent backend's.
+    struct LaggingStore {
+        store: Arc<SqliteStore>,
+        visible: Arc<AtomicI64>,
+    }
+
+    impl KVStore for LaggingStore {
+        fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.store.set(key, value)
+        }
+
+        fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+            self.store.compare_and_set(key, expected, value)
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>> {
+            self.store.get(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.store.delete(key)
+        }
+
+        fn add_tx(&self, raw_tx: &TxRaw) -> Result<()> {
+            self.store.add_tx(raw_tx)
+        }
+
+        fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+            let visible = self.visible.load(Ordering::SeqCst);
+            Ok(self.store.get_txs(from)?.into_iter().take_while(|tx| tx.id <= visible).collect())
+        }
+
+        fn stream_txs(&self, from: i64) -> TxStream {
+            let (store, visible) = (self.store.clone(), self.visible.clone());
+            TxStream::new(from, Box::new(move |after, limit| {
+                let visible = visible.load(Ordering::SeqCst);
+                Ok(store.get_txs(after)?.into_iter().take_while(|tx| tx.id <= visible).take(limit).collect())
+            }))
+        }
+    }
+
+    #[test]
+    fn test_staleness() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let visible = Arc::new(AtomicI64::new(i64::MAX));
+        let store: Arc<dyn KVStore> = Arc::new(LaggingStore {
+            store: Arc::new(SqliteStore::new(&uri).unwrap()),
+            visible: visible.clone(),
+        });
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        transact(&mut transactor, 1000, "seen");
+        let mut conn = Conn::open_read_only(store.clone());
+        assert_eq!(conn.db().unwrap().staleness(), 0);
+
+        // The metadata names a transaction which reading the log
+        // doesn't return yet.
+        visible.store(transactor.latest_tx, Ordering::SeqCst);
+        transact(&mut transactor, 1001, "unseen");
+        let db = conn.db().unwrap();
+        assert!(db.staleness() > 0);
+        assert_eq!(db.latest_tx, transactor.latest_tx);
+
+        conn.set_max_staleness(0, Duration::from_millis(50));
+        assert!(conn.db().is_err());
+
+        let catch_up = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            visible.store(i64::MAX, Ordering::SeqCst);
+        });
+        conn.set_max_staleness(0, Duration::from_secs(5));
+        let db = conn.db().unwrap();
+        catch_up.join().unwrap();
+        assert_eq!(db.staleness(), 0);
+        assert_eq!(db.entity(Entity(1001)).unwrap()["db:doc"], vec![Value::String("unseen".into())]);
+    }
+
+    /// Counts log appends, to see how transactions were grouped.
+    struct CountingStore {
+        store: SqliteStore,
+        appends: Mutex<Vec<usize>>,
+    }
+
+    impl KVStore for CountingStore {
+        fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.store.set(key, value)
+        }
+
+        fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+            self.store.compare_and_set(key, expected, value)
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>> {
+            self.store.get(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.store.delete(key)
+        }
+
+        fn add_tx(&self, raw_tx: &TxRaw) -> Result<()> {
+            self.add_txs(std::slice::from_ref(raw_tx))
+        }
+
+        fn add_txs(&self, raw_txs: &[TxRaw]) -> Result<()> {
+            self.appends.lock()?.push(raw_txs.len());
+            self.store.add_txs(raw_txs)
+        }
+
+        fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+            self.store.get_txs(from)
+        }
+
+        fn stream_txs(&self, from: i64) -> TxStream {
+            self.store.stream_txs(from)
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store = Arc::new(CountingStore { store: SqliteStore::new(&uri).unwrap(), appends: Mutex::new(vec![]) });
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        store.appends.lock().unwrap().clear();
+
+        // Queued before the transactor runs, so they're one group.
+        let doc = |entity, doc: &str, key: Option<&str>| Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(entity), "db:doc", doc))],
+            idempotency_key: key.map(String::from),
+            return_datoms: true,
+        };
+        let txs = vec![
+            doc(1000, "first", Some("key")),
+            Tx { items: vec![TxItem::Addition(Fact::new(Entity(1001), "nonexistent", "x"))], ..doc(0, "", None) },
+            doc(1002, "retried", Some("key")),
+            doc(1003, "last", None),
+        ];
+        let reports: Vec<_> = txs.into_iter().map(|tx| {
+            let (report_send, report_recv) = mpsc::channel();
+            transactor.send.send(Event::Tx(tx, report_send)).unwrap();
+            report_recv
+        }).collect();
+        let handle = TxHandle::new(&transactor);
+        let running = thread::spawn(move || transactor.run().unwrap());
+        let reports: Vec<TxReport> = reports.iter().map(|r| r.recv().unwrap()).collect();
+        handle.close().unwrap();
+        running.join().unwrap();
+
+        // Each transaction succeeds or fails on its own, and a retry
+        // of a transaction in the same group sees it.
+        assert!(matches!(reports[1], TxReport::Failure(_)));
+        assert_eq!(reports[2], reports[0]);
+        let committed: Vec<i64> = [&reports[0], &reports[3]].iter().map(|report| match **report {
+            TxReport::Success { tx: Entity(tx), datoms: Some(_), .. } => tx,
+            ref report => panic!("expected success, got {:?}", report),
+        }).collect();
+
+        assert_eq!(*store.appends.lock().unwrap(), vec![2]);
+        let log = store.get_txs(-1).unwrap();
+        let logged: Vec<i64> = log[log.len() - 2..].iter().map(|tx| tx.id).collect();
+        assert_eq!(logged, committed);
+        assert!(store.get_metadata().unwrap().next_id > committed[1]);
+    }
+
+    #[test]
+    fn test_stop_preempts_queued_txs() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(&uri).unwrap());
+        let mut transactor = Transactor::new(store).unwrap();
+        let handle = TxHandle::new(&transactor);
+
+        let (report_send, report_recv) = mpsc::channel();
+        let tx = Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(1000), "db:doc", "queued"))],
+            idempotency_key: None,
+            return_datoms: false,
+        };
+        transactor.send.send(Event::Tx(tx, report_send)).unwrap();
+        handle.close().unwrap();
+
+        transactor.run().unwrap();
+        drop(transactor);
+        assert!(report_recv.recv().is_err());
+    }
+
+    /// Fails the next `failures` metadata writes.
+    struct FlakyMetadataStore {
+        store: SqliteStore,
+        failures: AtomicI64,
+    }
+
+    impl KVStore for FlakyMetadataStore {
+        fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.store.set(key, value)
+        }
+
+        fn compare_and_set(&self, key: &str, expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+            if key == "db_metadata" && self.failures.fetch_sub(1, Ordering::SeqCst) > 0 {
+                return Err("metadata unavailable".into());
+            }
+            self.store.compare_and_set(key, expected, value)
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>> {
+            self.store.get(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.store.delete(key)
+        }
+
+        fn add_tx(&self, raw_tx: &TxRaw) -> Result<()> {
+            self.store.add_tx(raw_tx)
+        }
+
+        fn add_txs(&self, raw_txs: &[TxRaw]) -> Result<()> {
+            self.store.add_txs(raw_txs)
+        }
+
+        fn get_txs(&self, from: i64) -> Result<Vec<TxRaw>> {
+            self.store.get_txs(from)
+        }
+
+        fn stream_txs(&self, from: i64) -> TxStream {
+            self.store.stream_txs(from)
+        }
+    }
+
+    #[test]
+    fn test_metadata_save_failure_after_log_append() {
+        let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+        let store = Arc::new(FlakyMetadataStore { store: SqliteStore::new(&uri).unwrap(), failures: AtomicI64::new(0) });
+        let mut transactor = Transactor::new(store.clone()).unwrap();
+        let doc = |entity, doc: &str| Tx {
+            items: vec![TxItem::Addition(Fact::new(Entity(entity), "db:doc", doc))],
+            idempotency_key: None,
+            return_datoms: false,
+        };
+
+        // A save that fails fewer times than it's tried is retried.
+        store.failures.store(METADATA_SAVE_ATTEMPTS as i64 - 1, Ordering::SeqCst);
+        let tx = transactor.process_txs(vec![doc(1000, "retried")]).pop().unwrap().unwrap().tx;
+        assert!(transactor.halted.is_none());
+        assert!(store.get_metadata().unwrap().next_id > tx.0);
+
+        // One that keeps failing still leaves the tx committed, since
+        // it's in the log, but stops the transactor.
+        store.failures.store(i64::MAX, Ordering::SeqCst);
+        let (report_send, report_recv) = mpsc::channel();
+        transactor.send.send(Event::Tx(doc(1001, "durable"), report_send)).unwrap();
+        let running = thread::spawn(move || transactor.run());
+        assert!(matches!(report_recv.recv().unwrap(), TxReport::Success { .. }));
+        assert!(running.join().unwrap().is_err());
+
+        store.failures.store(0, Ordering::SeqCst);
+        let restarted = Transactor::new(store.clone()).unwrap();
+        assert_eq!(restarted.current_db.entity(Entity(1001)).unwrap()["db:doc"], vec![Value::String("durable".into())]);
+    }
 }
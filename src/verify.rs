@@ -0,0 +1,61 @@
+//! Checks everything a database has stored against its checksums
+//! (see `checksum`), to find corruption before a query or a reindex
+//! runs into it. `clio-admin verify` prints the corrupt keys found.
+
+use std::collections::HashSet;
+
+use backends::KVStore;
+use db::DbMetadata;
+use durable_tree::verify_tree;
+use {Error, Record, Result};
+
+/// Checks the metadata, every segment of each index's durable tree,
+/// and every entry in the tx log, returning the keys of those which
+/// are corrupt. The indices can't be found without the metadata, so
+/// if it's corrupt only the log is checked.
+pub fn verify(store: &dyn KVStore) -> Result<Vec<String>> {
+    let mut corrupt = vec![];
+    match DbMetadata::from_stored(&store.get("db_metadata")?) {
+        Ok(metadata) => {
+            let mut seen = HashSet::new();
+            for root in &[metadata.eav, metadata.ave, metadata.aev, metadata.vae, metadata.tea] {
+                if !root.is_empty() {
+                    corrupt.extend(verify_tree::<Record>(root, store, &mut seen)?);
+                }
+            }
+        }
+        Err(Error::Corruption { key }) => corrupt.push(key),
+        Err(e) => return Err(e),
+    }
+    corrupt.extend(store.corrupt_txs()?);
+    Ok(corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use backends::sqlite::SqliteStore;
+    use tx::Transactor;
+
+    #[test]
+    fn test_verify_reports_corrupt_keys() {
+        let store: Arc<dyn KVStore> = Arc::new(SqliteStore::new(":memory:").unwrap());
+        Transactor::new(store.clone()).unwrap();
+        assert!(verify(&*store).unwrap().is_empty());
+
+        let flip_last_byte = |key: &str| {
+            let mut blob = store.get(key).unwrap();
+            *blob.last_mut().unwrap() ^= 1;
+            store.set(key, &blob).unwrap();
+        };
+        let root = store.get_metadata().unwrap().aev;
+        flip_last_byte(&root);
+        assert_eq!(verify(&*store).unwrap(), vec![root]);
+
+        flip_last_byte("db_metadata");
+        assert_eq!(verify(&*store).unwrap(), vec!["db_metadata".to_string()]);
+    }
+}